@@ -0,0 +1,88 @@
+use clap::{App as Config, Arg, ArgMatches};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use super::common;
+use super::import_users::uuid_arg;
+use lib::db::pg;
+use lib::realms::RealmService;
+use lib::result::{Context, Error, Result};
+use lib::seed::export::{ExportOptions, ExportService};
+
+pub async fn cmd(args: Option<&ArgMatches<'_>>) -> Result<()> {
+    let database_args = common::database_args(args)?.into();
+    let args = args.ok_or_else(|| Error::msg("missing args for export"))?;
+
+    let path = args.value_of("file").unwrap_or("-");
+    let options = ExportOptions {
+        secrets: args.is_present("secrets"),
+    };
+
+    let pool = pg::new(database_args).await?;
+
+    let realm_id = match (uuid_arg(args, "realm-id")?, args.value_of("realm")) {
+        (Some(realm_id), _) => realm_id,
+        (None, Some(name)) => RealmService::new(&pool)?
+            .by_name(name.to_owned())
+            .await
+            .with_context(|| format!("no realm named {}", name))?
+            .id,
+        (None, None) => return Err(Error::msg("no realm or realm id provided")),
+    };
+
+    let realm = ExportService::new(&pool)?.export(realm_id, &options).await?;
+
+    match path {
+        "-" => {
+            let stdout = io::stdout();
+            let mut writer = stdout.lock();
+
+            serde_json::to_writer_pretty(&mut writer, &realm)?;
+            writeln!(writer)?;
+        },
+        path => {
+            let file = File::create(path).with_context(|| format!("unable to create {}", path))?;
+            let mut writer = BufWriter::new(file);
+
+            serde_json::to_writer_pretty(&mut writer, &realm)?;
+            writeln!(writer)?;
+            writer.flush()?;
+        },
+    }
+
+    Ok(())
+}
+
+pub fn cfg() -> Config<'static, 'static> {
+    let cfg = Config::new("export")
+        .about("Export a realm as a seed file that setup --seed-files can load")
+        .arg(
+            Arg::with_name("realm")
+                .long("realm")
+                .takes_value(true)
+                .required_unless("realm-id")
+                .help("the realm's name"),
+        )
+        .arg(
+            Arg::with_name("realm-id")
+                .long("realm-id")
+                .takes_value(true)
+                .conflicts_with("realm"),
+        )
+        .arg(
+            Arg::with_name("file")
+                .long("file")
+                .short("f")
+                .takes_value(true)
+                .help("path to write to, or - for stdout"),
+        )
+        .arg(
+            Arg::with_name("secrets")
+                .long("secrets")
+                .help("include authority salts and password digests so users can still sign in"),
+        );
+
+    let cfg = common::database_cfg(cfg);
+
+    cfg
+}
@@ -0,0 +1,75 @@
+use clap::{App as Config, Arg, ArgMatches};
+use std::fs::File;
+use std::io::{self, BufWriter};
+
+use super::common;
+use super::import_users::{batch_size_arg, format_arg, uuid_arg};
+use lib::bulk::{BulkService, RecordWriter};
+use lib::db::pg;
+use lib::result::{Context, Error, Result};
+
+pub async fn cmd(args: Option<&ArgMatches<'_>>) -> Result<()> {
+    let database_args = common::database_args(args)?.into();
+    let args = args.ok_or_else(|| Error::msg("missing args for export-users"))?;
+
+    let path = args.value_of("file").unwrap_or("-");
+    let format = format_arg(args, path)?;
+    let realm_id = uuid_arg(args, "realm-id")?.ok_or_else(|| Error::msg("no realm id provided"))?;
+    let batch_size = batch_size_arg(args)?;
+
+    let pool = pg::new(database_args).await?;
+    let service = BulkService::new(&pool)?;
+
+    let report = match path {
+        "-" => {
+            let stdout = io::stdout();
+            let mut writer = RecordWriter::new(stdout.lock(), format);
+
+            service.export(realm_id, &mut writer, batch_size).await?
+        },
+        path => {
+            let file = File::create(path).with_context(|| format!("unable to create {}", path))?;
+            let mut writer = RecordWriter::new(BufWriter::new(file), format);
+
+            service.export(realm_id, &mut writer, batch_size).await?
+        },
+    };
+
+    // stdout may be the export itself
+    eprintln!("{}", serde_json::to_string_pretty(&report)?);
+
+    Ok(())
+}
+
+pub fn cfg() -> Config<'static, 'static> {
+    let cfg = Config::new("export-users")
+        .about("Export a realm's users and the roles and permissions granted to them as csv or jsonl")
+        .arg(
+            Arg::with_name("realm-id")
+                .long("realm-id")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("file")
+                .long("file")
+                .short("f")
+                .takes_value(true)
+                .help("path to write to, or - for stdout"),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .help("csv or jsonl; taken from the file's extension when left out"),
+        )
+        .arg(
+            Arg::with_name("batch-size")
+                .long("batch-size")
+                .takes_value(true),
+        );
+
+    let cfg = common::database_cfg(cfg);
+
+    cfg
+}
@@ -0,0 +1,116 @@
+use clap::{App as Config, Arg, ArgMatches};
+use std::fs::File;
+use std::io::{self, BufReader};
+use uuid::Uuid;
+
+use super::common;
+use lib::audit::Actor;
+use lib::bulk::{self, BulkService, Format, ImportOptions};
+use lib::db::pg;
+use lib::result::{Context, Error, Result};
+
+pub async fn cmd(args: Option<&ArgMatches<'_>>) -> Result<()> {
+    let database_args = common::database_args(args)?.into();
+    let args = args.ok_or_else(|| Error::msg("missing args for import-users"))?;
+
+    let path = args.value_of("file").unwrap_or("-");
+    let format = format_arg(args, path)?;
+
+    let options = ImportOptions {
+        realm_id: uuid_arg(args, "realm-id")?.ok_or_else(|| Error::msg("no realm id provided"))?,
+        authority_id: uuid_arg(args, "authority-id")?,
+        batch_size: batch_size_arg(args)?,
+        dry_run: args.is_present("dry-run"),
+    };
+
+    let pool = pg::new(database_args).await?;
+    let service = BulkService::new(&pool)?;
+
+    let report = match path {
+        "-" => {
+            let stdin = io::stdin();
+            let records = bulk::records(stdin.lock(), format);
+
+            service.import(&Actor::system(), &options, records).await?
+        },
+        path => {
+            let file = File::open(path).with_context(|| format!("unable to open {}", path))?;
+            let records = bulk::records(BufReader::new(file), format);
+
+            service.import(&Actor::system(), &options, records).await?
+        },
+    };
+
+    println!("{}", serde_json::to_string_pretty(&report)?);
+
+    if report.failed > 0 {
+        return Err(Error::msg(format!("{} of {} rows failed to import", report.failed, report.total)));
+    }
+
+    Ok(())
+}
+
+pub fn cfg() -> Config<'static, 'static> {
+    let cfg = Config::new("import-users")
+        .about("Import users, their roles and bcrypt digests into a realm from csv or jsonl")
+        .arg(
+            Arg::with_name("realm-id")
+                .long("realm-id")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("file")
+                .long("file")
+                .short("f")
+                .takes_value(true)
+                .help("path to read from, or - for stdin"),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .help("csv or jsonl; taken from the file's extension when left out"),
+        )
+        .arg(
+            Arg::with_name("authority-id")
+                .long("authority-id")
+                .takes_value(true)
+                .help("the username_password authority password digests are attached to"),
+        )
+        .arg(
+            Arg::with_name("batch-size")
+                .long("batch-size")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help("check every row against the database without keeping any of it"),
+        );
+
+    let cfg = common::database_cfg(cfg);
+
+    cfg
+}
+
+pub fn format_arg(args: &ArgMatches<'_>, path: &str) -> Result<Format> {
+    match args.value_of("format") {
+        Some(format) => format.parse(),
+        None => Format::from_path(path).ok_or_else(|| Error::msg("no format provided and none in the file name")),
+    }
+}
+
+pub fn uuid_arg(args: &ArgMatches<'_>, name: &str) -> Result<Option<Uuid>> {
+    match args.value_of(name) {
+        Some(value) => Ok(Some(value.parse().with_context(|| format!("{} isn't a uuid", name))?)),
+        None => Ok(None),
+    }
+}
+
+pub fn batch_size_arg(args: &ArgMatches<'_>) -> Result<usize> {
+    match args.value_of("batch-size") {
+        Some(value) => value.parse().context("batch-size isn't a number"),
+        None => Ok(bulk::DEFAULT_BATCH_SIZE),
+    }
+}
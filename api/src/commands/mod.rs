@@ -1,5 +1,9 @@
 mod common;
 
+pub mod export;
+pub mod export_users;
+pub mod import_users;
 pub mod migrate;
 pub mod server;
 pub mod setup;
+pub mod sync;
@@ -27,7 +27,7 @@ pub async fn cmd(args: Option<&ArgMatches<'_>>) -> Result<()> {
     .context("unable to seed oxidauth base data")?;
 
     if let Some(seed_files) = seed_files {
-        for seed_file in seed_files.split(",").into_iter() {
+        for seed_file in seed_files.split(",") {
             let file_str = std::fs::read_to_string(seed_file)?;
             let mut realm = seed::json::from_bytes(&file_str)?;
 
@@ -84,9 +84,10 @@ pub fn cfg() -> Config<'static, 'static> {
     cfg
 }
 
-fn setup_args<'a>(
-    args: Option<&'a ArgMatches<'_>>,
-) -> Result<(&'a str, &'a str, &'a str, &'a str, &'a str, Option<&'a str>)> {
+// username, email, password, password salt, client key and seed files
+type SetupArgs<'a> = (&'a str, &'a str, &'a str, &'a str, &'a str, Option<&'a str>);
+
+fn setup_args<'a>(args: Option<&'a ArgMatches<'_>>) -> Result<SetupArgs<'a>> {
     if args.is_none() {
         return Err(Error::msg("missing args for setup_args"));
     }
@@ -0,0 +1,70 @@
+use clap::{App as Config, Arg, ArgMatches};
+
+use super::common;
+use lib::audit::Actor;
+use lib::db::pg;
+use lib::result::{Context, Error, Result};
+use lib::seed::json;
+use lib::seed::sync::{SyncOptions, SyncService};
+
+pub async fn cmd(args: Option<&ArgMatches<'_>>) -> Result<()> {
+    let database_args = common::database_args(args)?.into();
+    let args = args.ok_or_else(|| Error::msg("missing args for sync"))?;
+
+    let path = args.value_of("file").ok_or_else(|| Error::msg("no file provided"))?;
+    let file_str = std::fs::read_to_string(path).with_context(|| format!("unable to read {}", path))?;
+    let realm = json::from_bytes(&file_str).with_context(|| format!("unable to parse {}", path))?;
+
+    let options = SyncOptions {
+        prune: args.is_present("prune"),
+        users: args.is_present("users"),
+    };
+
+    let pool = pg::new(database_args).await?;
+    let service = SyncService::new(&pool)?;
+
+    let plan = service.plan(&realm, &options).await?;
+
+    print!("{}", plan);
+
+    if !args.is_present("apply") || plan.is_empty() {
+        return Ok(());
+    }
+
+    service.apply(&Actor::system(), &plan).await?;
+
+    println!("applied {} changes to realm {}", plan.changes.len(), plan.realm);
+
+    Ok(())
+}
+
+pub fn cfg() -> Config<'static, 'static> {
+    let cfg = Config::new("sync")
+        .about("Plan, and with --apply make, the changes that bring a realm in line with a seed file")
+        .arg(
+            Arg::with_name("file")
+                .long("file")
+                .short("f")
+                .takes_value(true)
+                .required(true),
+        )
+        .arg(
+            Arg::with_name("apply")
+                .long("apply")
+                .help("make the planned changes, all in one transaction"),
+        )
+        .arg(
+            Arg::with_name("prune")
+                .long("prune")
+                .help("remove roles, permissions, authorities and grants the file doesn't declare"),
+        )
+        .arg(
+            Arg::with_name("users")
+                .long("users")
+                .help("sync the users in the file as well"),
+        );
+
+    let cfg = common::database_cfg(cfg);
+
+    cfg
+}
@@ -6,7 +6,6 @@ extern crate clap;
 extern crate serde_derive;
 
 use clap::App as Config;
-use dotenv;
 use std::path::Path;
 
 use lib::result::Result;
@@ -14,7 +13,7 @@ use lib::result::Result;
 mod commands;
 mod server;
 
-pub const APP_NAME: &'static str = "oxidauth";
+pub const APP_NAME: &str = "oxidauth";
 
 #[actix_web::main]
 async fn main() -> Result<()> {
@@ -29,6 +28,10 @@ async fn main() -> Result<()> {
         .subcommand(commands::migrate::cfg())
         .subcommand(commands::setup::cfg())
         .subcommand(commands::server::cfg())
+        .subcommand(commands::import_users::cfg())
+        .subcommand(commands::export_users::cfg())
+        .subcommand(commands::export::cfg())
+        .subcommand(commands::sync::cfg())
         .get_matches();
 
     use commands::*;
@@ -40,6 +43,10 @@ async fn main() -> Result<()> {
             }
         }
         ("server", args) => server::cmd(args).await?,
+        ("import-users", args) => import_users::cmd(args).await?,
+        ("export-users", args) => export_users::cmd(args).await?,
+        ("export", args) => export::cmd(args).await?,
+        ("sync", args) => sync::cmd(args).await?,
         _ => {}
     }
 
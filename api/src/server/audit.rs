@@ -0,0 +1,31 @@
+use super::common::{authorize, realm_permission, Response};
+use actix_web::{web, HttpResponse};
+use lib::audit::{AuditFilter, AuditService};
+use lib::db::page::PageParams;
+use lib::jwt::Claims;
+
+pub fn mount(cfg: &mut web::ServiceConfig) {
+    cfg.route("/audit", web::get().to(list));
+}
+
+async fn list(
+    claims: Claims,
+    filter: web::Query<AuditFilter>,
+    page: web::Query<PageParams>,
+    service: web::Data<AuditService>,
+) -> HttpResponse {
+    // a realm's own admins can read its events; everything else takes the
+    // global permission
+    let permission = match filter.realm_id {
+        Some(realm_id) => realm_permission(realm_id, "audit", "read"),
+        None => "oxidauth:audit:read".to_string(),
+    };
+
+    if let Err(err) = authorize(&claims, &permission) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.query(&filter, &page).await;
+
+    Response::from_result(result).json()
+}
@@ -1,6 +1,12 @@
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
 use uuid::Uuid;
 use lib::http_response::Response;
+use lib::jwt::Claims;
+use lib::permissions::permission::Permission;
+use lib::users::UserService;
 use actix_web::{web, HttpResponse};
+use lib::audit::Actor;
 use lib::{AuthorityService, User, authorities::strategies::Authority};
 use lib::db::pg::Pool;
 use lib::result::{Error, Result};
@@ -16,6 +22,7 @@ pub fn mount(cfg: &mut web::ServiceConfig) {
     cfg.route("/register", web::post().to(register));
     cfg.route("/authenticate", web::post().to(authenticate));
     cfg.route("/public_keys/{client_key}", web::get().to(public_keys));
+    cfg.route("/can", web::post().to(can_with_context));
     cfg.route("/can/{challenge}", web::get().to(can));
 }
 
@@ -31,19 +38,19 @@ enum AuthParams {
     UsernamePassword(UsernamePasswordAuthParams),
 }
 
-async fn register(service: web::Data<UsernamePasswordService>, params: web::Json<RegisterParams>) -> HttpResponse {
+async fn register(actor: Actor, service: web::Data<UsernamePasswordService>, params: web::Json<RegisterParams>) -> HttpResponse {
     use RegisterParams::*;
     let result = match params.into_inner() {
-        UsernamePassword(params) => service.register(params.client_key, params).await,
+        UsernamePassword(params) => service.register(&actor, params.client_key, params).await,
     };
 
     Response::from_result(result).json()
 }
 
-async fn authenticate(service: web::Data<UsernamePasswordService>, params: web::Json<AuthParams>) -> HttpResponse {
+async fn authenticate(actor: Actor, service: web::Data<UsernamePasswordService>, params: web::Json<AuthParams>) -> HttpResponse {
     use AuthParams::*;
     let result = match params.into_inner() {
-        UsernamePassword(params) => service.authenticate(params).await,
+        UsernamePassword(params) => service.authenticate(&actor, params).await,
     };
 
     Response::from_result(result).json()
@@ -55,6 +62,48 @@ async fn public_keys(service: web::Data<AuthorityService>, params: web::Path<Uui
     Response::from_result(result).json()
 }
 
-async fn can(params: web::Json<()>) -> HttpResponse {
-    HttpResponse::Ok().finish()
+#[derive(Deserialize)]
+struct CanParams {
+    challenge: String,
+    #[serde(default)]
+    context: JsonValue,
+}
+
+#[derive(Serialize)]
+struct CanResult {
+    allowed: bool,
+}
+
+// the query string is the request context for conditional grants
+async fn can(claims: Claims, challenge: web::Path<String>, context: web::Query<HashMap<String, String>>, users: web::Data<UserService>) -> HttpResponse {
+    let result = match serde_json::to_value(context.into_inner()) {
+        Ok(context) => check(&claims, &challenge, &context, &users).await,
+        Err(err) => Err(err.into()),
+    };
+
+    Response::from_result(result).json()
+}
+
+async fn can_with_context(claims: Claims, params: web::Json<CanParams>, users: web::Data<UserService>) -> HttpResponse {
+    let CanParams { challenge, context } = params.into_inner();
+
+    let result = check(&claims, &challenge, &context, &users).await;
+
+    Response::from_result(result).json()
+}
+
+async fn check(claims: &Claims, challenge: &str, context: &JsonValue, users: &UserService) -> Result<CanResult> {
+    let permission: Permission = challenge.parse()?;
+
+    // the profile is only worth loading when a condition might look at it
+    let profile = match claims.sub {
+        Some(user_id) if !claims.conditional_grants.is_empty() => users.by_id(user_id).await?.profile,
+        _ => JsonValue::Null,
+    };
+
+    let grants = claims.permission_set_with(&profile, context)?;
+
+    Ok(CanResult {
+        allowed: grants.can(&permission) && claims.reaches(&permission),
+    })
 }
@@ -1,6 +1,10 @@
-use super::common::Response;
+use super::common::{authorize, ensure_realm, realm_permission, versioned, Response};
 use actix_web::{web, HttpResponse};
-use lib::authorities::{AuthorityCreate, AuthorityService, AuthorityUpdate};
+use lib::audit::Actor;
+use lib::db::page::PageParams;
+use lib::db::patch::Precondition;
+use lib::jwt::Claims;
+use lib::authorities::{AuthorityCreate, AuthorityFilter, AuthorityService, AuthorityUpdate};
 use uuid::Uuid;
 
 pub fn mount(cfg: &mut web::ServiceConfig) {
@@ -13,37 +17,156 @@ pub fn mount(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::resource("/authorities/{id}")
             .route(web::get().to(show))
-            .route(web::post().to(update)),
+            .route(web::post().to(update))
+            .route(web::patch().to(update))
+            .route(web::delete().to(delete)),
+    );
+
+    cfg.service(
+        web::resource("/realms/{realm_id}/authorities")
+            .route(web::get().to(realm_list))
+            .route(web::post().to(realm_create)),
+    );
+
+    cfg.service(
+        web::resource("/realms/{realm_id}/authorities/{id}")
+            .route(web::get().to(realm_show)),
     );
 }
 
-async fn list(service: web::Data<AuthorityService>) -> HttpResponse {
-    let result = service.all().await;
+async fn list(
+    claims: Claims,
+    filter: web::Query<AuthorityFilter>,
+    page: web::Query<PageParams>,
+    service: web::Data<AuthorityService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:authorities:read") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.list(&filter, &page).await;
 
     Response::from_result(result).json()
 }
 
 async fn create(
+    claims: Claims,
+    actor: Actor,
     params: web::Json<AuthorityCreate>,
     service: web::Data<AuthorityService>,
 ) -> HttpResponse {
-    let result = service.create(params.into_inner()).await;
+    if let Err(err) = authorize(&claims, "oxidauth:authorities:create") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.create(&actor, params.into_inner()).await;
 
     Response::from_result(result).json()
 }
 
-async fn show(params: web::Path<Uuid>, service: web::Data<AuthorityService>) -> HttpResponse {
+async fn show(
+    claims: Claims,
+    params: web::Path<Uuid>,
+    service: web::Data<AuthorityService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:authorities:read") {
+        return Response::<()>::error(err).forbidden();
+    }
+
     let result = service.by_id(params.into_inner()).await;
 
-    Response::from_result(result).json()
+    versioned(result)
 }
 
 async fn update(
+    claims: Claims,
+    actor: Actor,
+    precondition: Precondition,
     id: web::Path<Uuid>,
     params: web::Json<AuthorityUpdate>,
     service: web::Data<AuthorityService>,
 ) -> HttpResponse {
-    let result = service.update(id.into_inner(), params.into_inner()).await;
+    if let Err(err) = authorize(&claims, "oxidauth:authorities:update") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.update(&actor, id.into_inner(), params.into_inner(), &precondition).await;
+
+    versioned(result)
+}
+
+async fn delete(
+    claims: Claims,
+    actor: Actor,
+    id: web::Path<Uuid>,
+    service: web::Data<AuthorityService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:authorities:delete") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.delete(&actor, id.into_inner()).await;
+
+    Response::from_result(result).json()
+}
+
+async fn realm_list(
+    claims: Claims,
+    realm_id: web::Path<Uuid>,
+    filter: web::Query<AuthorityFilter>,
+    page: web::Query<PageParams>,
+    service: web::Data<AuthorityService>,
+) -> HttpResponse {
+    let realm_id = realm_id.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "authorities", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let mut filter = filter.into_inner();
+    filter.realm_id = Some(realm_id);
+
+    let result = service.list(&filter, &page).await;
+
+    Response::from_result(result).json()
+}
+
+async fn realm_create(
+    claims: Claims,
+    actor: Actor,
+    realm_id: web::Path<Uuid>,
+    params: web::Json<AuthorityCreate>,
+    service: web::Data<AuthorityService>,
+) -> HttpResponse {
+    let realm_id = realm_id.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "authorities", "create")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let mut params = params.into_inner();
+    params.realm_id = realm_id;
+
+    let result = service.create(&actor, params).await;
 
     Response::from_result(result).json()
 }
+
+async fn realm_show(
+    claims: Claims,
+    params: web::Path<(Uuid, Uuid)>,
+    service: web::Data<AuthorityService>,
+) -> HttpResponse {
+    let (realm_id, id) = params.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "authorities", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service
+        .by_id(id)
+        .await
+        .and_then(|authority| ensure_realm(realm_id, authority.realm_id).map(|_| authority));
+
+    versioned(result)
+}
@@ -1,6 +1,10 @@
 use lib::result::{Error, Result};
-use actix_web::HttpResponse;
+use lib::db::patch::{etag, Stale, Versioned};
+use lib::jwt::Claims;
+use lib::permissions::permission::Permission;
+use actix_web::{http::header, HttpResponse};
 use serde::Serialize;
+use uuid::Uuid;
 
 #[derive(Serialize)]
 pub struct Response<T>{
@@ -51,4 +55,71 @@ impl<T: Serialize> Response<T> {
         HttpResponse::Ok()
             .json(self)
     }
+
+    pub fn forbidden(&self) -> HttpResponse {
+        HttpResponse::Forbidden()
+            .json(self)
+    }
+
+    pub fn not_found(&self) -> HttpResponse {
+        HttpResponse::NotFound()
+            .json(self)
+    }
+}
+
+// a 404 when the lookup found nothing, the usual error response otherwise
+pub fn missing(err: Error) -> HttpResponse {
+    if matches!(err.downcast_ref::<sqlx::Error>(), Some(sqlx::Error::RowNotFound)) {
+        return Response::<()>::error(err).not_found();
+    }
+
+    Response::<()>::error(err).json()
+}
+
+// like `from_result(..).json()`, but with the ETag an If-Match can send back
+// and a 412 when an update was made against an old version
+pub fn versioned<T: Serialize + Versioned>(result: Result<T>) -> HttpResponse {
+    match result {
+        Ok(payload) => {
+            let mut response = HttpResponse::Ok();
+
+            if let Some(version) = payload.version() {
+                response.set_header(header::ETAG, etag(version));
+            }
+
+            response.json(Response::payload(payload))
+        },
+        Err(err) if err.is::<Stale>() => HttpResponse::PreconditionFailed().json(Response::<()>::error(err)),
+        Err(err) => Response::<()>::error(err).json(),
+    }
+}
+
+pub fn authorize(claims: &Claims, challenge: &str) -> Result<()> {
+    let permission: Permission = challenge.parse()?;
+    let grants = claims.permission_set()?;
+
+    if grants.can(&permission) && claims.reaches(&permission) {
+        return Ok(());
+    }
+
+    Err(Error::msg(format!("missing permission: {}", challenge)))
+}
+
+// the user a self-service route acts on, once they're allowed to use it
+pub fn caller(claims: &Claims, challenge: &str) -> Result<Uuid> {
+    authorize(claims, challenge)?;
+
+    claims.sub.ok_or_else(|| Error::msg("token has no subject"))
+}
+
+pub fn realm_permission(realm_id: Uuid, resource: &str, action: &str) -> String {
+    format!("oxidauth:realms.{}.{}:{}", realm_id, resource, action)
+}
+
+pub fn ensure_realm(realm_id: Uuid, resource_realm_id: Uuid) -> Result<()> {
+    if realm_id != resource_realm_id {
+        return Err(Error::msg(format!("not found in realm {}", realm_id)));
+    }
+
+    Ok(())
 }
@@ -0,0 +1,179 @@
+use super::common::{authorize, ensure_realm, realm_permission, Response};
+use actix_web::{web, HttpResponse};
+use lib::audit::Actor;
+use lib::db::page::PageParams;
+use lib::domains::{DomainCreate, DomainFilter, DomainService};
+use lib::jwt::Claims;
+use uuid::Uuid;
+
+pub fn mount(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/domains")
+            .route(web::get().to(list))
+            .route(web::post().to(create)),
+    );
+
+    cfg.service(
+        web::resource("/domains/{name}")
+            .route(web::get().to(show))
+            .route(web::delete().to(delete)),
+    );
+
+    cfg.service(
+        web::resource("/realms/{realm_id}/domains")
+            .route(web::get().to(realm_list))
+            .route(web::post().to(realm_create)),
+    );
+
+    cfg.service(
+        web::resource("/realms/{realm_id}/domains/{name}")
+            .route(web::get().to(realm_show))
+            .route(web::delete().to(realm_delete)),
+    );
+}
+
+async fn list(
+    claims: Claims,
+    filter: web::Query<DomainFilter>,
+    page: web::Query<PageParams>,
+    service: web::Data<DomainService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:domains:read") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.list(&filter, &page).await;
+
+    Response::from_result(result).json()
+}
+
+async fn create(
+    claims: Claims,
+    actor: Actor,
+    params: web::Json<DomainCreate>,
+    service: web::Data<DomainService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:domains:create") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.create(&actor, params.into_inner()).await;
+
+    Response::from_result(result).json()
+}
+
+async fn show(
+    claims: Claims,
+    name: web::Path<String>,
+    service: web::Data<DomainService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:domains:read") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.by_name(name.into_inner()).await;
+
+    Response::from_result(result).json()
+}
+
+async fn delete(
+    claims: Claims,
+    actor: Actor,
+    name: web::Path<String>,
+    service: web::Data<DomainService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:domains:delete") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.delete(&actor, name.into_inner()).await;
+
+    Response::from_result(result).json()
+}
+
+async fn realm_list(
+    claims: Claims,
+    realm_id: web::Path<Uuid>,
+    filter: web::Query<DomainFilter>,
+    page: web::Query<PageParams>,
+    service: web::Data<DomainService>,
+) -> HttpResponse {
+    let realm_id = realm_id.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "domains", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let mut filter = filter.into_inner();
+    filter.realm_id = Some(realm_id);
+
+    let result = service.list(&filter, &page).await;
+
+    Response::from_result(result).json()
+}
+
+async fn realm_create(
+    claims: Claims,
+    actor: Actor,
+    realm_id: web::Path<Uuid>,
+    params: web::Json<DomainCreate>,
+    service: web::Data<DomainService>,
+) -> HttpResponse {
+    let realm_id = realm_id.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "domains", "create")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let mut params = params.into_inner();
+    params.realm_id = realm_id;
+
+    let result = service.create(&actor, params).await;
+
+    Response::from_result(result).json()
+}
+
+async fn realm_show(
+    claims: Claims,
+    params: web::Path<(Uuid, String)>,
+    service: web::Data<DomainService>,
+) -> HttpResponse {
+    let (realm_id, name) = params.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "domains", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service
+        .by_name(name)
+        .await
+        .and_then(|domain| ensure_realm(realm_id, domain.realm_id).map(|_| domain));
+
+    Response::from_result(result).json()
+}
+
+async fn realm_delete(
+    claims: Claims,
+    actor: Actor,
+    params: web::Path<(Uuid, String)>,
+    service: web::Data<DomainService>,
+) -> HttpResponse {
+    let (realm_id, name) = params.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "domains", "delete")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let domain = service
+        .by_name(name.clone())
+        .await
+        .and_then(|domain| ensure_realm(realm_id, domain.realm_id));
+
+    if let Err(err) = domain {
+        return Response::<()>::error(err).json();
+    }
+
+    let result = service.delete(&actor, name).await;
+
+    Response::from_result(result).json()
+}
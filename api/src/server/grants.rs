@@ -0,0 +1,286 @@
+use super::common::{authorize, realm_permission, Response};
+use actix_web::{web, HttpResponse};
+use lib::audit::Actor;
+use lib::db::page::PageParams;
+use lib::jwt::Claims;
+use lib::grants::{GrantFilter, GrantKind, GrantOptions, GrantService, GrantWindow, PermissionType};
+use uuid::Uuid;
+
+pub fn mount(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/grants/user_permissions")
+            .route(web::get().to(list_user_permissions))
+            .route(web::post().to(create_user_permission)),
+    );
+
+    cfg.service(
+        web::resource("/grants/user_permissions/{id}")
+            .route(web::delete().to(delete_user_permission)),
+    );
+
+    cfg.service(
+        web::resource("/grants/user_roles")
+            .route(web::get().to(list_user_roles))
+            .route(web::post().to(create_user_role)),
+    );
+
+    cfg.service(
+        web::resource("/grants/user_roles/{id}")
+            .route(web::delete().to(delete_user_role)),
+    );
+
+    cfg.service(
+        web::resource("/grants/role_roles")
+            .route(web::get().to(list_role_roles))
+            .route(web::post().to(create_role_role)),
+    );
+
+    cfg.service(
+        web::resource("/grants/role_roles/{id}")
+            .route(web::delete().to(delete_role_role)),
+    );
+
+    cfg.service(
+        web::resource("/grants/role_permissions")
+            .route(web::get().to(list_role_permissions))
+            .route(web::post().to(create_role_permission)),
+    );
+
+    cfg.service(
+        web::resource("/grants/role_permissions/{id}")
+            .route(web::delete().to(delete_role_permission)),
+    );
+
+    cfg.service(
+        web::resource("/grants/{id}")
+            .route(web::delete().to(delete)),
+    );
+}
+
+#[derive(Deserialize)]
+struct UserPermissionParams {
+    realm_id: Uuid,
+    user_id: Uuid,
+    permission_id: Uuid,
+    #[serde(flatten)]
+    options: GrantOptions,
+}
+
+#[derive(Deserialize)]
+struct UserRoleParams {
+    realm_id: Uuid,
+    user_id: Uuid,
+    role_id: Uuid,
+    #[serde(flatten)]
+    window: GrantWindow,
+}
+
+#[derive(Deserialize)]
+struct RoleRoleParams {
+    realm_id: Uuid,
+    parent_id: Uuid,
+    child_id: Uuid,
+}
+
+#[derive(Deserialize)]
+struct RolePermissionParams {
+    realm_id: Uuid,
+    role_id: Uuid,
+    permission_id: Uuid,
+    #[serde(flatten)]
+    options: GrantOptions,
+}
+
+async fn list_user_permissions(
+    claims: Claims,
+    filter: web::Query<GrantFilter>,
+    page: web::Query<PageParams>,
+    service: web::Data<GrantService>,
+) -> HttpResponse {
+    if let Err(err) = authorize_list(&claims, &filter) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.user_permissions(&filter, &page).await;
+
+    Response::from_result(result).json()
+}
+
+async fn create_user_permission(claims: Claims, actor: Actor, params: web::Json<UserPermissionParams>, service: web::Data<GrantService>) -> HttpResponse {
+    let UserPermissionParams { realm_id, user_id, permission_id, options } = params.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "grants", "create")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service
+        .create(&actor, realm_id, PermissionType::UserPermission(user_id, permission_id, options))
+        .await;
+
+    Response::from_result(result).json()
+}
+
+async fn delete_user_permission(claims: Claims, actor: Actor, id: web::Path<Uuid>, service: web::Data<GrantService>) -> HttpResponse {
+    let id = id.into_inner();
+
+    if let Err(response) = authorize_delete(&claims, &service, Some(GrantKind::UserPermission), id).await {
+        return response;
+    }
+
+    let result = service.delete_kind(&actor, GrantKind::UserPermission, id).await;
+
+    Response::from_result(result).json()
+}
+
+async fn list_user_roles(
+    claims: Claims,
+    filter: web::Query<GrantFilter>,
+    page: web::Query<PageParams>,
+    service: web::Data<GrantService>,
+) -> HttpResponse {
+    if let Err(err) = authorize_list(&claims, &filter) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.user_roles(&filter, &page).await;
+
+    Response::from_result(result).json()
+}
+
+async fn create_user_role(claims: Claims, actor: Actor, params: web::Json<UserRoleParams>, service: web::Data<GrantService>) -> HttpResponse {
+    let UserRoleParams { realm_id, user_id, role_id, window } = params.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "grants", "create")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service
+        .create(&actor, realm_id, PermissionType::UserRole(user_id, role_id, window))
+        .await;
+
+    Response::from_result(result).json()
+}
+
+async fn delete_user_role(claims: Claims, actor: Actor, id: web::Path<Uuid>, service: web::Data<GrantService>) -> HttpResponse {
+    let id = id.into_inner();
+
+    if let Err(response) = authorize_delete(&claims, &service, Some(GrantKind::UserRole), id).await {
+        return response;
+    }
+
+    let result = service.delete_kind(&actor, GrantKind::UserRole, id).await;
+
+    Response::from_result(result).json()
+}
+
+async fn list_role_roles(
+    claims: Claims,
+    filter: web::Query<GrantFilter>,
+    page: web::Query<PageParams>,
+    service: web::Data<GrantService>,
+) -> HttpResponse {
+    if let Err(err) = authorize_list(&claims, &filter) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.role_roles(&filter, &page).await;
+
+    Response::from_result(result).json()
+}
+
+async fn create_role_role(claims: Claims, actor: Actor, params: web::Json<RoleRoleParams>, service: web::Data<GrantService>) -> HttpResponse {
+    let RoleRoleParams { realm_id, parent_id, child_id } = params.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "grants", "create")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service
+        .create(&actor, realm_id, PermissionType::RoleRole(parent_id, child_id))
+        .await;
+
+    Response::from_result(result).json()
+}
+
+async fn delete_role_role(claims: Claims, actor: Actor, id: web::Path<Uuid>, service: web::Data<GrantService>) -> HttpResponse {
+    let id = id.into_inner();
+
+    if let Err(response) = authorize_delete(&claims, &service, Some(GrantKind::RoleRole), id).await {
+        return response;
+    }
+
+    let result = service.delete_kind(&actor, GrantKind::RoleRole, id).await;
+
+    Response::from_result(result).json()
+}
+
+async fn list_role_permissions(
+    claims: Claims,
+    filter: web::Query<GrantFilter>,
+    page: web::Query<PageParams>,
+    service: web::Data<GrantService>,
+) -> HttpResponse {
+    if let Err(err) = authorize_list(&claims, &filter) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.role_permissions(&filter, &page).await;
+
+    Response::from_result(result).json()
+}
+
+async fn create_role_permission(claims: Claims, actor: Actor, params: web::Json<RolePermissionParams>, service: web::Data<GrantService>) -> HttpResponse {
+    let RolePermissionParams { realm_id, role_id, permission_id, options } = params.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "grants", "create")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service
+        .create(&actor, realm_id, PermissionType::RolePermission(role_id, permission_id, options))
+        .await;
+
+    Response::from_result(result).json()
+}
+
+async fn delete_role_permission(claims: Claims, actor: Actor, id: web::Path<Uuid>, service: web::Data<GrantService>) -> HttpResponse {
+    let id = id.into_inner();
+
+    if let Err(response) = authorize_delete(&claims, &service, Some(GrantKind::RolePermission), id).await {
+        return response;
+    }
+
+    let result = service.delete_kind(&actor, GrantKind::RolePermission, id).await;
+
+    Response::from_result(result).json()
+}
+
+async fn delete(claims: Claims, actor: Actor, id: web::Path<Uuid>, service: web::Data<GrantService>) -> HttpResponse {
+    let id = id.into_inner();
+
+    if let Err(response) = authorize_delete(&claims, &service, None, id).await {
+        return response;
+    }
+
+    let result = service.delete(&actor, id).await;
+
+    Response::from_result(result).json()
+}
+
+// a grant is deleted with the permission of the realm it was made in
+async fn authorize_delete(claims: &Claims, service: &GrantService, kind: Option<GrantKind>, id: Uuid) -> Result<(), HttpResponse> {
+    let realm_id = service
+        .realm_id(kind, id)
+        .await
+        .map_err(|err| Response::<()>::error(err).json())?;
+
+    authorize(claims, &realm_permission(realm_id, "grants", "delete"))
+        .map_err(|err| Response::<()>::error(err).forbidden())
+}
+
+fn authorize_list(claims: &Claims, filter: &GrantFilter) -> lib::result::Result<()> {
+    match filter.realm_id {
+        Some(realm_id) => authorize(claims, &realm_permission(realm_id, "grants", "read")),
+        None => authorize(claims, "oxidauth:grants:read"),
+    }
+}
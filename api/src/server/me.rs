@@ -0,0 +1,81 @@
+use super::common::{caller, versioned, Response};
+use actix_web::{web, HttpResponse};
+use lib::audit::Actor;
+use lib::db::patch::Precondition;
+use lib::grants::GrantService;
+use lib::jwt::Claims;
+use lib::users::{ProfileUpdate, UserService};
+use lib::result::Error;
+use lib::AuthorityService;
+
+// everything here acts on the caller, taken from the token's `sub`; nothing
+// in the request can point it at anyone else
+pub fn mount(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/me")
+            .route(web::get().to(show))
+            .route(web::patch().to(update)),
+    );
+
+    cfg.route("/me/grants", web::get().to(grants));
+    cfg.route("/me/authorities", web::get().to(authorities));
+}
+
+async fn show(claims: Claims, service: web::Data<UserService>) -> HttpResponse {
+    let user_id = match caller(&claims, "oxidauth:me.profile:read") {
+        Ok(user_id) => user_id,
+        Err(err) => return Response::<()>::error(err).forbidden(),
+    };
+
+    let result = service.by_id(user_id).await;
+
+    versioned(result)
+}
+
+async fn update(
+    claims: Claims,
+    actor: Actor,
+    precondition: Precondition,
+    params: web::Json<ProfileUpdate>,
+    service: web::Data<UserService>,
+) -> HttpResponse {
+    let user_id = match caller(&claims, "oxidauth:me.profile:update") {
+        Ok(user_id) => user_id,
+        Err(err) => return Response::<()>::error(err).forbidden(),
+    };
+
+    let result = service.update_profile(&actor, user_id, params.into_inner(), &precondition).await;
+
+    versioned(result)
+}
+
+// the grants are the ones held in the realm that issued the token
+async fn grants(claims: Claims, service: web::Data<GrantService>) -> HttpResponse {
+    let user_id = match caller(&claims, "oxidauth:me.grants:read") {
+        Ok(user_id) => user_id,
+        Err(err) => return Response::<()>::error(err).forbidden(),
+    };
+
+    let realm_id = match &claims.issuer {
+        Some(issuer) => issuer.realm_id,
+        None => return Response::<()>::error(Error::msg("token has no issuing realm")).forbidden(),
+    };
+
+    let result = service
+        .by_user_id(realm_id, user_id)
+        .await
+        .and_then(|root| root.ensure_resolved());
+
+    Response::from_result(result).json()
+}
+
+async fn authorities(claims: Claims, service: web::Data<AuthorityService>) -> HttpResponse {
+    let user_id = match caller(&claims, "oxidauth:me.authorities:read") {
+        Ok(user_id) => user_id,
+        Err(err) => return Response::<()>::error(err).forbidden(),
+    };
+
+    let result = service.linked_by_user_id(user_id).await;
+
+    Response::from_result(result).json()
+}
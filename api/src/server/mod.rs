@@ -2,6 +2,7 @@ use actix_cors::Cors;
 use actix_web::{web, App, HttpServer, HttpResponse}; 
 use std::fmt::Debug;
 use std::net::ToSocketAddrs;
+use std::time::Duration;
 
 use lib::middleware::Jwt;
 use lib::db::pg;
@@ -11,26 +12,42 @@ use lib::authorities::strategies::{
     username_password,
 };
 
+mod audit;
 mod auth;
 mod authorities;
 mod common;
+mod domains;
+mod grants;
+mod me;
 mod permissions;
 mod realms;
 mod refresh_tokens;
+mod relations;
 mod roles;
+mod sessions;
 mod users;
+mod webhooks;
 
 pub async fn start<T: ToSocketAddrs + Debug>(bind: T, database_args: pg::Args<'_>) -> Result<()> {
     let pool = pg::new(database_args).await?;
 
     let username_password: username_password::AuthService = strategies::Authority::new(&pool)?;
 
+    let audit_service = lib::audit::AuditService::new(&pool)?;
     let authority_service = lib::authorities::AuthorityService::new(&pool)?;
-    // let domain_service = lib::domains::DomainService::new(&pool)?;
+    let domain_service = lib::domains::DomainService::new(&pool)?;
+    let export_service = lib::seed::export::ExportService::new(&pool)?;
     let grant_service = lib::grants::GrantService::new(&pool)?;
+    let permission_service = lib::permissions::permission_service::PermissionService::new(&pool)?;
     let realm_service = lib::realms::RealmService::new(&pool)?;
+    let relation_service = lib::relations::RelationService::new(&pool)?;
     let role_service = lib::roles::RoleService::new(&pool)?;
+    let session_service = lib::sessions::SessionService::new(&pool)?;
     let user_service = lib::users::UserService::new(&pool)?;
+    let webhook_service = lib::webhooks::WebhookService::new(&pool)?;
+
+    sweep_expired_grants(grant_service.clone(), Duration::from_secs(60));
+    deliver_webhooks(webhook_service.clone(), Duration::from_secs(5));
 
     HttpServer::new(move || {
         let pool = web::Data::new(pool.clone());
@@ -38,39 +55,59 @@ pub async fn start<T: ToSocketAddrs + Debug>(bind: T, database_args: pg::Args<'_
         let skip_paths = vec![
             "/register".into(),
             "/authenticate".into(),
+            "/refresh".into(),
             "/public_keys".into(),
         ];
 
-        let jwt_middleware = Jwt::new(authority_service.clone(), skip_paths);
+        let jwt_middleware = Jwt::new(authority_service.clone(), grant_service.clone(), session_service.clone(), skip_paths);
         let cors_middleware = Cors::permissive();
 
         let username_password = web::Data::new(username_password.clone());
 
+        let audit_service = web::Data::new(audit_service.clone());
         let authority_service = web::Data::new(authority_service.clone());
-        // let domain_service = web::Data::new(domain_service.clone())?;
+        let domain_service = web::Data::new(domain_service.clone());
+        let export_service = web::Data::new(export_service.clone());
         let grant_service = web::Data::new(grant_service.clone());
+        let permission_service = web::Data::new(permission_service.clone());
         let realm_service = web::Data::new(realm_service.clone());
+        let relation_service = web::Data::new(relation_service.clone());
         let role_service = web::Data::new(role_service.clone());
+        let session_service = web::Data::new(session_service.clone());
         let user_service = web::Data::new(user_service.clone());
+        let webhook_service = web::Data::new(webhook_service.clone());
 
         App::new()
             .wrap(jwt_middleware)
             .wrap(cors_middleware)
             .app_data(pool)
             .app_data(username_password)
+            .app_data(audit_service)
             .app_data(authority_service)
-            // .app_data(domain_service)
+            .app_data(domain_service)
+            .app_data(export_service)
             .app_data(grant_service)
+            .app_data(permission_service)
             .app_data(realm_service)
+            .app_data(relation_service)
             .app_data(role_service)
+            .app_data(session_service)
             .app_data(user_service)
+            .app_data(webhook_service)
+            .configure(audit::mount)
             .configure(auth::mount)
             .configure(authorities::mount)
+            .configure(domains::mount)
+            .configure(grants::mount)
+            .configure(me::mount)
             .configure(permissions::mount)
             .configure(realms::mount)
             .configure(refresh_tokens::mount)
+            .configure(relations::mount)
             .configure(roles::mount)
+            .configure(sessions::mount)
             .configure(users::mount)
+            .configure(webhooks::mount)
             .default_service(web::route().to(test_db))
     })
         .bind(bind)?
@@ -80,6 +117,45 @@ pub async fn start<T: ToSocketAddrs + Debug>(bind: T, database_args: pg::Args<'_
     Ok(())
 }
 
+fn sweep_expired_grants(service: lib::grants::GrantService, every: Duration) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(every);
+
+        loop {
+            interval.tick().await;
+
+            match service.sweep_expired().await {
+                Ok(0) => {},
+                Ok(swept) => log::info!("swept {} expired grants", swept),
+                Err(err) => log::error!("unable to sweep expired grants: {}", err),
+            }
+        }
+    });
+}
+
+// drains the outbox a batch at a time, going straight back for another while
+// deliveries are getting through so a backlog doesn't wait on the interval
+fn deliver_webhooks(service: lib::webhooks::WebhookService, every: Duration) {
+    actix_web::rt::spawn(async move {
+        let mut interval = actix_web::rt::time::interval(every);
+
+        loop {
+            interval.tick().await;
+
+            loop {
+                match service.deliver_due().await {
+                    Ok(0) => break,
+                    Ok(delivered) => log::info!("delivered {} webhooks", delivered),
+                    Err(err) => {
+                        log::error!("unable to deliver webhooks: {}", err);
+                        break;
+                    },
+                }
+            }
+        }
+    });
+}
+
 pub async fn test_db() -> HttpResponse {
     HttpResponse::Ok().body(r#"{ "success": true }"#)
 }
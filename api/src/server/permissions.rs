@@ -1,4 +1,181 @@
+use super::common::{authorize, ensure_realm, realm_permission, versioned, Response};
 use actix_web::{web, HttpResponse};
+use lib::audit::Actor;
+use lib::db::page::PageParams;
+use lib::db::patch::Precondition;
+use lib::jwt::Claims;
+use lib::permissions::permission::Permission as PermissionRaw;
+use lib::permissions::permission_service::{PermissionCreate, PermissionFilter, PermissionService, PermissionUpdate};
+use uuid::Uuid;
 
 pub fn mount(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/permissions")
+            .route(web::get().to(list))
+            .route(web::post().to(create)),
+    );
+
+    cfg.service(
+        web::resource("/permissions/{id}")
+            .route(web::get().to(show))
+            .route(web::post().to(update))
+            .route(web::patch().to(update))
+            .route(web::delete().to(delete)),
+    );
+
+    cfg.service(
+        web::resource("/realms/{realm_id}/permissions")
+            .route(web::get().to(realm_list))
+            .route(web::post().to(realm_create)),
+    );
+
+    cfg.service(
+        web::resource("/realms/{realm_id}/permissions/{id}")
+            .route(web::get().to(realm_show)),
+    );
+}
+
+async fn list(
+    claims: Claims,
+    filter: web::Query<PermissionFilter>,
+    page: web::Query<PageParams>,
+    service: web::Data<PermissionService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:permissions:read") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.list(&filter, &page).await;
+
+    Response::from_result(result).json()
+}
+
+async fn create(
+    claims: Claims,
+    actor: Actor,
+    params: web::Json<PermissionCreate>,
+    service: web::Data<PermissionService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:permissions:create") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.create(&actor, params.into_inner()).await;
+
+    Response::from_result(result).json()
+}
+
+async fn show(
+    claims: Claims,
+    params: web::Path<Uuid>,
+    service: web::Data<PermissionService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:permissions:read") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.by_id(params.into_inner()).await;
+
+    versioned(result)
+}
+
+async fn update(
+    claims: Claims,
+    actor: Actor,
+    precondition: Precondition,
+    id: web::Path<Uuid>,
+    params: web::Json<PermissionUpdate>,
+    service: web::Data<PermissionService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:permissions:update") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.update(&actor, id.into_inner(), params.into_inner(), &precondition).await;
+
+    versioned(result)
+}
+
+async fn delete(
+    claims: Claims,
+    actor: Actor,
+    id: web::Path<Uuid>,
+    service: web::Data<PermissionService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:permissions:delete") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.delete(&actor, id.into_inner()).await;
+
+    Response::from_result(result).json()
+}
+
+async fn realm_list(
+    claims: Claims,
+    realm_id: web::Path<Uuid>,
+    filter: web::Query<PermissionFilter>,
+    page: web::Query<PageParams>,
+    service: web::Data<PermissionService>,
+) -> HttpResponse {
+    let realm_id = realm_id.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "permissions", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let mut filter = filter.into_inner();
+    filter.realm_id = Some(realm_id);
+
+    let result = service.list(&filter, &page).await;
+
+    Response::from_result(result).json()
+}
+
+async fn realm_create(
+    claims: Claims,
+    actor: Actor,
+    realm_id: web::Path<Uuid>,
+    params: web::Json<PermissionCreate>,
+    service: web::Data<PermissionService>,
+) -> HttpResponse {
+    let realm_id = realm_id.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "permissions", "create")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let mut params = params.into_inner();
+    params.realm_id = realm_id;
+
+    let scoped = PermissionRaw::new(&params.realm, &params.resource, &params.action)
+        .map_err(Into::into)
+        .and_then(|permission| permission.validate_within(realm_id));
+
+    if let Err(err) = scoped {
+        return Response::<()>::error(err).json();
+    }
+
+    let result = service.create(&actor, params).await;
+
+    Response::from_result(result).json()
+}
+
+async fn realm_show(
+    claims: Claims,
+    params: web::Path<(Uuid, Uuid)>,
+    service: web::Data<PermissionService>,
+) -> HttpResponse {
+    let (realm_id, id) = params.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "permissions", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service
+        .by_id(id)
+        .await
+        .and_then(|permission| ensure_realm(realm_id, permission.realm_id).map(|_| permission));
+
+    versioned(result)
 }
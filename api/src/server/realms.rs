@@ -1,4 +1,134 @@
+use super::common::{authorize, realm_permission, versioned, Response};
 use actix_web::{web, HttpResponse};
+use lib::audit::Actor;
+use lib::db::page::PageParams;
+use lib::db::patch::Precondition;
+use lib::jwt::Claims;
+use lib::realms::{RealmCreate, RealmFilter, RealmService, RealmUpdate};
+use lib::seed::export::{ExportOptions, ExportService};
+use uuid::Uuid;
 
 pub fn mount(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/realms")
+            .route(web::get().to(list))
+            .route(web::post().to(create)),
+    );
+
+    cfg.service(
+        web::resource("/realms/{id}")
+            .route(web::get().to(show))
+            .route(web::post().to(update))
+            .route(web::patch().to(update))
+            .route(web::delete().to(delete)),
+    );
+
+    cfg.service(
+        web::resource("/realms/{id}/export")
+            .route(web::get().to(export)),
+    );
+}
+
+async fn list(
+    claims: Claims,
+    filter: web::Query<RealmFilter>,
+    page: web::Query<PageParams>,
+    service: web::Data<RealmService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:realms:read") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.list(&filter, &page).await;
+
+    Response::from_result(result).json()
+}
+
+async fn create(
+    claims: Claims,
+    actor: Actor,
+    params: web::Json<RealmCreate>,
+    service: web::Data<RealmService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:realms:create") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.create(&actor, params.into_inner()).await;
+
+    Response::from_result(result).json()
+}
+
+async fn show(
+    claims: Claims,
+    id: web::Path<Uuid>,
+    service: web::Data<RealmService>,
+) -> HttpResponse {
+    let id = id.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(id, "realm", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.by_id(id).await;
+
+    versioned(result)
+}
+
+async fn update(
+    claims: Claims,
+    actor: Actor,
+    precondition: Precondition,
+    id: web::Path<Uuid>,
+    params: web::Json<RealmUpdate>,
+    service: web::Data<RealmService>,
+) -> HttpResponse {
+    let id = id.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(id, "realm", "update")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.update(&actor, id, params.into_inner(), &precondition).await;
+
+    versioned(result)
+}
+
+// the realm as a seed file; the salts and digests only with ?secrets=true
+async fn export(
+    claims: Claims,
+    id: web::Path<Uuid>,
+    options: web::Query<ExportOptions>,
+    service: web::Data<ExportService>,
+) -> HttpResponse {
+    let id = id.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(id, "export", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    if options.secrets {
+        if let Err(err) = authorize(&claims, &realm_permission(id, "secrets", "read")) {
+            return Response::<()>::error(err).forbidden();
+        }
+    }
+
+    let result = service.export(id, &options).await;
+
+    Response::from_result(result).json()
+}
+
+async fn delete(
+    claims: Claims,
+    actor: Actor,
+    id: web::Path<Uuid>,
+    service: web::Data<RealmService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:realms:delete") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.delete(&actor, id.into_inner()).await;
+
+    Response::from_result(result).json()
 }
@@ -1,4 +1,23 @@
 use actix_web::{web, HttpResponse};
+use lib::audit::Actor;
+use lib::authorities::strategies::username_password::AuthService as UsernamePasswordService;
+use lib::http_response::Response;
+use uuid::Uuid;
 
 pub fn mount(cfg: &mut web::ServiceConfig) {
+    cfg.route("/refresh", web::post().to(refresh));
+}
+
+#[derive(Deserialize)]
+struct RefreshParams {
+    client_key: Uuid,
+    refresh_token: Uuid,
+}
+
+async fn refresh(actor: Actor, service: web::Data<UsernamePasswordService>, params: web::Json<RefreshParams>) -> HttpResponse {
+    let params = params.into_inner();
+
+    let result = service.refresh(&actor, params.client_key, params.refresh_token).await;
+
+    Response::from_result(result).json()
 }
@@ -0,0 +1,191 @@
+use super::common::{authorize, realm_permission, Response};
+use actix_web::{web, HttpResponse};
+use lib::audit::Actor;
+use lib::db::page::PageParams;
+use lib::jwt::Claims;
+use lib::relations::{ObjectRef, RelationRewriteCreate, RelationService, RelationTupleCreate, RelationTupleFilter};
+use lib::result::{Error, Result};
+use uuid::Uuid;
+
+pub fn mount(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/relations/tuples")
+            .route(web::get().to(list_tuples))
+            .route(web::post().to(create_tuple)),
+    );
+
+    cfg.service(
+        web::resource("/relations/tuples/{id}")
+            .route(web::delete().to(delete_tuple)),
+    );
+
+    cfg.service(
+        web::resource("/relations/rewrites")
+            .route(web::get().to(list_rewrites))
+            .route(web::post().to(create_rewrite)),
+    );
+
+    cfg.service(
+        web::resource("/relations/rewrites/{id}")
+            .route(web::delete().to(delete_rewrite)),
+    );
+
+    cfg.route("/relations/check", web::get().to(check));
+    cfg.route("/relations/expand", web::get().to(expand));
+    cfg.route("/relations/list_objects", web::get().to(list_objects));
+}
+
+#[derive(Deserialize)]
+struct RealmParams {
+    realm_id: Uuid,
+}
+
+#[derive(Deserialize)]
+struct CheckParams {
+    realm_id: Uuid,
+    object: ObjectRef,
+    relation: String,
+    user_id: Option<Uuid>,
+}
+
+#[derive(Deserialize)]
+struct ExpandParams {
+    realm_id: Uuid,
+    object: ObjectRef,
+    relation: String,
+}
+
+#[derive(Deserialize)]
+struct ListObjectsParams {
+    realm_id: Uuid,
+    object_type: String,
+    relation: String,
+    user_id: Option<Uuid>,
+}
+
+#[derive(Serialize)]
+struct CheckResult {
+    allowed: bool,
+}
+
+async fn list_tuples(
+    claims: Claims,
+    filter: web::Query<RelationTupleFilter>,
+    page: web::Query<PageParams>,
+    service: web::Data<RelationService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, &realm_permission(filter.realm_id, "relations", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.tuples(&filter, &page).await;
+
+    Response::from_result(result).json()
+}
+
+async fn create_tuple(claims: Claims, actor: Actor, params: web::Json<RelationTupleCreate>, service: web::Data<RelationService>) -> HttpResponse {
+    if let Err(err) = authorize(&claims, &realm_permission(params.realm_id, "relations", "create")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.create_tuple(&actor, params.into_inner()).await;
+
+    Response::from_result(result).json()
+}
+
+async fn delete_tuple(claims: Claims, actor: Actor, id: web::Path<Uuid>, service: web::Data<RelationService>) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:relations:delete") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.delete_tuple(&actor, id.into_inner()).await;
+
+    Response::from_result(result).json()
+}
+
+async fn list_rewrites(
+    claims: Claims,
+    params: web::Query<RealmParams>,
+    page: web::Query<PageParams>,
+    service: web::Data<RelationService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, &realm_permission(params.realm_id, "relations", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.rewrites(params.realm_id, &page).await;
+
+    Response::from_result(result).json()
+}
+
+async fn create_rewrite(claims: Claims, actor: Actor, params: web::Json<RelationRewriteCreate>, service: web::Data<RelationService>) -> HttpResponse {
+    if let Err(err) = authorize(&claims, &realm_permission(params.realm_id, "relations", "create")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.create_rewrite(&actor, params.into_inner()).await;
+
+    Response::from_result(result).json()
+}
+
+async fn delete_rewrite(claims: Claims, actor: Actor, id: web::Path<Uuid>, service: web::Data<RelationService>) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:relations:delete") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.delete_rewrite(&actor, id.into_inner()).await;
+
+    Response::from_result(result).json()
+}
+
+async fn check(claims: Claims, params: web::Query<CheckParams>, service: web::Data<RelationService>) -> HttpResponse {
+    let user_id = match subject_user(&claims, params.realm_id, params.user_id) {
+        Ok(user_id) => user_id,
+        Err(err) => return Response::<()>::error(err).forbidden(),
+    };
+
+    let result = service
+        .check(params.realm_id, &params.object, &params.relation, user_id)
+        .await
+        .map(|allowed| CheckResult { allowed });
+
+    Response::from_result(result).json()
+}
+
+async fn expand(claims: Claims, params: web::Query<ExpandParams>, service: web::Data<RelationService>) -> HttpResponse {
+    if let Err(err) = authorize(&claims, &realm_permission(params.realm_id, "relations", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.expand(params.realm_id, &params.object, &params.relation).await;
+
+    Response::from_result(result).json()
+}
+
+async fn list_objects(claims: Claims, params: web::Query<ListObjectsParams>, service: web::Data<RelationService>) -> HttpResponse {
+    let user_id = match subject_user(&claims, params.realm_id, params.user_id) {
+        Ok(user_id) => user_id,
+        Err(err) => return Response::<()>::error(err).forbidden(),
+    };
+
+    let result = service
+        .list_objects(params.realm_id, &params.object_type, &params.relation, user_id)
+        .await;
+
+    Response::from_result(result).json()
+}
+
+// anyone may ask about themselves; asking about someone else takes the
+// realm's relations:check permission
+fn subject_user(claims: &Claims, realm_id: Uuid, user_id: Option<Uuid>) -> Result<Uuid> {
+    match (user_id, claims.sub) {
+        (Some(user_id), sub) if sub != Some(user_id) => {
+            authorize(claims, &realm_permission(realm_id, "relations", "check"))?;
+
+            Ok(user_id)
+        },
+        (Some(user_id), _) => Ok(user_id),
+        (None, Some(sub)) => Ok(sub),
+        (None, None) => Err(Error::msg("user_id is required for tokens without a subject")),
+    }
+}
@@ -1,6 +1,11 @@
-use super::common::Response;
+use super::common::{authorize, ensure_realm, missing, realm_permission, versioned, Response};
 use actix_web::{web, HttpResponse};
-use lib::roles::{RoleCreate, RoleService, RoleUpdate};
+use lib::audit::Actor;
+use lib::db::page::PageParams;
+use lib::db::patch::Precondition;
+use lib::grants::GrantService;
+use lib::jwt::Claims;
+use lib::roles::{RoleCreate, RoleFilter, RoleService, RoleUpdate};
 use uuid::Uuid;
 
 pub fn mount(cfg: &mut web::ServiceConfig) {
@@ -13,34 +18,191 @@ pub fn mount(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::resource("/roles/{id}")
             .route(web::get().to(show))
-            .route(web::post().to(update)),
+            .route(web::post().to(update))
+            .route(web::patch().to(update))
+            .route(web::delete().to(delete)),
+    );
+
+    cfg.service(
+        web::resource("/roles/{id}/tree")
+            .route(web::get().to(tree)),
+    );
+
+    cfg.service(
+        web::resource("/realms/{realm_id}/roles")
+            .route(web::get().to(realm_list))
+            .route(web::post().to(realm_create)),
+    );
+
+    cfg.service(
+        web::resource("/realms/{realm_id}/roles/{id}")
+            .route(web::get().to(realm_show)),
     );
 }
 
-async fn list(service: web::Data<RoleService>) -> HttpResponse {
-    let result = service.all().await;
+async fn list(
+    claims: Claims,
+    filter: web::Query<RoleFilter>,
+    page: web::Query<PageParams>,
+    service: web::Data<RoleService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:roles:read") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.list(&filter, &page).await;
 
     Response::from_result(result).json()
 }
 
-async fn create(params: web::Json<RoleCreate>, service: web::Data<RoleService>) -> HttpResponse {
-    let result = service.create(params.into_inner()).await;
+async fn create(
+    claims: Claims,
+    actor: Actor,
+    params: web::Json<RoleCreate>,
+    service: web::Data<RoleService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:roles:create") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.create(&actor, params.into_inner()).await;
 
     Response::from_result(result).json()
 }
 
-async fn show(params: web::Path<Uuid>, service: web::Data<RoleService>) -> HttpResponse {
+async fn show(
+    claims: Claims,
+    params: web::Path<Uuid>,
+    service: web::Data<RoleService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:roles:read") {
+        return Response::<()>::error(err).forbidden();
+    }
+
     let result = service.by_id(params.into_inner()).await;
 
-    Response::from_result(result).json()
+    versioned(result)
 }
 
 async fn update(
+    claims: Claims,
+    actor: Actor,
+    precondition: Precondition,
     id: web::Path<Uuid>,
     params: web::Json<RoleUpdate>,
     service: web::Data<RoleService>,
 ) -> HttpResponse {
-    let result = service.update(id.into_inner(), params.into_inner()).await;
+    if let Err(err) = authorize(&claims, "oxidauth:roles:update") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.update(&actor, id.into_inner(), params.into_inner(), &precondition).await;
+
+    versioned(result)
+}
+
+async fn delete(
+    claims: Claims,
+    actor: Actor,
+    id: web::Path<Uuid>,
+    service: web::Data<RoleService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:roles:delete") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.delete(&actor, id.into_inner()).await;
+
+    Response::from_result(result).json()
+}
+
+#[derive(Deserialize)]
+struct TreeParams {
+    realm_id: Uuid,
+}
+
+async fn tree(
+    claims: Claims,
+    id: web::Path<Uuid>,
+    params: web::Query<TreeParams>,
+    service: web::Data<GrantService>,
+) -> HttpResponse {
+    let TreeParams { realm_id } = params.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "grants", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = match service.by_role_id(id.into_inner()).await {
+        Ok(root) => root,
+        Err(err) => return missing(err),
+    };
+
+    // a role from another realm is answered the same as one that doesn't exist
+    if result.role.as_ref().map(|node| node.role.realm_id) != Some(realm_id) {
+        return missing(sqlx::Error::RowNotFound.into());
+    }
+
+    Response::from_result(result.ensure_resolved()).json()
+}
+
+async fn realm_list(
+    claims: Claims,
+    realm_id: web::Path<Uuid>,
+    filter: web::Query<RoleFilter>,
+    page: web::Query<PageParams>,
+    service: web::Data<RoleService>,
+) -> HttpResponse {
+    let realm_id = realm_id.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "roles", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let mut filter = filter.into_inner();
+    filter.realm_id = Some(realm_id);
+
+    let result = service.list(&filter, &page).await;
 
     Response::from_result(result).json()
 }
+
+async fn realm_create(
+    claims: Claims,
+    actor: Actor,
+    realm_id: web::Path<Uuid>,
+    params: web::Json<RoleCreate>,
+    service: web::Data<RoleService>,
+) -> HttpResponse {
+    let realm_id = realm_id.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "roles", "create")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let mut params = params.into_inner();
+    params.realm_id = realm_id;
+
+    let result = service.create(&actor, params).await;
+
+    Response::from_result(result).json()
+}
+
+async fn realm_show(
+    claims: Claims,
+    params: web::Path<(Uuid, Uuid)>,
+    service: web::Data<RoleService>,
+) -> HttpResponse {
+    let (realm_id, id) = params.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "roles", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service
+        .by_id(id)
+        .await
+        .and_then(|role| ensure_realm(realm_id, role.realm_id).map(|_| role));
+
+    versioned(result)
+}
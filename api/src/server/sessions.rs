@@ -0,0 +1,130 @@
+use super::common::{authorize, caller, Response};
+use actix_web::{web, HttpResponse};
+use lib::audit::Actor;
+use lib::db::page::PageParams;
+use lib::jwt::Claims;
+use lib::result::{Error, Result};
+use lib::sessions::{Session, SessionService};
+use uuid::Uuid;
+
+pub fn mount(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/me/sessions")
+            .route(web::get().to(my_list))
+            .route(web::delete().to(my_revoke_all)),
+    );
+
+    cfg.service(
+        web::resource("/me/sessions/{id}")
+            .route(web::delete().to(my_revoke)),
+    );
+
+    cfg.service(
+        web::resource("/users/{user_id}/sessions")
+            .route(web::get().to(list))
+            .route(web::delete().to(revoke_all)),
+    );
+
+    cfg.service(
+        web::resource("/users/{user_id}/sessions/{id}")
+            .route(web::delete().to(revoke)),
+    );
+}
+
+#[derive(Deserialize)]
+struct RevokeAllParams {
+    // sign out everywhere else but stay signed in here
+    #[serde(default)]
+    keep_current: bool,
+}
+
+async fn my_list(claims: Claims, page: web::Query<PageParams>, service: web::Data<SessionService>) -> HttpResponse {
+    let user_id = match caller(&claims, "oxidauth:me.sessions:read") {
+        Ok(user_id) => user_id,
+        Err(err) => return Response::<()>::error(err).forbidden(),
+    };
+
+    let result = service.by_user_id(user_id, &page).await;
+
+    Response::from_result(result).json()
+}
+
+async fn my_revoke(claims: Claims, actor: Actor, id: web::Path<Uuid>, service: web::Data<SessionService>) -> HttpResponse {
+    let user_id = match caller(&claims, "oxidauth:me.sessions:delete") {
+        Ok(user_id) => user_id,
+        Err(err) => return Response::<()>::error(err).forbidden(),
+    };
+
+    let result = revoke_for(&service, &actor, user_id, id.into_inner()).await;
+
+    Response::from_result(result).json()
+}
+
+async fn my_revoke_all(
+    claims: Claims,
+    actor: Actor,
+    params: web::Query<RevokeAllParams>,
+    service: web::Data<SessionService>,
+) -> HttpResponse {
+    let user_id = match caller(&claims, "oxidauth:me.sessions:delete") {
+        Ok(user_id) => user_id,
+        Err(err) => return Response::<()>::error(err).forbidden(),
+    };
+
+    let except = match params.keep_current {
+        true => claims.sid,
+        false => None,
+    };
+
+    let result = service.revoke_all(&actor, user_id, except).await;
+
+    Response::from_result(result).json()
+}
+
+async fn list(
+    claims: Claims,
+    user_id: web::Path<Uuid>,
+    page: web::Query<PageParams>,
+    service: web::Data<SessionService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:sessions:read") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.by_user_id(user_id.into_inner(), &page).await;
+
+    Response::from_result(result).json()
+}
+
+async fn revoke(claims: Claims, actor: Actor, path: web::Path<(Uuid, Uuid)>, service: web::Data<SessionService>) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:sessions:delete") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let (user_id, id) = path.into_inner();
+
+    let result = revoke_for(&service, &actor, user_id, id).await;
+
+    Response::from_result(result).json()
+}
+
+async fn revoke_all(claims: Claims, actor: Actor, user_id: web::Path<Uuid>, service: web::Data<SessionService>) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:sessions:delete") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.revoke_all(&actor, user_id.into_inner(), None).await;
+
+    Response::from_result(result).json()
+}
+
+// a session id alone isn't enough; it has to belong to the user in the path
+async fn revoke_for(service: &SessionService, actor: &Actor, user_id: Uuid, id: Uuid) -> Result<Session> {
+    let session = service.by_id(id).await?;
+
+    if session.user_id != user_id {
+        return Err(Error::msg(format!("session {} not found", id)));
+    }
+
+    service.revoke(actor, id).await
+}
@@ -1,6 +1,11 @@
-use super::common::Response;
+use super::common::{authorize, missing, realm_permission, versioned, Response};
 use actix_web::{web, HttpResponse};
-use lib::users::{UserCreate, UserService, UserUpdate};
+use lib::audit::Actor;
+use lib::db::page::PageParams;
+use lib::db::patch::Precondition;
+use lib::grants::GrantService;
+use lib::jwt::Claims;
+use lib::users::{UserCreate, UserDelete, UserFilter, UserSearch, UserService, UserUpdate};
 use uuid::Uuid;
 
 pub fn mount(cfg: &mut web::ServiceConfig) {
@@ -13,34 +18,235 @@ pub fn mount(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::resource("/users/{id}")
             .route(web::get().to(show))
-            .route(web::post().to(update)),
+            .route(web::post().to(update))
+            .route(web::patch().to(update))
+            .route(web::delete().to(delete)),
+    );
+
+    cfg.service(
+        web::resource("/users/{id}/tree")
+            .route(web::get().to(tree)),
+    );
+
+    cfg.service(
+        web::resource("/users/{id}/explain")
+            .route(web::get().to(explain)),
+    );
+
+    cfg.service(
+        web::resource("/realms/{realm_id}/users")
+            .route(web::get().to(realm_list)),
+    );
+
+    // ahead of `{id}` so "search" isn't taken for one
+    cfg.service(
+        web::resource("/realms/{realm_id}/users/search")
+            .route(web::get().to(realm_search)),
+    );
+
+    cfg.service(
+        web::resource("/realms/{realm_id}/users/{id}")
+            .route(web::get().to(realm_show)),
     );
 }
 
-async fn list(service: web::Data<UserService>) -> HttpResponse {
-    let result = service.all().await;
+async fn list(
+    claims: Claims,
+    filter: web::Query<UserFilter>,
+    page: web::Query<PageParams>,
+    service: web::Data<UserService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:users:read") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.list(&filter, &page).await;
 
     Response::from_result(result).json()
 }
 
-async fn create(params: web::Json<UserCreate>, service: web::Data<UserService>) -> HttpResponse {
-    let result = service.create(params.into_inner()).await;
+async fn create(
+    claims: Claims,
+    actor: Actor,
+    params: web::Json<UserCreate>,
+    service: web::Data<UserService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:users:create") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.create(&actor, params.into_inner()).await;
 
     Response::from_result(result).json()
 }
 
-async fn show(params: web::Path<Uuid>, service: web::Data<UserService>) -> HttpResponse {
+async fn show(
+    claims: Claims,
+    params: web::Path<Uuid>,
+    service: web::Data<UserService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:users:read") {
+        return Response::<()>::error(err).forbidden();
+    }
+
     let result = service.by_id(params.into_inner()).await;
 
-    Response::from_result(result).json()
+    versioned(result)
 }
 
 async fn update(
+    claims: Claims,
+    actor: Actor,
+    precondition: Precondition,
     id: web::Path<Uuid>,
     params: web::Json<UserUpdate>,
     service: web::Data<UserService>,
 ) -> HttpResponse {
-    let result = service.update(id.into_inner(), params.into_inner()).await;
+    if let Err(err) = authorize(&claims, "oxidauth:users:update") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.update(&actor, id.into_inner(), params.into_inner(), &precondition).await;
+
+    versioned(result)
+}
+
+async fn delete(
+    claims: Claims,
+    actor: Actor,
+    id: web::Path<Uuid>,
+    params: web::Query<UserDelete>,
+    service: web::Data<UserService>,
+) -> HttpResponse {
+    if let Err(err) = authorize(&claims, "oxidauth:users:delete") {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.delete(&actor, id.into_inner(), params.into_inner()).await;
 
     Response::from_result(result).json()
 }
+
+#[derive(Deserialize)]
+struct TreeParams {
+    realm_id: Uuid,
+}
+
+async fn tree(
+    claims: Claims,
+    id: web::Path<Uuid>,
+    params: web::Query<TreeParams>,
+    service: web::Data<GrantService>,
+    users: web::Data<UserService>,
+) -> HttpResponse {
+    let TreeParams { realm_id } = params.into_inner();
+    let id = id.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "grants", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    if let Err(response) = ensure_member(&users, realm_id, id).await {
+        return response;
+    }
+
+    let result = service
+        .by_user_id(realm_id, id)
+        .await
+        .and_then(|root| root.ensure_resolved());
+
+    Response::from_result(result).json()
+}
+
+#[derive(Deserialize)]
+struct ExplainParams {
+    realm_id: Uuid,
+    challenge: String,
+}
+
+async fn explain(
+    claims: Claims,
+    id: web::Path<Uuid>,
+    params: web::Query<ExplainParams>,
+    service: web::Data<GrantService>,
+    users: web::Data<UserService>,
+) -> HttpResponse {
+    let ExplainParams { realm_id, challenge } = params.into_inner();
+    let id = id.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "grants", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    if let Err(response) = ensure_member(&users, realm_id, id).await {
+        return response;
+    }
+
+    let result = service.explain(realm_id, id, &challenge).await;
+
+    Response::from_result(result).json()
+}
+
+// a realm only gets to look at the grants of its own users
+async fn ensure_member(users: &UserService, realm_id: Uuid, id: Uuid) -> Result<(), HttpResponse> {
+    users
+        .in_realm(realm_id, id)
+        .await
+        .map(|_| ())
+        .map_err(missing)
+}
+
+async fn realm_list(
+    claims: Claims,
+    realm_id: web::Path<Uuid>,
+    filter: web::Query<UserFilter>,
+    page: web::Query<PageParams>,
+    service: web::Data<UserService>,
+) -> HttpResponse {
+    let realm_id = realm_id.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "users", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let mut filter = filter.into_inner();
+    filter.realm_id = Some(realm_id);
+
+    let result = service.list(&filter, &page).await;
+
+    Response::from_result(result).json()
+}
+
+async fn realm_search(
+    claims: Claims,
+    realm_id: web::Path<Uuid>,
+    search: web::Query<UserSearch>,
+    page: web::Query<PageParams>,
+    service: web::Data<UserService>,
+) -> HttpResponse {
+    let realm_id = realm_id.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "users", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.search(realm_id, &search, &page).await;
+
+    Response::from_result(result).json()
+}
+
+async fn realm_show(
+    claims: Claims,
+    params: web::Path<(Uuid, Uuid)>,
+    service: web::Data<UserService>,
+) -> HttpResponse {
+    let (realm_id, id) = params.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "users", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.in_realm(realm_id, id).await;
+
+    versioned(result)
+}
@@ -0,0 +1,186 @@
+use super::common::{authorize, ensure_realm, realm_permission, versioned, Response};
+use actix_web::{web, HttpResponse};
+use lib::audit::Actor;
+use lib::db::page::PageParams;
+use lib::db::patch::Precondition;
+use lib::jwt::Claims;
+use lib::webhooks::{WebhookDeliveryFilter, WebhookService, WebhookSubscriptionCreate, WebhookSubscriptionUpdate};
+use uuid::Uuid;
+
+pub fn mount(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/realms/{realm_id}/webhooks")
+            .route(web::get().to(list))
+            .route(web::post().to(create)),
+    );
+
+    cfg.service(
+        web::resource("/realms/{realm_id}/webhooks/{id}")
+            .route(web::get().to(show))
+            .route(web::post().to(update))
+            .route(web::patch().to(update))
+            .route(web::delete().to(delete)),
+    );
+
+    cfg.service(
+        web::resource("/realms/{realm_id}/webhook_deliveries")
+            .route(web::get().to(deliveries)),
+    );
+
+    cfg.service(
+        web::resource("/realms/{realm_id}/webhook_deliveries/{id}/replay")
+            .route(web::post().to(replay)),
+    );
+}
+
+#[derive(Deserialize)]
+struct DeliveryParams {
+    subscription_id: Option<Uuid>,
+    status: Option<String>,
+}
+
+async fn list(
+    claims: Claims,
+    realm_id: web::Path<Uuid>,
+    page: web::Query<PageParams>,
+    service: web::Data<WebhookService>,
+) -> HttpResponse {
+    let realm_id = realm_id.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "webhooks", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service.subscriptions(realm_id, &page).await;
+
+    Response::from_result(result).json()
+}
+
+async fn create(
+    claims: Claims,
+    actor: Actor,
+    realm_id: web::Path<Uuid>,
+    params: web::Json<WebhookSubscriptionCreate>,
+    service: web::Data<WebhookService>,
+) -> HttpResponse {
+    let realm_id = realm_id.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "webhooks", "create")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let mut params = params.into_inner();
+    params.realm_id = realm_id;
+
+    let result = service.create(&actor, params).await;
+
+    Response::from_result(result).json()
+}
+
+async fn show(claims: Claims, params: web::Path<(Uuid, Uuid)>, service: web::Data<WebhookService>) -> HttpResponse {
+    let (realm_id, id) = params.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "webhooks", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let result = service
+        .subscription(id)
+        .await
+        .and_then(|subscription| ensure_realm(realm_id, subscription.realm_id).map(|_| subscription));
+
+    versioned(result)
+}
+
+async fn update(
+    claims: Claims,
+    actor: Actor,
+    precondition: Precondition,
+    path: web::Path<(Uuid, Uuid)>,
+    params: web::Json<WebhookSubscriptionUpdate>,
+    service: web::Data<WebhookService>,
+) -> HttpResponse {
+    let (realm_id, id) = path.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "webhooks", "update")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    if let Err(err) = in_realm(&service, realm_id, id).await {
+        return Response::<()>::error(err).json();
+    }
+
+    let result = service.update(&actor, id, params.into_inner(), &precondition).await;
+
+    versioned(result)
+}
+
+async fn delete(claims: Claims, actor: Actor, path: web::Path<(Uuid, Uuid)>, service: web::Data<WebhookService>) -> HttpResponse {
+    let (realm_id, id) = path.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "webhooks", "delete")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    if let Err(err) = in_realm(&service, realm_id, id).await {
+        return Response::<()>::error(err).json();
+    }
+
+    let result = service.delete(&actor, id).await;
+
+    Response::from_result(result).json()
+}
+
+// `?status=dead` is the dead-letter view
+async fn deliveries(
+    claims: Claims,
+    realm_id: web::Path<Uuid>,
+    params: web::Query<DeliveryParams>,
+    page: web::Query<PageParams>,
+    service: web::Data<WebhookService>,
+) -> HttpResponse {
+    let realm_id = realm_id.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "webhooks", "read")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let params = params.into_inner();
+
+    let filter = WebhookDeliveryFilter {
+        realm_id,
+        subscription_id: params.subscription_id,
+        status: params.status,
+    };
+
+    let result = service.deliveries(&filter, &page).await;
+
+    Response::from_result(result).json()
+}
+
+async fn replay(claims: Claims, actor: Actor, path: web::Path<(Uuid, Uuid)>, service: web::Data<WebhookService>) -> HttpResponse {
+    let (realm_id, id) = path.into_inner();
+
+    if let Err(err) = authorize(&claims, &realm_permission(realm_id, "webhooks", "update")) {
+        return Response::<()>::error(err).forbidden();
+    }
+
+    let delivery = service
+        .delivery(id)
+        .await
+        .and_then(|delivery| ensure_realm(realm_id, delivery.realm_id));
+
+    if let Err(err) = delivery {
+        return Response::<()>::error(err).json();
+    }
+
+    let result = service.replay(&actor, id).await;
+
+    Response::from_result(result).json()
+}
+
+async fn in_realm(service: &WebhookService, realm_id: Uuid, id: Uuid) -> lib::result::Result<()> {
+    let subscription = service.subscription(id).await?;
+
+    ensure_realm(realm_id, subscription.realm_id)
+}
@@ -0,0 +1 @@
+ALTER TABLE realms ADD COLUMN max_role_depth INTEGER NOT NULL DEFAULT 16;
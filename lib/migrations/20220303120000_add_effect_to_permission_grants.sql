@@ -0,0 +1,5 @@
+ALTER TABLE user_permission_grants ADD COLUMN effect VARCHAR(8) NOT NULL DEFAULT 'allow';
+ALTER TABLE user_permission_grants ADD CONSTRAINT user_permission_grants_effect_check CHECK (effect IN ('allow', 'deny'));
+
+ALTER TABLE role_permission_grants ADD COLUMN effect VARCHAR(8) NOT NULL DEFAULT 'allow';
+ALTER TABLE role_permission_grants ADD CONSTRAINT role_permission_grants_effect_check CHECK (effect IN ('allow', 'deny'));
@@ -0,0 +1,3 @@
+ALTER TABLE user_permission_grants ADD COLUMN condition TEXT;
+
+ALTER TABLE role_permission_grants ADD COLUMN condition TEXT;
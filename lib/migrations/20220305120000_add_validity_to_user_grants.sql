@@ -0,0 +1,13 @@
+ALTER TABLE user_permission_grants ADD COLUMN starts_at TIMESTAMP;
+ALTER TABLE user_permission_grants ADD COLUMN expires_at TIMESTAMP;
+ALTER TABLE user_permission_grants ADD COLUMN swept_at TIMESTAMP;
+ALTER TABLE user_permission_grants ADD CONSTRAINT user_permission_grants_validity_check CHECK (starts_at IS NULL OR expires_at IS NULL OR starts_at < expires_at);
+
+CREATE INDEX user_permission_grants_expires_at_idx ON user_permission_grants(expires_at) WHERE swept_at IS NULL;
+
+ALTER TABLE user_role_grants ADD COLUMN starts_at TIMESTAMP;
+ALTER TABLE user_role_grants ADD COLUMN expires_at TIMESTAMP;
+ALTER TABLE user_role_grants ADD COLUMN swept_at TIMESTAMP;
+ALTER TABLE user_role_grants ADD CONSTRAINT user_role_grants_validity_check CHECK (starts_at IS NULL OR expires_at IS NULL OR starts_at < expires_at);
+
+CREATE INDEX user_role_grants_expires_at_idx ON user_role_grants(expires_at) WHERE swept_at IS NULL;
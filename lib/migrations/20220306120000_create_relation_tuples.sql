@@ -0,0 +1,33 @@
+CREATE TABLE relation_tuples (
+    id UUID PRIMARY KEY NOT NULL DEFAULT uuid_generate_v4(),
+    realm_id UUID NOT NULL,
+    object_type VARCHAR(64) NOT NULL,
+    object_id VARCHAR(255) NOT NULL,
+    relation VARCHAR(64) NOT NULL,
+    subject_type VARCHAR(64) NOT NULL,
+    subject_id VARCHAR(255) NOT NULL,
+    subject_relation VARCHAR(64),
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT relation_tuples_realms_fk FOREIGN KEY(realm_id) REFERENCES realms(id)
+);
+
+CREATE UNIQUE INDEX relation_tuples_unique_idx ON relation_tuples(
+    realm_id, object_type, object_id, relation,
+    subject_type, subject_id, COALESCE(subject_relation, '')
+);
+CREATE INDEX relation_tuples_subject_idx ON relation_tuples(realm_id, subject_type, subject_id);
+
+CREATE TABLE relation_rewrites (
+    id UUID PRIMARY KEY NOT NULL DEFAULT uuid_generate_v4(),
+    realm_id UUID NOT NULL,
+    object_type VARCHAR(64) NOT NULL,
+    relation VARCHAR(64) NOT NULL,
+    implied_by VARCHAR(64) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT relation_rewrites_realms_fk FOREIGN KEY(realm_id) REFERENCES realms(id),
+    CONSTRAINT relation_rewrites_unique UNIQUE(realm_id, object_type, relation, implied_by)
+);
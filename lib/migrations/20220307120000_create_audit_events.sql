@@ -0,0 +1,30 @@
+CREATE TABLE audit_events (
+    id UUID PRIMARY KEY NOT NULL DEFAULT uuid_generate_v4(),
+    realm_id UUID,
+    actor_id UUID,
+    action VARCHAR(64) NOT NULL,
+    target_type VARCHAR(64) NOT NULL,
+    target_id UUID,
+    ip VARCHAR(64),
+    user_agent TEXT,
+    details JSONB NOT NULL DEFAULT '{}'::jsonb,
+    before JSONB,
+    after JSONB,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX audit_events_realm_id_idx ON audit_events(realm_id);
+CREATE INDEX audit_events_actor_id_idx ON audit_events(actor_id);
+CREATE INDEX audit_events_action_idx ON audit_events(action);
+CREATE INDEX audit_events_target_idx ON audit_events(target_type, target_id);
+CREATE INDEX audit_events_created_at_idx ON audit_events(created_at);
+
+CREATE FUNCTION audit_events_append_only() RETURNS TRIGGER AS $$
+BEGIN
+    RAISE EXCEPTION 'audit_events is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_events_append_only
+BEFORE UPDATE OR DELETE ON audit_events
+FOR EACH ROW EXECUTE PROCEDURE audit_events_append_only();
@@ -0,0 +1,39 @@
+CREATE TABLE webhook_subscriptions (
+    id UUID PRIMARY KEY NOT NULL DEFAULT uuid_generate_v4(),
+    realm_id UUID NOT NULL,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    events TEXT[] NOT NULL,
+    status VARCHAR(32) NOT NULL DEFAULT 'enabled',
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT webhook_subscriptions_realms_fk FOREIGN KEY(realm_id) REFERENCES realms(id)
+);
+
+CREATE INDEX webhook_subscriptions_realm_id_idx ON webhook_subscriptions(realm_id);
+
+-- the outbox: rows are written in the same transaction as the change they
+-- describe and the delivery worker works through them from here
+CREATE TABLE webhook_deliveries (
+    id UUID PRIMARY KEY NOT NULL DEFAULT uuid_generate_v4(),
+    subscription_id UUID NOT NULL,
+    realm_id UUID NOT NULL,
+    event VARCHAR(64) NOT NULL,
+    payload JSONB NOT NULL,
+    status VARCHAR(32) NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_status INTEGER,
+    last_error TEXT,
+    delivered_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT webhook_deliveries_subscriptions_fk FOREIGN KEY(subscription_id)
+        REFERENCES webhook_subscriptions(id) ON DELETE CASCADE,
+    CONSTRAINT webhook_deliveries_status_check CHECK (status IN ('pending', 'delivered', 'dead'))
+);
+
+CREATE INDEX webhook_deliveries_due_idx ON webhook_deliveries(next_attempt_at) WHERE status = 'pending';
+CREATE INDEX webhook_deliveries_realm_status_idx ON webhook_deliveries(realm_id, status);
@@ -0,0 +1,25 @@
+CREATE TABLE sessions (
+    id UUID PRIMARY KEY NOT NULL DEFAULT uuid_generate_v4(),
+    user_id UUID NOT NULL,
+    realm_id UUID NOT NULL,
+    device VARCHAR(255),
+    ip VARCHAR(64),
+    user_agent TEXT,
+    last_seen_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at TIMESTAMP NOT NULL,
+    revoked_at TIMESTAMP,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    CONSTRAINT sessions_realms_fk FOREIGN KEY(realm_id) REFERENCES realms(id)
+);
+
+CREATE INDEX sessions_user_id_idx ON sessions(user_id) WHERE revoked_at IS NULL;
+CREATE INDEX sessions_realm_id_idx ON sessions(realm_id);
+
+-- a refresh token belongs to the session it keeps alive and goes with it
+ALTER TABLE refresh_tokens ADD COLUMN session_id UUID;
+ALTER TABLE refresh_tokens ADD CONSTRAINT refresh_tokens_sessions_fk
+    FOREIGN KEY(session_id) REFERENCES sessions(id) ON DELETE CASCADE;
+
+CREATE INDEX refresh_tokens_session_id_idx ON refresh_tokens(session_id);
@@ -0,0 +1,30 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+-- everything a user can be searched by, as one lowercased string; the
+-- profile keys here are the ones lib/src/users.rs highlights
+CREATE FUNCTION user_search_document(
+    username VARCHAR,
+    email VARCHAR,
+    first_name VARCHAR,
+    last_name VARCHAR,
+    profile JSONB
+) RETURNS TEXT AS $$
+    SELECT lower(
+        coalesce(username, '') || ' ' ||
+        coalesce(email, '') || ' ' ||
+        coalesce(first_name, '') || ' ' ||
+        coalesce(last_name, '') || ' ' ||
+        coalesce(profile->>'display_name', '') || ' ' ||
+        coalesce(profile->>'nickname', '') || ' ' ||
+        coalesce(profile->>'company', '') || ' ' ||
+        coalesce(profile->>'phone', '')
+    );
+$$ LANGUAGE sql IMMUTABLE;
+
+CREATE INDEX users_search_fts_idx ON users USING GIN (
+    to_tsvector('simple', user_search_document(username, email, first_name, last_name, profile))
+);
+
+CREATE INDEX users_search_trgm_idx ON users USING GIN (
+    user_search_document(username, email, first_name, last_name, profile) gin_trgm_ops
+);
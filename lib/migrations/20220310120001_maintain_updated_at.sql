@@ -0,0 +1,42 @@
+-- updated_at doubles as the version handed out in ETags, so it has to move on
+-- every write, including two in the same transaction; hence clock_timestamp()
+-- rather than CURRENT_TIMESTAMP
+CREATE FUNCTION set_updated_at() RETURNS TRIGGER AS $$
+BEGIN
+    NEW.updated_at = clock_timestamp();
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DO $$
+DECLARE
+    name TEXT;
+BEGIN
+    FOREACH name IN ARRAY ARRAY[
+        'realms',
+        'domains',
+        'users',
+        'permissions',
+        'roles',
+        'user_permission_grants',
+        'user_role_grants',
+        'role_role_grants',
+        'role_permission_grants',
+        'authorities',
+        'user_authorities',
+        'refresh_tokens',
+        'key_pairs',
+        'relation_tuples',
+        'relation_rewrites',
+        'webhook_subscriptions',
+        'webhook_deliveries',
+        'sessions'
+    ] LOOP
+        EXECUTE format(
+            'CREATE TRIGGER %I BEFORE UPDATE ON %I FOR EACH ROW EXECUTE PROCEDURE set_updated_at()',
+            name || '_set_updated_at',
+            name
+        );
+    END LOOP;
+END;
+$$;
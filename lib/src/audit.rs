@@ -0,0 +1,178 @@
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use sqlx::{Executor, Postgres};
+use uuid::Uuid;
+
+use crate::db::page::{ListQuery, Page, PageParams, SortKey};
+use crate::db::pg::Pool;
+use crate::jwt::Claims;
+use crate::result::Result;
+
+pub const GRANT_EXPIRED: &str = "grant.expired";
+
+const AUDIT_SORTS: &[SortKey] = &[
+    SortKey::desc("created_at", "timestamp"),
+];
+
+// who is making a change and from where; handlers get one from the request
+// and anything the system does on its own uses `Actor::system()`
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Actor {
+    pub user_id: Option<Uuid>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl Actor {
+    pub fn system() -> Self {
+        Self::default()
+    }
+
+    pub fn new(claims: Option<&Claims>, ip: Option<String>, user_agent: Option<String>) -> Self {
+        Self {
+            user_id: claims.and_then(|claims| claims.sub),
+            ip,
+            user_agent,
+        }
+    }
+
+    pub fn event(&self, action: &str, target_type: &str, target_id: Option<Uuid>) -> AuditEventCreate {
+        AuditEventCreate {
+            realm_id: None,
+            actor_id: self.user_id,
+            action: action.to_string(),
+            target_type: target_type.to_string(),
+            target_id,
+            ip: self.ip.clone(),
+            user_agent: self.user_agent.clone(),
+            details: JsonValue::Object(Default::default()),
+            before: None,
+            after: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct AuditEvent {
+    pub id: Uuid,
+    pub realm_id: Option<Uuid>,
+    pub actor_id: Option<Uuid>,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: Option<Uuid>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: JsonValue,
+    pub before: Option<JsonValue>,
+    pub after: Option<JsonValue>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug)]
+pub struct AuditEventCreate {
+    pub realm_id: Option<Uuid>,
+    // None when the system acted on its own, e.g. the grant sweeper
+    pub actor_id: Option<Uuid>,
+    pub action: String,
+    pub target_type: String,
+    pub target_id: Option<Uuid>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: JsonValue,
+    pub before: Option<JsonValue>,
+    pub after: Option<JsonValue>,
+}
+
+impl AuditEventCreate {
+    pub fn realm(mut self, realm_id: Uuid) -> Self {
+        self.realm_id = Some(realm_id);
+        self
+    }
+
+    pub fn details(mut self, details: JsonValue) -> Self {
+        self.details = details;
+        self
+    }
+
+    pub fn before<T: Serialize>(mut self, before: &T) -> Self {
+        self.before = serde_json::to_value(before).ok();
+        self
+    }
+
+    pub fn after<T: Serialize>(mut self, after: &T) -> Self {
+        self.after = serde_json::to_value(after).ok();
+        self
+    }
+
+    // pass the transaction making the change so the event only exists if
+    // the change does
+    pub async fn record<'c, E>(self, executor: E) -> Result<AuditEvent>
+    where
+        E: Executor<'c, Database = Postgres>,
+    {
+        let result = sqlx::query_as::<_, AuditEvent>(r#"
+            INSERT INTO audit_events (
+                realm_id, actor_id, action,
+                target_type, target_id,
+                ip, user_agent,
+                details, before, after
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+            RETURNING *;
+        "#)
+            .bind(self.realm_id)
+            .bind(self.actor_id)
+            .bind(self.action)
+            .bind(self.target_type)
+            .bind(self.target_id)
+            .bind(self.ip)
+            .bind(self.user_agent)
+            .bind(self.details)
+            .bind(self.before)
+            .bind(self.after)
+            .fetch_one(executor)
+            .await?;
+
+        Ok(result)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditFilter {
+    pub realm_id: Option<Uuid>,
+    pub actor_id: Option<Uuid>,
+    pub action: Option<String>,
+    pub target_type: Option<String>,
+    pub target_id: Option<Uuid>,
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+}
+
+#[derive(Clone)]
+pub struct AuditService {
+    pool: Pool,
+}
+
+impl AuditService {
+    pub fn new(pool: &Pool) -> Result<Self> {
+        let service = Self {
+            pool: pool.clone(),
+        };
+
+        Ok(service)
+    }
+
+    // newest first unless sorted otherwise
+    pub async fn query(&self, filter: &AuditFilter, page: &PageParams) -> Result<Page<AuditEvent>> {
+        ListQuery::new("audit_events", AUDIT_SORTS)
+            .filter("realm_id = ?", filter.realm_id)
+            .filter("actor_id = ?", filter.actor_id)
+            .filter("action = ?", filter.action.as_ref())
+            .filter("target_type = ?", filter.target_type.as_ref())
+            .filter("target_id = ?", filter.target_id)
+            .filter("created_at >= ?", filter.since)
+            .filter("created_at < ?", filter.until)
+            .fetch(&self.pool, page)
+            .await
+    }
+}
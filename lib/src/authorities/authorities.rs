@@ -1,13 +1,32 @@
 use chrono::NaiveDateTime;
 use openssl::base64;
+use sqlx::{Postgres, Transaction};
 use uuid::Uuid;
 use serde_json::value::Value as JsonValue;
 
+use crate::audit::Actor;
+use crate::db::page::{ListQuery, Page, PageParams, SortKey};
+use crate::db::patch::{Patch, Precondition, Versioned};
 use crate::db::pg::{Pool, QueryResult};
+use crate::permissions::permission::ADMIN_REALM;
 use crate::result::{Result, Context};
 use super::strategies::StrategyType;
 use crate::{RealmService, KeyPair, PublicKey};
 
+const AUTHORITY_SORTS: &[SortKey] = &[
+    SortKey::new("created_at", "timestamp"),
+    SortKey::new("name", "varchar"),
+];
+
+// `name` matches on a prefix
+#[derive(Debug, Default, Deserialize)]
+pub struct AuthorityFilter {
+    pub realm_id: Option<Uuid>,
+    pub status: Option<String>,
+    pub strategy: Option<String>,
+    pub name: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Authority {
     pub id: Uuid,
@@ -21,6 +40,12 @@ pub struct Authority {
     pub updated_at: Option<NaiveDateTime>,
 }
 
+impl Versioned for Authority {
+    fn version(&self) -> Option<NaiveDateTime> {
+        self.updated_at
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct AuthorityCreate {
     pub realm_id: Uuid,
@@ -31,12 +56,13 @@ pub struct AuthorityCreate {
     pub params: JsonValue,
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct AuthorityUpdate {
-    pub client_key: Option<Uuid>,
-    pub name: String,
-    pub status: String,
-    pub params: JsonValue,
+    pub client_key: Patch<Uuid>,
+    pub name: Patch<String>,
+    pub status: Patch<String>,
+    pub params: Patch<JsonValue>,
 }
 
 #[derive(Clone)]
@@ -53,14 +79,14 @@ impl AuthorityService {
         Ok(service)
     }
 
-    pub async fn all(&self) -> Result<Vec<Authority>> {
-        let authorities = sqlx::query_as::<_, Authority>(r#"
-            SELECT * FROM authorities
-        "#)
-            .fetch_all(&self.pool)
-            .await?;
-
-        Ok(authorities)
+    pub async fn list(&self, filter: &AuthorityFilter, page: &PageParams) -> Result<Page<Authority>> {
+        ListQuery::new("authorities", AUTHORITY_SORTS)
+            .filter("realm_id = ?", filter.realm_id)
+            .filter("status = ?", filter.status.as_ref())
+            .filter("strategy = ?", filter.strategy.as_ref())
+            .prefix("name", filter.name.as_ref())
+            .fetch(&self.pool, page)
+            .await
     }
 
     pub fn by_id_query(id: Uuid) -> QueryResult<'static, Authority> {
@@ -114,7 +140,29 @@ impl AuthorityService {
         Ok(public_keys)
     }
 
-    pub async fn create(&self, authority: AuthorityCreate) -> Result<Authority> {
+    pub async fn is_admin_realm(&self, realm_id: Uuid) -> Result<bool> {
+        let (admin,): (bool,) = sqlx::query_as(r#"
+            SELECT EXISTS(SELECT 1 FROM realms WHERE id = $1 AND name = $2)
+        "#)
+            .bind(realm_id)
+            .bind(ADMIN_REALM)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(admin)
+    }
+
+    pub async fn create(&self, actor: &Actor, authority: AuthorityCreate) -> Result<Authority> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = Self::create_in(&mut tx, actor, authority).await?;
+
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    pub async fn create_in(tx: &mut Transaction<'_, Postgres>, actor: &Actor, authority: AuthorityCreate) -> Result<Authority> {
         // let a = authority.clone();
         //
         // let result = sqlx::query(r#"
@@ -145,31 +193,108 @@ impl AuthorityService {
             .bind(authority.status)
             .bind(authority.strategy)
             .bind(authority.params)
-            .fetch_one(&self.pool)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        actor.event("authority.created", "authorities", Some(result.id))
+            .realm(result.realm_id)
+            .after(&redacted(&result))
+            .record(&mut *tx)
             .await?;
 
         Ok(result)
     }
 
-    pub async fn update(&self, id: Uuid, authority: AuthorityUpdate) -> Result<Authority> {
+    pub async fn update(&self, actor: &Actor, id: Uuid, authority: AuthorityUpdate, precondition: &Precondition) -> Result<Authority> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = Self::update_in(&mut tx, actor, id, authority, precondition).await?;
+
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    pub async fn update_in(tx: &mut Transaction<'_, Postgres>, actor: &Actor, id: Uuid, authority: AuthorityUpdate, precondition: &Precondition) -> Result<Authority> {
+        let client_key = authority.client_key.required("client_key")?;
+        let name = authority.name.required("name")?;
+        let status = authority.status.required("status")?;
+        let params = authority.params.required("params")?;
+
+        let before = sqlx::query_as::<_, Authority>(r#"
+            SELECT * FROM authorities
+            WHERE id = $1
+            FOR UPDATE
+        "#)
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        precondition.check(&before)?;
+
         let result = sqlx::query_as::<_, Authority>(r#"
-            UPDATE authorities 
-            SET name = $2
+            UPDATE authorities
+            SET
+                client_key = COALESCE($2, client_key),
+                name = COALESCE($3, name),
+                status = COALESCE($4, status),
+                params = COALESCE($5, params)
             WHERE id = $1
             RETURNING *;
         "#)
             .bind(id)
-            .bind(authority.name)
-            .bind(authority.status)
-            .bind(authority.params)
-            .fetch_one(&self.pool)
+            .bind(client_key)
+            .bind(name)
+            .bind(status)
+            .bind(params)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        actor.event("authority.updated", "authorities", Some(id))
+            .realm(result.realm_id)
+            .before(&redacted(&before))
+            .after(&redacted(&result))
+            .record(&mut *tx)
             .await?;
 
         Ok(result)
     }
 
-    pub async fn delete(&self, id: Uuid) -> Result<()> {
-        todo!()
+    pub async fn delete(&self, actor: &Actor, id: Uuid) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        Self::delete_in(&mut tx, actor, id).await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_in(tx: &mut Transaction<'_, Postgres>, actor: &Actor, id: Uuid) -> Result<()> {
+        sqlx::query(r#"
+            DELETE FROM user_authorities
+            WHERE authority_id = $1
+        "#)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+
+        let deleted = sqlx::query_as::<_, Authority>(r#"
+            DELETE FROM authorities
+            WHERE id = $1
+            RETURNING *;
+        "#)
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        actor.event("authority.deleted", "authorities", Some(id))
+            .realm(deleted.realm_id)
+            .before(&redacted(&deleted))
+            .record(&mut *tx)
+            .await?;
+
+        Ok(())
     }
 
     pub fn create_user_authority_query(user_authority: UserAuthorityCreate) -> QueryResult<'static, UserAuthority> {
@@ -196,6 +321,54 @@ impl AuthorityService {
 
         Ok(user_authorities)
     }
+
+    // the identities a user can sign in with, without their credentials
+    pub async fn linked_by_user_id(&self, user_id: Uuid) -> Result<Vec<LinkedAuthority>> {
+        let results = sqlx::query_as::<_, LinkedAuthority>(r#"
+            SELECT
+                user_authorities.id,
+                user_authorities.authority_id,
+                user_authorities.realm_id,
+                authorities.name,
+                authorities.strategy,
+                user_authorities.created_at
+            FROM user_authorities
+            JOIN authorities ON authorities.id = user_authorities.authority_id
+            WHERE user_authorities.user_id = $1
+            ORDER BY user_authorities.created_at
+        "#)
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(results)
+    }
+}
+
+// params that would let someone forge a credential for the authority
+pub const SECRET_PARAMS: &[&str] = &["password_salt"];
+
+pub fn without_secrets(params: &JsonValue) -> JsonValue {
+    let mut params = params.clone();
+
+    if let Some(params) = params.as_object_mut() {
+        for key in SECRET_PARAMS.iter() {
+            params.remove(*key);
+        }
+    }
+
+    params
+}
+
+// what the audit log keeps of an authority; the password salt stays out of it
+fn redacted(authority: &Authority) -> JsonValue {
+    let mut value = serde_json::to_value(authority).unwrap_or_default();
+
+    if let Some(params) = value.get_mut("params") {
+        *params = without_secrets(params);
+    }
+
+    value
 }
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
@@ -209,6 +382,16 @@ pub struct UserAuthority {
     pub updated_at: Option<NaiveDateTime>,
 }
 
+#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct LinkedAuthority {
+    pub id: Uuid,
+    pub authority_id: Uuid,
+    pub realm_id: Uuid,
+    pub name: String,
+    pub strategy: StrategyType,
+    pub created_at: Option<NaiveDateTime>,
+}
+
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct UserAuthorityCreate {
     pub user_id: Uuid,
@@ -4,17 +4,19 @@ use std::fmt;
 use uuid::Uuid;
 
 use crate::{
+    audit::Actor,
     result::Result,
     db::pg::Pool, grants::tree::RootNode, permission_service::Permission,
     Authority as AuthorityRow, AuthorityService, User, UserAuthorityCreate, UserCreate,
     UserService,
     RealmService,
     jwt::Claims,
+    webhooks,
 };
 
 pub mod username_password;
 
-#[derive(Clone, Serialize, Deserialize, sqlx::Type)]
+#[derive(Clone, PartialEq, Serialize, Deserialize, sqlx::Type)]
 #[serde(rename_all = "snake_case")]
 #[sqlx(rename = "VARCHAR")]
 #[sqlx(rename_all = "snake_case")]
@@ -32,6 +34,14 @@ impl fmt::Debug for StrategyType {
     }
 }
 
+// what a successful authentication hands back: a short-lived jwt and the
+// refresh token for the session it belongs to
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AuthTokens {
+    pub jwt: String,
+    pub refresh_token: Uuid,
+}
+
 #[async_trait]
 pub trait Authority: Sync {
     type AuthParams;
@@ -49,9 +59,9 @@ pub trait Authority: Sync {
         params: Self::RegisterParams,
     ) -> Result<(UserCreate, JsonValue)>;
 
-    async fn authenticate(&self, params: Self::AuthParams) -> Result<String>;
+    async fn authenticate(&self, actor: &Actor, params: Self::AuthParams) -> Result<AuthTokens>;
 
-    async fn register(&self, client_key: Uuid, params: Self::RegisterParams) -> Result<User> {
+    async fn register(&self, actor: &Actor, client_key: Uuid, params: Self::RegisterParams) -> Result<User> {
         let pool = self.pool();
 
         let authority = AuthorityService::by_client_key_query(client_key)
@@ -77,6 +87,20 @@ pub trait Authority: Sync {
             .fetch_one(&mut tx)
             .await?;
 
+        actor.event("user.registered", "users", Some(user.id))
+            .realm(authority.realm_id)
+            .details(serde_json::json!({ "authority_id": authority.id }))
+            .after(&user)
+            .record(&mut tx)
+            .await?;
+
+        let data = serde_json::json!({
+            "user": user,
+            "authority_id": authority.id,
+        });
+
+        webhooks::enqueue(&mut tx, authority.realm_id, webhooks::USER_REGISTERED, data).await?;
+
         tx.commit().await?;
 
         Ok(user)
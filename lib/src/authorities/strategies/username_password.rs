@@ -1,14 +1,17 @@
 use anyhow::{Context, Error, Result};
 use async_trait::async_trait;
 use bcrypt;
+use serde_json::json;
 use serde_json::value::{Map, Value as JsonValue};
 use uuid::Uuid;
 
-use crate::{RealmService, jwt};
+use crate::{RealmService, bulk, jwt};
+use crate::audit::Actor;
 use crate::db::pg::Pool;
+use crate::sessions::SessionService;
 use crate::{
-    authorities::strategies, authorities::AuthorityService, permission_service::Permission,
-    Authority as AuthorityRow, GrantService, User, UserCreate, UserService,
+    authorities::strategies::{self, AuthTokens}, authorities::AuthorityService, permission_service::Permission,
+    Authority as AuthorityRow, GrantService, User, UserAuthority, UserCreate, UserService,
     grants::tree::RootNode,
 };
 
@@ -18,6 +21,7 @@ pub struct AuthService {
     realms: RealmService,
     authorities: AuthorityService,
     grants: GrantService,
+    sessions: SessionService,
     users: UserService,
 }
 
@@ -56,6 +60,9 @@ pub struct AuthParams {
     pub client_key: Uuid,
     pub username: String,
     pub password: String,
+    // a name for the session, e.g. "Work laptop"
+    #[serde(default)]
+    pub device: Option<String>,
 }
 
 #[async_trait]
@@ -64,16 +71,18 @@ impl strategies::Authority for AuthService {
     type AuthParams = AuthParams;
 
     fn new(pool: &Pool) -> Result<Self> {
-        let realms = RealmService::new(&pool)?;
-        let authorities = AuthorityService::new(&pool)?;
-        let grants = GrantService::new(&pool)?;
-        let users = UserService::new(&pool)?;
+        let realms = RealmService::new(pool)?;
+        let authorities = AuthorityService::new(pool)?;
+        let grants = GrantService::new(pool)?;
+        let sessions = SessionService::new(pool)?;
+        let users = UserService::new(pool)?;
 
         let service = AuthService {
             pool: pool.to_owned(),
             realms,
             authorities,
             grants,
+            sessions,
             users,
         };
 
@@ -108,46 +117,137 @@ impl strategies::Authority for AuthService {
         Ok((user_create, JsonValue::Object(params)))
     }
 
-    async fn authenticate(&self, params: Self::AuthParams) -> Result<String> {
+    // every attempt is recorded, whichever way it goes
+    async fn authenticate(&self, actor: &Actor, params: Self::AuthParams) -> Result<AuthTokens> {
+        let username = params.username.clone();
+        let client_key = params.client_key;
+
+        let result = self.issue_tokens(actor, params).await;
+
+        let event = match &result {
+            Ok((realm_id, user_id, _)) => actor.event("auth.succeeded", "users", Some(*user_id))
+                .realm(*realm_id)
+                .details(json!({ "client_key": client_key })),
+            Err(err) => actor.event("auth.failed", "users", None)
+                .details(json!({
+                    "client_key": client_key,
+                    "username": username,
+                    "reason": err.to_string(),
+                })),
+        };
+
+        if let Err(err) = event.record(&self.pool).await {
+            log::error!("unable to record authentication for {}: {}", username, err);
+        }
+
+        result.map(|(_, _, tokens)| tokens)
+    }
+}
+
+impl AuthService {
+    // trades a session's refresh token for a new jwt and refresh token
+    pub async fn refresh(&self, actor: &Actor, client_key: Uuid, refresh_token: Uuid) -> Result<AuthTokens> {
+        let authority = self.authorities.by_client_key(client_key).await?;
+        let (session, refresh_token) = self.sessions.refresh(actor, authority.realm_id, refresh_token).await?;
+        let user = self.users.by_id(session.user_id).await?;
+
+        let jwt = self.token_for(&authority, user, session.id).await?;
+
+        Ok(AuthTokens { jwt, refresh_token })
+    }
+
+    // the realm and user the tokens were issued for, along with the tokens
+    async fn issue_tokens(&self, actor: &Actor, params: AuthParams) -> Result<(Uuid, Uuid, AuthTokens)> {
         let AuthParams {
             client_key,
             username,
             password,
+            device,
         } = params;
 
         let user = self.users.by_username(username).await?;
         let authority = self.authorities.by_client_key(client_key).await?;
-        let keys = RealmService::key_pairs_by_id_query(&self.pool, authority.realm_id).await?;
-        let salt = get_string_from(&authority.params, "password_salt")?;
-
         let credentials = self.authorities.user_authority_by_user_id(user.id).await?;
 
-        for credential in credentials.iter() {
-            let hashed = get_string_from(&credential.params, "password_digest")?;
+        if verify_password(&authority, &credentials, &password)? {
+            let user_id = user.id;
+            let (session, refresh_token) = self.sessions.start(actor, authority.realm_id, user_id, device).await?;
 
-            if bcrypt::verify(format!("{}:::{}", &salt, &password), hashed)? {
-                let permission_tree = self.grants.by_user_id(authority.realm_id, user.id).await?;
+            let jwt = self.token_for(&authority, user, session.id).await?;
 
-                let grants = permission_tree.permissions();
+            return Ok((authority.realm_id, user_id, AuthTokens { jwt, refresh_token }))
+        }
 
-                let claims = jwt::Claims {
-                    first_name: user.first_name,
-                    last_name: user.last_name,
-                    email: user.email,
-                    exp: jwt::exp(std::time::Duration::from_secs(60 * 60 * 48)),
-                    grants,
-                };
+        Err(Error::msg("unable to authenticate"))
+    }
 
-                let result = claims.encode(&keys.last().unwrap().private_key)?;
+    // a jwt for `user` in the authority's realm, built from their grants as
+    // they stand right now
+    async fn token_for(&self, authority: &AuthorityRow, user: User, sid: Uuid) -> Result<String> {
+        let keys = RealmService::key_pairs_by_id_query(&self.pool, authority.realm_id).await?;
+        let permission_tree = self.grants.by_user_id(authority.realm_id, user.id).await?;
 
-                return Ok(result)
-            }
+        for error in permission_tree.errors.iter() {
+            log::warn!("skipped while resolving grants for user {}: {}", user.id, error);
         }
 
-        Err(Error::msg("unable to authenticate"))
+        // authorities can opt into tokens that carry role names
+        // instead of the expanded permissions
+        let (grants, roles, conditional_grants) = match get_string_from(&authority.params, "token_grants") {
+            Ok("roles") => (vec![], permission_tree.role_names(), vec![]),
+            _ => (
+                permission_tree.condensed_permissions(),
+                vec![],
+                permission_tree.conditional_permissions(),
+            ),
+        };
+
+        // a token shouldn't outlive the grants it was built from
+        let mut exp = jwt::exp(std::time::Duration::from_secs(60 * 60 * 48));
+
+        if let Some(expires_at) = permission_tree.expires_at() {
+            exp = exp.min(expires_at.timestamp().max(0) as usize);
+        }
+
+        let claims = jwt::Claims {
+            sub: Some(user.id),
+            sid: Some(sid),
+            first_name: user.first_name,
+            last_name: user.last_name,
+            email: user.email,
+            exp,
+            grants,
+            roles,
+            conditional_grants,
+            issuer: None,
+        };
+
+        claims.encode(&keys.last().unwrap().private_key)
     }
 }
 
+// only a credential made for this authority signs in through it; a password
+// set or imported in another realm says nothing about this one
+fn verify_password(authority: &AuthorityRow, credentials: &[UserAuthority], password: &str) -> Result<bool> {
+    let salt = get_string_from(&authority.params, "password_salt")?;
+
+    for credential in credentials.iter().filter(|credential| credential.authority_id == authority.id) {
+        let hashed = get_string_from(&credential.params, "password_digest")?;
+
+        // digests brought over by an import were made without our salt
+        let candidate = match get_string_from(&credential.params, "password_scheme") {
+            Ok(bulk::IMPORTED_PASSWORD_SCHEME) => password.to_string(),
+            _ => format!("{}:::{}", &salt, &password),
+        };
+
+        if bcrypt::verify(candidate, hashed)? {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
 fn get_string_from<'a>(value: &'a JsonValue, key: &str) -> Result<&'a str> {
     let result = value
         .as_object()
@@ -159,3 +259,60 @@ fn get_string_from<'a>(value: &'a JsonValue, key: &str) -> Result<&'a str> {
 
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorities::strategies::StrategyType;
+
+    fn authority(salt: &str) -> AuthorityRow {
+        AuthorityRow {
+            id: Uuid::new_v4(),
+            realm_id: Uuid::new_v4(),
+            client_key: Uuid::new_v4(),
+            name: "username_password".to_string(),
+            status: "enabled".to_string(),
+            strategy: StrategyType::UsernamePassword,
+            params: json!({ "password_salt": salt }),
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    fn credential(authority: &AuthorityRow, params: JsonValue) -> UserAuthority {
+        UserAuthority {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            authority_id: authority.id,
+            realm_id: authority.realm_id,
+            params,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    #[test]
+    fn test_verify_password() {
+        let acme = authority("acme-salt");
+        let globex = authority("globex-salt");
+
+        let salted = bcrypt::hash("acme-salt:::hunter2", 4).unwrap();
+        let imported = bcrypt::hash("hunter2", 4).unwrap();
+
+        let credentials = vec![
+            credential(&acme, json!({ "password_digest": salted })),
+            credential(&globex, json!({
+                "password_digest": imported,
+                "password_scheme": bulk::IMPORTED_PASSWORD_SCHEME,
+            })),
+        ];
+
+        assert!(verify_password(&acme, &credentials, "hunter2").unwrap());
+        assert!(!verify_password(&acme, &credentials, "hunter3").unwrap());
+        assert!(verify_password(&globex, &credentials, "hunter2").unwrap());
+
+        // a password imported into globex doesn't open acme
+        assert!(!verify_password(&acme, &credentials[1..], "hunter2").unwrap());
+        assert!(!verify_password(&authority("other-salt"), &credentials, "hunter2").unwrap());
+    }
+}
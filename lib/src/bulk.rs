@@ -0,0 +1,724 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+use serde_json::json;
+use serde_json::Value as JsonValue;
+use sqlx::{Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::audit::Actor;
+use crate::authorities::strategies::StrategyType;
+use crate::db::pg::Pool;
+use crate::grants::{GrantOptions, GrantService, GrantWindow};
+use crate::permissions::permission::{grant_string, parse_grant, Effect, Permission};
+use crate::result::{Error, Result};
+use crate::{AuthorityService, UserAuthorityCreate, UserCreate, UserService};
+
+pub const DEFAULT_BATCH_SIZE: usize = 500;
+
+// how an imported digest is checked at sign in; it's a plain bcrypt of the
+// password, where ours are salted with the authority's password_salt first
+pub const IMPORTED_PASSWORD_SCHEME: &str = "bcrypt";
+
+const LIST_SEPARATOR: char = ';';
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Csv,
+    Jsonl,
+}
+
+impl Format {
+    pub fn from_path(path: &str) -> Option<Self> {
+        let extension = path.rsplit('.').next()?;
+
+        extension.parse().ok()
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value.to_lowercase().as_str() {
+            "csv" => Ok(Format::Csv),
+            "jsonl" | "ndjson" => Ok(Format::Jsonl),
+            _ => Err(Error::msg(format!("unknown format {}; try csv or jsonl", value))),
+        }
+    }
+}
+
+// one user as it's imported and exported; roles are role names in the realm
+// and permissions are `realm:resource:action` strings, `!` marking a deny
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct UserRecord {
+    pub username: String,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub first_name: Option<String>,
+    #[serde(default)]
+    pub last_name: Option<String>,
+    #[serde(default)]
+    pub status: Option<String>,
+    #[serde(default)]
+    pub kind: Option<String>,
+    #[serde(default)]
+    pub profile: Option<JsonValue>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    // a bcrypt digest of the bare password, e.g. from the system being left
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_digest: Option<String>,
+}
+
+// csv can't nest, so lists are `;` separated and the profile is json text
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CsvRecord {
+    username: String,
+    email: Option<String>,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    status: Option<String>,
+    kind: Option<String>,
+    profile: Option<String>,
+    roles: Option<String>,
+    permissions: Option<String>,
+    password_digest: Option<String>,
+}
+
+impl TryFrom<CsvRecord> for UserRecord {
+    type Error = Error;
+
+    fn try_from(record: CsvRecord) -> Result<Self> {
+        let profile = match non_empty(record.profile) {
+            Some(profile) => Some(serde_json::from_str(&profile).map_err(|err| Error::msg(format!("profile isn't json: {}", err)))?),
+            None => None,
+        };
+
+        Ok(Self {
+            username: record.username,
+            email: non_empty(record.email),
+            first_name: non_empty(record.first_name),
+            last_name: non_empty(record.last_name),
+            status: non_empty(record.status),
+            kind: non_empty(record.kind),
+            profile,
+            roles: split_list(record.roles),
+            permissions: split_list(record.permissions),
+            password_digest: non_empty(record.password_digest),
+        })
+    }
+}
+
+impl From<&UserRecord> for CsvRecord {
+    fn from(record: &UserRecord) -> Self {
+        Self {
+            username: record.username.clone(),
+            email: record.email.clone(),
+            first_name: record.first_name.clone(),
+            last_name: record.last_name.clone(),
+            status: record.status.clone(),
+            kind: record.kind.clone(),
+            profile: record.profile.as_ref().map(|profile| profile.to_string()),
+            roles: Some(record.roles.join(&LIST_SEPARATOR.to_string())),
+            permissions: Some(record.permissions.join(&LIST_SEPARATOR.to_string())),
+            password_digest: record.password_digest.clone(),
+        }
+    }
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    value.filter(|value| !value.trim().is_empty())
+}
+
+fn split_list(value: Option<String>) -> Vec<String> {
+    value
+        .unwrap_or_default()
+        .split(LIST_SEPARATOR)
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+// reads records one at a time so a file never has to fit in memory; a record
+// that can't be parsed comes out as an error rather than ending the read
+pub fn records<'a, R: BufRead + 'a>(reader: R, format: Format) -> Box<dyn Iterator<Item = Result<UserRecord>> + 'a> {
+    match format {
+        Format::Csv => {
+            let records = csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_reader(reader)
+                .into_deserialize::<CsvRecord>()
+                .map(|record| record.map_err(Error::new).and_then(UserRecord::try_from));
+
+            Box::new(records)
+        },
+        Format::Jsonl => {
+            let records = reader
+                .lines()
+                .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+                .map(|line| Ok(serde_json::from_str(&line?)?));
+
+            Box::new(records)
+        },
+    }
+}
+
+// writes records in the same shape `records` reads them
+pub struct RecordWriter<W: Write> {
+    format: Format,
+    csv: Option<csv::Writer<W>>,
+    jsonl: Option<W>,
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub fn new(writer: W, format: Format) -> Self {
+        match format {
+            Format::Csv => Self { format, csv: Some(csv::Writer::from_writer(writer)), jsonl: None },
+            Format::Jsonl => Self { format, csv: None, jsonl: Some(writer) },
+        }
+    }
+
+    pub fn write(&mut self, record: &UserRecord) -> Result<()> {
+        if let Some(writer) = self.csv.as_mut() {
+            writer.serialize(CsvRecord::from(record))?;
+        }
+
+        if let Some(writer) = self.jsonl.as_mut() {
+            serde_json::to_writer(&mut *writer, record)?;
+            writer.write_all(b"\n")?;
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(writer) = self.csv.as_mut() {
+            writer.flush()?;
+        }
+
+        if let Some(writer) = self.jsonl.as_mut() {
+            writer.flush()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ImportOptions {
+    pub realm_id: Uuid,
+    // the username_password authority imported digests are attached to
+    pub authority_id: Option<Uuid>,
+    pub batch_size: usize,
+    // runs every insert, then rolls each batch back
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub total: usize,
+    pub imported: usize,
+    pub failed: usize,
+    pub errors: Vec<RowError>,
+}
+
+// `row` counts records from 1, not counting a csv header
+#[derive(Debug, Serialize)]
+pub struct RowError {
+    pub row: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    pub error: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ExportReport {
+    pub users: usize,
+    // grants with a condition or a validity window, which a record has no
+    // way to carry
+    pub skipped_grants: i64,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct ExportRow {
+    id: Uuid,
+    username: String,
+    email: Option<String>,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    status: String,
+    kind: String,
+    profile: JsonValue,
+    roles: Vec<String>,
+    allows: Vec<String>,
+    denies: Vec<String>,
+    skipped_grants: i64,
+}
+
+impl TryFrom<ExportRow> for UserRecord {
+    type Error = Error;
+
+    fn try_from(row: ExportRow) -> Result<Self> {
+        let allows = row.allows.iter().map(|permission| (Effect::Allow, permission));
+        let denies = row.denies.iter().map(|permission| (Effect::Deny, permission));
+
+        let permissions = allows
+            .chain(denies)
+            .map(|(effect, permission)| Ok(grant_string(effect, &permission.parse::<Permission>()?)))
+            .collect::<Result<Vec<String>>>()?;
+
+        Ok(Self {
+            username: row.username,
+            email: row.email,
+            first_name: row.first_name,
+            last_name: row.last_name,
+            status: Some(row.status),
+            kind: Some(row.kind),
+            profile: Some(row.profile),
+            roles: row.roles,
+            permissions,
+            password_digest: None,
+        })
+    }
+}
+
+// what an import looks names up against, loaded once up front
+struct Lookups {
+    roles: HashMap<String, Uuid>,
+    permissions: HashMap<String, Uuid>,
+    authority_id: Option<Uuid>,
+}
+
+#[derive(Clone)]
+pub struct BulkService {
+    pool: Pool,
+}
+
+impl BulkService {
+    pub fn new(pool: &Pool) -> Result<Self> {
+        let service = Self {
+            pool: pool.clone(),
+        };
+
+        Ok(service)
+    }
+
+    // each batch is its own transaction and each row its own savepoint, so a
+    // bad row is reported without taking the rest of its batch with it
+    pub async fn import<I>(&self, actor: &Actor, options: &ImportOptions, records: I) -> Result<ImportReport>
+    where
+        I: Iterator<Item = Result<UserRecord>>,
+    {
+        let lookups = self.lookups(options).await?;
+        let batch_size = options.batch_size.max(1);
+
+        let mut report = ImportReport { dry_run: options.dry_run, ..ImportReport::default() };
+        let mut records = records.enumerate().peekable();
+
+        while records.peek().is_some() {
+            let mut tx = self.pool.begin().await?;
+
+            for (index, record) in records.by_ref().take(batch_size) {
+                let row = index + 1;
+                report.total += 1;
+
+                let record = match record {
+                    Ok(record) => record,
+                    Err(err) => {
+                        report.failed += 1;
+                        report.errors.push(RowError { row, username: None, error: err.to_string() });
+                        continue;
+                    },
+                };
+
+                sqlx::query("SAVEPOINT import_row").execute(&mut tx).await?;
+
+                match Self::import_one(&mut tx, actor, options.realm_id, &lookups, &record).await {
+                    Ok(()) => {
+                        sqlx::query("RELEASE SAVEPOINT import_row").execute(&mut tx).await?;
+                        report.imported += 1;
+                    },
+                    Err(err) => {
+                        sqlx::query("ROLLBACK TO SAVEPOINT import_row").execute(&mut tx).await?;
+                        report.failed += 1;
+                        report.errors.push(RowError { row, username: Some(record.username), error: err.to_string() });
+                    },
+                }
+            }
+
+            match options.dry_run {
+                true => tx.rollback().await?,
+                false => tx.commit().await?,
+            }
+        }
+
+        Ok(report)
+    }
+
+    // every user with a credential or grant in the realm, with the roles and
+    // permissions granted to them there; digests are never exported
+    pub async fn export<W: Write>(&self, realm_id: Uuid, writer: &mut RecordWriter<W>, batch_size: usize) -> Result<ExportReport> {
+        let mut report = ExportReport::default();
+        let mut after: Option<Uuid> = None;
+
+        loop {
+            let rows = sqlx::query_as::<_, ExportRow>(r#"
+                SELECT
+                    users.id, users.username, users.email,
+                    users.first_name, users.last_name,
+                    users.status, users.kind, users.profile,
+                    ARRAY(
+                        SELECT roles.name::text FROM user_role_grants
+                        JOIN roles ON roles.id = user_role_grants.role_id
+                        WHERE user_role_grants.user_id = users.id
+                        AND user_role_grants.realm_id = $1
+                        AND user_role_grants.swept_at IS NULL
+                        AND user_role_grants.starts_at IS NULL
+                        AND user_role_grants.expires_at IS NULL
+                        ORDER BY roles.name
+                    ) AS roles,
+                    ARRAY(
+                        SELECT permissions.realm || ':' || permissions.resource || ':' || permissions.action
+                        FROM user_permission_grants
+                        JOIN permissions ON permissions.id = user_permission_grants.permission_id
+                        WHERE user_permission_grants.user_id = users.id
+                        AND user_permission_grants.realm_id = $1
+                        AND user_permission_grants.swept_at IS NULL
+                        AND user_permission_grants.effect = 'allow'
+                        AND user_permission_grants.condition IS NULL
+                        AND user_permission_grants.starts_at IS NULL
+                        AND user_permission_grants.expires_at IS NULL
+                        ORDER BY 1
+                    ) AS allows,
+                    ARRAY(
+                        SELECT permissions.realm || ':' || permissions.resource || ':' || permissions.action
+                        FROM user_permission_grants
+                        JOIN permissions ON permissions.id = user_permission_grants.permission_id
+                        WHERE user_permission_grants.user_id = users.id
+                        AND user_permission_grants.realm_id = $1
+                        AND user_permission_grants.swept_at IS NULL
+                        AND user_permission_grants.effect = 'deny'
+                        AND user_permission_grants.condition IS NULL
+                        AND user_permission_grants.starts_at IS NULL
+                        AND user_permission_grants.expires_at IS NULL
+                        ORDER BY 1
+                    ) AS denies,
+                    (
+                        SELECT COUNT(*) FROM user_role_grants
+                        WHERE user_id = users.id
+                        AND realm_id = $1
+                        AND swept_at IS NULL
+                        AND (starts_at IS NOT NULL OR expires_at IS NOT NULL)
+                    ) + (
+                        SELECT COUNT(*) FROM user_permission_grants
+                        WHERE user_id = users.id
+                        AND realm_id = $1
+                        AND swept_at IS NULL
+                        AND (
+                            condition IS NOT NULL
+                            OR starts_at IS NOT NULL
+                            OR expires_at IS NOT NULL
+                        )
+                    ) AS skipped_grants
+                FROM users
+                WHERE users.deleted_at IS NULL
+                AND ($2::uuid IS NULL OR users.id > $2)
+                AND (
+                    EXISTS(SELECT 1 FROM user_authorities WHERE user_id = users.id AND realm_id = $1)
+                    OR EXISTS(SELECT 1 FROM user_role_grants WHERE user_id = users.id AND realm_id = $1)
+                    OR EXISTS(SELECT 1 FROM user_permission_grants WHERE user_id = users.id AND realm_id = $1)
+                )
+                ORDER BY users.id
+                LIMIT $3
+            "#)
+                .bind(realm_id)
+                .bind(after)
+                .bind(batch_size.max(1) as i64)
+                .fetch_all(&self.pool)
+                .await?;
+
+            let last = match rows.last() {
+                Some(row) => row.id,
+                None => break,
+            };
+
+            for row in rows.into_iter() {
+                report.users += 1;
+                report.skipped_grants += row.skipped_grants;
+
+                writer.write(&UserRecord::try_from(row)?)?;
+            }
+
+            after = Some(last);
+        }
+
+        writer.flush()?;
+
+        Ok(report)
+    }
+
+    async fn lookups(&self, options: &ImportOptions) -> Result<Lookups> {
+        let roles: Vec<(String, Uuid)> = sqlx::query_as(r#"
+            SELECT name, id FROM roles
+            WHERE realm_id = $1
+        "#)
+            .bind(options.realm_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let permissions: Vec<(String, Uuid)> = sqlx::query_as(r#"
+            SELECT realm || ':' || resource || ':' || action, id FROM permissions
+            WHERE realm_id = $1
+        "#)
+            .bind(options.realm_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        if let Some(authority_id) = options.authority_id {
+            let authority = AuthorityService::new(&self.pool)?.by_id(authority_id).await?;
+
+            if authority.realm_id != options.realm_id {
+                return Err(Error::msg(format!("authority {} isn't in realm {}", authority_id, options.realm_id)));
+            }
+
+            if !matches!(authority.strategy, StrategyType::UsernamePassword) {
+                return Err(Error::msg(format!("authority {} doesn't take passwords", authority_id)));
+            }
+        }
+
+        Ok(Lookups {
+            roles: roles.into_iter().collect(),
+            permissions: permissions.into_iter().collect(),
+            authority_id: options.authority_id,
+        })
+    }
+
+    async fn import_one(
+        tx: &mut Transaction<'_, Postgres>,
+        actor: &Actor,
+        realm_id: Uuid,
+        lookups: &Lookups,
+        record: &UserRecord,
+    ) -> Result<()> {
+        let (role_ids, permission_ids) = resolve(record, lookups)?;
+
+        let user = UserService::create_query(UserCreate {
+            username: record.username.clone(),
+            email: record.email.clone(),
+            first_name: record.first_name.clone(),
+            last_name: record.last_name.clone(),
+            profile: record.profile.clone().unwrap_or_else(|| json!({})),
+            status: record.status.clone().unwrap_or_else(|| "enabled".to_string()),
+            kind: record.kind.clone().unwrap_or_else(|| "human".to_string()),
+        })
+            .fetch_one(&mut *tx)
+            .await?;
+
+        if let (Some(digest), Some(authority_id)) = (&record.password_digest, lookups.authority_id) {
+            let user_authority = UserAuthorityCreate {
+                user_id: user.id,
+                authority_id,
+                realm_id,
+                params: json!({
+                    "username": user.username,
+                    "password_digest": digest,
+                    "password_scheme": IMPORTED_PASSWORD_SCHEME,
+                }),
+            };
+
+            AuthorityService::create_user_authority_query(user_authority)
+                .fetch_one(&mut *tx)
+                .await?;
+        }
+
+        for role_id in role_ids.into_iter() {
+            GrantService::create_user_role_query(realm_id, user.id, role_id, GrantWindow::default())
+                .fetch_one(&mut *tx)
+                .await?;
+        }
+
+        for (permission_id, effect) in permission_ids.into_iter() {
+            GrantService::create_user_permission_query(realm_id, user.id, permission_id, GrantOptions::with_effect(effect))
+                .fetch_one(&mut *tx)
+                .await?;
+        }
+
+        actor.event("user.imported", "users", Some(user.id))
+            .realm(realm_id)
+            .details(json!({
+                "roles": record.roles,
+                "permissions": record.permissions,
+                "password": record.password_digest.is_some(),
+            }))
+            .after(&user)
+            .record(&mut *tx)
+            .await?;
+
+        Ok(())
+    }
+}
+
+// the role ids and the permission ids with their effects for one record
+type Resolved = (Vec<Uuid>, Vec<(Uuid, Effect)>);
+
+// checks a record against the realm before anything is written for it
+fn resolve(record: &UserRecord, lookups: &Lookups) -> Result<Resolved> {
+    if record.username.trim().is_empty() {
+        return Err(Error::msg("username is required"));
+    }
+
+    if let Some(digest) = &record.password_digest {
+        if !is_bcrypt(digest) {
+            return Err(Error::msg("password_digest isn't a bcrypt digest"));
+        }
+
+        if lookups.authority_id.is_none() {
+            return Err(Error::msg("password_digest given but no authority to attach it to"));
+        }
+    }
+
+    let role_ids = record.roles
+        .iter()
+        .map(|name| lookups.roles.get(name).copied().ok_or_else(|| Error::msg(format!("no role {} in the realm", name))))
+        .collect::<Result<Vec<Uuid>>>()?;
+
+    let permission_ids = record.permissions
+        .iter()
+        .map(|name| {
+            let (effect, permission) = parse_grant(name)?;
+
+            match lookups.permissions.get(&permission.to_string()) {
+                Some(id) => Ok((*id, effect)),
+                None => Err(Error::msg(format!("no permission {} in the realm", permission))),
+            }
+        })
+        .collect::<Result<Vec<(Uuid, Effect)>>>()?;
+
+    Ok((role_ids, permission_ids))
+}
+
+// $2a$, $2b$ or $2y$, a two digit cost, then 53 characters of salt and hash
+pub fn is_bcrypt(digest: &str) -> bool {
+    let parts: Vec<&str> = digest.splitn(4, '$').collect();
+
+    match parts.as_slice() {
+        ["", version, cost, rest] => {
+            ["2a", "2b", "2y"].contains(version)
+                && cost.len() == 2
+                && cost.chars().all(|c| c.is_ascii_digit())
+                && rest.len() == 53
+                && rest.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '/')
+        },
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "$2b$12$R9h/cIPz0gi.URNNX3kh2OPST9/PgBkqquzi.Ss7KIUgO2t0jWMUW";
+
+    #[test]
+    fn test_csv_round_trip() {
+        let input = "\
+username,email,first_name,last_name,status,kind,profile,roles,permissions,password_digest
+ann,ann@example.com,Ann,,,,\"{\"\"company\"\":\"\"Acme\"\"}\",admin; support,app:reports:read,$2b$12$R9h/cIPz0gi.URNNX3kh2OPST9/PgBkqquzi.Ss7KIUgO2t0jWMUW
+bob,,,,,,{not json,,,
+";
+
+        let parsed: Vec<Result<UserRecord>> = records(input.as_bytes(), Format::Csv).collect();
+
+        let ann = parsed[0].as_ref().unwrap();
+        assert_eq!(ann.username, "ann");
+        assert_eq!(ann.last_name, None);
+        assert_eq!(ann.profile, Some(json!({ "company": "Acme" })));
+        assert_eq!(ann.roles, vec!["admin", "support"]);
+        assert_eq!(ann.permissions, vec!["app:reports:read"]);
+        assert_eq!(ann.password_digest.as_deref(), Some(DIGEST));
+        assert!(parsed[1].is_err());
+
+        let mut output = vec![];
+        let mut writer = RecordWriter::new(&mut output, Format::Csv);
+        writer.write(ann).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        let again: Vec<UserRecord> = records(output.as_slice(), Format::Csv).map(|record| record.unwrap()).collect();
+        assert_eq!(&again[0], ann);
+    }
+
+    #[test]
+    fn test_jsonl() {
+        let input = "{\"username\":\"ann\",\"roles\":[\"admin\"]}\n\n{\"email\":\"no username\"}\n";
+
+        let parsed: Vec<Result<UserRecord>> = records(input.as_bytes(), Format::Jsonl).collect();
+
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].as_ref().unwrap().roles, vec!["admin"]);
+        assert!(parsed[1].is_err());
+    }
+
+    #[test]
+    fn test_export_denies() {
+        let row = ExportRow {
+            id: Uuid::new_v4(),
+            username: "ann".to_string(),
+            email: None,
+            first_name: None,
+            last_name: None,
+            status: "enabled".to_string(),
+            kind: "human".to_string(),
+            profile: json!({}),
+            roles: vec![],
+            allows: vec!["app:**:**".to_string()],
+            denies: vec!["app:billing.**:delete".to_string()],
+            skipped_grants: 0,
+        };
+
+        let record = UserRecord::try_from(row).unwrap();
+
+        assert_eq!(record.permissions, vec!["app:**:**", "!app:billing.**:delete"]);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let lookups = Lookups {
+            roles: vec![("admin".to_string(), Uuid::new_v4())].into_iter().collect(),
+            permissions: HashMap::new(),
+            authority_id: None,
+        };
+
+        let record = UserRecord { username: "ann".to_string(), roles: vec!["admin".to_string()], ..UserRecord::default() };
+        assert_eq!(resolve(&record, &lookups).unwrap().0, vec![lookups.roles["admin"]]);
+
+        let unknown = UserRecord { roles: vec!["owner".to_string()], ..record.clone() };
+        assert!(resolve(&unknown, &lookups).is_err());
+
+        let no_authority = UserRecord { password_digest: Some(DIGEST.to_string()), ..record.clone() };
+        assert!(resolve(&no_authority, &lookups).is_err());
+
+        let reports = Uuid::new_v4();
+        let lookups = Lookups {
+            permissions: vec![("app:reports:read".to_string(), reports)].into_iter().collect(),
+            ..lookups
+        };
+
+        let granted = UserRecord { permissions: vec!["app:reports:read".to_string(), "!app:reports:read".to_string()], ..record.clone() };
+        assert_eq!(resolve(&granted, &lookups).unwrap().1, vec![(reports, Effect::Allow), (reports, Effect::Deny)]);
+
+        let unknown = UserRecord { permissions: vec!["!app:billing:read".to_string()], ..record.clone() };
+        assert!(resolve(&unknown, &lookups).is_err());
+
+        assert!(is_bcrypt(DIGEST));
+        assert!(!is_bcrypt("$1$abc$def"));
+        assert!(!is_bcrypt("plaintext"));
+    }
+}
@@ -1,2 +1,4 @@
 pub mod mysql;
+pub mod page;
+pub mod patch;
 pub mod pg;
@@ -0,0 +1,475 @@
+use chrono::NaiveDateTime;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+use sqlx::postgres::PgRow;
+use uuid::Uuid;
+
+use crate::db::pg::{Pool, QueryResult};
+use crate::result::{Error, Result};
+
+pub const DEFAULT_LIMIT: i64 = 50;
+pub const MAX_LIMIT: i64 = 500;
+
+pub fn limit(limit: Option<i64>) -> i64 {
+    limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    #[default]
+    Asc,
+    Desc,
+}
+
+impl Direction {
+    fn keyword(&self) -> &'static str {
+        match self {
+            Direction::Asc => "ASC",
+            Direction::Desc => "DESC",
+        }
+    }
+
+    fn comparison(&self) -> &'static str {
+        match self {
+            Direction::Asc => ">",
+            Direction::Desc => "<",
+        }
+    }
+}
+
+// the part of the query string every list route shares
+#[derive(Debug, Default, Deserialize)]
+pub struct PageParams {
+    // `next` from the previous page
+    pub after: Option<String>,
+    pub limit: Option<i64>,
+    pub sort: Option<String>,
+    // the sort's own direction when left out
+    pub direction: Option<Direction>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    // how many match the filters, across every page
+    pub total: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next: Option<String>,
+}
+
+// a column a list can be sorted on; `field` is its name in the query string
+// and the row's json, `cast` the postgres type a cursor value is read back as
+#[derive(Debug)]
+pub struct SortKey {
+    pub field: &'static str,
+    pub cast: &'static str,
+    pub direction: Direction,
+}
+
+impl SortKey {
+    pub const fn new(field: &'static str, cast: &'static str) -> Self {
+        Self { field, cast, direction: Direction::Asc }
+    }
+
+    // for sorts that only make sense biggest first, like a search score
+    pub const fn desc(field: &'static str, cast: &'static str) -> Self {
+        Self { field, cast, direction: Direction::Desc }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Uuid(Uuid),
+    Text(String),
+    Timestamp(NaiveDateTime),
+    Int(i64),
+}
+
+impl Value {
+    fn bind<'q, T>(self, query: QueryResult<'q, T>) -> QueryResult<'q, T> {
+        match self {
+            Value::Uuid(value) => query.bind(value),
+            Value::Text(value) => query.bind(value),
+            Value::Timestamp(value) => query.bind(value),
+            Value::Int(value) => query.bind(value),
+        }
+    }
+}
+
+impl From<Uuid> for Value {
+    fn from(value: Uuid) -> Self {
+        Value::Uuid(value)
+    }
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Text(value)
+    }
+}
+
+impl From<&String> for Value {
+    fn from(value: &String) -> Self {
+        Value::Text(value.clone())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Text(value.to_string())
+    }
+}
+
+impl From<NaiveDateTime> for Value {
+    fn from(value: NaiveDateTime) -> Self {
+        Value::Timestamp(value)
+    }
+}
+
+// the unique column that breaks ties between rows with the same sort value
+const ID: SortKey = SortKey::new("id", "uuid");
+
+// where the last page left off; it carries the sort it was made under so it
+// can't be replayed against a different one
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Cursor {
+    sort: String,
+    direction: Direction,
+    value: String,
+    key: String,
+}
+
+impl Cursor {
+    fn encode(&self) -> Result<String> {
+        Ok(base64::encode_config(serde_json::to_vec(self)?, base64::URL_SAFE_NO_PAD))
+    }
+
+    fn decode(cursor: &str) -> Result<Self> {
+        let bytes = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| Error::msg("invalid page cursor"))?;
+
+        serde_json::from_slice(&bytes).map_err(|_| Error::msg("invalid page cursor"))
+    }
+
+    // the sort value and key of a row, read back out of its json
+    fn from_row<T: Serialize>(row: &T, sort: &SortKey, key: &SortKey, direction: Direction) -> Result<Self> {
+        let row = serde_json::to_value(row)?;
+
+        let field = |field: &str| match &row[field] {
+            JsonValue::String(value) => Ok(value.clone()),
+            JsonValue::Null => Err(Error::msg(format!("can't page on an empty {}", field))),
+            value => Ok(value.to_string()),
+        };
+
+        Ok(Self {
+            sort: sort.field.to_string(),
+            direction,
+            value: field(sort.field)?,
+            key: field(key.field)?,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Plan {
+    select: String,
+    count: String,
+    binds: Vec<Value>,
+    // the leading binds the count query uses
+    filters: usize,
+}
+
+// builds the list, count and keyset queries for one table from a set of
+// optional filters; every `?` in a condition is the filter's value
+pub struct ListQuery {
+    table: &'static str,
+    source: String,
+    sorts: &'static [SortKey],
+    key: &'static SortKey,
+    conditions: Vec<String>,
+    binds: Vec<Value>,
+}
+
+impl ListQuery {
+    // the first of `sorts` is the default
+    pub fn new(table: &'static str, sorts: &'static [SortKey]) -> Self {
+        Self {
+            table,
+            source: table.to_string(),
+            sorts,
+            key: &ID,
+            conditions: vec![],
+            binds: vec![],
+        }
+    }
+
+    // lists the rows of a subquery instead of a table; `binds` are its `$n`
+    // and filters are numbered on from them
+    pub fn over(table: &'static str, source: String, binds: Vec<Value>, sorts: &'static [SortKey]) -> Self {
+        Self {
+            table,
+            source: format!("({}) AS {}", source, table),
+            sorts,
+            key: &ID,
+            conditions: vec![],
+            binds,
+        }
+    }
+
+    // for tables keyed on something other than an `id`
+    pub fn keyed(mut self, key: &'static SortKey) -> Self {
+        self.key = key;
+        self
+    }
+
+    pub fn and(mut self, condition: &str) -> Self {
+        self.conditions.push(condition.to_string());
+        self
+    }
+
+    pub fn filter<V: Into<Value>>(mut self, condition: &str, value: Option<V>) -> Self {
+        if let Some(value) = value {
+            self.binds.push(value.into());
+            self.conditions.push(condition.replace('?', &format!("${}", self.binds.len())));
+        }
+
+        self
+    }
+
+    pub fn prefix(self, column: &str, prefix: Option<&String>) -> Self {
+        let pattern = prefix.map(|prefix| format!("{}%", escape_like(prefix)));
+
+        self.filter(&format!("{} ILIKE ?", column), pattern)
+    }
+
+    pub async fn fetch<T>(self, pool: &Pool, params: &PageParams) -> Result<Page<T>>
+    where
+        T: for<'r> sqlx::FromRow<'r, PgRow> + Serialize + Send + Unpin,
+    {
+        let sort = self.sort(params)?;
+        let direction = params.direction.unwrap_or(sort.direction);
+        let limit = limit(params.limit);
+        let plan = self.plan(params)?;
+
+        let mut count = sqlx::query_as::<_, (i64,)>(&plan.count);
+
+        for value in plan.binds.iter().take(plan.filters).cloned() {
+            count = value.bind(count);
+        }
+
+        let (total,) = count.fetch_one(pool).await?;
+
+        let mut select = sqlx::query_as::<_, T>(&plan.select);
+
+        for value in plan.binds.into_iter() {
+            select = value.bind(select);
+        }
+
+        let mut items = select.fetch_all(pool).await?;
+
+        // one extra row was asked for to tell whether there's another page
+        let next = match items.len() as i64 > limit {
+            true => {
+                items.truncate(limit as usize);
+
+                let last = items.last().expect("a page past its limit has a last row");
+
+                Some(Cursor::from_row(last, sort, self.key, direction)?.encode()?)
+            },
+            false => None,
+        };
+
+        Ok(Page { items, total, next })
+    }
+
+    fn sort(&self, params: &PageParams) -> Result<&'static SortKey> {
+        match &params.sort {
+            None => self.sorts.first().ok_or_else(|| Error::msg(format!("{} can't be sorted", self.table))),
+            Some(field) => self.sorts
+                .iter()
+                .find(|sort| sort.field == field)
+                .ok_or_else(|| {
+                    let fields: Vec<&str> = self.sorts.iter().map(|sort| sort.field).collect();
+
+                    Error::msg(format!("can't sort {} by {}; try one of {}", self.table, field, fields.join(", ")))
+                }),
+        }
+    }
+
+    fn plan(&self, params: &PageParams) -> Result<Plan> {
+        let sort = self.sort(params)?;
+        let direction = params.direction.unwrap_or(sort.direction);
+        let filters = self.binds.len();
+
+        let mut conditions = self.conditions.clone();
+        let mut binds = self.binds.clone();
+
+        if let Some(after) = &params.after {
+            let cursor = Cursor::decode(after)?;
+
+            if cursor.sort != sort.field || cursor.direction != direction {
+                return Err(Error::msg("page cursor was made for a different sort"));
+            }
+
+            binds.push(Value::Text(cursor.value));
+            binds.push(Value::Text(cursor.key));
+
+            conditions.push(format!(
+                "({}, {}) {} (CAST(${} AS {}), CAST(${} AS {}))",
+                sort.field,
+                self.key.field,
+                direction.comparison(),
+                binds.len() - 1,
+                sort.cast,
+                binds.len(),
+                self.key.cast,
+            ));
+        }
+
+        binds.push(Value::Int(limit(params.limit) + 1));
+
+        let count_where = where_clause(&self.conditions);
+        let select_where = where_clause(&conditions);
+
+        Ok(Plan {
+            select: format!(
+                "SELECT * FROM {}{} ORDER BY {} {dir}, {} {dir} LIMIT ${}",
+                self.source,
+                select_where,
+                sort.field,
+                self.key.field,
+                binds.len(),
+                dir = direction.keyword(),
+            ),
+            count: format!("SELECT COUNT(*) FROM {}{}", self.source, count_where),
+            binds,
+            filters,
+        })
+    }
+}
+
+fn where_clause(conditions: &[String]) -> String {
+    match conditions.is_empty() {
+        true => String::new(),
+        false => format!(" WHERE ({})", conditions.join(") AND (")),
+    }
+}
+
+// so a user's `%` or `_` in a LIKE pattern are matched literally
+pub fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SORTS: [SortKey; 2] = [
+        SortKey::new("created_at", "timestamp"),
+        SortKey::new("username", "varchar"),
+    ];
+
+    #[derive(Serialize)]
+    struct Row {
+        id: Uuid,
+        username: String,
+    }
+
+    #[test]
+    fn test_plan() {
+        let realm_id = Uuid::new_v4();
+
+        let query = ListQuery::new("users", &SORTS)
+            .and("deleted_at IS NULL")
+            .filter("status = ?", Some("enabled".to_string()))
+            .filter("kind = ?", None::<String>)
+            .filter("realm_id = ? OR owner_realm_id = ?", Some(realm_id))
+            .prefix("email", Some(&"a_b%".to_string()));
+
+        let plan = query.plan(&PageParams::default()).unwrap();
+
+        assert_eq!(plan.count, "SELECT COUNT(*) FROM users WHERE (deleted_at IS NULL) AND (status = $1) AND (realm_id = $2 OR owner_realm_id = $2) AND (email ILIKE $3)");
+        assert_eq!(plan.select, "SELECT * FROM users WHERE (deleted_at IS NULL) AND (status = $1) AND (realm_id = $2 OR owner_realm_id = $2) AND (email ILIKE $3) ORDER BY created_at ASC, id ASC LIMIT $4");
+        assert_eq!(plan.binds, vec![
+            Value::Text("enabled".to_string()),
+            Value::Uuid(realm_id),
+            Value::Text("a\\_b\\%%".to_string()),
+            Value::Int(DEFAULT_LIMIT + 1),
+        ]);
+        assert_eq!(plan.filters, 3);
+
+        let params = PageParams { sort: Some("email".to_string()), ..PageParams::default() };
+        assert!(query.plan(&params).is_err());
+    }
+
+    #[test]
+    fn test_cursor() {
+        let row = Row { id: Uuid::new_v4(), username: "bob".to_string() };
+        let cursor = Cursor::from_row(&row, &SORTS[1], &ID, Direction::Desc).unwrap();
+
+        assert_eq!(cursor.value, "bob");
+        assert_eq!(cursor.key, row.id.to_string());
+        assert_eq!(Cursor::decode(&cursor.encode().unwrap()).unwrap(), cursor);
+        assert!(Cursor::decode("not a cursor").is_err());
+
+        let query = ListQuery::new("users", &SORTS).filter("status = ?", Some("enabled".to_string()));
+        let mut params = PageParams {
+            after: Some(cursor.encode().unwrap()),
+            limit: Some(10),
+            sort: Some("username".to_string()),
+            direction: Some(Direction::Desc),
+        };
+
+        let plan = query.plan(&params).unwrap();
+
+        assert_eq!(plan.count, "SELECT COUNT(*) FROM users WHERE (status = $1)");
+        assert_eq!(plan.select, "SELECT * FROM users WHERE (status = $1) AND ((username, id) < (CAST($2 AS varchar), CAST($3 AS uuid))) ORDER BY username DESC, id DESC LIMIT $4");
+        assert_eq!(plan.binds[1..], [Value::Text("bob".to_string()), Value::Text(row.id.to_string()), Value::Int(11)]);
+
+        params.direction = None;
+        assert!(query.plan(&params).is_err());
+    }
+
+    #[test]
+    fn test_over() {
+        const SCORED: [SortKey; 1] = [SortKey::desc("score", "float8")];
+
+        let source = "SELECT *, similarity(username, $1) AS score FROM users".to_string();
+        let query = ListQuery::over("matches", source, vec!["bob".into()], &SCORED)
+            .filter("status = ?", Some("enabled"));
+
+        let plan = query.plan(&PageParams::default()).unwrap();
+
+        assert_eq!(plan.count, "SELECT COUNT(*) FROM (SELECT *, similarity(username, $1) AS score FROM users) AS matches WHERE (status = $2)");
+        assert_eq!(plan.select, "SELECT * FROM (SELECT *, similarity(username, $1) AS score FROM users) AS matches WHERE (status = $2) ORDER BY score DESC, id DESC LIMIT $3");
+        assert_eq!(plan.filters, 2);
+    }
+
+    #[test]
+    fn test_keyed() {
+        const NAME: SortKey = SortKey::new("name", "varchar");
+        const NAMES: [SortKey; 1] = [NAME];
+
+        #[derive(Serialize)]
+        struct Domain {
+            name: String,
+        }
+
+        let row = Domain { name: "example.com".to_string() };
+        let cursor = Cursor::from_row(&row, &NAMES[0], &NAME, Direction::Asc).unwrap();
+
+        assert_eq!(cursor.key, "example.com");
+
+        let query = ListQuery::new("domains", &NAMES).keyed(&NAME);
+        let params = PageParams { after: Some(cursor.encode().unwrap()), ..PageParams::default() };
+
+        let plan = query.plan(&params).unwrap();
+
+        assert_eq!(plan.select, "SELECT * FROM domains WHERE ((name, name) > (CAST($1 AS varchar), CAST($2 AS varchar))) ORDER BY name ASC, name ASC LIMIT $3");
+        assert!(Cursor::from_row(&row, &NAMES[0], &ID, Direction::Asc).is_err());
+    }
+}
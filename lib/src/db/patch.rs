@@ -0,0 +1,205 @@
+use std::fmt;
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::result::{Error, Result};
+
+// one field of a partial update: left out of the body, sent as null, or
+// given a value. fields need `#[serde(default)]` so leaving one out works
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Patch<T> {
+    #[default]
+    Missing,
+    Null,
+    Value(T),
+}
+
+impl<T> Patch<T> {
+    pub fn is_missing(&self) -> bool {
+        matches!(self, Patch::Missing)
+    }
+
+    pub fn value(self) -> Option<T> {
+        match self {
+            Patch::Value(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_ref(&self) -> Patch<&T> {
+        match self {
+            Patch::Missing => Patch::Missing,
+            Patch::Null => Patch::Null,
+            Patch::Value(value) => Patch::Value(value),
+        }
+    }
+
+    // for nullable columns, bound as `col = CASE WHEN $n THEN $m ELSE col END`
+    // so null can be told apart from leaving the column alone
+    pub fn parts(self) -> (bool, Option<T>) {
+        let set = !self.is_missing();
+
+        (set, self.value())
+    }
+
+    // for NOT NULL columns, bound as `col = COALESCE($n, col)`
+    pub fn required(self, field: &str) -> Result<Option<T>> {
+        match self {
+            Patch::Null => Err(Error::msg(format!("{} can't be null", field))),
+            patch => Ok(patch.value()),
+        }
+    }
+}
+
+// a value that's always written; none clears it
+impl<T> From<Option<T>> for Patch<T> {
+    fn from(value: Option<T>) -> Self {
+        value.map(Patch::Value).unwrap_or(Patch::Null)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for Patch<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let value = Option::<T>::deserialize(deserializer)?;
+
+        Ok(value.map(Patch::Value).unwrap_or(Patch::Null))
+    }
+}
+
+impl<T: Serialize> Serialize for Patch<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Patch::Value(value) => serializer.serialize_some(value),
+            _ => serializer.serialize_none(),
+        }
+    }
+}
+
+// anything whose `updated_at` is handed out as an ETag
+pub trait Versioned {
+    fn version(&self) -> Option<NaiveDateTime>;
+}
+
+pub fn etag(version: NaiveDateTime) -> String {
+    format!("\"{:x}\"", version.timestamp_nanos() / 1000)
+}
+
+// the update was made against a version that's since been replaced
+#[derive(Debug)]
+pub struct Stale;
+
+impl fmt::Display for Stale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "it has changed since it was read; fetch it again and retry")
+    }
+}
+
+impl std::error::Error for Stale {}
+
+// the versions an update may be applied to, from an If-Match header; with
+// no header, or `*`, it applies to any
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Precondition {
+    versions: Option<Vec<NaiveDateTime>>,
+}
+
+impl Precondition {
+    pub fn any() -> Self {
+        Self::default()
+    }
+
+    pub fn parse(header: &str) -> Result<Self> {
+        if header.trim() == "*" {
+            return Ok(Self::any());
+        }
+
+        let versions = header
+            .split(',')
+            .map(|tag| {
+                let tag = tag.trim();
+                let tag = tag.strip_prefix("W/").unwrap_or(tag);
+
+                let micros = tag
+                    .strip_prefix('"')
+                    .and_then(|tag| tag.strip_suffix('"'))
+                    .and_then(|tag| i64::from_str_radix(tag, 16).ok())
+                    .ok_or_else(|| Error::msg(format!("invalid If-Match: {}", header)))?;
+
+                Ok(NaiveDateTime::from_timestamp(micros.div_euclid(1_000_000), (micros.rem_euclid(1_000_000) * 1000) as u32))
+            })
+            .collect::<Result<Vec<NaiveDateTime>>>()?;
+
+        Ok(Self { versions: Some(versions) })
+    }
+
+    // call with the row locked FOR UPDATE so nothing can slip in between
+    pub fn check<T: Versioned>(&self, current: &T) -> Result<()> {
+        let versions = match &self.versions {
+            Some(versions) => versions,
+            None => return Ok(()),
+        };
+
+        match current.version() {
+            Some(version) if versions.contains(&version) => Ok(()),
+            _ => Err(Error::new(Stale)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default, Deserialize, Serialize)]
+    struct Update {
+        #[serde(default)]
+        name: Patch<String>,
+        #[serde(default)]
+        email: Patch<String>,
+        #[serde(default)]
+        age: Patch<i32>,
+    }
+
+    struct Row(NaiveDateTime);
+
+    impl Versioned for Row {
+        fn version(&self) -> Option<NaiveDateTime> {
+            Some(self.0)
+        }
+    }
+
+    #[test]
+    fn test_patch() {
+        let update: Update = serde_json::from_str(r#"{ "email": null, "age": 3 }"#).unwrap();
+
+        assert_eq!(update.name, Patch::Missing);
+        assert_eq!(update.email, Patch::Null);
+        assert_eq!(update.age, Patch::Value(3));
+
+        assert_eq!(update.email.clone().parts(), (true, None));
+        assert_eq!(update.name.clone().parts(), (false, None));
+        assert!(update.email.required("email").is_err());
+        assert_eq!(update.age.required("age").unwrap(), Some(3));
+
+        assert_eq!(Patch::from(Some(1)), Patch::Value(1));
+        assert_eq!(Patch::<i32>::from(None), Patch::Null);
+    }
+
+    #[test]
+    fn test_precondition() {
+        let version = NaiveDateTime::from_timestamp(1_646_136_000, 123_456_000);
+        let row = Row(version);
+
+        let tag = etag(version);
+        let precondition = Precondition::parse(&tag).unwrap();
+
+        assert!(precondition.check(&row).is_ok());
+        assert!(Precondition::parse(&format!("W/{}, \"1\"", tag)).unwrap().check(&row).is_ok());
+        assert!(Precondition::parse("*").unwrap().check(&row).is_ok());
+        assert!(Precondition::parse("nope").is_err());
+
+        let stale = Precondition::parse("\"1\"").unwrap().check(&row).unwrap_err();
+        assert!(stale.is::<Stale>());
+    }
+}
@@ -50,7 +50,7 @@ pub async fn new(args: Args<'_>) -> Result<Pool> {
         .connect(&conn_str)
         .await?;
 
-    return Ok(pool);
+    Ok(pool)
 }
 
 pub async fn ping(db: &Pool) -> Result<()> {
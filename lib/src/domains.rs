@@ -1,21 +1,38 @@
 use chrono::NaiveDateTime;
 use uuid::Uuid;
 
+use crate::audit::Actor;
+use crate::db::page::{ListQuery, Page, PageParams, SortKey};
 use crate::db::pg::Pool;
 use crate::result::Result;
 
+// domains have no id, so their name breaks ties instead
+const DOMAIN_KEY: SortKey = SortKey::new("name", "varchar");
+
+const DOMAIN_SORTS: &[SortKey] = &[
+    DOMAIN_KEY,
+    SortKey::new("created_at", "timestamp"),
+];
+
+// `name` matches on a prefix
+#[derive(Debug, Default, Deserialize)]
+pub struct DomainFilter {
+    pub realm_id: Option<Uuid>,
+    pub name: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Domain {
-    realm_id: Uuid,
-    name: String,
+    pub realm_id: Uuid,
+    pub name: String,
     created_at: Option<NaiveDateTime>,
     updated_at: Option<NaiveDateTime>,
 }
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct DomainCreate {
-    name: String,
-    realm_id: Uuid,
+    pub name: String,
+    pub realm_id: Uuid,
 }
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
@@ -37,29 +54,31 @@ impl DomainService {
         Ok(service)
     }
 
-    pub async fn all(&self) -> Result<Vec<Domain>> {
-        let domains = sqlx::query_as::<_, Domain>(r#"
-            SELECT * FROM domains
-        "#)
-            .fetch_all(&self.pool)
-            .await?;
-
-        Ok(domains)
+    pub async fn list(&self, filter: &DomainFilter, page: &PageParams) -> Result<Page<Domain>> {
+        ListQuery::new("domains", DOMAIN_SORTS)
+            .keyed(&DOMAIN_KEY)
+            .filter("realm_id = ?", filter.realm_id)
+            .prefix("name", filter.name.as_ref())
+            .fetch(&self.pool, page)
+            .await
     }
 
-    pub async fn by_id(&self, id: Uuid) -> Result<Domain> {
+    // domains are keyed by name; there is no id column
+    pub async fn by_name(&self, name: String) -> Result<Domain> {
         let result = sqlx::query_as::<_, Domain>(r#"
             SELECT * FROM domains
-            WHERE id = $1
+            WHERE name = $1
         "#)
-            .bind(id)
+            .bind(name)
             .fetch_one(&self.pool)
             .await?;
 
         Ok(result)
     }
 
-    pub async fn create(&self, domain: DomainCreate) -> Result<Domain> {
+    pub async fn create(&self, actor: &Actor, domain: DomainCreate) -> Result<Domain> {
+        let mut tx = self.pool.begin().await?;
+
         let result = sqlx::query_as::<_, Domain>(r#"
             INSERT INTO domains
             (name, realm_id)
@@ -68,20 +87,28 @@ impl DomainService {
         "#)
             .bind(domain.name)
             .bind(domain.realm_id)
-            .fetch_one(&self.pool)
+            .fetch_one(&mut tx)
             .await?;
 
+        actor.event("domain.created", "domains", None)
+            .realm(result.realm_id)
+            .after(&result)
+            .record(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
         Ok(result)
     }
 
-    pub async fn update(&self, id: Uuid, domain: DomainUpdate) -> Result<Domain> {
+    pub async fn update(&self, name: String, domain: DomainUpdate) -> Result<Domain> {
         let result = sqlx::query_as::<_, Domain>(r#"
             UPDATE domains 
             SET name = $2
-            WHERE id = $1
+            WHERE name = $1
             RETURNING *;
         "#)
-            .bind(id)
+            .bind(name)
             .bind(domain.name)
             .fetch_one(&self.pool)
             .await?;
@@ -89,7 +116,83 @@ impl DomainService {
         Ok(result)
     }
 
-    pub async fn delete(&self, id: Uuid) -> Result<()> {
-        todo!()
+    pub async fn delete(&self, actor: &Actor, name: String) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        let deleted = sqlx::query_as::<_, Domain>(r#"
+            DELETE FROM domains
+            WHERE name = $1
+            RETURNING *;
+        "#)
+            .bind(name)
+            .fetch_one(&mut tx)
+            .await?;
+
+        actor.event("domain.deleted", "domains", None)
+            .realm(deleted.realm_id)
+            .before(&deleted)
+            .record(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::{AuditFilter, AuditService};
+    use crate::seed::testing;
+
+    // domains page on their name, having no id, and the audit log they
+    // write pages newest first
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_list_pages() {
+        let pool = testing::pool().await;
+        let (mut realm, suffix) = testing::unique(&testing::realm());
+
+        crate::seed::seed(&pool, &mut realm).await.unwrap();
+
+        let realm_id = realm.id.unwrap();
+        let domains = DomainService::new(&pool).unwrap();
+        let names: Vec<String> = ["a", "b", "c"].iter().map(|name| format!("{}{}.example.com", name, suffix)).collect();
+
+        for name in names.iter() {
+            domains.create(&Actor::system(), DomainCreate { name: name.clone(), realm_id }).await.unwrap();
+        }
+
+        let filter = DomainFilter { realm_id: Some(realm_id), name: None };
+        let mut page = PageParams { limit: Some(2), ..PageParams::default() };
+
+        let first = domains.list(&filter, &page).await.unwrap();
+        assert_eq!(first.total, 3);
+        assert_eq!(first.items.iter().map(|domain| &domain.name).collect::<Vec<_>>(), vec![&names[0], &names[1]]);
+
+        page.after = first.next;
+
+        let second = domains.list(&filter, &page).await.unwrap();
+        assert_eq!(second.items.iter().map(|domain| &domain.name).collect::<Vec<_>>(), vec![&names[2]]);
+        assert!(second.next.is_none());
+
+        let audit = AuditService::new(&pool).unwrap();
+        let filter = AuditFilter {
+            realm_id: Some(realm_id),
+            action: Some("domain.created".to_string()),
+            ..AuditFilter::default()
+        };
+        let mut page = PageParams { limit: Some(2), ..PageParams::default() };
+
+        let first = audit.query(&filter, &page).await.unwrap();
+        assert_eq!(first.total, 3);
+        assert_eq!(first.items.len(), 2);
+
+        page.after = first.next;
+
+        let second = audit.query(&filter, &page).await.unwrap();
+        assert_eq!(second.items.len(), 1);
+        assert!(first.items[1].created_at >= second.items[0].created_at);
     }
 }
@@ -0,0 +1,481 @@
+use std::convert::TryFrom;
+use uuid::Uuid;
+
+use crate::grants::tree::{GrantType, PermissionNode, RoleNode, RootNode};
+use crate::permissions::permission::{compare, Effect, Permission};
+use crate::result::Result;
+
+const NEAR_MISS_LIMIT: usize = 5;
+
+#[derive(Debug, Serialize)]
+pub struct Explanation {
+    pub challenge: String,
+    pub allowed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub matched: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub denied_by: Option<String>,
+    pub paths: Vec<Vec<Step>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub near_misses: Vec<NearMiss>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub conditional: Vec<ConditionalMatch>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepKind {
+    Role,
+    Permission,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Step {
+    pub kind: StepKind,
+    pub id: Uuid,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub grant: Option<GrantType>,
+}
+
+// a conditional grant that matches the challenge; whether it applies is only
+// known once the request is in hand, so it takes no part in the decision
+#[derive(Debug, Serialize)]
+pub struct ConditionalMatch {
+    pub permission: String,
+    pub effect: Effect,
+    pub condition: String,
+    pub path: Vec<Step>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NearMiss {
+    pub permission: String,
+    pub matched_parts: Vec<&'static str>,
+    pub path: Vec<Step>,
+}
+
+pub fn explain(root: &RootNode, challenge: &str) -> Result<Explanation> {
+    let permission: Permission = challenge.parse()?;
+    let (conditional, paths): (Vec<_>, Vec<_>) = permission_paths(root)
+        .into_iter()
+        .partition(|(_, _, path)| condition_of(path).is_some());
+    let conditional = conditional_matches(&permission, conditional);
+
+    let mut allows = grants_with(&paths, Effect::Allow);
+    let denies = grants_with(&paths, Effect::Deny);
+
+    // a deny wins over whatever allow would have matched
+    if let Some(denied_by) = permission.get_denying(&denies).map(|grant| grant.to_string()) {
+        let paths = paths_to(paths, Effect::Deny, &denied_by);
+
+        return Ok(Explanation {
+            challenge: challenge.to_string(),
+            allowed: false,
+            matched: None,
+            denied_by: Some(denied_by),
+            paths,
+            near_misses: vec![],
+            conditional,
+        });
+    }
+
+    let matched: Option<String> = permission
+        .get_matching(&mut allows)
+        .map(|grant| grant.to_string());
+
+    if let Some(matched) = matched {
+        let paths = paths_to(paths, Effect::Allow, &matched);
+
+        return Ok(Explanation {
+            challenge: challenge.to_string(),
+            allowed: true,
+            matched: Some(matched),
+            denied_by: None,
+            paths,
+            near_misses: vec![],
+            conditional,
+        });
+    }
+
+    let mut near_misses: Vec<NearMiss> = paths
+        .into_iter()
+        .filter(|(effect, _, _)| *effect == Effect::Allow)
+        .filter_map(|(_, grant, path)| {
+            let matched_parts = matched_parts(&permission, &grant.parse().ok()?);
+
+            Some(NearMiss {
+                permission: grant,
+                matched_parts,
+                path,
+            })
+        })
+        .filter(|near_miss| !near_miss.matched_parts.is_empty())
+        .collect();
+
+    near_misses.sort_by(|left, right| {
+        right.matched_parts.len()
+            .cmp(&left.matched_parts.len())
+            .then_with(|| left.permission.cmp(&right.permission))
+    });
+    near_misses.truncate(NEAR_MISS_LIMIT);
+
+    Ok(Explanation {
+        challenge: challenge.to_string(),
+        allowed: false,
+        matched: None,
+        denied_by: None,
+        paths: vec![],
+        near_misses,
+        conditional,
+    })
+}
+
+fn grants_with(paths: &[(Effect, String, Vec<Step>)], effect: Effect) -> Vec<Permission> {
+    paths
+        .iter()
+        .filter(|(grant_effect, _, _)| *grant_effect == effect)
+        .filter_map(|(_, grant, _)| Permission::try_from(grant).ok())
+        .collect()
+}
+
+fn paths_to(paths: Vec<(Effect, String, Vec<Step>)>, effect: Effect, grant: &str) -> Vec<Vec<Step>> {
+    paths
+        .into_iter()
+        .filter(|(grant_effect, name, _)| *grant_effect == effect && name == grant)
+        .map(|(_, _, path)| path)
+        .collect()
+}
+
+fn conditional_matches(challenge: &Permission, paths: Vec<(Effect, String, Vec<Step>)>) -> Vec<ConditionalMatch> {
+    paths
+        .into_iter()
+        .filter(|(_, grant, _)| {
+            Permission::try_from(grant)
+                .map(|grant| challenge.matches(&grant))
+                .unwrap_or(false)
+        })
+        .filter_map(|(effect, permission, path)| {
+            let condition = condition_of(&path)?.to_string();
+
+            Some(ConditionalMatch {
+                permission,
+                effect,
+                condition,
+                path,
+            })
+        })
+        .collect()
+}
+
+fn condition_of(path: &[Step]) -> Option<&str> {
+    path.last()?.grant.as_ref()?.condition()
+}
+
+fn matched_parts(challenge: &Permission, grant: &Permission) -> Vec<&'static str> {
+    let mut results = vec![];
+
+    if compare(&challenge.realm, &grant.realm) {
+        results.push("realm");
+    }
+
+    if compare(&challenge.resource, &grant.resource) {
+        results.push("resource");
+    }
+
+    if compare(&challenge.action, &grant.action) {
+        results.push("action");
+    }
+
+    results
+}
+
+// every permission reachable from the root along with the role edges that lead to it
+fn permission_paths(root: &RootNode) -> Vec<(Effect, String, Vec<Step>)> {
+    let mut results = vec![];
+
+    if let Some(user) = &root.user {
+        collect_permissions(&user.permissions, &[], &mut results);
+        collect_roles(&user.roles, &[], &mut results);
+    }
+
+    if let Some(role) = &root.role {
+        collect_role(role, &[], &mut results);
+    }
+
+    results
+}
+
+fn collect_roles(roles: &Option<Vec<RoleNode>>, prefix: &[Step], results: &mut Vec<(Effect, String, Vec<Step>)>) {
+    if let Some(roles) = roles {
+        for role in roles.iter() {
+            collect_role(role, prefix, results);
+        }
+    }
+}
+
+fn collect_role(role: &RoleNode, prefix: &[Step], results: &mut Vec<(Effect, String, Vec<Step>)>) {
+    let mut path = prefix.to_vec();
+
+    path.push(Step {
+        kind: StepKind::Role,
+        id: role.role.id,
+        name: role.role.name.clone(),
+        grant: role.grant.clone(),
+    });
+
+    collect_permissions(&role.permissions, &path, results);
+    collect_roles(&role.roles, &path, results);
+}
+
+fn collect_permissions(permissions: &Option<Vec<PermissionNode>>, prefix: &[Step], results: &mut Vec<(Effect, String, Vec<Step>)>) {
+    if let Some(permissions) = permissions {
+        for node in permissions.iter() {
+            let name = node.permission.to_string();
+            let mut path = prefix.to_vec();
+
+            path.push(Step {
+                kind: StepKind::Permission,
+                id: node.permission.id,
+                name: name.clone(),
+                grant: Some(node.grant.clone()),
+            });
+
+            results.push((node.effect(), name, path));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDateTime, Utc};
+    use crate::grants::tree::UserNode;
+    use crate::grants::{RolePermission, UserPermission, UserRole};
+    use crate::permissions::permission_service::Permission as PermissionRow;
+    use crate::roles::Role;
+    use crate::users::User;
+    use serde_json::json;
+
+    fn parse(input: &str) -> Permission {
+        input.parse().unwrap()
+    }
+
+    fn now() -> NaiveDateTime {
+        Utc::now().naive_utc()
+    }
+
+    fn row(name: &str) -> PermissionRow {
+        let permission = parse(name);
+
+        PermissionRow {
+            id: Uuid::new_v4(),
+            realm: permission.realm,
+            resource: permission.resource,
+            action: permission.action,
+            realm_id: Uuid::nil(),
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    fn user_grant(name: &str, effect: Effect, condition: Option<&str>) -> PermissionNode {
+        let permission = row(name);
+
+        let grant = GrantType::UserPermission(UserPermission {
+            id: Uuid::new_v4(),
+            realm_id: Uuid::nil(),
+            user_id: Uuid::nil(),
+            permission_id: permission.id,
+            effect,
+            condition: condition.map(String::from),
+            starts_at: None,
+            expires_at: None,
+            swept_at: None,
+            created_at: now(),
+            updated_at: now(),
+        });
+
+        PermissionNode { permission, grant }
+    }
+
+    fn role_grant(role_id: Uuid, name: &str) -> PermissionNode {
+        let permission = row(name);
+
+        let grant = GrantType::RolePermission(RolePermission {
+            id: Uuid::new_v4(),
+            realm_id: Uuid::nil(),
+            role_id,
+            permission_id: permission.id,
+            effect: Effect::Allow,
+            condition: None,
+            created_at: now(),
+            updated_at: now(),
+        });
+
+        PermissionNode { permission, grant }
+    }
+
+    // a role granted straight to the user, holding allows for `permissions`
+    fn role(name: &str, permissions: &[&str]) -> RoleNode {
+        let id = Uuid::new_v4();
+
+        let grant = GrantType::UserRole(UserRole {
+            id: Uuid::new_v4(),
+            realm_id: Uuid::nil(),
+            user_id: Uuid::nil(),
+            role_id: id,
+            starts_at: None,
+            expires_at: None,
+            swept_at: None,
+            created_at: now(),
+            updated_at: now(),
+        });
+
+        RoleNode {
+            role: Role {
+                id,
+                realm_id: Uuid::nil(),
+                name: name.to_string(),
+                created_at: None,
+                updated_at: None,
+            },
+            roles: None,
+            permissions: Some(permissions.iter().map(|name| role_grant(id, name)).collect()),
+            grant: Some(grant),
+            repeated: false,
+        }
+    }
+
+    fn root(roles: Vec<RoleNode>, permissions: Vec<PermissionNode>) -> RootNode {
+        let user = User {
+            id: Uuid::nil(),
+            username: "wile".to_string(),
+            email: None,
+            first_name: None,
+            last_name: None,
+            profile: json!({}),
+            status: "enabled".to_string(),
+            kind: "human".to_string(),
+            created_at: None,
+            updated_at: None,
+            deleted_at: None,
+        };
+
+        RootNode {
+            user: Some(UserNode {
+                user,
+                roles: Some(roles),
+                permissions: Some(permissions),
+            }),
+            role: None,
+            errors: vec![],
+        }
+    }
+
+    fn names(path: &[Step]) -> Vec<&str> {
+        path.iter().map(|step| step.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_explain_allow() {
+        let root = root(vec![role("app:reader", &["app:invoices.*:read"])], vec![]);
+
+        let result = explain(&root, "app:invoices.42:read").unwrap();
+
+        assert!(result.allowed);
+        assert_eq!(result.matched.as_deref(), Some("app:invoices.*:read"));
+        assert_eq!(result.denied_by, None);
+        assert_eq!(result.paths.len(), 1);
+        assert_eq!(names(&result.paths[0]), vec!["app:reader", "app:invoices.*:read"]);
+        assert!(matches!(result.paths[0][0].kind, StepKind::Role));
+        assert!(matches!(result.paths[0][1].kind, StepKind::Permission));
+        assert!(result.near_misses.is_empty());
+    }
+
+    #[test]
+    fn test_explain_deny() {
+        let root = root(
+            vec![role("app:reader", &["app:invoices.*:read"])],
+            vec![user_grant("app:invoices.42:*", Effect::Deny, None)],
+        );
+
+        let result = explain(&root, "app:invoices.42:read").unwrap();
+
+        assert!(!result.allowed);
+        assert_eq!(result.matched, None);
+        assert_eq!(result.denied_by.as_deref(), Some("app:invoices.42:*"));
+        assert_eq!(result.paths.len(), 1);
+        assert_eq!(names(&result.paths[0]), vec!["app:invoices.42:*"]);
+
+        // the deny only covers invoice 42
+        let result = explain(&root, "app:invoices.7:read").unwrap();
+
+        assert!(result.allowed);
+        assert_eq!(result.denied_by, None);
+    }
+
+    #[test]
+    fn test_explain_near_misses() {
+        let root = root(
+            vec![role("app:reader", &["app:invoices.*:read", "app:users:read"])],
+            vec![user_grant("other:users:write", Effect::Allow, None)],
+        );
+
+        let result = explain(&root, "app:invoices.42:delete").unwrap();
+
+        assert!(!result.allowed);
+        assert_eq!(result.matched, None);
+        assert_eq!(result.denied_by, None);
+        assert!(result.paths.is_empty());
+
+        let misses: Vec<(&str, Vec<&str>)> = result.near_misses
+            .iter()
+            .map(|miss| (miss.permission.as_str(), miss.matched_parts.clone()))
+            .collect();
+
+        assert_eq!(misses, vec![
+            ("app:invoices.*:read", vec!["realm", "resource"]),
+            ("app:users:read", vec!["realm"]),
+        ]);
+        assert_eq!(names(&result.near_misses[0].path), vec!["app:reader", "app:invoices.*:read"]);
+    }
+
+    #[test]
+    fn test_explain_conditional() {
+        let condition = r#"request.ip == "10.0.0.1""#;
+        let root = root(vec![], vec![
+            user_grant("app:invoices.*:read", Effect::Allow, Some(condition)),
+            user_grant("app:reports.*:read", Effect::Allow, Some(condition)),
+        ]);
+
+        let result = explain(&root, "app:invoices.42:read").unwrap();
+
+        // a conditional grant is listed but doesn't decide anything
+        assert!(!result.allowed);
+        assert_eq!(result.matched, None);
+        assert!(result.near_misses.is_empty());
+        assert_eq!(result.conditional.len(), 1);
+
+        let conditional = &result.conditional[0];
+
+        assert_eq!(conditional.permission, "app:invoices.*:read");
+        assert_eq!(conditional.effect, Effect::Allow);
+        assert_eq!(conditional.condition, condition);
+        assert_eq!(names(&conditional.path), vec!["app:invoices.*:read"]);
+    }
+
+    #[test]
+    fn test_matched_parts() {
+        let challenge = parse("app:invoices.42:read");
+
+        let result = matched_parts(&challenge, &parse("app:invoices.*:write"));
+        assert_eq!(result, vec!["realm", "resource"]);
+
+        let result = matched_parts(&challenge, &parse("other:users:read"));
+        assert_eq!(result, vec!["action"]);
+
+        let result = matched_parts(&challenge, &parse("other:users:write"));
+        assert!(result.is_empty());
+    }
+}
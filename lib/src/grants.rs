@@ -1,15 +1,33 @@
 use chrono::NaiveDateTime;
-use crate::db::pg::Pool;
-use crate::result::Result;
+use crate::audit::{self, Actor};
+use crate::db::page::{ListQuery, Page, PageParams, SortKey};
+use crate::db::pg::{Pool, QueryResult};
+use crate::explain;
+use crate::result::{Error, Result};
+use crate::webhooks;
+use super::permissions::condition::Condition;
+use super::permissions::permission::{Effect, Permission as PermissionRaw, ADMIN_REALM};
 use super::permissions::permission_service::Permission;
+use serde_json::json;
+use serde_json::Value as JsonValue;
+use sqlx::{Postgres, Transaction};
 use uuid::Uuid;
 
+const GRANT_SORTS: &[SortKey] = &[
+    SortKey::new("created_at", "timestamp"),
+];
+
 #[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct UserPermission {
     pub id: Uuid,
     pub realm_id: Uuid,
     pub user_id: Uuid,
     pub permission_id: Uuid,
+    pub effect: Effect,
+    pub condition: Option<String>,
+    pub starts_at: Option<NaiveDateTime>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub swept_at: Option<NaiveDateTime>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
 }
@@ -20,6 +38,9 @@ pub struct UserRole {
     pub realm_id: Uuid,
     pub user_id: Uuid,
     pub role_id: Uuid,
+    pub starts_at: Option<NaiveDateTime>,
+    pub expires_at: Option<NaiveDateTime>,
+    pub swept_at: Option<NaiveDateTime>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
 }
@@ -40,16 +61,90 @@ pub struct RolePermission {
     pub realm_id: Uuid,
     pub role_id: Uuid,
     pub permission_id: Uuid,
+    pub effect: Effect,
+    pub condition: Option<String>,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
 }
 
 #[derive(Debug)]
 pub enum PermissionType {
-    UserPermission(Uuid, Uuid),
-    UserRole(Uuid, Uuid),
+    UserPermission(Uuid, Uuid, GrantOptions),
+    UserRole(Uuid, Uuid, GrantWindow),
     RoleRole(Uuid, Uuid),
-    RolePermission(Uuid, Uuid),
+    RolePermission(Uuid, Uuid, GrantOptions),
+}
+
+// when a grant to a user applies; either end may be left open
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct GrantWindow {
+    pub starts_at: Option<NaiveDateTime>,
+    pub expires_at: Option<NaiveDateTime>,
+}
+
+impl GrantWindow {
+    pub fn is_open(&self) -> bool {
+        self.starts_at.is_none() && self.expires_at.is_none()
+    }
+}
+
+// what a permission grant carries besides the permission itself
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct GrantOptions {
+    #[serde(default)]
+    pub effect: Effect,
+    pub condition: Option<String>,
+    #[serde(flatten)]
+    pub window: GrantWindow,
+}
+
+impl GrantOptions {
+    pub fn allow() -> Self {
+        Self::default()
+    }
+
+    pub fn with_effect(effect: Effect) -> Self {
+        Self {
+            effect,
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum GrantKind {
+    UserPermission,
+    UserRole,
+    RoleRole,
+    RolePermission,
+}
+
+impl GrantKind {
+    pub const ALL: [GrantKind; 4] = [
+        GrantKind::UserPermission,
+        GrantKind::UserRole,
+        GrantKind::RoleRole,
+        GrantKind::RolePermission,
+    ];
+
+    pub fn table(&self) -> &'static str {
+        match self {
+            GrantKind::UserPermission => "user_permission_grants",
+            GrantKind::UserRole => "user_role_grants",
+            GrantKind::RoleRole => "role_role_grants",
+            GrantKind::RolePermission => "role_permission_grants",
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct GrantFilter {
+    pub realm_id: Option<Uuid>,
+    pub user_id: Option<Uuid>,
+    pub role_id: Option<Uuid>,
+    pub permission_id: Option<Uuid>,
+    pub parent_id: Option<Uuid>,
+    pub child_id: Option<Uuid>,
 }
 
 #[derive(Clone)]
@@ -72,63 +167,505 @@ impl GrantService {
         Ok(result)
     }
 
-    pub async fn by_role_id(&self, role_id: Uuid) -> Result<()> {
-        todo!()
+    pub async fn explain(&self, realm_id: Uuid, user_id: Uuid, challenge: &str) -> Result<explain::Explanation> {
+        let root = self.by_user_id(realm_id, user_id).await?.ensure_resolved()?;
+
+        explain::explain(&root, challenge)
+    }
+
+    pub async fn by_role_id(&self, role_id: Uuid) -> Result<tree::RootNode> {
+        let service = tree::Service { pool: self.pool.clone() };
+
+        let result = service.by_role_id(role_id).await?;
+
+        Ok(result)
+    }
+
+    pub async fn user_permissions(&self, filter: &GrantFilter, page: &PageParams) -> Result<Page<UserPermission>> {
+        ListQuery::new("user_permission_grants", GRANT_SORTS)
+            .filter("realm_id = ?", filter.realm_id)
+            .filter("user_id = ?", filter.user_id)
+            .filter("permission_id = ?", filter.permission_id)
+            .fetch(&self.pool, page)
+            .await
     }
 
-    pub async fn create(&self, realm_id: Uuid, permission_type: PermissionType) -> Result<()> {
+    pub async fn user_roles(&self, filter: &GrantFilter, page: &PageParams) -> Result<Page<UserRole>> {
+        ListQuery::new("user_role_grants", GRANT_SORTS)
+            .filter("realm_id = ?", filter.realm_id)
+            .filter("user_id = ?", filter.user_id)
+            .filter("role_id = ?", filter.role_id)
+            .fetch(&self.pool, page)
+            .await
+    }
+
+    pub async fn role_roles(&self, filter: &GrantFilter, page: &PageParams) -> Result<Page<RoleRole>> {
+        ListQuery::new("role_role_grants", GRANT_SORTS)
+            .filter("realm_id = ?", filter.realm_id)
+            .filter("parent_id = ?", filter.parent_id)
+            .filter("child_id = ?", filter.child_id)
+            .fetch(&self.pool, page)
+            .await
+    }
+
+    pub async fn role_permissions(&self, filter: &GrantFilter, page: &PageParams) -> Result<Page<RolePermission>> {
+        ListQuery::new("role_permission_grants", GRANT_SORTS)
+            .filter("realm_id = ?", filter.realm_id)
+            .filter("role_id = ?", filter.role_id)
+            .filter("permission_id = ?", filter.permission_id)
+            .fetch(&self.pool, page)
+            .await
+    }
+
+    pub async fn create(&self, actor: &Actor, realm_id: Uuid, permission_type: PermissionType) -> Result<tree::GrantType> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = Self::create_in(&mut tx, actor, realm_id, permission_type).await?;
+
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    pub async fn create_in(tx: &mut Transaction<'_, Postgres>, actor: &Actor, realm_id: Uuid, permission_type: PermissionType) -> Result<tree::GrantType> {
         use PermissionType::*;
 
-        match permission_type {
-            UserPermission(user_id, permission_id) =>
-                self.create_user_permission(realm_id, user_id, permission_id).await?,
-            UserRole(user_id, role_id) =>
-                self.create_user_role(realm_id, user_id, role_id).await?,
-            RoleRole(parent_id, child_id) =>
-                self.create_role_role(realm_id, parent_id, child_id).await?,
-            RolePermission(role_id, permission_id) =>
-                self.create_role_permission(realm_id, role_id, permission_id).await?,
+        Self::validate(&mut *tx, realm_id, &permission_type).await?;
+
+        let grant = match permission_type {
+            UserPermission(user_id, permission_id, options) => tree::GrantType::UserPermission(
+                Self::create_user_permission_query(realm_id, user_id, permission_id, options)
+                    .fetch_one(&mut *tx)
+                    .await?
+            ),
+            UserRole(user_id, role_id, window) => tree::GrantType::UserRole(
+                Self::create_user_role_query(realm_id, user_id, role_id, window)
+                    .fetch_one(&mut *tx)
+                    .await?
+            ),
+            RoleRole(parent_id, child_id) => tree::GrantType::RoleRole(
+                Self::create_role_role_query(realm_id, parent_id, child_id)
+                    .fetch_one(&mut *tx)
+                    .await?
+            ),
+            RolePermission(role_id, permission_id, options) => tree::GrantType::RolePermission(
+                Self::create_role_permission_query(realm_id, role_id, permission_id, options)
+                    .fetch_one(&mut *tx)
+                    .await?
+            ),
         };
 
+        actor.event("grant.created", grant.kind().table(), Some(grant.id()))
+            .realm(realm_id)
+            .after(&grant)
+            .record(&mut *tx)
+            .await?;
+
+        if let tree::GrantType::UserRole(grant) = &grant {
+            let data = json!({
+                "user_id": grant.user_id,
+                "role_id": grant.role_id,
+                "grant_id": grant.id,
+                "starts_at": grant.starts_at,
+                "expires_at": grant.expires_at,
+            });
+
+            webhooks::enqueue(&mut *tx, realm_id, webhooks::USER_ROLE_GRANTED, data).await?;
+        }
+
+        Ok(grant)
+    }
+
+    // the realm a grant was made in, looked up in one table or all of them
+    pub async fn realm_id(&self, kind: Option<GrantKind>, id: Uuid) -> Result<Uuid> {
+        let kinds = match kind {
+            Some(kind) => vec![kind],
+            None => GrantKind::ALL.to_vec(),
+        };
+
+        for kind in kinds.iter() {
+            let query = format!("SELECT realm_id FROM {} WHERE id = $1", kind.table());
+
+            let row: Option<(Uuid,)> = sqlx::query_as(&query)
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?;
+
+            if let Some((realm_id,)) = row {
+                return Ok(realm_id);
+            }
+        }
+
+        Err(sqlx::Error::RowNotFound.into())
+    }
+
+    pub async fn delete(&self, actor: &Actor, id: Uuid) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        let mut deleted = 0;
+
+        for kind in GrantKind::ALL.iter() {
+            deleted += Self::delete_from(&mut tx, actor, *kind, id).await?;
+        }
+
+        if deleted == 0 {
+            return Err(sqlx::Error::RowNotFound.into());
+        }
+
+        tx.commit().await?;
+
         Ok(())
     }
 
-    pub async fn delete(&self, id: Uuid) -> Result<()> {
-        todo!()
+    pub async fn delete_kind(&self, actor: &Actor, kind: GrantKind, id: Uuid) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        if Self::delete_from(&mut tx, actor, kind, id).await? == 0 {
+            return Err(sqlx::Error::RowNotFound.into());
+        }
+
+        tx.commit().await?;
+
+        Ok(())
     }
 
-    async fn create_user_permission(&self, realm_id: Uuid, user_id: Uuid, permission_id: Uuid) -> Result<()> {
-        let _result = sqlx::query_as::<_, UserPermission>(r#"
-            INSERT INTO user_permission_grants (realm_id, user_id, permission_id)
-            VALUES ($1, $2, $3)
+    pub async fn revoke(&self, actor: &Actor, realm_id: Uuid, permission_type: PermissionType) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        Self::revoke_in(&mut tx, actor, realm_id, permission_type).await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn revoke_in(tx: &mut Transaction<'_, Postgres>, actor: &Actor, realm_id: Uuid, permission_type: PermissionType) -> Result<()> {
+        use PermissionType::*;
+
+        let (kind, left, right, columns) = match permission_type {
+            UserPermission(user_id, permission_id, _) => (GrantKind::UserPermission, user_id, permission_id, "user_id = $2 AND permission_id = $3"),
+            UserRole(user_id, role_id, _) => (GrantKind::UserRole, user_id, role_id, "user_id = $2 AND role_id = $3"),
+            RoleRole(parent_id, child_id) => (GrantKind::RoleRole, parent_id, child_id, "parent_id = $2 AND child_id = $3"),
+            RolePermission(role_id, permission_id, _) => (GrantKind::RolePermission, role_id, permission_id, "role_id = $2 AND permission_id = $3"),
+        };
+
+        let query = format!(r#"
+            DELETE FROM {table}
+            WHERE realm_id = $1
+            AND {columns}
+            RETURNING id, to_jsonb({table}.*)
+        "#, table = kind.table(), columns = columns);
+
+        let revoked = sqlx::query_as::<_, (Uuid, JsonValue)>(&query)
+            .bind(realm_id)
+            .bind(left)
+            .bind(right)
+            .fetch_all(&mut *tx)
+            .await?;
+
+        if revoked.is_empty() {
+            return Err(sqlx::Error::RowNotFound.into());
+        }
+
+        for (id, before) in revoked.iter() {
+            actor.event("grant.revoked", kind.table(), Some(*id))
+                .realm(realm_id)
+                .before(before)
+                .record(&mut *tx)
+                .await?;
+
+            if let GrantKind::UserRole = kind {
+                Self::role_revoked(&mut *tx, realm_id, before, "revoked").await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // resolution already ignores grants past their expiry; this marks them
+    // as swept and leaves a trail of what ran out in the audit log
+    pub async fn sweep_expired(&self) -> Result<usize> {
+        let mut tx = self.pool.begin().await?;
+
+        let user_permissions = sqlx::query_as::<_, UserPermission>(r#"
+            UPDATE user_permission_grants
+            SET swept_at = CURRENT_TIMESTAMP
+            WHERE expires_at <= CURRENT_TIMESTAMP
+            AND swept_at IS NULL
             RETURNING *;
         "#)
-            .bind(realm_id)
+            .fetch_all(&mut tx)
+            .await?;
+
+        let user_roles = sqlx::query_as::<_, UserRole>(r#"
+            UPDATE user_role_grants
+            SET swept_at = CURRENT_TIMESTAMP
+            WHERE expires_at <= CURRENT_TIMESTAMP
+            AND swept_at IS NULL
+            RETURNING *;
+        "#)
+            .fetch_all(&mut tx)
+            .await?;
+
+        let actor = Actor::system();
+
+        for grant in user_permissions.iter() {
+            actor.event(audit::GRANT_EXPIRED, GrantKind::UserPermission.table(), Some(grant.id))
+                .realm(grant.realm_id)
+                .details(json!({ "expires_at": grant.expires_at }))
+                .before(grant)
+                .record(&mut tx)
+                .await?;
+        }
+
+        for grant in user_roles.iter() {
+            actor.event(audit::GRANT_EXPIRED, GrantKind::UserRole.table(), Some(grant.id))
+                .realm(grant.realm_id)
+                .details(json!({ "expires_at": grant.expires_at }))
+                .before(grant)
+                .record(&mut tx)
+                .await?;
+
+            Self::role_revoked(&mut tx, grant.realm_id, &json!(grant), "expired").await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(user_permissions.len() + user_roles.len())
+    }
+
+    async fn delete_from(tx: &mut Transaction<'_, Postgres>, actor: &Actor, kind: GrantKind, id: Uuid) -> Result<u64> {
+        let query = format!("DELETE FROM {table} WHERE id = $1 RETURNING realm_id, to_jsonb({table}.*)", table = kind.table());
+
+        let deleted = sqlx::query_as::<_, (Uuid, JsonValue)>(&query)
+            .bind(id)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+        match deleted {
+            Some((realm_id, before)) => {
+                actor.event("grant.deleted", kind.table(), Some(id))
+                    .realm(realm_id)
+                    .before(&before)
+                    .record(&mut *tx)
+                    .await?;
+
+                if let GrantKind::UserRole = kind {
+                    Self::role_revoked(tx, realm_id, &before, "deleted").await?;
+                }
+
+                Ok(1)
+            },
+            None => Ok(0),
+        }
+    }
+
+    // `grant` is the user_role_grants row, however the role was lost
+    pub(crate) async fn role_revoked(tx: &mut Transaction<'_, Postgres>, realm_id: Uuid, grant: &JsonValue, reason: &str) -> Result<()> {
+        let data = json!({
+            "user_id": grant["user_id"],
+            "role_id": grant["role_id"],
+            "grant_id": grant["id"],
+            "reason": reason,
+        });
+
+        webhooks::enqueue(&mut *tx, realm_id, webhooks::USER_ROLE_REVOKED, data).await?;
+
+        Ok(())
+    }
+
+    async fn validate(
+        tx: &mut Transaction<'_, Postgres>,
+        realm_id: Uuid,
+        permission_type: &PermissionType,
+    ) -> Result<()> {
+        use PermissionType::*;
+
+        match permission_type {
+            UserPermission(user_id, permission_id, options) => {
+                Self::validate_user(tx, *user_id).await?;
+                Self::validate_permission_realm(tx, realm_id, *permission_id).await?;
+                Self::validate_options(options)?;
+                Self::validate_window(&options.window)?;
+            },
+            UserRole(user_id, role_id, window) => {
+                Self::validate_user(tx, *user_id).await?;
+                Self::validate_role_realm(tx, realm_id, *role_id).await?;
+                Self::validate_window(window)?;
+            },
+            RoleRole(parent_id, child_id) => {
+                Self::validate_role_realm(tx, realm_id, *parent_id).await?;
+                Self::validate_role_realm(tx, realm_id, *child_id).await?;
+                Self::validate_acyclic(tx, *parent_id, *child_id).await?;
+            },
+            RolePermission(role_id, permission_id, options) => {
+                Self::validate_role_realm(tx, realm_id, *role_id).await?;
+                Self::validate_permission_realm(tx, realm_id, *permission_id).await?;
+                Self::validate_options(options)?;
+
+                if !options.window.is_open() {
+                    return Err(Error::msg("only grants to users can start or expire"));
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    fn validate_options(options: &GrantOptions) -> Result<()> {
+        if let Some(condition) = &options.condition {
+            Condition::parse(condition)
+                .map_err(|err| Error::msg(format!("invalid condition '{}': {}", condition, err)))?;
+        }
+
+        Ok(())
+    }
+
+    async fn validate_user(tx: &mut Transaction<'_, Postgres>, user_id: Uuid) -> Result<()> {
+        let (exists,): (bool,) = sqlx::query_as(r#"
+            SELECT EXISTS(
+                SELECT 1 FROM users
+                WHERE id = $1
+                AND deleted_at IS NULL
+            )
+        "#)
             .bind(user_id)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        if !exists {
+            return Err(Error::msg(format!("user {} not found", user_id)));
+        }
+
+        Ok(())
+    }
+
+    async fn validate_role_realm(tx: &mut Transaction<'_, Postgres>, realm_id: Uuid, role_id: Uuid) -> Result<()> {
+        let row: Option<(Uuid,)> = sqlx::query_as(r#"
+            SELECT realm_id FROM roles
+            WHERE id = $1
+        "#)
+            .bind(role_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+        match row {
+            None => Err(Error::msg(format!("role {} not found", role_id))),
+            Some((role_realm_id,)) if role_realm_id != realm_id => Err(Error::msg(format!(
+                "role {} belongs to realm {}, not {}",
+                role_id, role_realm_id, realm_id,
+            ))),
+            Some(_) => Ok(()),
+        }
+    }
+
+    async fn validate_permission_realm(tx: &mut Transaction<'_, Postgres>, realm_id: Uuid, permission_id: Uuid) -> Result<()> {
+        let row: Option<(Uuid, String, String, String, String)> = sqlx::query_as(r#"
+            SELECT permissions.realm_id, permissions.realm, permissions.resource, permissions.action, realms.name
+            FROM permissions
+            JOIN realms ON realms.id = permissions.realm_id
+            WHERE permissions.id = $1
+        "#)
             .bind(permission_id)
-            .fetch_one(&self.pool)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+        match row {
+            None => Err(Error::msg(format!("permission {} not found", permission_id))),
+            Some((permission_realm_id, ..)) if permission_realm_id != realm_id => Err(Error::msg(format!(
+                "permission {} belongs to realm {}, not {}",
+                permission_id, permission_realm_id, realm_id,
+            ))),
+            // only the admin realm can hand out the service's own
+            // permissions beyond its own resources
+            Some((_, realm, resource, action, name)) if name != ADMIN_REALM => {
+                PermissionRaw::new(&realm, &resource, &action)?.validate_within(realm_id)
+            },
+            Some(_) => Ok(()),
+        }
+    }
+
+    async fn validate_acyclic(tx: &mut Transaction<'_, Postgres>, parent_id: Uuid, child_id: Uuid) -> Result<()> {
+        if parent_id == child_id {
+            return Err(Error::msg(format!("role {} can't inherit from itself", parent_id)));
+        }
+
+        // serialize concurrent role_role inserts so two opposing edges can't both pass the check
+        sqlx::query("LOCK TABLE role_role_grants IN SHARE ROW EXCLUSIVE MODE")
+            .execute(&mut *tx)
             .await?;
 
+        // the new edge closes a cycle if the parent is already reachable from the child
+        let (exists,): (bool,) = sqlx::query_as(r#"
+            WITH RECURSIVE descendants(role_id) AS (
+                SELECT child_id FROM role_role_grants
+                WHERE parent_id = $1
+                UNION
+                SELECT role_role_grants.child_id FROM role_role_grants
+                JOIN descendants ON role_role_grants.parent_id = descendants.role_id
+            )
+            SELECT EXISTS(
+                SELECT 1 FROM descendants
+                WHERE role_id = $2
+            )
+        "#)
+            .bind(child_id)
+            .bind(parent_id)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        if exists {
+            return Err(Error::msg(format!(
+                "granting role {} to role {} would create a cycle",
+                child_id, parent_id,
+            )));
+        }
+
         Ok(())
     }
 
-    async fn create_user_role(&self, realm_id: Uuid, user_id: Uuid, role_id: Uuid) -> Result<()> {
-        let _result = sqlx::query_as::<_, UserRole>(r#"
-            INSERT INTO user_role_grants (realm_id, user_id, role_id)
-            VALUES ($1, $2, $3)
+    fn validate_window(window: &GrantWindow) -> Result<()> {
+        if let (Some(starts_at), Some(expires_at)) = (window.starts_at, window.expires_at) {
+            if starts_at >= expires_at {
+                return Err(Error::msg("a grant must start before it expires"));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn create_user_permission_query(realm_id: Uuid, user_id: Uuid, permission_id: Uuid, options: GrantOptions) -> QueryResult<'static, UserPermission> {
+        sqlx::query_as::<_, UserPermission>(r#"
+            INSERT INTO user_permission_grants (
+                realm_id, user_id, permission_id,
+                effect, condition, starts_at, expires_at
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7)
             RETURNING *;
         "#)
             .bind(realm_id)
             .bind(user_id)
-            .bind(role_id)
-            .fetch_one(&self.pool)
-            .await?;
+            .bind(permission_id)
+            .bind(options.effect)
+            .bind(options.condition)
+            .bind(options.window.starts_at)
+            .bind(options.window.expires_at)
+    }
 
-        Ok(())
+    pub fn create_user_role_query(realm_id: Uuid, user_id: Uuid, role_id: Uuid, window: GrantWindow) -> QueryResult<'static, UserRole> {
+        sqlx::query_as::<_, UserRole>(r#"
+            INSERT INTO user_role_grants (realm_id, user_id, role_id, starts_at, expires_at)
+            VALUES ($1, $2, $3, $4, $5)
+            RETURNING *;
+        "#)
+            .bind(realm_id)
+            .bind(user_id)
+            .bind(role_id)
+            .bind(window.starts_at)
+            .bind(window.expires_at)
     }
 
-    async fn create_role_role(&self, realm_id: Uuid, parent_id: Uuid, child_id: Uuid) -> Result<()> {
-        let _result = sqlx::query_as::<_, RoleRole>(r#"
+    pub fn create_role_role_query(realm_id: Uuid, parent_id: Uuid, child_id: Uuid) -> QueryResult<'static, RoleRole> {
+        sqlx::query_as::<_, RoleRole>(r#"
             INSERT INTO role_role_grants (realm_id, parent_id, child_id)
             VALUES ($1, $2, $3)
             RETURNING *;
@@ -136,25 +673,19 @@ impl GrantService {
             .bind(realm_id)
             .bind(parent_id)
             .bind(child_id)
-            .fetch_one(&self.pool)
-            .await?;
-
-        Ok(())
     }
 
-    async fn create_role_permission(&self, realm_id: Uuid, role_id: Uuid, permission_id: Uuid) -> Result<()> {
-        let _result = sqlx::query_as::<_, RolePermission>(r#"
-            INSERT INTO role_permission_grants (realm_id, role_id, permission_id)
-            VALUES ($1, $2, $3)
+    pub fn create_role_permission_query(realm_id: Uuid, role_id: Uuid, permission_id: Uuid, options: GrantOptions) -> QueryResult<'static, RolePermission> {
+        sqlx::query_as::<_, RolePermission>(r#"
+            INSERT INTO role_permission_grants (realm_id, role_id, permission_id, effect, condition)
+            VALUES ($1, $2, $3, $4, $5)
             RETURNING *;
         "#)
             .bind(realm_id)
             .bind(role_id)
             .bind(permission_id)
-            .fetch_one(&self.pool)
-            .await?;
-
-        Ok(())
+            .bind(options.effect)
+            .bind(options.condition)
     }
 }
 
@@ -166,14 +697,28 @@ pub mod tree {
     use super::RoleRole;
     use super::UserPermission;
 
-    use std::collections::HashMap;
+    use std::collections::{HashMap, HashSet};
+    use std::fmt;
 
     use crate::db::pg::Pool;
-    use crate::result::Result;
+    use crate::result::{Error, Result};
+    use crate::permissions::condition::ConditionalGrant;
+    use crate::permissions::permission::{condense, Effect, DENY_PREFIX};
     use crate::permissions::permission_service::Permission;
     use crate::roles::Role;
     use crate::users::User;
 
+    // the roles a closure starts from; $1 is the realm and $2 the user or role id
+    pub(crate) const USER_ROLE_SEED: &str = r#"
+        SELECT role_id FROM user_role_grants
+        WHERE realm_id = $1
+        AND user_id = $2
+        AND (starts_at IS NULL OR starts_at <= CURRENT_TIMESTAMP)
+        AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+    "#;
+
+    const ROLE_SEED: &str = "SELECT $2::uuid";
+
     pub struct Service {
         pub pool: Pool,
     }
@@ -188,15 +733,19 @@ pub mod tree {
                 role_permissions_map,
                 role_roles_map,
                 permission_map,
+                max_role_depth,
             } = self.query_results(realm_id, user_id).await?;
 
-            let permissions = self.permission_nodes(Some(&user_permissions), &permission_map);
-            let roles = self.role_nodes(
+            let mut resolution = Resolution::new(max_role_depth);
+
+            let permissions = Self::permission_nodes(Some(&user_permissions), &permission_map, &mut resolution);
+            let roles = Self::role_nodes(
                 Some(&user_roles),
                 &role_map,
                 &role_roles_map,
                 &role_permissions_map,
                 &permission_map,
+                &mut resolution,
             );
 
             let user = Some(UserNode {
@@ -208,13 +757,54 @@ pub mod tree {
             let root = RootNode {
                 user,
                 role: None,
+                errors: resolution.errors,
             };
 
             Ok(root)
         }
 
-        pub async fn by_role_id(&self, realm_id: Uuid, role_id: Uuid) -> Result<RootNode> {
-            todo!()
+        pub async fn by_role_id(&self, role_id: Uuid) -> Result<RootNode> {
+            let RoleQueryResult {
+                role,
+                role_permissions,
+                role_roles,
+                role_map,
+                role_permissions_map,
+                role_roles_map,
+                permission_map,
+                max_role_depth,
+            } = self.role_query_results(role_id).await?;
+
+            let mut resolution = Resolution::new(max_role_depth);
+            resolution.ancestors.push(role.id);
+            resolution.expanded.insert(role.id);
+            resolution.offset = 1;
+
+            let permissions = Self::permission_nodes(Some(&role_permissions), &permission_map, &mut resolution);
+            let roles = Self::role_nodes(
+                Some(&role_roles),
+                &role_map,
+                &role_roles_map,
+                &role_permissions_map,
+                &permission_map,
+                &mut resolution,
+            );
+
+            let role = Some(RoleNode {
+                role,
+                roles,
+                permissions,
+                grant: None,
+                repeated: false,
+            });
+
+            let root = RootNode {
+                user: None,
+                role,
+                errors: resolution.errors,
+            };
+
+            Ok(root)
         }
 
         async fn query_results(&self,
@@ -224,14 +814,18 @@ pub mod tree {
             let user = sqlx::query_as::<_, User>(r#"
                 SELECT * FROM users
                 WHERE id = $1
+                AND deleted_at IS NULL
             "#)
                 .bind(user_id)
                 .fetch_one(&self.pool);
 
+            // grants to users outside their window are left out entirely
             let user_permissions = sqlx::query_as::<_, UserPermission>(r#"
                 SELECT * FROM user_permission_grants
                 WHERE realm_id = $1
                 AND user_id = $2
+                AND (starts_at IS NULL OR starts_at <= CURRENT_TIMESTAMP)
+                AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
             "#)
                 .bind(realm_id)
                 .bind(user_id)
@@ -241,85 +835,209 @@ pub mod tree {
                 SELECT * FROM user_role_grants
                 WHERE realm_id = $1
                 AND user_id = $2
+                AND (starts_at IS NULL OR starts_at <= CURRENT_TIMESTAMP)
+                AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
             "#)
                 .bind(realm_id)
                 .bind(user_id)
                 .fetch_all(&self.pool);
 
-            let permissions = sqlx::query_as::<_, Permission>(r#"
-                SELECT * FROM permissions
-                WHERE realm_id = $1
+            let closure_maps = self.closure_maps(realm_id, user_id, USER_ROLE_SEED);
+
+            let (
+                user,
+                user_permissions,
+                user_roles,
+                closure_maps,
+            ) = futures::join!(
+                user,
+                user_permissions,
+                user_roles,
+                closure_maps,
+            );
+
+            let user = user?;
+            let user_permissions = user_permissions?;
+            let user_roles = user_roles?;
+            let ClosureMaps {
+                role_map,
+                role_permissions_map,
+                role_roles_map,
+                permission_map,
+                max_role_depth,
+            } = closure_maps?;
+
+            let user_permissions: Vec<(Uuid, GrantType)> = user_permissions
+                .into_iter()
+                .map(|row| (row.permission_id, GrantType::UserPermission(row)))
+                .collect();
+            
+            let user_roles: Vec<(Uuid, GrantType)> = user_roles
+                .into_iter()
+                .map(|row| (row.role_id, GrantType::UserRole(row)))
+                .collect();
+
+            Ok(UserQueryResult {
+                user,
+                user_permissions,
+                user_roles,
+                role_map,
+                role_permissions_map,
+                role_roles_map,
+                permission_map,
+                max_role_depth,
+            })
+        }
+
+        async fn role_query_results(&self, role_id: Uuid) -> Result<RoleQueryResult> {
+            let role = sqlx::query_as::<_, Role>(r#"
+                SELECT * FROM roles
+                WHERE id = $1
             "#)
+                .bind(role_id)
+                .fetch_one(&self.pool)
+                .await?;
+
+            let ClosureMaps {
+                role_map,
+                role_permissions_map,
+                role_roles_map,
+                permission_map,
+                max_role_depth,
+            } = self.closure_maps(role.realm_id, role.id, ROLE_SEED).await?;
+
+            let role_permissions = role_permissions_map
+                .get(&role.id)
+                .cloned()
+                .unwrap_or_default();
+
+            let role_roles = role_roles_map
+                .get(&role.id)
+                .cloned()
+                .unwrap_or_default();
+
+            Ok(RoleQueryResult {
+                role,
+                role_permissions,
+                role_roles,
+                role_map,
+                role_permissions_map,
+                role_roles_map,
+                permission_map,
+                max_role_depth,
+            })
+        }
+
+        // loads only the roles reachable from the seed (and the grants and
+        // permissions hanging off of them) instead of every row in the realm
+        async fn closure_maps(&self, realm_id: Uuid, root_id: Uuid, seed: &str) -> Result<ClosureMaps> {
+            let closure = format!(r#"
+                WITH RECURSIVE closure(role_id) AS (
+                    {}
+                    UNION
+                    SELECT role_role_grants.child_id FROM role_role_grants
+                    JOIN closure ON role_role_grants.parent_id = closure.role_id
+                    WHERE role_role_grants.realm_id = $1
+                )
+            "#, seed);
+
+            let roles_query = format!(r#"
+                {}
+                SELECT roles.* FROM roles
+                WHERE roles.id IN (SELECT role_id FROM closure)
+            "#, closure);
+
+            let role_roles_query = format!(r#"
+                {}
+                SELECT role_role_grants.* FROM role_role_grants
+                WHERE role_role_grants.realm_id = $1
+                AND role_role_grants.parent_id IN (SELECT role_id FROM closure)
+            "#, closure);
+
+            let role_permissions_query = format!(r#"
+                {}
+                SELECT role_permission_grants.* FROM role_permission_grants
+                WHERE role_permission_grants.realm_id = $1
+                AND role_permission_grants.role_id IN (SELECT role_id FROM closure)
+            "#, closure);
+
+            let permissions_query = format!(r#"
+                {}
+                SELECT permissions.* FROM permissions
+                WHERE permissions.realm_id = $1
+                AND (
+                    permissions.id IN (
+                        SELECT permission_id FROM role_permission_grants
+                        WHERE realm_id = $1
+                        AND role_id IN (SELECT role_id FROM closure)
+                    )
+                    OR permissions.id IN (
+                        SELECT permission_id FROM user_permission_grants
+                        WHERE realm_id = $1
+                        AND user_id = $2
+                        AND (starts_at IS NULL OR starts_at <= CURRENT_TIMESTAMP)
+                        AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+                    )
+                )
+            "#, closure);
+
+            let roles = sqlx::query_as::<_, Role>(&roles_query)
                 .bind(realm_id)
+                .bind(root_id)
                 .fetch_all(&self.pool);
 
-            let roles = sqlx::query_as::<_, Role>(r#"
-                SELECT * FROM roles
-                WHERE realm_id = $1
-            "#)
+            let role_roles = sqlx::query_as::<_, RoleRole>(&role_roles_query)
                 .bind(realm_id)
+                .bind(root_id)
                 .fetch_all(&self.pool);
 
-            let role_permissions = sqlx::query_as::<_, RolePermission>(r#"
-                SELECT * FROM role_permission_grants
-                WHERE realm_id = $1
-            "#)
+            let role_permissions = sqlx::query_as::<_, RolePermission>(&role_permissions_query)
                 .bind(realm_id)
+                .bind(root_id)
                 .fetch_all(&self.pool);
 
-            let role_roles = sqlx::query_as::<_, RoleRole>(r#"
-                SELECT * FROM role_role_grants
-                WHERE realm_id = $1
-            "#)
+            let permissions = sqlx::query_as::<_, Permission>(&permissions_query)
                 .bind(realm_id)
+                .bind(root_id)
                 .fetch_all(&self.pool);
 
+            let max_role_depth = sqlx::query_as::<_, (i32,)>(r#"
+                SELECT max_role_depth FROM realms
+                WHERE id = $1
+            "#)
+                .bind(realm_id)
+                .fetch_one(&self.pool);
+
             let (
-                user,
-                user_permissions,
-                user_roles,
                 roles,
                 role_permissions,
                 role_roles,
                 permissions,
+                max_role_depth,
             ) = futures::join!(
-                user,
-                user_permissions,
-                user_roles,
                 roles,
                 role_permissions,
                 role_roles,
                 permissions,
+                max_role_depth,
             );
 
-            let user = user?;
-            let user_permissions = user_permissions?;
-            let user_roles = user_roles?;
             let roles = roles?;
             let role_permissions = role_permissions?;
             let role_roles = role_roles?;
             let permissions = permissions?;
-
-            let user_permissions: Vec<(Uuid, GrantType)> = user_permissions
-                .into_iter()
-                .map(|row| (row.permission_id, GrantType::UserPermission(row)))
-                .collect();
-            
-            let user_roles: Vec<(Uuid, GrantType)> = user_roles
-                .into_iter()
-                .map(|row| (row.role_id.clone(), GrantType::UserRole(row)))
-                .collect();
+            let (max_role_depth,) = max_role_depth?;
 
             let permission_map: HashMap<Uuid, Permission> = permissions
                 .into_iter()
-                .map(|row| (row.id.clone(), row))
+                .map(|row| (row.id, row))
                 .collect::<Vec<(Uuid, Permission)>>()
                 .into_iter()
                 .collect();
 
             let role_map: HashMap<Uuid, Role> = roles
                 .into_iter()
-                .map(|row| (row.id.clone(), row))
+                .map(|row| (row.id, row))
                 .collect::<Vec<(Uuid, Role)>>()
                 .into_iter()
                 .collect();
@@ -338,88 +1056,206 @@ pub mod tree {
                 .into_iter()
                 .fold(HashMap::new(), |mut dict, role_role| {
                     dict.entry(role_role.parent_id)
-                        .and_modify(|arr| arr.push((role_role.parent_id, GrantType::RoleRole(role_role.clone()))))
-                        .or_insert(vec![(role_role.parent_id, GrantType::RoleRole(role_role))]);
+                        .and_modify(|arr| arr.push((role_role.child_id, GrantType::RoleRole(role_role.clone()))))
+                        .or_insert(vec![(role_role.child_id, GrantType::RoleRole(role_role))]);
 
                     dict
                 });
 
-            Ok(UserQueryResult {
-                user,
-                user_permissions,
-                user_roles,
+            Ok(ClosureMaps {
                 role_map,
                 role_permissions_map,
                 role_roles_map,
                 permission_map,
+                max_role_depth: max_role_depth.max(0) as usize,
             })
         }
 
         fn role_nodes(
-            &self,
             roles: Option<&Vec<(Uuid, GrantType)>>,
             role_map: &HashMap<Uuid, Role>,
             role_roles_map: &HashMap<Uuid, Vec<(Uuid, GrantType)>>,
             role_permissions_map: &HashMap<Uuid, Vec<(Uuid, GrantType)>>,
             permission_map: &HashMap<Uuid, Permission>,
+            resolution: &mut Resolution,
         ) -> Option<Vec<RoleNode>> {
-            if roles.is_none() { return None }
+            let roles = roles?;
 
-            let results = roles
-                .unwrap()
-                .into_iter()
-                .map(|(role_id, grant)| {
-                    let grant = grant.clone();
-                    let role = role_map.get(&role_id).unwrap().clone();
-                    let permissions = self.permission_nodes(
-                        role_permissions_map.get(&role_id),
-                        permission_map,
-                    );
-                    let roles = self.role_nodes(
-                        role_roles_map.get(&role_id),
-                        role_map,
-                        role_roles_map,
-                        role_permissions_map,
-                        permission_map,
-                    );
-
-                    RoleNode {
+            let mut results = vec![];
+
+            for (role_id, grant) in roles.iter() {
+                if resolution.ancestors.contains(role_id) {
+                    let error = InheritanceError::Cycle {
+                        roles: resolution.path_names(role_map, *role_id),
+                    };
+
+                    resolution.errors.push(error);
+
+                    continue;
+                }
+
+                if resolution.depth() >= resolution.max_depth {
+                    let error = InheritanceError::DepthExceeded {
+                        roles: resolution.path_names(role_map, *role_id),
+                        max_depth: resolution.max_depth,
+                    };
+
+                    resolution.errors.push(error);
+
+                    continue;
+                }
+
+                let grant = Some(grant.clone());
+                let role = match role_map.get(role_id) {
+                    Some(role) => role.clone(),
+                    None => {
+                        resolution.errors.push(InheritanceError::MissingRole { role_id: *role_id });
+
+                        continue;
+                    },
+                };
+
+                // a role reachable along more than one path is only expanded
+                // the first time it is seen
+                if !resolution.expanded.insert(*role_id) {
+                    results.push(RoleNode {
                         role,
-                        roles,
-                        permissions,
+                        roles: None,
+                        permissions: None,
                         grant,
-                    }
-                })
-                .collect();
+                        repeated: true,
+                    });
+
+                    continue;
+                }
+
+                let permissions = Self::permission_nodes(
+                    role_permissions_map.get(role_id),
+                    permission_map,
+                    resolution,
+                );
+
+                resolution.ancestors.push(*role_id);
+
+                let roles = Self::role_nodes(
+                    role_roles_map.get(role_id),
+                    role_map,
+                    role_roles_map,
+                    role_permissions_map,
+                    permission_map,
+                    resolution,
+                );
+
+                resolution.ancestors.pop();
+
+                results.push(RoleNode {
+                    role,
+                    roles,
+                    permissions,
+                    grant,
+                    repeated: false,
+                });
+            }
 
             Some(results)
         }
 
         fn permission_nodes(
-            &self,
             permissions: Option<&Vec<(Uuid, GrantType)>>,
             permission_map: &HashMap<Uuid, Permission>,
+            resolution: &mut Resolution,
         ) -> Option<Vec<PermissionNode>> {
-            if permissions.is_none() { return None }
+            let mut results = vec![];
 
-            let results = permissions
-                .unwrap()
-                .into_iter()
-                .map(|(permission_id, grant)| {
-                    let grant = grant.clone();
-                    let permission = permission_map.get(&permission_id).unwrap().clone();
+            for (permission_id, grant) in permissions?.iter() {
+                let permission = match permission_map.get(permission_id) {
+                    Some(permission) => permission.clone(),
+                    None => {
+                        resolution.errors.push(InheritanceError::MissingPermission { permission_id: *permission_id });
 
-                    PermissionNode {
-                        grant,
-                        permission,
-                    }
-                })
-                .collect();
+                        continue;
+                    },
+                };
+
+                results.push(PermissionNode {
+                    grant: grant.clone(),
+                    permission,
+                });
+            }
 
             Some(results)
         }
     }
 
+    // the bookkeeping for a single walk down the role_role grants
+    struct Resolution {
+        max_depth: usize,
+        // ancestors that don't count towards the depth (the root of a role tree)
+        offset: usize,
+        ancestors: Vec<Uuid>,
+        expanded: HashSet<Uuid>,
+        errors: Vec<InheritanceError>,
+    }
+
+    impl Resolution {
+        fn new(max_depth: usize) -> Self {
+            Self {
+                max_depth,
+                offset: 0,
+                ancestors: vec![],
+                expanded: HashSet::new(),
+                errors: vec![],
+            }
+        }
+
+        fn depth(&self) -> usize {
+            self.ancestors.len() - self.offset
+        }
+
+        fn path_names(&self, role_map: &HashMap<Uuid, Role>, role_id: Uuid) -> Vec<String> {
+            self.ancestors
+                .iter()
+                .chain(std::iter::once(&role_id))
+                .map(|id| match role_map.get(id) {
+                    Some(role) => role.name.clone(),
+                    None => id.to_string(),
+                })
+                .collect()
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq, Serialize)]
+    #[serde(tag = "kind", rename_all = "snake_case")]
+    pub enum InheritanceError {
+        Cycle { roles: Vec<String> },
+        DepthExceeded { roles: Vec<String>, max_depth: usize },
+        // a grant whose role or permission didn't come back with the rest
+        // of the tree, e.g. one deleted while it was being loaded
+        MissingRole { role_id: Uuid },
+        MissingPermission { permission_id: Uuid },
+    }
+
+    impl fmt::Display for InheritanceError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                InheritanceError::Cycle { roles } => {
+                    write!(f, "role inheritance cycle: {}", roles.join(" -> "))
+                },
+                InheritanceError::DepthExceeded { roles, max_depth } => {
+                    write!(f, "role inheritance deeper than {} levels: {}", max_depth, roles.join(" -> "))
+                },
+                InheritanceError::MissingRole { role_id } => {
+                    write!(f, "granted role {} could not be loaded", role_id)
+                },
+                InheritanceError::MissingPermission { permission_id } => {
+                    write!(f, "granted permission {} could not be loaded", permission_id)
+                },
+            }
+        }
+    }
+
+    impl std::error::Error for InheritanceError {}
+
     #[derive(Clone, Debug, Serialize)]
     #[serde(rename_all = "snake_case")]
     #[serde(untagged)]
@@ -430,15 +1266,76 @@ pub mod tree {
         RolePermission(super::RolePermission),
     }
 
+    impl GrantType {
+        pub fn id(&self) -> Uuid {
+            match self {
+                GrantType::UserPermission(grant) => grant.id,
+                GrantType::UserRole(grant) => grant.id,
+                GrantType::RoleRole(grant) => grant.id,
+                GrantType::RolePermission(grant) => grant.id,
+            }
+        }
+
+        pub fn kind(&self) -> super::GrantKind {
+            match self {
+                GrantType::UserPermission(_) => super::GrantKind::UserPermission,
+                GrantType::UserRole(_) => super::GrantKind::UserRole,
+                GrantType::RoleRole(_) => super::GrantKind::RoleRole,
+                GrantType::RolePermission(_) => super::GrantKind::RolePermission,
+            }
+        }
+
+        pub fn effect(&self) -> Effect {
+            match self {
+                GrantType::UserPermission(grant) => grant.effect,
+                GrantType::RolePermission(grant) => grant.effect,
+                _ => Effect::Allow,
+            }
+        }
+
+        pub fn condition(&self) -> Option<&str> {
+            match self {
+                GrantType::UserPermission(grant) => grant.condition.as_deref(),
+                GrantType::RolePermission(grant) => grant.condition.as_deref(),
+                _ => None,
+            }
+        }
+
+        pub fn expires_at(&self) -> Option<NaiveDateTime> {
+            match self {
+                GrantType::UserPermission(grant) => grant.expires_at,
+                GrantType::UserRole(grant) => grant.expires_at,
+                _ => None,
+            }
+        }
+    }
+
     #[derive(Debug, Serialize)]
     pub struct RootNode {
         #[serde(skip_serializing_if = "Option::is_none")]
         pub user: Option<UserNode>,
         #[serde(skip_serializing_if = "Option::is_none")]
         pub role: Option<RoleNode>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        pub errors: Vec<InheritanceError>,
     }
 
     impl RootNode {
+        // cycles and runaway depth are skipped while resolving so that
+        // authentication keeps working, but admins should see them as errors
+        pub fn ensure_resolved(self) -> Result<Self> {
+            if self.errors.is_empty() {
+                return Ok(self)
+            }
+
+            let messages: Vec<String> = self.errors
+                .iter()
+                .map(|error| error.to_string())
+                .collect();
+
+            Err(Error::msg(messages.join("; ")))
+        }
+
         pub fn roles(&self) -> Vec<String> {
             if let Some(user) = &self.user {
                 return user.roles()
@@ -462,6 +1359,51 @@ pub mod tree {
 
             vec![]
         }
+
+        pub fn conditional_permissions(&self) -> Vec<ConditionalGrant> {
+            if let Some(user) = &self.user {
+                return user.conditional_permissions()
+            }
+
+            if let Some(role) = &self.role {
+                return role.conditional_permissions()
+            }
+
+            vec![]
+        }
+
+        // what goes into a token: duplicates and grants covered by a broader
+        // grant are dropped
+        pub fn condensed_permissions(&self) -> Vec<String> {
+            condense(&self.permissions())
+        }
+
+        // the earliest a grant to the user runs out, which is as long as a
+        // token built from this tree should live
+        pub fn expires_at(&self) -> Option<NaiveDateTime> {
+            let user = self.user.as_ref()?;
+
+            let permissions = user.permissions
+                .iter()
+                .flatten()
+                .filter_map(|p| p.grant.expires_at());
+
+            let roles = user.roles
+                .iter()
+                .flatten()
+                .filter_map(|r| r.grant.as_ref()?.expires_at());
+
+            permissions.chain(roles).min()
+        }
+
+        pub fn role_names(&self) -> Vec<String> {
+            let mut roles = self.roles();
+
+            roles.sort();
+            roles.dedup();
+
+            roles
+        }
     }
 
     #[derive(Debug, Serialize)]
@@ -476,7 +1418,7 @@ pub mod tree {
     impl UserNode {
         pub fn roles(&self) -> Vec<String> {
             match &self.roles {
-                Some(roles) => roles.iter().map(|r| r.roles()).flatten().collect(),
+                Some(roles) => roles.iter().flat_map(|r| r.roles()).collect(),
                 None => vec![],
             }
         }
@@ -485,19 +1427,41 @@ pub mod tree {
             let mut results = vec![];
 
             if let Some(roles) = &self.roles {
-                let mut strings = roles.iter().map(|r| r.permissions()).flatten().collect();
+                let mut strings = roles.iter().flat_map(|r| r.permissions()).collect();
 
                 results.append(&mut strings);
             }
 
             if let Some(permissions) = &self.permissions {
-                let mut strings = permissions.iter().map(|p| p.permission.to_string()).collect();
+                let mut strings = permissions
+                    .iter()
+                    .filter(|p| p.condition().is_none())
+                    .map(|p| p.grant_string())
+                    .collect();
 
                 results.append(&mut strings);
             }
 
             results
         }
+
+        pub fn conditional_permissions(&self) -> Vec<ConditionalGrant> {
+            let mut results = vec![];
+
+            if let Some(roles) = &self.roles {
+                let mut grants = roles.iter().flat_map(|r| r.conditional_permissions()).collect();
+
+                results.append(&mut grants);
+            }
+
+            if let Some(permissions) = &self.permissions {
+                let mut grants = permissions.iter().filter_map(|p| p.conditional_grant()).collect();
+
+                results.append(&mut grants);
+            }
+
+            results
+        }
     }
 
 
@@ -508,13 +1472,16 @@ pub mod tree {
         pub roles: Option<Vec<RoleNode>>,
         #[serde(skip_serializing_if = "Option::is_none")]
         pub permissions: Option<Vec<PermissionNode>>,
-        pub grant: GrantType,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub grant: Option<GrantType>,
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        pub repeated: bool,
     }
 
     impl RoleNode {
         pub fn roles(&self) -> Vec<String> {
             let mut roles = match &self.roles {
-                Some(roles) => roles.iter().map(|r| r.roles()).flatten().collect(),
+                Some(roles) => roles.iter().flat_map(|r| r.roles()).collect(),
                 None => vec![],
             };
 
@@ -527,32 +1494,41 @@ pub mod tree {
             let mut results = vec![];
 
             if let Some(roles) = &self.roles {
-                let mut strings = roles.iter().map(|r| r.permissions()).flatten().collect();
+                let mut strings = roles.iter().flat_map(|r| r.permissions()).collect();
 
                 results.append(&mut strings);
             }
 
             if let Some(permissions) = &self.permissions {
-                let mut strings = permissions.iter().map(|p| p.permission.to_string()).collect();
+                let mut strings = permissions
+                    .iter()
+                    .filter(|p| p.condition().is_none())
+                    .map(|p| p.grant_string())
+                    .collect();
 
                 results.append(&mut strings);
             }
 
             results
         }
-    }
 
-    #[derive(Debug, Serialize)]
-    pub struct CondensedRoot {
-        roles: Option<Vec<CondensedRole>>,
-        permissions: Option<Vec<String>>,
-    }
+        pub fn conditional_permissions(&self) -> Vec<ConditionalGrant> {
+            let mut results = vec![];
 
-    #[derive(Debug, Serialize)]
-    pub struct CondensedRole {
-        role: String,
-        roles: Option<Vec<CondensedRole>>,
-        permissions: Option<Vec<String>>,
+            if let Some(roles) = &self.roles {
+                let mut grants = roles.iter().flat_map(|r| r.conditional_permissions()).collect();
+
+                results.append(&mut grants);
+            }
+
+            if let Some(permissions) = &self.permissions {
+                let mut grants = permissions.iter().filter_map(|p| p.conditional_grant()).collect();
+
+                results.append(&mut grants);
+            }
+
+            results
+        }
     }
 
     #[derive(Debug, Serialize)]
@@ -561,6 +1537,33 @@ pub mod tree {
         pub grant: GrantType,
     }
 
+    impl PermissionNode {
+        pub fn effect(&self) -> Effect {
+            self.grant.effect()
+        }
+
+        // the permission as it goes into a token, `!` prefixed for a deny
+        pub fn grant_string(&self) -> String {
+            match self.effect() {
+                Effect::Allow => self.permission.to_string(),
+                Effect::Deny => format!("{}{}", DENY_PREFIX, self.permission),
+            }
+        }
+
+        pub fn condition(&self) -> Option<&str> {
+            self.grant.condition()
+        }
+
+        pub fn conditional_grant(&self) -> Option<ConditionalGrant> {
+            let condition = self.condition()?;
+
+            Some(ConditionalGrant {
+                grant: self.grant_string(),
+                condition: condition.to_string(),
+            })
+        }
+    }
+
     struct UserQueryResult {
         user: User,
         user_permissions: Vec<(Uuid, GrantType)>,
@@ -569,6 +1572,7 @@ pub mod tree {
         role_permissions_map: HashMap<Uuid, Vec<(Uuid, GrantType)>>,
         role_roles_map: HashMap<Uuid, Vec<(Uuid, GrantType)>>,
         permission_map: HashMap<Uuid, Permission>,
+        max_role_depth: usize,
     }
 
     struct RoleQueryResult {
@@ -579,5 +1583,253 @@ pub mod tree {
         role_permissions_map: HashMap<Uuid, Vec<(Uuid, GrantType)>>,
         role_roles_map: HashMap<Uuid, Vec<(Uuid, GrantType)>>,
         permission_map: HashMap<Uuid, Permission>,
+        max_role_depth: usize,
+    }
+
+    struct ClosureMaps {
+        role_map: HashMap<Uuid, Role>,
+        role_permissions_map: HashMap<Uuid, Vec<(Uuid, GrantType)>>,
+        role_roles_map: HashMap<Uuid, Vec<(Uuid, GrantType)>>,
+        permission_map: HashMap<Uuid, Permission>,
+        max_role_depth: usize,
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use super::super::RoleRole;
+        use chrono::Utc;
+
+        // roles by name, with the role_role grants between them and a
+        // permission on each
+        struct Fixture {
+            ids: HashMap<&'static str, Uuid>,
+            role_map: HashMap<Uuid, Role>,
+            role_roles_map: HashMap<Uuid, Vec<(Uuid, GrantType)>>,
+            role_permissions_map: HashMap<Uuid, Vec<(Uuid, GrantType)>>,
+            permission_map: HashMap<Uuid, Permission>,
+        }
+
+        impl Fixture {
+            fn new(names: &[&'static str], edges: &[(&'static str, &'static str)]) -> Self {
+                let ids: HashMap<&'static str, Uuid> = names.iter().map(|name| (*name, Uuid::new_v4())).collect();
+                let now = Utc::now().naive_utc();
+                let mut fixture = Fixture {
+                    ids,
+                    role_map: HashMap::new(),
+                    role_roles_map: HashMap::new(),
+                    role_permissions_map: HashMap::new(),
+                    permission_map: HashMap::new(),
+                };
+
+                for name in names {
+                    let id = fixture.id(name);
+                    let permission_id = Uuid::new_v4();
+
+                    fixture.role_map.insert(id, Role {
+                        id,
+                        realm_id: Uuid::nil(),
+                        name: name.to_string(),
+                        created_at: None,
+                        updated_at: None,
+                    });
+
+                    fixture.permission_map.insert(permission_id, Permission {
+                        id: permission_id,
+                        realm: "app".to_string(),
+                        resource: name.to_string(),
+                        action: "read".to_string(),
+                        realm_id: Uuid::nil(),
+                        created_at: None,
+                        updated_at: None,
+                    });
+
+                    fixture.role_permissions_map.insert(id, vec![(permission_id, GrantType::RolePermission(RolePermission {
+                        id: Uuid::new_v4(),
+                        realm_id: Uuid::nil(),
+                        role_id: id,
+                        permission_id,
+                        effect: Effect::Allow,
+                        condition: None,
+                        created_at: now,
+                        updated_at: now,
+                    }))]);
+                }
+
+                for (parent, child) in edges {
+                    let (parent_id, child_id) = (fixture.id(parent), fixture.id(child));
+
+                    fixture.role_roles_map.entry(parent_id).or_default().push((child_id, GrantType::RoleRole(RoleRole {
+                        id: Uuid::new_v4(),
+                        realm_id: Uuid::nil(),
+                        parent_id,
+                        child_id,
+                        created_at: now,
+                        updated_at: now,
+                    })));
+                }
+
+                fixture
+            }
+
+            fn id(&self, name: &str) -> Uuid {
+                self.ids[name]
+            }
+
+            // the tree for a user granted `root`, and whatever went wrong building it
+            fn resolve(&self, root: &str, max_depth: usize) -> (Vec<RoleNode>, Vec<InheritanceError>) {
+                let now = Utc::now().naive_utc();
+                let user_roles = vec![(self.id(root), GrantType::UserRole(UserRole {
+                    id: Uuid::new_v4(),
+                    realm_id: Uuid::nil(),
+                    user_id: Uuid::nil(),
+                    role_id: self.id(root),
+                    starts_at: None,
+                    expires_at: None,
+                    swept_at: None,
+                    created_at: now,
+                    updated_at: now,
+                }))];
+
+                let mut resolution = Resolution::new(max_depth);
+
+                let roles = Service::role_nodes(
+                    Some(&user_roles),
+                    &self.role_map,
+                    &self.role_roles_map,
+                    &self.role_permissions_map,
+                    &self.permission_map,
+                    &mut resolution,
+                );
+
+                (roles.unwrap(), resolution.errors)
+            }
+        }
+
+        fn children(node: &RoleNode) -> Vec<&RoleNode> {
+            node.roles.iter().flatten().collect()
+        }
+
+        #[test]
+        fn test_cycle() {
+            let fixture = Fixture::new(&["a", "b"], &[("a", "b"), ("b", "a")]);
+
+            let (roles, errors) = fixture.resolve("a", 8);
+
+            assert_eq!(errors, vec![InheritanceError::Cycle {
+                roles: vec!["a".to_string(), "b".to_string(), "a".to_string()],
+            }]);
+
+            // the walk stops at the edge that closes the cycle
+            let b = children(&roles[0])[0];
+            assert_eq!(b.role.name, "b");
+            assert!(children(b).is_empty());
+        }
+
+        #[test]
+        fn test_depth_exceeded() {
+            let fixture = Fixture::new(&["r0", "r1", "r2", "r3"], &[("r0", "r1"), ("r1", "r2"), ("r2", "r3")]);
+
+            let (_, errors) = fixture.resolve("r0", 4);
+            assert!(errors.is_empty());
+
+            let (roles, errors) = fixture.resolve("r0", 3);
+
+            assert_eq!(errors, vec![InheritanceError::DepthExceeded {
+                roles: vec!["r0".to_string(), "r1".to_string(), "r2".to_string(), "r3".to_string()],
+                max_depth: 3,
+            }]);
+
+            let r2 = children(children(&roles[0])[0])[0];
+            assert_eq!(r2.role.name, "r2");
+            assert!(children(r2).is_empty());
+        }
+
+        #[test]
+        fn test_diamond() {
+            let fixture = Fixture::new(
+                &["top", "left", "right", "bottom"],
+                &[("top", "left"), ("top", "right"), ("left", "bottom"), ("right", "bottom")],
+            );
+
+            let (roles, errors) = fixture.resolve("top", 8);
+            assert!(errors.is_empty());
+
+            let top = &roles[0];
+            let (left, right) = (children(top)[0], children(top)[1]);
+
+            let first = children(left)[0];
+            assert_eq!(first.role.name, "bottom");
+            assert!(!first.repeated);
+            assert_eq!(first.permissions.as_ref().unwrap().len(), 1);
+
+            let second = children(right)[0];
+            assert_eq!(second.role.name, "bottom");
+            assert!(second.repeated);
+            assert!(second.roles.is_none());
+            assert!(second.permissions.is_none());
+
+            // bottom is only counted once
+            assert_eq!(top.permissions(), vec!["app:bottom:read", "app:left:read", "app:right:read", "app:top:read"]);
+        }
+
+        #[test]
+        fn test_missing() {
+            let mut fixture = Fixture::new(&["a", "b"], &[("a", "b")]);
+            let b = fixture.id("b");
+            let (permission_id, _) = fixture.role_permissions_map[&fixture.id("a")][0].clone();
+
+            fixture.role_map.remove(&b);
+            fixture.permission_map.remove(&permission_id);
+
+            let (roles, errors) = fixture.resolve("a", 8);
+
+            assert_eq!(errors, vec![
+                InheritanceError::MissingPermission { permission_id },
+                InheritanceError::MissingRole { role_id: b },
+            ]);
+
+            assert_eq!(roles[0].role.name, "a");
+            assert!(children(&roles[0]).is_empty());
+            assert!(roles[0].permissions.as_ref().unwrap().is_empty());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seed::testing;
+    use crate::users::{UserDelete, UserService};
+
+    // the tree and explain routes rely on `in_realm` to keep a realm to its
+    // own users and on the tree leaving deleted users out
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_user_outside_realm() {
+        let pool = testing::pool().await;
+        let (mut acme, acme_suffix) = testing::unique(&testing::realm());
+        let (mut other, other_suffix) = testing::unique(&testing::realm());
+
+        crate::seed::seed(&pool, &mut acme).await.unwrap();
+        crate::seed::seed(&pool, &mut other).await.unwrap();
+
+        let users = UserService::new(&pool).unwrap();
+        let grants = GrantService::new(&pool).unwrap();
+        let realm_id = acme.id.unwrap();
+
+        let wile = users.by_username(format!("wile{}", acme_suffix)).await.unwrap();
+        let stranger = users.by_username(format!("wile{}", other_suffix)).await.unwrap();
+
+        assert!(users.in_realm(realm_id, wile.id).await.is_ok());
+        assert!(users.in_realm(realm_id, stranger.id).await.is_err());
+
+        let root = grants.by_user_id(realm_id, wile.id).await.unwrap();
+        assert_eq!(root.user.unwrap().permissions(), vec![format!("acme{}:anvils:drop", acme_suffix)]);
+
+        users.delete(&Actor::system(), wile.id, UserDelete::default()).await.unwrap();
+
+        assert!(users.in_realm(realm_id, wile.id).await.is_err());
+        assert!(grants.by_user_id(realm_id, wile.id).await.is_err());
     }
 }
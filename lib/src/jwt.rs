@@ -1,21 +1,47 @@
 use crate::User;
+use crate::permissions::condition::{Condition, ConditionalGrant, Environment};
+use crate::permissions::permission::{parse_grant, Effect, ParseError, Permission};
+use crate::permissions::permission_set::PermissionSet;
 use jsonwebtoken::{decode, Algorithm, Validation, DecodingKey, EncodingKey, Header, encode, errors};
 use serde::{Deserialize, Serialize};
 use std::time;
 use std::ops::Add;
 use crate::result::Result;
+use chrono::Utc;
+use serde_json::Value as JsonValue;
+use uuid::Uuid;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Claims {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sub: Option<Uuid>,
+    // the session the token was issued for; the Jwt middleware turns the
+    // token away once that session is revoked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sid: Option<Uuid>,
     pub first_name: Option<String>,
     pub last_name: Option<String>,
     pub email: Option<String>,
     pub exp: usize,
     pub grants: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conditional_grants: Vec<ConditionalGrant>,
+    // whose key verified the token; filled in by the Jwt middleware, never
+    // read from the token itself
+    #[serde(skip)]
+    pub issuer: Option<Issuer>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Issuer {
+    pub realm_id: Uuid,
+    pub admin: bool,
 }
 
 impl Claims {
-    pub fn encode(&self, encoding_key: &Vec<u8>) -> Result<String> {
+    pub fn encode(&self, encoding_key: &[u8]) -> Result<String> {
         let encoding_key = EncodingKey::from_rsa_pem(encoding_key).unwrap();
 
         let result = encode(
@@ -27,8 +53,54 @@ impl Claims {
         Ok(result)
     }
 
+    pub fn permissions(&self) -> std::result::Result<Vec<(Effect, Permission)>, ParseError> {
+        self.grants
+            .iter()
+            .map(|grant| parse_grant(grant))
+            .collect()
+    }
+
+    pub fn permission_set(&self) -> std::result::Result<PermissionSet, ParseError> {
+        Ok(self.permissions()?.into_iter().collect())
+    }
+
+    // the unconditional grants plus every conditional grant whose condition
+    // holds for this user and request
+    pub fn permission_set_with(&self, profile: &JsonValue, context: &JsonValue) -> Result<PermissionSet> {
+        let mut permissions = self.permissions()?;
+
+        if !self.conditional_grants.is_empty() {
+            let claims = serde_json::to_value(self)?;
+            let environment = Environment {
+                claims: &claims,
+                profile,
+                context,
+                now: Utc::now().naive_utc(),
+            };
+
+            for conditional in self.conditional_grants.iter() {
+                let condition = Condition::parse(&conditional.condition)?;
+
+                if condition.evaluate(&environment) {
+                    permissions.push(parse_grant(&conditional.grant)?);
+                }
+            }
+        }
+
+        Ok(permissions.into_iter().collect())
+    }
+
+    // a token signed by any realm but the admin realm only carries the
+    // service's own permissions for that realm's resources
+    pub fn reaches(&self, challenge: &Permission) -> bool {
+        match &self.issuer {
+            Some(issuer) if !issuer.admin => challenge.within_realm(issuer.realm_id),
+            _ => true,
+        }
+    }
+
     pub fn decode(token: String, decoding_key: Vec<u8>) -> Result<Claims> {
-        let decoding_key = DecodingKey::from_rsa_pem(&&decoding_key).unwrap();
+        let decoding_key = DecodingKey::from_rsa_pem(&decoding_key).unwrap();
         let result = decode::<Claims>(&token, &decoding_key, &Validation::new(Algorithm::RS256))?;
 
         Ok(result.claims)
@@ -78,11 +150,16 @@ mod tests {
             .as_secs();
 
         let test_claims = Claims {
+            sub: None,
+            sid: None,
             first_name: Some("Bob".to_string()),
             last_name: Some("Dylan".to_string()),
             email: Some("a@b.c".to_string()),
             exp: exp as usize,
             grants: vec![],
+            roles: vec![],
+            conditional_grants: vec![],
+            issuer: None,
         };
 
         let token = match encode(&Header::new(Algorithm::RS256), &test_claims, &encoding_key) {
@@ -101,6 +178,6 @@ mod tests {
                 }
             };
 
-        println!("{}", token_data.claims.email)
+        println!("{:?}", token_data.claims.email)
     }
 }
@@ -2,19 +2,26 @@
 #[macro_use] extern crate futures;
 #[macro_use] extern crate serde_derive;
 
+pub mod audit;
 pub mod authorities;
+pub mod bulk;
 pub mod db;
+pub mod domains;
+pub mod explain;
 pub mod grants;
 pub mod jwt;
 pub mod http_response;
 pub mod middleware;
 pub mod permissions;
 pub mod realms;
+pub mod relations;
 pub mod result;
 pub mod roles;
 pub mod rsa;
 pub mod seed;
+pub mod sessions;
 pub mod users;
+pub mod webhooks;
 
 pub use authorities::*;
 pub use db::*;
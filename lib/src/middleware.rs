@@ -4,16 +4,21 @@ use std::pin::Pin;
 use std::rc::Rc;
 use std::task::{Context, Poll};
 
+use crate::audit::Actor;
 use crate::authorities::authorities::AuthorityService;
+use crate::db::patch::Precondition;
 use crate::db::pg::Pool;
+use crate::grants::GrantService;
 use crate::http_response::Response as JsonResponse;
-use crate::jwt::Claims;
+use crate::jwt::{Claims, Issuer};
 use crate::result::Error as BaseError;
+use crate::sessions::SessionService;
 use crate::PublicKey;
 
 use actix_service::{Service, Transform};
 use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
 use actix_web::{
+    error::ErrorBadRequest,
     error::ErrorUnauthorized,
     error::ResponseError,
     http::{self, HeaderMap, Method},
@@ -28,11 +33,18 @@ use base64::DecodeError;
 pub struct Jwt {
     pub skip_paths: Vec<String>,
     pub authority_service: AuthorityService,
+    pub grant_service: GrantService,
+    pub session_service: SessionService,
 }
 
 impl Jwt {
-    pub fn new(authority_service: AuthorityService, skip_paths: Vec<String>) -> Self {
-        Jwt { authority_service, skip_paths }
+    pub fn new(
+        authority_service: AuthorityService,
+        grant_service: GrantService,
+        session_service: SessionService,
+        skip_paths: Vec<String>,
+    ) -> Self {
+        Jwt { authority_service, grant_service, session_service, skip_paths }
     }
 }
 
@@ -55,6 +67,8 @@ where
             service: Rc::new(RefCell::new(service)),
             skip_paths: self.skip_paths.clone(),
             authority_service: self.authority_service.clone(),
+            grant_service: self.grant_service.clone(),
+            session_service: self.session_service.clone(),
         })
     }
 }
@@ -63,6 +77,8 @@ pub struct JwtMiddleware<S> {
     service: Rc<RefCell<S>>,
     skip_paths: Vec<String>,
     authority_service: AuthorityService,
+    grant_service: GrantService,
+    session_service: SessionService,
 }
 
 impl<S, B> Service for JwtMiddleware<S>
@@ -96,9 +112,21 @@ where
 
 
         let authority_service = self.authority_service.clone();
+        let grant_service = self.grant_service.clone();
+        let session_service = self.session_service.clone();
 
         Box::pin(async move {
-            match extract_claims(&req.headers(), authority_service).await {
+            let claims = match extract_claims(req.headers(), authority_service).await {
+                Ok(claims) => ensure_session(claims, session_service).await,
+                Err(err) => Err(err),
+            };
+
+            let claims = match claims {
+                Ok(claims) => expand_roles(claims, grant_service).await,
+                Err(err) => Err(err),
+            };
+
+            match claims {
                 Ok(claims) => {
                     req.extensions_mut().insert(claims);
                     service.call(req).await
@@ -129,6 +157,7 @@ pub enum ClaimsError {
     NoHeader,
     NoClientKey,
     FailedSignature,
+    Revoked,
     DecodeError(DecodeError),
     JwtError(jsonwebtoken::errors::Error),
     JwtParseError(http::header::ToStrError),
@@ -143,6 +172,7 @@ impl fmt::Display for ClaimsError {
             NoHeader => write!(f, "no authoriation header found"),
             NoClientKey => write!(f, "no client key header found"),
             FailedSignature => write!(f, "failed to validate token"),
+            Revoked => write!(f, "session has been revoked"),
             DecodeError(err) => write!(f, "error decoding jwt: {}", err),
             JwtError(err) => write!(f, "error getting jwt: {}", err),
             JwtParseError(err) => write!(f, "error getting jwt: {}", err),
@@ -156,7 +186,7 @@ impl std::error::Error for ClaimsError {
         "ClaimsError"
     }
 
-    fn cause(&self) -> Option<&(dyn std::error::Error)> {
+    fn cause(&self) -> Option<&dyn std::error::Error> {
         None
     }
 }
@@ -191,7 +221,54 @@ pub async fn extract_claims(
         .await
         .map_err(|err| ClaimsError::Other(err.into()))?;
 
-    return decode_claims(headers, public_keys);
+    let mut claims = decode_claims(headers, public_keys)?;
+
+    if let Some(issuer) = claims.issuer.as_mut() {
+        issuer.admin = authority_service
+            .is_admin_realm(issuer.realm_id)
+            .await
+            .map_err(|err| ClaimsError::Other(err.into()))?;
+    }
+
+    Ok(claims)
+}
+
+// tokens issued for a session stop working as soon as it's revoked, rather
+// than whenever they happen to expire
+pub async fn ensure_session(claims: Claims, session_service: SessionService) -> ClaimsResult {
+    let sid = match claims.sid {
+        Some(sid) => sid,
+        None => return Ok(claims),
+    };
+
+    match session_service.touch(sid).await {
+        Ok(true) => Ok(claims),
+        Ok(false) => Err(ClaimsError::Revoked),
+        Err(err) => Err(ClaimsError::Other(err.into())),
+    }
+}
+
+// tokens from authorities set to `token_grants: roles` only name the roles,
+// so the permissions they stand for are resolved here, as they are now
+pub async fn expand_roles(mut claims: Claims, grant_service: GrantService) -> ClaimsResult {
+    if !claims.grants.is_empty() || claims.roles.is_empty() {
+        return Ok(claims);
+    }
+
+    let (realm_id, user_id) = match (&claims.issuer, claims.sub) {
+        (Some(issuer), Some(user_id)) => (issuer.realm_id, user_id),
+        _ => return Ok(claims),
+    };
+
+    let tree = grant_service
+        .by_user_id(realm_id, user_id)
+        .await
+        .map_err(|err| ClaimsError::Other(err.into()))?;
+
+    claims.grants = tree.condensed_permissions();
+    claims.conditional_grants = tree.conditional_permissions();
+
+    Ok(claims)
 }
 
 pub fn decode_claims(
@@ -202,7 +279,7 @@ pub fn decode_claims(
         .get("authorization")
         .ok_or(ClaimsError::NoHeader)?
         .to_str()
-        .map_err(|err| ClaimsError::JwtParseError(err))?
+        .map_err(ClaimsError::JwtParseError)?
         .replace("Bearer ", "");
 
     let client_key = headers
@@ -218,12 +295,14 @@ pub fn decode_claims(
         let public_key = key.decoded_public_key()
             .map_err(|err| ClaimsError::FailedSignature)?;
 
-        if let Ok(claim) = Claims::decode(token.clone(), public_key) {
+        if let Ok(mut claim) = Claims::decode(token.clone(), public_key) {
+            claim.issuer = Some(Issuer { realm_id: key.realm_id, admin: false });
+
             return Ok(claim);
         }
     }
 
-    return Err(ClaimsError::FailedSignature);
+    Err(ClaimsError::FailedSignature)
 }
 
 impl FromRequest for Claims {
@@ -233,8 +312,57 @@ impl FromRequest for Claims {
 
     fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
         match req.extensions().get::<Claims>() {
-            Some(claims) => return ok(claims.clone()),
-            None => return err(ClaimsError::NoHeader),
+            Some(claims) => ok(claims.clone()),
+            None => err(ClaimsError::NoHeader),
+        }
+    }
+}
+
+// the claims aren't required, so this also works on the paths the Jwt
+// middleware skips, e.g. to record who tried to authenticate from where
+impl FromRequest for Actor {
+    type Config = ();
+    type Error = Error;
+    type Future = Ready<Result<Actor, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let ip = req
+            .connection_info()
+            .realip_remote_addr()
+            .map(|ip| ip.to_string());
+
+        let user_agent = req
+            .headers()
+            .get(http::header::USER_AGENT)
+            .and_then(|user_agent| user_agent.to_str().ok())
+            .map(|user_agent| user_agent.to_string());
+
+        let extensions = req.extensions();
+
+        ok(Actor::new(extensions.get::<Claims>(), ip, user_agent))
+    }
+}
+
+// from If-Match; without one an update goes through whatever the version
+impl FromRequest for Precondition {
+    type Config = ();
+    type Error = Error;
+    type Future = Ready<Result<Precondition, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let header = match req.headers().get(http::header::IF_MATCH) {
+            Some(header) => header,
+            None => return ok(Precondition::any()),
+        };
+
+        let precondition = header
+            .to_str()
+            .map_err(BaseError::new)
+            .and_then(Precondition::parse);
+
+        match precondition {
+            Ok(precondition) => ok(precondition),
+            Err(error) => err(ErrorBadRequest(error.to_string())),
         }
     }
 }
@@ -0,0 +1,550 @@
+use chrono::{Datelike, NaiveDateTime, Timelike};
+use serde_json::Value as JsonValue;
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+// A small expression language for conditions on permission grants.
+//
+//     context.org_id == profile.org_id && now.hour >= 9 && now.hour < 17
+//     claims.email != null || context.method in ['GET', 'HEAD']
+//
+// Paths start at `claims` (the token), `profile` (the user's profile),
+// `context` (whatever the caller passes along with the request) or `now`
+// (UTC, with year, month, day, hour, minute, weekday and timestamp). A path
+// that doesn't exist evaluates to null, and only `true` counts as true.
+const ROOTS: [&str; 4] = ["claims", "profile", "context", "now"];
+
+// conditions come from whoever can create grants, so parsing (which
+// recurses for every `!`, parenthesis and list) is kept within bounds
+const MAX_LENGTH: usize = 4096;
+const MAX_DEPTH: usize = 64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Expr {
+    Literal(JsonValue),
+    Path(Vec<String>),
+    List(Vec<Expr>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    In,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Comma,
+    Dot,
+    And,
+    Or,
+    Not,
+    Op(Op),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConditionError {
+    UnexpectedCharacter(usize, char),
+    UnterminatedString(usize),
+    UnexpectedToken(String),
+    UnexpectedEnd,
+    UnknownRoot(String),
+    TooLong(usize),
+    TooDeep(usize),
+}
+
+impl fmt::Display for ConditionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ConditionError::*;
+
+        match &self {
+            UnexpectedCharacter(at, c) => write!(f, "unexpected character '{}' at {}", c, at),
+            UnterminatedString(at) => write!(f, "unterminated string starting at {}", at),
+            UnexpectedToken(token) => write!(f, "unexpected {}", token),
+            UnexpectedEnd => write!(f, "unexpected end of condition"),
+            UnknownRoot(root) => write!(f, "unknown root '{}', expected one of {}", root, ROOTS.join(", ")),
+            TooLong(max) => write!(f, "a condition can be at most {} bytes long", max),
+            TooDeep(max) => write!(f, "a condition can nest at most {} levels deep", max),
+        }
+    }
+}
+
+impl std::error::Error for ConditionError {}
+
+pub struct Environment<'a> {
+    pub claims: &'a JsonValue,
+    pub profile: &'a JsonValue,
+    pub context: &'a JsonValue,
+    pub now: NaiveDateTime,
+}
+
+// a grant that only applies while its condition holds; tokens carry these
+// separately so the decision can be made with the request in hand
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ConditionalGrant {
+    pub grant: String,
+    pub condition: String,
+}
+
+impl Condition {
+    pub fn parse(input: &str) -> Result<Self, ConditionError> {
+        if input.len() > MAX_LENGTH {
+            return Err(ConditionError::TooLong(MAX_LENGTH));
+        }
+
+        let tokens = tokenize(input)?;
+        let mut parser = Parser { tokens, position: 0, depth: 0 };
+
+        let expr = parser.or()?;
+
+        if let Some(token) = parser.peek() {
+            return Err(ConditionError::UnexpectedToken(format!("{:?}", token)));
+        }
+
+        Ok(Condition {
+            source: input.to_string(),
+            expr,
+        })
+    }
+
+    pub fn evaluate(&self, environment: &Environment) -> bool {
+        evaluate(&self.expr, environment) == JsonValue::Bool(true)
+    }
+}
+
+impl FromStr for Condition {
+    type Err = ConditionError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        Condition::parse(input)
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, ConditionError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).cloned();
+
+        let (token, width) = match (c, next) {
+            (c, _) if c.is_whitespace() => {
+                i += 1;
+                continue;
+            },
+            ('(', _) => (Token::LParen, 1),
+            (')', _) => (Token::RParen, 1),
+            ('[', _) => (Token::LBracket, 1),
+            (']', _) => (Token::RBracket, 1),
+            (',', _) => (Token::Comma, 1),
+            ('.', _) => (Token::Dot, 1),
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('=', Some('=')) => (Token::Op(Op::Eq), 2),
+            ('!', Some('=')) => (Token::Op(Op::Ne), 2),
+            ('<', Some('=')) => (Token::Op(Op::Le), 2),
+            ('>', Some('=')) => (Token::Op(Op::Ge), 2),
+            ('!', _) => (Token::Not, 1),
+            ('<', _) => (Token::Op(Op::Lt), 1),
+            ('>', _) => (Token::Op(Op::Gt), 1),
+            ('\'', _) | ('"', _) => {
+                let end = chars[i + 1..]
+                    .iter()
+                    .position(|other| *other == c)
+                    .ok_or(ConditionError::UnterminatedString(i))?;
+
+                let value: String = chars[i + 1..i + 1 + end].iter().collect();
+
+                (Token::Str(value), end + 2)
+            },
+            (c, next) if c.is_ascii_digit() || (c == '-' && next.is_some_and(|n| n.is_ascii_digit())) => {
+                let mut end = i + 1;
+
+                while end < chars.len() && (chars[end].is_ascii_digit() || chars[end] == '.') {
+                    end += 1;
+                }
+
+                let value: String = chars[i..end].iter().collect();
+                let value = value
+                    .parse::<f64>()
+                    .map_err(|_| ConditionError::UnexpectedToken(value.clone()))?;
+
+                (Token::Num(value), end - i)
+            },
+            (c, _) if c.is_ascii_alphabetic() || c == '_' => {
+                let mut end = i + 1;
+
+                while end < chars.len() && (chars[end].is_ascii_alphanumeric() || chars[end] == '_') {
+                    end += 1;
+                }
+
+                let value: String = chars[i..end].iter().collect();
+
+                let token = match value.as_str() {
+                    "in" => Token::Op(Op::In),
+                    _ => Token::Ident(value),
+                };
+
+                (token, end - i)
+            },
+            (c, _) => return Err(ConditionError::UnexpectedCharacter(i, c)),
+        };
+
+        tokens.push(token);
+        i += width;
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<Token, ConditionError> {
+        let token = self.tokens
+            .get(self.position)
+            .cloned()
+            .ok_or(ConditionError::UnexpectedEnd)?;
+
+        self.position += 1;
+
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), ConditionError> {
+        let token = self.next()?;
+
+        if token != expected {
+            return Err(ConditionError::UnexpectedToken(format!("{:?}", token)));
+        }
+
+        Ok(())
+    }
+
+    fn descend(&mut self) -> Result<(), ConditionError> {
+        self.depth += 1;
+
+        if self.depth > MAX_DEPTH {
+            return Err(ConditionError::TooDeep(MAX_DEPTH));
+        }
+
+        Ok(())
+    }
+
+    fn or(&mut self) -> Result<Expr, ConditionError> {
+        let mut left = self.and()?;
+
+        while self.peek() == Some(&Token::Or) {
+            self.position += 1;
+            left = Expr::Or(Box::new(left), Box::new(self.and()?));
+        }
+
+        Ok(left)
+    }
+
+    fn and(&mut self) -> Result<Expr, ConditionError> {
+        let mut left = self.not()?;
+
+        while self.peek() == Some(&Token::And) {
+            self.position += 1;
+            left = Expr::And(Box::new(left), Box::new(self.not()?));
+        }
+
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Expr, ConditionError> {
+        if self.peek() == Some(&Token::Not) {
+            self.position += 1;
+            self.descend()?;
+
+            let expr = self.not()?;
+            self.depth -= 1;
+
+            return Ok(Expr::Not(Box::new(expr)));
+        }
+
+        self.compare()
+    }
+
+    fn compare(&mut self) -> Result<Expr, ConditionError> {
+        let left = self.value()?;
+
+        if let Some(Token::Op(op)) = self.peek().cloned() {
+            self.position += 1;
+            let right = self.value()?;
+
+            return Ok(Expr::Compare(op, Box::new(left), Box::new(right)));
+        }
+
+        Ok(left)
+    }
+
+    fn value(&mut self) -> Result<Expr, ConditionError> {
+        match self.next()? {
+            Token::Str(value) => Ok(Expr::Literal(JsonValue::String(value))),
+            Token::Num(value) => Ok(Expr::Literal(serde_json::json!(value))),
+            Token::LParen => {
+                self.descend()?;
+
+                let expr = self.or()?;
+                self.expect(Token::RParen)?;
+                self.depth -= 1;
+
+                Ok(expr)
+            },
+            Token::LBracket => {
+                let mut items = vec![];
+
+                if self.peek() == Some(&Token::RBracket) {
+                    self.position += 1;
+                    return Ok(Expr::List(items));
+                }
+
+                self.descend()?;
+
+                loop {
+                    items.push(self.value()?);
+
+                    match self.next()? {
+                        Token::Comma => continue,
+                        Token::RBracket => break,
+                        token => return Err(ConditionError::UnexpectedToken(format!("{:?}", token))),
+                    }
+                }
+
+                self.depth -= 1;
+
+                Ok(Expr::List(items))
+            },
+            Token::Ident(ident) => match ident.as_str() {
+                "true" => Ok(Expr::Literal(JsonValue::Bool(true))),
+                "false" => Ok(Expr::Literal(JsonValue::Bool(false))),
+                "null" => Ok(Expr::Literal(JsonValue::Null)),
+                root if !ROOTS.contains(&root) => Err(ConditionError::UnknownRoot(ident.clone())),
+                _ => {
+                    let mut path = vec![ident];
+
+                    while self.peek() == Some(&Token::Dot) {
+                        self.position += 1;
+
+                        match self.next()? {
+                            Token::Ident(ident) => path.push(ident),
+                            token => return Err(ConditionError::UnexpectedToken(format!("{:?}", token))),
+                        }
+                    }
+
+                    Ok(Expr::Path(path))
+                },
+            },
+            token => Err(ConditionError::UnexpectedToken(format!("{:?}", token))),
+        }
+    }
+}
+
+fn evaluate(expr: &Expr, environment: &Environment) -> JsonValue {
+    match expr {
+        Expr::Literal(value) => value.clone(),
+        Expr::Path(path) => resolve(path, environment),
+        Expr::List(items) => JsonValue::Array(items.iter().map(|item| evaluate(item, environment)).collect()),
+        Expr::Not(expr) => JsonValue::Bool(!truthy(&evaluate(expr, environment))),
+        Expr::And(left, right) => JsonValue::Bool(
+            truthy(&evaluate(left, environment)) && truthy(&evaluate(right, environment))
+        ),
+        Expr::Or(left, right) => JsonValue::Bool(
+            truthy(&evaluate(left, environment)) || truthy(&evaluate(right, environment))
+        ),
+        Expr::Compare(op, left, right) => {
+            let left = evaluate(left, environment);
+            let right = evaluate(right, environment);
+
+            JsonValue::Bool(compare(*op, &left, &right))
+        },
+    }
+}
+
+fn resolve(path: &[String], environment: &Environment) -> JsonValue {
+    let (root, rest) = match path.split_first() {
+        Some(parts) => parts,
+        None => return JsonValue::Null,
+    };
+
+    let mut value = match root.as_str() {
+        "claims" => environment.claims,
+        "profile" => environment.profile,
+        "context" => environment.context,
+        "now" => return time_field(environment.now, rest),
+        _ => return JsonValue::Null,
+    };
+
+    for key in rest.iter() {
+        value = match value.get(key) {
+            Some(value) => value,
+            None => return JsonValue::Null,
+        };
+    }
+
+    value.clone()
+}
+
+fn time_field(now: NaiveDateTime, rest: &[String]) -> JsonValue {
+    let field = match rest {
+        [field] => field.as_str(),
+        _ => return JsonValue::Null,
+    };
+
+    match field {
+        "year" => now.year().into(),
+        "month" => now.month().into(),
+        "day" => now.day().into(),
+        "hour" => now.hour().into(),
+        "minute" => now.minute().into(),
+        "weekday" => now.weekday().number_from_monday().into(),
+        "timestamp" => now.timestamp().into(),
+        _ => JsonValue::Null,
+    }
+}
+
+fn truthy(value: &JsonValue) -> bool {
+    *value == JsonValue::Bool(true)
+}
+
+fn equal(left: &JsonValue, right: &JsonValue) -> bool {
+    match (left.as_f64(), right.as_f64()) {
+        (Some(left), Some(right)) => left == right,
+        _ => left == right,
+    }
+}
+
+fn compare(op: Op, left: &JsonValue, right: &JsonValue) -> bool {
+    let ordering = match (left, right) {
+        (JsonValue::Number(_), JsonValue::Number(_)) => left.as_f64().unwrap().partial_cmp(&right.as_f64().unwrap()),
+        (JsonValue::String(left), JsonValue::String(right)) => Some(left.cmp(right)),
+        _ => None,
+    };
+
+    match op {
+        Op::Eq => equal(left, right),
+        Op::Ne => !equal(left, right),
+        Op::Lt => ordering == Some(Ordering::Less),
+        Op::Le => ordering == Some(Ordering::Less) || ordering == Some(Ordering::Equal),
+        Op::Gt => ordering == Some(Ordering::Greater),
+        Op::Ge => ordering == Some(Ordering::Greater) || ordering == Some(Ordering::Equal),
+        Op::In => match right {
+            JsonValue::Array(items) => items.iter().any(|item| equal(left, item)),
+            JsonValue::String(haystack) => left.as_str().is_some_and(|needle| haystack.contains(needle)),
+            JsonValue::Object(map) => left.as_str().is_some_and(|key| map.contains_key(key)),
+            _ => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use serde_json::json;
+
+    #[test]
+    fn test_evaluate() {
+        let claims = json!({ "email": "a@b.c", "grants": [] });
+        let profile = json!({ "org_id": "org-1", "tags": ["billing"], "suspended": false });
+        let context = json!({ "org_id": "org-1", "method": "GET", "amount": 250 });
+
+        let environment = Environment {
+            claims: &claims,
+            profile: &profile,
+            context: &context,
+            // a Wednesday
+            now: NaiveDate::from_ymd(2022, 3, 2).and_hms(10, 30, 0),
+        };
+
+        let tests = vec![
+            ("context.org_id == profile.org_id", true),
+            ("context.org_id == 'org-2'", false),
+            ("now.hour >= 9 && now.hour < 17 && now.weekday <= 5", true),
+            ("now.hour >= 17 || now.hour < 9", false),
+            ("context.method in ['GET', \"HEAD\"]", true),
+            ("'billing' in profile.tags", true),
+            ("!profile.suspended", true),
+            ("!(context.amount > 100)", false),
+            ("context.amount <= 250.0 && context.amount != -1", true),
+            ("claims.email != null && profile.missing.deeper == null", true),
+            ("profile.missing", false),
+            ("context.amount > 'ten'", false),
+        ];
+
+        for (input, expected) in tests.into_iter() {
+            let condition = Condition::parse(input).unwrap();
+
+            assert_eq!(condition.evaluate(&environment), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Condition::parse("request.ip == '1'"), Err(ConditionError::UnknownRoot("request".to_string())));
+        assert_eq!(Condition::parse("context.a == 'b"), Err(ConditionError::UnterminatedString(13)));
+        assert_eq!(Condition::parse("context.a = 1"), Err(ConditionError::UnexpectedCharacter(10, '=')));
+        assert_eq!(Condition::parse("context.a =="), Err(ConditionError::UnexpectedEnd));
+        assert!(Condition::parse("(context.a == 1").is_err());
+        assert!(Condition::parse("context.a == 1 context.b").is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let nested = |depth: usize| format!("{}context.a{}", "(".repeat(depth), ")".repeat(depth));
+
+        assert!(Condition::parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(Condition::parse(&nested(MAX_DEPTH + 1)), Err(ConditionError::TooDeep(MAX_DEPTH)));
+        assert_eq!(Condition::parse(&format!("{}true", "!".repeat(MAX_DEPTH + 1))), Err(ConditionError::TooDeep(MAX_DEPTH)));
+        assert_eq!(Condition::parse(&format!("{}1{}", "[".repeat(MAX_DEPTH + 1), "]".repeat(MAX_DEPTH + 1))), Err(ConditionError::TooDeep(MAX_DEPTH)));
+
+        // siblings don't add up, only nesting does
+        let siblings = vec![nested(MAX_DEPTH); 8].join(" && ");
+        assert!(Condition::parse(&siblings).is_ok());
+
+        let long = vec!["context.a == 1"; MAX_LENGTH / 8].join(" || ");
+        assert_eq!(Condition::parse(&long), Err(ConditionError::TooLong(MAX_LENGTH)));
+        assert_eq!(Condition::parse(&"(".repeat(1_000_000)), Err(ConditionError::TooLong(MAX_LENGTH)));
+    }
+}
@@ -1,2 +1,4 @@
+pub mod condition;
 pub mod permission;
+pub mod permission_set;
 pub mod permission_service;
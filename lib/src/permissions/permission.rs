@@ -1,62 +1,369 @@
 use std::cmp;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use uuid::Uuid;
+
+use crate::result::{Error, Result};
+use super::permission_service::Permission as PermissionRow;
+
+const PARTS: [&str; 3] = ["realm", "resource", "action"];
+
+// marks a deny when grants are carried around as strings, like in a token
+pub const DENY_PREFIX: &str = "!";
+
+// the realm part of the permissions this service checks for itself, and the
+// name of the realm allowed to hand them out without limits
+pub const ADMIN_REALM: &str = "oxidauth";
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename = "VARCHAR")]
+#[sqlx(rename_all = "snake_case")]
+pub enum Effect {
+    #[default]
+    Allow,
+    Deny,
+}
+
+// parts are kept in their escaped form (`\:`, `\.` and `\\`) so that two
+// permissions are equal exactly when their strings are
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Permission {
+    pub realm: String,
+    pub resource: String,
+    pub action: String,
+}
 
-#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
-pub struct Permission<'a> {
-    pub realm: &'a str,
-    pub resource: &'a str,
-    pub action: &'a str,
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ParseError {
+    MissingParts(String),
+    TooManyParts(String),
+    EmptyPart(String, &'static str),
+    InvalidEscape(String),
 }
 
-impl Permission<'_> {
-    pub fn can(&self, permissions: &mut Vec<Permission>) -> bool {
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use ParseError::*;
+
+        match &self {
+            MissingParts(input) => write!(f, "a permission must have all three parts: '{}'", input),
+            TooManyParts(input) => write!(f, "a permission can only have three parts: '{}'", input),
+            EmptyPart(input, part) => write!(f, "the {} of '{}' can't be empty", part, input),
+            InvalidEscape(input) => write!(f, "only '\\:', '\\.' and '\\\\' can be escaped: '{}'", input),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl Permission {
+    pub fn new(realm: &str, resource: &str, action: &str) -> std::result::Result<Self, ParseError> {
+        let input = [realm, resource, action].join(":");
+
+        for (name, part) in PARTS.iter().zip([realm, resource, action].iter()) {
+            if !valid_escapes(part) {
+                return Err(ParseError::InvalidEscape(input));
+            }
+
+            if part.is_empty() {
+                return Err(ParseError::EmptyPart(input, name));
+            }
+
+            if split_unescaped(part, ':').len() > 1 {
+                return Err(ParseError::TooManyParts(input));
+            }
+        }
+
+        Ok(Permission {
+            realm: realm.to_string(),
+            resource: resource.to_string(),
+            action: action.to_string(),
+        })
+    }
+
+    pub fn can(&self, permissions: &mut [Permission]) -> bool {
         self.get_matching(permissions).is_some()
     }
 
-    pub fn get_matching<'a>(&self, permissions: &'a mut Vec<Permission>) -> Option<&Permission<'a>> {
+    // a matching deny wins over any allow
+    pub fn is_allowed(&self, allows: &mut [Permission], denies: &[Permission]) -> bool {
+        self.get_denying(denies).is_none() && self.can(allows)
+    }
+
+    pub fn get_denying<'a>(&self, denies: &'a [Permission]) -> Option<&'a Permission> {
+        denies.iter().find(|deny| self.matches(deny))
+    }
+
+    pub fn matches(&self, grant: &Permission) -> bool {
+        grant == self || (
+            compare(&self.realm, &grant.realm) &&
+            compare(&self.resource, &grant.resource) &&
+            compare(&self.action, &grant.action)
+        )
+    }
+
+    pub fn get_matching<'a>(&self, permissions: &'a mut [Permission]) -> Option<&'a Permission> {
         // we sort here so we can get the most permissive permission
         permissions.sort();
         // we reverse because rust sorts ASC (least -> greatest)
         permissions.reverse();
 
-        for permission in permissions.iter() {
-            if permission == self {
-                return Some(permission);
-            }
+        permissions.iter().find(|permission| self.matches(permission))
+    }
 
-            if 
-                compare(self.realm, permission.realm) &&
-                compare(self.resource, permission.resource) &&
-                compare(self.action, permission.action)
-            {
-                return Some(permission);
-            }
+    // true when every challenge this permission grants is also granted by
+    // `general`; stricter than compare so that condensing never widens or
+    // narrows what a token allows
+    pub fn covered_by(&self, general: &Permission) -> bool {
+        covers_part(&general.realm, &self.realm) &&
+        covers_part(&general.resource, &self.resource) &&
+        covers_part(&general.action, &self.action)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.realm.starts_with(DENY_PREFIX) {
+            return Err(Error::msg(format!("the realm '{}' can't start with '{}'", self.realm, DENY_PREFIX)));
         }
 
-        return None;
+        validate_part("realm", &self.realm)?;
+        validate_part("resource", &self.resource)?;
+        validate_part("action", &self.action)?;
+
+        Ok(())
     }
 
-    pub fn from_string<'a>(input: &'a str) -> Permission<'a> {
-        let parts: Vec<&'a str> = input.split(":").collect();
+    // false when this reaches the service's own permissions outside of
+    // `realms.<realm_id>.` or the caller's own `me.` resources
+    pub fn within_realm(&self, realm_id: Uuid) -> bool {
+        if !compare(ADMIN_REALM, &self.realm) {
+            return true;
+        }
 
-        if parts.len() < 3 {
-            panic!("a permission must have all three parts: '{}'", input);
+        covers_part(&format!("realms.{}.**", realm_id), &self.resource) || covers_part("me.**", &self.resource)
+    }
+
+    pub fn validate_within(&self, realm_id: Uuid) -> Result<()> {
+        if !self.within_realm(realm_id) {
+            return Err(Error::msg(format!("'{}' reaches beyond realm {}", self, realm_id)));
+        }
+
+        Ok(())
+    }
+}
+
+// a grant as written in a token or a seed file, `!` marking a deny
+pub fn parse_grant(input: &str) -> std::result::Result<(Effect, Permission), ParseError> {
+    if let Some(permission) = input.strip_prefix(DENY_PREFIX) {
+        return Ok((Effect::Deny, permission.parse()?));
+    }
+
+    Ok((Effect::Allow, input.parse()?))
+}
+
+pub fn grant_string(effect: Effect, permission: &Permission) -> String {
+    match effect {
+        Effect::Allow => permission.to_string(),
+        Effect::Deny => format!("{}{}", DENY_PREFIX, permission),
+    }
+}
+
+pub fn escape(input: &str) -> String {
+    let mut result = String::with_capacity(input.len());
+
+    for c in input.chars() {
+        if c == ':' || c == '.' || c == '\\' {
+            result.push('\\');
+        }
+
+        result.push(c);
+    }
+
+    result
+}
+
+pub fn unescape(input: &str) -> String {
+    let mut result = String::with_capacity(input.len());
+    let mut escaped = false;
+
+    for c in input.chars() {
+        if c == '\\' && !escaped {
+            escaped = true;
+            continue;
         }
 
-        for field in parts[0..3].iter() {
-            if field.len() == 0 {
-                panic!("a permission must have all three parts: '{}'", input);
+        escaped = false;
+        result.push(c);
+    }
+
+    result
+}
+
+fn valid_escapes(input: &str) -> bool {
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some(':') | Some('.') | Some('\\') => {},
+                _ => return false,
             }
         }
+    }
 
-        Permission {
-            realm: parts[0],
-            resource: parts[1],
-            action: parts[2],
+    true
+}
+
+// splits on every separator that isn't escaped; expects valid escapes
+pub fn split_unescaped(input: &str, separator: char) -> Vec<&str> {
+    let mut results = vec![];
+    let mut start = 0;
+    let mut escaped = false;
+
+    for (i, c) in input.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+
+        if c == '\\' {
+            escaped = true;
+            continue;
+        }
+
+        if c == separator {
+            results.push(&input[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+
+    results.push(&input[start..]);
+
+    results
+}
+
+// mirrors what compare understands: `*` matches a single segment and `**`
+// matches everything after it, so `**` is only meaningful as the last segment
+fn validate_part(name: &str, part: &str) -> Result<()> {
+    if part.is_empty() {
+        return Err(Error::msg(format!("the {} of a permission can't be empty", name)));
+    }
+
+    if !valid_escapes(part) {
+        return Err(Error::msg(format!("the {} '{}' has an invalid escape", name, part)));
+    }
+
+    let segments = split_unescaped(part, '.');
+
+    for (i, segment) in segments.iter().enumerate() {
+        if segment.is_empty() {
+            return Err(Error::msg(format!("the {} '{}' has an empty segment", name, part)));
+        }
+
+        if split_unescaped(segment, ':').len() > 1 || segment.chars().any(char::is_whitespace) {
+            return Err(Error::msg(format!("the {} '{}' contains an invalid character", name, part)));
+        }
+
+        if segment.contains("*") && *segment != "*" && *segment != "**" {
+            return Err(Error::msg(format!("the {} '{}' has a wildcard that isn't a whole segment", name, part)));
+        }
+
+        if *segment == "**" && i != segments.len() - 1 {
+            return Err(Error::msg(format!("the {} '{}' can only use '**' as its last segment", name, part)));
+        }
+    }
+
+    Ok(())
+}
+
+fn covers_part(general: &str, specific: &str) -> bool {
+    if general == specific || general == "**" {
+        return true;
+    }
+
+    let general_parts = split_unescaped(general, '.');
+    let specific_parts = split_unescaped(specific, '.');
+
+    for (i, part) in general_parts.iter().enumerate() {
+        if *part == "**" {
+            return true;
+        }
+
+        let other = match specific_parts.get(i) {
+            Some(other) => *other,
+            None => return false,
+        };
+
+        if other == "**" {
+            return false;
+        }
+
+        if *part != "*" && *part != other {
+            return false;
+        }
+    }
+
+    general_parts.len() == specific_parts.len()
+}
+
+// drops duplicates and any grant already covered by a broader one of the
+// same effect, keeping the most permissive grants first; grants that don't
+// parse are left as is
+pub fn condense(grants: &[String]) -> Vec<String> {
+    let mut allows: Vec<Permission> = vec![];
+    let mut denies: Vec<Permission> = vec![];
+    let mut unparsed: Vec<String> = vec![];
+
+    for grant in grants.iter() {
+        match parse_grant(grant) {
+            Ok((Effect::Allow, permission)) if !allows.contains(&permission) => allows.push(permission),
+            Ok((Effect::Deny, permission)) if !denies.contains(&permission) => denies.push(permission),
+            Ok(_) => {},
+            Err(_) if !unparsed.contains(grant) => unparsed.push(grant.clone()),
+            Err(_) => {},
+        }
+    }
+
+    let mut results: Vec<String> = vec![];
+
+    for (effect, permissions) in vec![(Effect::Allow, allows), (Effect::Deny, denies)].into_iter() {
+        for permission in condense_permissions(permissions).iter() {
+            results.push(grant_string(effect, permission));
+        }
+    }
+
+    results.append(&mut unparsed);
+
+    results
+}
+
+fn condense_permissions(mut permissions: Vec<Permission>) -> Vec<Permission> {
+    permissions.sort();
+    permissions.reverse();
+
+    let mut results: Vec<Permission> = vec![];
+
+    for (i, permission) in permissions.iter().enumerate() {
+        let redundant = permissions.iter().enumerate().any(|(j, other)| {
+            i != j &&
+            permission.covered_by(other) &&
+            // when two grants cover each other only the first one is kept
+            (!other.covered_by(permission) || j < i)
+        });
+
+        if !redundant {
+            results.push(permission.clone());
         }
     }
+
+    results
 }
 
-fn compare(test: &str, challenge: &str) -> bool {
+pub fn compare(test: &str, challenge: &str) -> bool {
     if test == challenge {
         return true;
     }
@@ -65,10 +372,10 @@ fn compare(test: &str, challenge: &str) -> bool {
         return true;
     }
 
-    let test_parts: Vec<&str> = test.split(".").collect();
-    let challenge_parts: Vec<&str> = challenge.split(".").collect();
+    let test_parts = split_unescaped(test, '.');
+    let challenge_parts = split_unescaped(challenge, '.');
 
-    if challenge_parts.len() == 0 {
+    if challenge_parts.is_empty() {
         return false;
     }
 
@@ -96,40 +403,40 @@ fn compare(test: &str, challenge: &str) -> bool {
         }
     }
 
-    return true;
+    true
 }
 
-impl PartialOrd for Permission<'_> {
+impl PartialOrd for Permission {
     fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
         Some(self.cmp(other))
     }
 }
 
-impl Ord for Permission<'_> {
+impl Ord for Permission {
     fn cmp(&self, other: &Permission) -> cmp::Ordering {
         if self == other {
             return cmp::Ordering::Equal;
         }
 
-        match sort_part(self.realm, other.realm) {
+        match sort_part(&self.realm, &other.realm) {
             None => {},
             Some(cmp::Ordering::Equal) => {},
             Some(result) => return result,
         }
 
-        match sort_part(self.resource, other.resource) {
+        match sort_part(&self.resource, &other.resource) {
             None => {},
             Some(cmp::Ordering::Equal) => {},
             Some(result) => return result,
         }
 
-        match sort_part(self.action, other.action) {
+        match sort_part(&self.action, &other.action) {
             None => {},
             Some(cmp::Ordering::Equal) => {},
             Some(result) => return result,
         }
 
-        return cmp::Ordering::Equal;
+        cmp::Ordering::Equal
     }
 }
 
@@ -154,34 +461,86 @@ fn sort_part(left: &str, right: &str) -> Option<cmp::Ordering> {
         return Some(cmp::Ordering::Less);
     }
 
-    return None
+    None
 }
 
-impl Into<String> for Permission<'_> {
-    fn into(self) -> String {
-        [
-            self.realm.clone(),
-            self.resource.clone(),
-            self.action.clone(),
-        ].join(":")
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.realm, self.resource, self.action)
     }
 }
 
-impl<'a> From<&'a str> for Permission<'a> {
-    fn from(input: &'a str) -> Self {
-        Permission::from_string(input)
+impl From<Permission> for String {
+    fn from(from: Permission) -> String {
+        from.to_string()
     }
 }
 
-impl<'a> From<&&'a str> for Permission<'a> {
-    fn from(input: &&'a str) -> Self {
-        Permission::from_string(input)
+impl FromStr for Permission {
+    type Err = ParseError;
+
+    fn from_str(input: &str) -> std::result::Result<Self, Self::Err> {
+        if !valid_escapes(input) {
+            return Err(ParseError::InvalidEscape(input.to_string()));
+        }
+
+        let parts = split_unescaped(input, ':');
+
+        if parts.len() < 3 {
+            return Err(ParseError::MissingParts(input.to_string()));
+        }
+
+        if parts.len() > 3 {
+            return Err(ParseError::TooManyParts(input.to_string()));
+        }
+
+        Permission::new(parts[0], parts[1], parts[2])
+    }
+}
+
+impl TryFrom<&str> for Permission {
+    type Error = ParseError;
+
+    fn try_from(input: &str) -> std::result::Result<Self, Self::Error> {
+        input.parse()
+    }
+}
+
+impl TryFrom<&String> for Permission {
+    type Error = ParseError;
+
+    fn try_from(input: &String) -> std::result::Result<Self, Self::Error> {
+        input.parse()
     }
 }
 
-impl<'a> From<&'a String> for Permission<'a> {
-    fn from(input: &'a String) -> Self {
-        Permission::from_string(input)
+impl TryFrom<String> for Permission {
+    type Error = ParseError;
+
+    fn try_from(input: String) -> std::result::Result<Self, Self::Error> {
+        input.parse()
+    }
+}
+
+impl TryFrom<&PermissionRow> for Permission {
+    type Error = ParseError;
+
+    fn try_from(row: &PermissionRow) -> std::result::Result<Self, Self::Error> {
+        Permission::new(&row.realm, &row.resource, &row.action)
+    }
+}
+
+impl Serialize for Permission {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Permission {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let input = String::deserialize(deserializer)?;
+
+        input.parse().map_err(de::Error::custom)
     }
 }
 
@@ -189,52 +548,60 @@ impl<'a> From<&'a String> for Permission<'a> {
 mod tests {
     use super::*;
 
+    fn parse(input: &str) -> Permission {
+        input.parse().unwrap()
+    }
+
+    fn raw(realm: &str, resource: &str, action: &str) -> Permission {
+        Permission {
+            realm: realm.to_string(),
+            resource: resource.to_string(),
+            action: action.to_string(),
+        }
+    }
+
     #[test]
     fn identity() {
-        let _permission = Permission {
-            realm: "realm",
-            resource: "resource",
-            action: "action",
-        };
+        let _permission = raw("realm", "resource", "action");
     }
 
     #[test]
     fn test_sorting() {
-        struct Test<'a> {
-            left: Permission<'a>,
-            right: Permission<'a>,
+        struct Test {
+            left: Permission,
+            right: Permission,
             output: cmp::Ordering,
         }
 
         let tests = vec![
             Test {
-                left: "**:**:**".into(),
-                right: "**:**:**".into(),
+                left: parse("**:**:**"),
+                right: parse("**:**:**"),
                 output: cmp::Ordering::Equal,
             },
             Test {
-                left: "thing1:thing2:thing3".into(),
-                right: "**:**:**".into(),
+                left: parse("thing1:thing2:thing3"),
+                right: parse("**:**:**"),
                 output: cmp::Ordering::Less,
             },
             Test {
-                left: "**:**:**".into(),
-                right: "thing1:thing2:thing3".into(),
+                left: parse("**:**:**"),
+                right: parse("thing1:thing2:thing3"),
                 output: cmp::Ordering::Greater,
             },
             Test {
-                left: "aa:bb:cc".into(),
-                right: "cc:bb:aa".into(),
+                left: parse("aa:bb:cc"),
+                right: parse("cc:bb:aa"),
                 output: cmp::Ordering::Equal,
             },
             Test {
-                left: "aa:bb:**".into(),
-                right: "cc:bb:**".into(),
+                left: parse("aa:bb:**"),
+                right: parse("cc:bb:**"),
                 output: cmp::Ordering::Equal,
             },
             Test {
-                left: "aa:bb:**".into(),
-                right: "cc:bb:*".into(),
+                left: parse("aa:bb:**"),
+                right: parse("cc:bb:*"),
                 output: cmp::Ordering::Greater,
             },
         ];
@@ -289,20 +656,11 @@ mod tests {
             },
         ];
 
-        let greater = Permission {
-            realm: "realm",
-            resource: "resouce",
-            action: "**",
-        };
+        let greater = raw("realm", "resouce", "**");
 
-        let lesser = Permission {
-            realm: "realm",
-            resource: "resouce",
-            action: "*",
-        };
+        let lesser = raw("realm", "resouce", "*");
 
         assert!(greater > lesser);
-        assert_eq!(true, greater > lesser);
 
         let mut list = vec![lesser.clone(), greater.clone()];
         list.sort();
@@ -310,7 +668,7 @@ mod tests {
         assert_eq!(list, vec![greater, lesser]);
 
         for Test { input, output } in tests {
-            let mut input: Vec<Permission> = input.into_iter().map(|string| string.into()).collect();
+            let mut input: Vec<Permission> = input.into_iter().map(parse).collect();
             input.sort();
             let input: Vec<String> = input.into_iter().map(|permission| permission.into()).collect();
 
@@ -323,7 +681,7 @@ mod tests {
         struct Test<'a> {
             permission: &'a str,
             permissions: Vec<&'a str>,
-            output: Option<Permission<'a>>,
+            output: Option<Permission>,
         }
 
         let tests = vec![
@@ -340,51 +698,51 @@ mod tests {
             Test {
                 permission: "realm:resource:action",
                 permissions: vec!["realm:resource:action"],
-                output: Some("realm:resource:action".into()),
+                output: Some(parse("realm:resource:action")),
             },
             Test {
                 permission: "realm:resource:action",
                 permissions: vec!["realm:resource:action", "realm:resource:other"],
-                output: Some("realm:resource:action".into()),
+                output: Some(parse("realm:resource:action")),
             },
             Test {
                 permission: "realm:resource:action",
                 permissions: vec!["realm:resource:action", "realm:resource:other"],
-                output: Some("realm:resource:action".into()),
+                output: Some(parse("realm:resource:action")),
             },
             Test {
                 permission: "realm:resource:action",
                 permissions: vec!["realm:resource:**", "realm:resource:other"],
-                output: Some("realm:resource:**".into()),
+                output: Some(parse("realm:resource:**")),
             },
             Test {
                 permission: "realm:resource.1:action",
                 permissions: vec!["realm:resource:other", "realm:resource.*:**"],
-                output: Some("realm:resource.*:**".into()),
+                output: Some(parse("realm:resource.*:**")),
             },
             Test {
                 permission: "realm:resource.1:action",
                 permissions: vec!["realm:resource:other", "realm:resource.*:**"],
-                output: Some("realm:resource.*:**".into()),
+                output: Some(parse("realm:resource.*:**")),
             },
             Test {
                 permission: "realm:resource.1:action",
                 permissions: vec!["**:**:**", "realm:resource.2:**"],
-                output: Some("**:**:**".into()),
+                output: Some(parse("**:**:**")),
             },
             Test {
                 permission: "realm:resource.1:action",
                 permissions: vec!["realm:resource.2:**", "**:**:**"],
-                output: Some("**:**:**".into()),
+                output: Some(parse("**:**:**")),
             },
         ];
 
         for Test { permission, permissions, output } in tests.into_iter() {
-            let permission: Permission = permission.into();
+            let permission = parse(permission);
 
             let mut permissions: Vec<Permission> = permissions
                 .into_iter()
-                .map(|string| string.into())
+                .map(parse)
                 .collect();
 
             // test can
@@ -402,7 +760,7 @@ mod tests {
                 (Some(output), Some(result)) => assert_eq!(&output, result),
                 (None, Some(result)) => assert_eq!(None, Some(result)),
                 (Some(output), None) => assert_eq!(Some(output), None),
-                (None, None) => assert!(true),
+                (None, None) => {}
             }
         }
     }
@@ -411,70 +769,214 @@ mod tests {
     fn from_str_and_string() {
         struct Test<'a> {
             input: &'a str,
-            result: Permission<'a>,
+            result: Permission,
         }
 
         let tests = vec![
             Test {
                 input: "realm:resource:action",
-                result: Permission { realm: "realm", resource: "resource", action: "action" },
+                result: raw("realm", "resource", "action"),
+            },
+            Test {
+                input: r"realm:host\:8080.users:read",
+                result: raw("realm", r"host\:8080.users", "read"),
             },
         ];
 
         for Test { input, result } in tests.into_iter() {
-            let permission: Permission = input.into();
+            let permission: Permission = input.parse().unwrap();
 
-            assert_eq!(result.realm, permission.realm);
-            assert_eq!(result.resource, permission.resource);
-            assert_eq!(result.action, permission.action);
+            assert_eq!(result, permission);
+            assert_eq!(input, permission.to_string());
 
             let string_intput: String = input.into();
-            let permission: Permission = (&string_intput).into();
+            let permission = Permission::try_from(&string_intput).unwrap();
+
+            assert_eq!(result, permission);
 
-            assert_eq!(result.realm, permission.realm);
-            assert_eq!(result.resource, permission.resource);
-            assert_eq!(result.action, permission.action);
+            let json = serde_json::to_string(&permission).unwrap();
+            let permission: Permission = serde_json::from_str(&json).unwrap();
+
+            assert_eq!(result, permission);
         }
     }
 
     #[test]
-    #[should_panic]
-    fn from_str_panic() {
+    fn from_str_errors() {
         struct Test<'a> {
             input: &'a str,
+            error: ParseError,
         }
 
         let tests = vec![
+            Test {
+                input: "realm:resource",
+                error: ParseError::MissingParts("realm:resource".to_string()),
+            },
+            Test {
+                input: "",
+                error: ParseError::MissingParts("".to_string()),
+            },
             Test {
                 input: "realm::",
+                error: ParseError::EmptyPart("realm::".to_string(), "resource"),
             },
             Test {
                 input: "::",
+                error: ParseError::EmptyPart("::".to_string(), "realm"),
             },
             Test {
-                input: "",
+                input: "realm:resource:action:extra",
+                error: ParseError::TooManyParts("realm:resource:action:extra".to_string()),
+            },
+            Test {
+                input: r"realm:resource:act\ion",
+                error: ParseError::InvalidEscape(r"realm:resource:act\ion".to_string()),
+            },
+            Test {
+                input: "realm:resource:action\\",
+                error: ParseError::InvalidEscape("realm:resource:action\\".to_string()),
             },
         ];
 
-        for Test { input } in tests.into_iter() {
-            let _permission: Permission = input.into();
+        for Test { input, error } in tests.into_iter() {
+            assert_eq!(input.parse::<Permission>(), Err(error));
         }
+
+        assert!(serde_json::from_str::<Permission>(r#""a:b""#).is_err());
+    }
+
+    #[test]
+    fn test_escapes() {
+        assert_eq!(escape("host:8080.local\\"), r"host\:8080\.local\\");
+        assert_eq!(unescape(r"host\:8080\.local\\"), "host:8080.local\\");
+        assert_eq!(split_unescaped(r"a\.b.c\\.d", '.'), vec![r"a\.b", r"c\\", "d"]);
+
+        // an escaped dot is part of the segment, not a separator
+        assert!(compare(r"a\.b", r"a\.b"));
+        assert!(!compare(r"a\.b", "a.*"));
+        assert!(compare("a.b", "a.*"));
+
+        let permission = Permission::new("realm", &escape("host:8080"), "read").unwrap();
+        assert_eq!(permission.to_string(), r"realm:host\:8080:read");
+        assert!(permission.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate() {
+        let valid = vec![
+            "realm:resource:action",
+            "realm:resource.*:action",
+            "realm:resource.**:**",
+            "**:**:**",
+            "realm:resource.1.sub:*",
+        ];
+
+        for input in valid.into_iter() {
+            let permission = parse(input);
+
+            assert!(permission.validate().is_ok(), "expected '{}' to be valid", input);
+        }
+
+        let invalid = vec![
+            raw("", "resource", "action"),
+            raw("realm", "resource..1", "action"),
+            raw("realm", "resource.", "action"),
+            raw("realm", "**.resource", "action"),
+            raw("realm", "resource.a*", "action"),
+            raw("realm", "resource", "act ion"),
+            raw("realm", "***", "action"),
+            raw("!realm", "resource", "action"),
+        ];
+
+        for permission in invalid.into_iter() {
+            assert!(permission.validate().is_err(), "expected {:?} to be invalid", permission);
+        }
+    }
+
+    #[test]
+    fn test_within_realm() {
+        let realm_id = Uuid::new_v4();
+        let own = |rest: &str| parse(&format!("oxidauth:realms.{}.{}", realm_id, rest));
+
+        assert!(own("users:read").within_realm(realm_id));
+        assert!(own("**:**").within_realm(realm_id));
+        assert!(parse("oxidauth:me.**:**").within_realm(realm_id));
+        assert!(parse("app:**:**").within_realm(realm_id));
+
+        assert!(!own("users:read").within_realm(Uuid::new_v4()));
+        assert!(!parse("oxidauth:**:**").within_realm(realm_id));
+        assert!(!parse("oxidauth:realms.*.users:read").within_realm(realm_id));
+        assert!(!parse("oxidauth:users:read").within_realm(realm_id));
+        assert!(!parse("**:**:**").within_realm(realm_id));
+        assert!(!parse("*:realms.**:*").within_realm(realm_id));
+
+        assert!(parse("oxidauth:**:**").validate_within(realm_id).is_err());
+    }
+
+    #[test]
+    fn test_denies() {
+        let mut allows = vec![parse("app:**:**")];
+        let denies = vec![parse("app:billing.**:delete")];
+
+        assert!(parse("app:billing.1:read").is_allowed(&mut allows, &denies));
+        assert!(!parse("app:billing.1:delete").is_allowed(&mut allows, &denies));
+        assert!(!parse("other:users:read").is_allowed(&mut allows, &denies));
+
+        assert_eq!(parse_grant("!app:billing.**:delete"), Ok((Effect::Deny, parse("app:billing.**:delete"))));
+        assert_eq!(parse_grant("app:**:**"), Ok((Effect::Allow, parse("app:**:**"))));
+        assert_eq!(grant_string(Effect::Deny, &parse("app:billing.**:delete")), "!app:billing.**:delete");
+    }
+
+    #[test]
+    fn test_condense() {
+        let grants: Vec<String> = vec![
+            "app:users:read",
+            "app:users:*",
+            "app:users:read",
+            "app:invoices.1:read",
+            "app:invoices.*:read",
+            "app:invoices:read",
+            "app:reports.**:*",
+            "app:reports.1:write",
+            "app:reports:*",
+            "app:settings:*",
+            "app:settings:**",
+            "other:users:read",
+            "!app:billing.**:delete",
+            "!app:billing.1:delete",
+            "app:billing.1:delete",
+        ].into_iter().map(String::from).collect();
+
+        let mut result = condense(&grants);
+        result.sort();
+
+        assert_eq!(result, vec![
+            "!app:billing.**:delete",
+            "app:billing.1:delete",
+            "app:invoices.*:read",
+            "app:invoices:read",
+            "app:reports.**:*",
+            "app:settings:**",
+            "app:users:*",
+            "other:users:read",
+        ]);
     }
 
     #[test]
     fn into_string() {
-        struct Test<'a> {
-            permission: Permission<'a>,
+        struct Test {
+            permission: Permission,
             result: String,
         }
 
         let tests = vec![
             Test {
-                permission: Permission { realm: "realm", resource: "resource", action: "action" },
+                permission: raw("realm", "resource", "action"),
                 result: "realm:resource:action".into(),
             },
             Test {
-                permission: Permission { realm: "", resource: "", action: "" },
+                permission: raw("", "", ""),
                 result: "::".into(),
             },
         ];
@@ -1,10 +1,31 @@
 use chrono::NaiveDateTime;
+use sqlx::{Postgres, Transaction};
 use uuid::Uuid;
 
 use super::permission::Permission as PermissionRaw;
+use crate::audit::Actor;
+use crate::db::page::{ListQuery, Page, PageParams, SortKey};
+use crate::db::patch::{Patch, Precondition, Versioned};
 use crate::db::pg::Pool;
 use crate::result::Result;
 
+const PERMISSION_SORTS: &[SortKey] = &[
+    SortKey::new("created_at", "timestamp"),
+    SortKey::new("realm", "varchar"),
+    SortKey::new("resource", "varchar"),
+    SortKey::new("action", "varchar"),
+];
+
+// `realm` here is the first part of the permission string, not the realm
+// it belongs to
+#[derive(Debug, Default, Deserialize)]
+pub struct PermissionFilter {
+    pub realm_id: Option<Uuid>,
+    pub realm: Option<String>,
+    pub resource: Option<String>,
+    pub action: Option<String>,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Permission {
     pub id: Uuid,
@@ -16,6 +37,12 @@ pub struct Permission {
     pub updated_at: Option<NaiveDateTime>,
 }
 
+impl Versioned for Permission {
+    fn version(&self) -> Option<NaiveDateTime> {
+        self.updated_at
+    }
+}
+
 impl std::fmt::Display for Permission {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}:{}:{}", self.realm, self.resource, self.action)
@@ -30,13 +57,15 @@ pub struct PermissionCreate {
     pub realm_id: Uuid,
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct PermissionUpdate {
-    pub realm: String,
-    pub resource: String,
-    pub action: String,
+    pub realm: Patch<String>,
+    pub resource: Patch<String>,
+    pub action: Patch<String>,
 }
 
+#[derive(Clone)]
 pub struct PermissionService {
     pool: Pool,
 }
@@ -50,14 +79,14 @@ impl PermissionService {
         Ok(service)
     }
 
-    pub async fn all(&self) -> Result<Vec<Permission>> {
-        let permissions = sqlx::query_as::<_, Permission>(r#"
-            SELECT * FROM permissions
-        "#)
-            .fetch_all(&self.pool)
-            .await?;
-
-        Ok(permissions)
+    pub async fn list(&self, filter: &PermissionFilter, page: &PageParams) -> Result<Page<Permission>> {
+        ListQuery::new("permissions", PERMISSION_SORTS)
+            .filter("realm_id = ?", filter.realm_id)
+            .filter("realm = ?", filter.realm.as_ref())
+            .filter("resource = ?", filter.resource.as_ref())
+            .filter("action = ?", filter.action.as_ref())
+            .fetch(&self.pool, page)
+            .await
     }
 
     pub async fn by_id(&self, id: Uuid) -> Result<Permission> {
@@ -72,7 +101,20 @@ impl PermissionService {
         Ok(result)
     }
 
-    pub async fn create(&self, permission: PermissionCreate) -> Result<Permission> {
+    pub async fn create(&self, actor: &Actor, permission: PermissionCreate) -> Result<Permission> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = Self::create_in(&mut tx, actor, permission).await?;
+
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    pub async fn create_in(tx: &mut Transaction<'_, Postgres>, actor: &Actor, permission: PermissionCreate) -> Result<Permission> {
+        PermissionRaw::new(&permission.realm, &permission.resource, &permission.action)?
+            .validate()?;
+
         let result = sqlx::query_as::<_, Permission>(r#"
             INSERT INTO permissions (
                 realm, resource, action,
@@ -84,13 +126,114 @@ impl PermissionService {
             .bind(permission.resource)
             .bind(permission.action)
             .bind(permission.realm_id)
-            .fetch_one(&self.pool)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        actor.event("permission.created", "permissions", Some(result.id))
+            .realm(result.realm_id)
+            .after(&result)
+            .record(&mut *tx)
+            .await?;
+
+        Ok(result)
+    }
+
+    pub async fn update(&self, actor: &Actor, id: Uuid, permission: PermissionUpdate, precondition: &Precondition) -> Result<Permission> {
+        let realm = permission.realm.required("realm")?;
+        let resource = permission.resource.required("resource")?;
+        let action = permission.action.required("action")?;
+
+        let mut tx = self.pool.begin().await?;
+
+        let before = sqlx::query_as::<_, Permission>(r#"
+            SELECT * FROM permissions
+            WHERE id = $1
+            FOR UPDATE
+        "#)
+            .bind(id)
+            .fetch_one(&mut tx)
             .await?;
 
+        precondition.check(&before)?;
+
+        // the parts that aren't changing still have to make a valid whole
+        PermissionRaw::new(
+            realm.as_ref().unwrap_or(&before.realm),
+            resource.as_ref().unwrap_or(&before.resource),
+            action.as_ref().unwrap_or(&before.action),
+        )?
+            .validate()?;
+
+        let result = sqlx::query_as::<_, Permission>(r#"
+            UPDATE permissions
+            SET
+                realm = COALESCE($2, realm),
+                resource = COALESCE($3, resource),
+                action = COALESCE($4, action)
+            WHERE id = $1
+            RETURNING *;
+        "#)
+            .bind(id)
+            .bind(realm)
+            .bind(resource)
+            .bind(action)
+            .fetch_one(&mut tx)
+            .await?;
+
+        actor.event("permission.updated", "permissions", Some(id))
+            .realm(result.realm_id)
+            .before(&before)
+            .after(&result)
+            .record(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
         Ok(result)
     }
 
-    pub async fn delete(&self, id: Uuid) -> Result<()> {
-        todo!()
+    pub async fn delete(&self, actor: &Actor, id: Uuid) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        Self::delete_in(&mut tx, actor, id).await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_in(tx: &mut Transaction<'_, Postgres>, actor: &Actor, id: Uuid) -> Result<()> {
+        sqlx::query(r#"
+            DELETE FROM user_permission_grants
+            WHERE permission_id = $1
+        "#)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query(r#"
+            DELETE FROM role_permission_grants
+            WHERE permission_id = $1
+        "#)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+
+        let deleted = sqlx::query_as::<_, Permission>(r#"
+            DELETE FROM permissions
+            WHERE id = $1
+            RETURNING *;
+        "#)
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        actor.event("permission.deleted", "permissions", Some(id))
+            .realm(deleted.realm_id)
+            .before(&deleted)
+            .record(&mut *tx)
+            .await?;
+
+        Ok(())
     }
 }
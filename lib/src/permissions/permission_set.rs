@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+use std::iter::FromIterator;
+
+use super::permission::{Effect, Permission};
+
+// the last part's separator leads to a node that only holds a grant
+const LAST_PART: usize = 2;
+
+// Grants compiled into tries of segments, one level per segment and a
+// separator edge between the realm, resource and action parts.
+//
+// Matching follows `compare` exactly, including a grant with more segments
+// than the challenge still matching it, and returns the grant the linear
+// `get_matching` would have returned. A matching deny wins over any allow.
+#[derive(Debug, Clone)]
+pub struct PermissionSet {
+    allows: Trie,
+    denies: Trie,
+}
+
+#[derive(Debug, Clone)]
+struct Trie {
+    grants: Vec<Permission>,
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Node {
+    children: HashMap<String, usize>,
+    star: Option<usize>,
+    globstar: Option<usize>,
+    separator: Option<usize>,
+    // the lowest index of the grants ending here (only set after the last part)
+    grant: Option<usize>,
+    // every separator reachable below this node without leaving the part,
+    // which is where a challenge that runs out of segments continues from
+    separators: Vec<usize>,
+}
+
+impl PermissionSet {
+    pub fn new(allows: Vec<Permission>) -> Self {
+        Self::with_denies(allows, vec![])
+    }
+
+    pub fn with_denies(allows: Vec<Permission>, denies: Vec<Permission>) -> Self {
+        Self {
+            allows: Trie::new(allows),
+            denies: Trie::new(denies),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.allows.grants.len() + self.denies.grants.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn allows(&self) -> std::slice::Iter<'_, Permission> {
+        self.allows.grants.iter()
+    }
+
+    pub fn denies(&self) -> std::slice::Iter<'_, Permission> {
+        self.denies.grants.iter()
+    }
+
+    pub fn can(&self, challenge: &Permission) -> bool {
+        self.matching(challenge).is_some()
+    }
+
+    // the allow that grants the challenge, unless a deny takes it away
+    pub fn matching(&self, challenge: &Permission) -> Option<&Permission> {
+        if self.denying(challenge).is_some() {
+            return None;
+        }
+
+        self.allows.matching(challenge)
+    }
+
+    pub fn denying(&self, challenge: &Permission) -> Option<&Permission> {
+        self.denies.matching(challenge)
+    }
+}
+
+impl Trie {
+    fn new(mut grants: Vec<Permission>) -> Self {
+        // the same order get_matching scans in, so the lowest index that
+        // matches is the grant it would have found first
+        grants.sort();
+        grants.reverse();
+
+        let mut set = Self {
+            grants: vec![],
+            nodes: vec![Node::default()],
+        };
+
+        for (index, grant) in grants.iter().enumerate() {
+            set.insert(index, grant);
+        }
+
+        set.collect_separators(0);
+        set.grants = grants;
+
+        set
+    }
+
+    fn matching(&self, challenge: &Permission) -> Option<&Permission> {
+        let parts = [
+            challenge.realm.as_str(),
+            challenge.resource.as_str(),
+            challenge.action.as_str(),
+        ];
+
+        let mut best = None;
+
+        self.search(0, &parts, 0, Some(parts[0]), &mut best);
+
+        best.map(|index| &self.grants[index])
+    }
+
+    fn insert(&mut self, index: usize, grant: &Permission) {
+        let parts = [&grant.realm, &grant.resource, &grant.action];
+        let mut node = 0;
+
+        for part in parts.iter() {
+            let mut rest = Some(part.as_str());
+
+            while let Some(remaining) = rest {
+                let (segment, tail) = next_segment(remaining);
+
+                node = match segment {
+                    "*" => self.star(node),
+                    "**" => self.globstar(node),
+                    _ => self.child(node, segment),
+                };
+
+                rest = tail;
+            }
+
+            node = self.separator(node);
+        }
+
+        let grant = &mut self.nodes[node].grant;
+
+        if grant.is_none() {
+            *grant = Some(index);
+        }
+    }
+
+    fn star(&mut self, node: usize) -> usize {
+        match self.nodes[node].star {
+            Some(star) => star,
+            None => {
+                let star = self.push();
+                self.nodes[node].star = Some(star);
+                star
+            },
+        }
+    }
+
+    fn globstar(&mut self, node: usize) -> usize {
+        match self.nodes[node].globstar {
+            Some(globstar) => globstar,
+            None => {
+                let globstar = self.push();
+                self.nodes[node].globstar = Some(globstar);
+                globstar
+            },
+        }
+    }
+
+    fn separator(&mut self, node: usize) -> usize {
+        match self.nodes[node].separator {
+            Some(separator) => separator,
+            None => {
+                let separator = self.push();
+                self.nodes[node].separator = Some(separator);
+                separator
+            },
+        }
+    }
+
+    fn child(&mut self, node: usize, segment: &str) -> usize {
+        if let Some(child) = self.nodes[node].children.get(segment) {
+            return *child;
+        }
+
+        let child = self.push();
+        self.nodes[node].children.insert(segment.to_string(), child);
+
+        child
+    }
+
+    fn push(&mut self) -> usize {
+        self.nodes.push(Node::default());
+        self.nodes.len() - 1
+    }
+
+    // fills in `separators` for this node and everything below it and
+    // returns them so the parent can take them on as well
+    fn collect_separators(&mut self, node: usize) -> Vec<usize> {
+        let mut separators = vec![];
+
+        let node_ref = &self.nodes[node];
+        let children: Vec<usize> = node_ref.children
+            .values()
+            .cloned()
+            .chain(node_ref.star)
+            .chain(node_ref.globstar)
+            .collect();
+        let separator = node_ref.separator;
+
+        if let Some(separator) = separator {
+            separators.push(separator);
+            self.collect_separators(separator);
+        }
+
+        for child in children.into_iter() {
+            let mut below = self.collect_separators(child);
+            separators.append(&mut below);
+        }
+
+        self.nodes[node].separators = separators.clone();
+
+        separators
+    }
+
+    fn search(&self, node: usize, parts: &[&str; 3], part: usize, rest: Option<&str>, best: &mut Option<usize>) {
+        // nothing can beat the first grant
+        if *best == Some(0) {
+            return;
+        }
+
+        let node_ref = &self.nodes[node];
+
+        let remaining = match rest {
+            Some(remaining) => remaining,
+            None => {
+                // the challenge ran out of segments; any grant continuing
+                // from here matches this part
+                for separator in node_ref.separators.iter() {
+                    self.next_part(*separator, parts, part, best);
+                }
+
+                return;
+            },
+        };
+
+        let (segment, tail) = next_segment(remaining);
+
+        // `**` matches whatever is left of the part, and anything the grant
+        // has after it is ignored
+        if let Some(globstar) = node_ref.globstar {
+            for separator in self.nodes[globstar].separators.iter() {
+                self.next_part(*separator, parts, part, best);
+            }
+        }
+
+        if let Some(star) = node_ref.star {
+            self.search(star, parts, part, tail, best);
+        }
+
+        if let Some(child) = node_ref.children.get(segment) {
+            self.search(*child, parts, part, tail, best);
+        }
+    }
+
+    fn next_part(&self, separator: usize, parts: &[&str; 3], part: usize, best: &mut Option<usize>) {
+        if part == LAST_PART {
+            if let Some(index) = self.nodes[separator].grant {
+                if best.is_none_or(|best| index < best) {
+                    *best = Some(index);
+                }
+            }
+
+            return;
+        }
+
+        self.search(separator, parts, part + 1, Some(parts[part + 1]), best);
+    }
+}
+
+impl FromIterator<Permission> for PermissionSet {
+    fn from_iter<I: IntoIterator<Item = Permission>>(iter: I) -> Self {
+        PermissionSet::new(iter.into_iter().collect())
+    }
+}
+
+impl FromIterator<(Effect, Permission)> for PermissionSet {
+    fn from_iter<I: IntoIterator<Item = (Effect, Permission)>>(iter: I) -> Self {
+        let mut allows = vec![];
+        let mut denies = vec![];
+
+        for (effect, permission) in iter.into_iter() {
+            match effect {
+                Effect::Allow => allows.push(permission),
+                Effect::Deny => denies.push(permission),
+            }
+        }
+
+        PermissionSet::with_denies(allows, denies)
+    }
+}
+
+// the first segment of a part and what follows it, honoring escaped dots
+fn next_segment(input: &str) -> (&str, Option<&str>) {
+    let mut escaped = false;
+
+    for (i, c) in input.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+
+        if c == '\\' {
+            escaped = true;
+            continue;
+        }
+
+        if c == '.' {
+            return (&input[..i], Some(&input[i + 1..]));
+        }
+    }
+
+    (input, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn parse(input: &str) -> Permission {
+        input.parse().unwrap()
+    }
+
+    fn set(grants: &[&str]) -> PermissionSet {
+        grants.iter().map(|grant| parse(grant)).collect()
+    }
+
+    #[test]
+    fn test_matching() {
+        let grants = set(&[
+            "realm:resource:other",
+            "realm:resource.*:**",
+            "app:users.**:read",
+            "app:invoices.1.lines:write",
+            r"app:host\.name:read",
+        ]);
+
+        assert_eq!(grants.matching(&parse("realm:resource.1:action")), Some(&parse("realm:resource.*:**")));
+        assert_eq!(grants.matching(&parse("app:users.1.profile:read")), Some(&parse("app:users.**:read")));
+        assert_eq!(grants.matching(&parse("app:users:read")), Some(&parse("app:users.**:read")));
+        // a longer grant still matches a shorter challenge, as compare does
+        assert!(grants.can(&parse("app:invoices.1:write")));
+        assert!(grants.can(&parse(r"app:host\.name:read")));
+        assert!(!grants.can(&parse("app:host.name:read")));
+        assert!(!grants.can(&parse("realm:other:action")));
+        assert!(!grants.can(&parse("app:invoices.2:write")));
+
+        assert!(!PermissionSet::new(vec![]).can(&parse("realm:resource:action")));
+    }
+
+    #[test]
+    fn test_denies() {
+        let grants = PermissionSet::with_denies(
+            vec![parse("app:**:**")],
+            vec![parse("app:billing.**:delete")],
+        );
+
+        assert!(grants.can(&parse("app:billing.1:read")));
+        assert!(!grants.can(&parse("app:billing.1:delete")));
+        assert_eq!(grants.denying(&parse("app:billing.1:delete")), Some(&parse("app:billing.**:delete")));
+        assert_eq!(grants.matching(&parse("app:billing.1:delete")), None);
+    }
+
+    fn part() -> impl Strategy<Value = String> {
+        prop::collection::vec(prop::sample::select(vec!["a", "b", "*", "**"]), 1..4)
+            .prop_map(|segments| segments.join("."))
+    }
+
+    fn permission() -> impl Strategy<Value = Permission> {
+        (part(), part(), part()).prop_map(|(realm, resource, action)| Permission {
+            realm,
+            resource,
+            action,
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn matches_linear_scan(
+            grants in prop::collection::vec(permission(), 0..12),
+            challenge in permission(),
+        ) {
+            let set = PermissionSet::new(grants.clone());
+
+            let mut linear = grants.clone();
+            let expected = challenge.get_matching(&mut linear).cloned();
+
+            prop_assert_eq!(set.can(&challenge), expected.is_some());
+            prop_assert_eq!(set.matching(&challenge).cloned(), expected);
+        }
+
+        #[test]
+        fn denies_match_linear_scan(
+            allows in prop::collection::vec(permission(), 0..8),
+            denies in prop::collection::vec(permission(), 0..4),
+            challenge in permission(),
+        ) {
+            let set = PermissionSet::with_denies(allows.clone(), denies.clone());
+
+            let mut linear = allows.clone();
+
+            prop_assert_eq!(set.can(&challenge), challenge.is_allowed(&mut linear, &denies));
+            prop_assert_eq!(set.denying(&challenge).is_some(), challenge.get_denying(&denies).is_some());
+        }
+    }
+}
@@ -1,27 +1,70 @@
 use chrono::NaiveDateTime;
 use openssl::rsa::Rsa;
 use openssl::base64;
+use sqlx::{Postgres, Transaction};
 use uuid::Uuid;
 
+use crate::audit::Actor;
+use crate::db::page::{ListQuery, Page, PageParams, SortKey};
+use crate::db::patch::{Patch, Precondition, Versioned};
 use crate::db::pg::Pool;
-use crate::result::Result;
+use crate::result::{Error, Result};
+
+const REALM_DEPENDENTS: [&str; 12] = [
+    "authorities",
+    "user_authorities",
+    "domains",
+    "roles",
+    "permissions",
+    "user_permission_grants",
+    "user_role_grants",
+    "role_role_grants",
+    "role_permission_grants",
+    "relation_tuples",
+    "relation_rewrites",
+    "webhook_subscriptions",
+];
+
+pub const DEFAULT_MAX_ROLE_DEPTH: i32 = 16;
+
+const REALM_SORTS: &[SortKey] = &[
+    SortKey::new("created_at", "timestamp"),
+    SortKey::new("name", "varchar"),
+];
+
+// `name` matches on a prefix
+#[derive(Debug, Default, Deserialize)]
+pub struct RealmFilter {
+    pub name: Option<String>,
+}
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Realm {
     pub id: Uuid,
     pub name: String,
+    pub max_role_depth: i32,
     pub created_at: Option<NaiveDateTime>,
     pub updated_at: Option<NaiveDateTime>,
 }
 
+impl Versioned for Realm {
+    fn version(&self) -> Option<NaiveDateTime> {
+        self.updated_at
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct RealmCreate {
     pub name: String,
+    #[serde(default)]
+    pub max_role_depth: Option<i32>,
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct RealmUpdate {
-    pub name: String,
+    pub name: Patch<String>,
+    pub max_role_depth: Patch<i32>,
 }
 
 #[derive(Clone)]
@@ -38,14 +81,11 @@ impl RealmService {
         Ok(service)
     }
 
-    pub async fn all(&self) -> Result<Vec<Realm>> {
-        let realms = sqlx::query_as::<_, Realm>(r#"
-            SELECT * FROM realms
-        "#)
-            .fetch_all(&self.pool)
-            .await?;
-
-        Ok(realms)
+    pub async fn list(&self, filter: &RealmFilter, page: &PageParams) -> Result<Page<Realm>> {
+        ListQuery::new("realms", REALM_SORTS)
+            .prefix("name", filter.name.as_ref())
+            .fetch(&self.pool, page)
+            .await
     }
 
     pub async fn by_name(&self, name: String) -> Result<Realm> {
@@ -72,40 +112,156 @@ impl RealmService {
         Ok(result)
     }
 
-    pub async fn create(&self, realm: RealmCreate) -> Result<Realm> {
+    pub async fn create(&self, actor: &Actor, realm: RealmCreate) -> Result<Realm> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = Self::create_in(&mut tx, actor, realm).await?;
+        Self::create_key_pair_in(&mut tx, result.id).await?;
+
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    // without a key pair; the caller adds one with `create_key_pair_in`
+    // before committing
+    pub async fn create_in(tx: &mut Transaction<'_, Postgres>, actor: &Actor, realm: RealmCreate) -> Result<Realm> {
+        let max_role_depth = realm.max_role_depth.unwrap_or(DEFAULT_MAX_ROLE_DEPTH);
+
+        validate_max_role_depth(max_role_depth)?;
+
         let result = sqlx::query_as::<_, Realm>(r#"
-            INSERT INTO realms (name) VALUES ($1)
+            INSERT INTO realms (name, max_role_depth) VALUES ($1, $2)
             RETURNING *;
         "#)
             .bind(realm.name)
-            .fetch_one(&self.pool)
+            .bind(max_role_depth)
+            .fetch_one(&mut *tx)
             .await?;
 
-        self.create_key_pair(result.id).await?;
+        actor.event("realm.created", "realms", Some(result.id))
+            .realm(result.id)
+            .after(&result)
+            .record(&mut *tx)
+            .await?;
+
+        Ok(result)
+    }
+
+    pub async fn update(&self, actor: &Actor, id: Uuid, realm: RealmUpdate, precondition: &Precondition) -> Result<Realm> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = Self::update_in(&mut tx, actor, id, realm, precondition).await?;
+
+        tx.commit().await?;
 
         Ok(result)
     }
 
-    pub async fn update(&self, id: Uuid, realm: RealmUpdate) -> Result<Realm> {
+    pub async fn update_in(tx: &mut Transaction<'_, Postgres>, actor: &Actor, id: Uuid, realm: RealmUpdate, precondition: &Precondition) -> Result<Realm> {
+        let name = realm.name.required("name")?;
+        let max_role_depth = realm.max_role_depth.required("max_role_depth")?;
+
+        if let Some(max_role_depth) = max_role_depth {
+            validate_max_role_depth(max_role_depth)?;
+        }
+
+        let before = sqlx::query_as::<_, Realm>(r#"
+            SELECT * FROM realms
+            WHERE id = $1
+            FOR UPDATE
+        "#)
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        precondition.check(&before)?;
+
         let result = sqlx::query_as::<_, Realm>(r#"
-            UPDATE realms 
-            SET name = $2
+            UPDATE realms
+            SET
+                name = COALESCE($2, name),
+                max_role_depth = COALESCE($3, max_role_depth)
             WHERE id = $1
             RETURNING *;
         "#)
             .bind(id)
-            .bind(realm.name)
-            .fetch_one(&self.pool)
+            .bind(name)
+            .bind(max_role_depth)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        actor.event("realm.updated", "realms", Some(id))
+            .realm(id)
+            .before(&before)
+            .after(&result)
+            .record(&mut *tx)
             .await?;
 
         Ok(result)
     }
 
-    pub async fn delete(&self, id: Uuid) -> Result<()> {
-        todo!()
+    pub async fn delete(&self, actor: &Actor, id: Uuid) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        for table in REALM_DEPENDENTS.iter() {
+            let query = format!("SELECT EXISTS(SELECT 1 FROM {} WHERE realm_id = $1)", table);
+
+            let (exists,): (bool,) = sqlx::query_as(&query)
+                .bind(id)
+                .fetch_one(&mut tx)
+                .await?;
+
+            if exists {
+                return Err(Error::msg(format!("unable to delete realm: it still has {}", table)));
+            }
+        }
+
+        sqlx::query(r#"
+            DELETE FROM refresh_tokens
+            WHERE realm_id = $1
+        "#)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query(r#"
+            DELETE FROM sessions
+            WHERE realm_id = $1
+        "#)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query(r#"
+            DELETE FROM key_pairs
+            WHERE realm_id = $1
+        "#)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+
+        let deleted = sqlx::query_as::<_, Realm>(r#"
+            DELETE FROM realms
+            WHERE id = $1
+            RETURNING *;
+        "#)
+            .bind(id)
+            .fetch_one(&mut tx)
+            .await?;
+
+        actor.event("realm.deleted", "realms", Some(id))
+            .realm(id)
+            .before(&deleted)
+            .record(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(())
     }
 
-    pub async fn create_key_pair(&self, realm_id: Uuid) -> Result<KeyPair> {
+    pub async fn create_key_pair_in(tx: &mut Transaction<'_, Postgres>, realm_id: Uuid) -> Result<KeyPair> {
         let key_pair = KeyPair::new(realm_id)?;
 
         let results = sqlx::query_as::<_, KeyPair>(r#"
@@ -117,7 +273,7 @@ impl RealmService {
             .bind(realm_id)
             .bind(key_pair.public_key)
             .bind(key_pair.private_key)
-            .fetch_one(&self.pool)
+            .fetch_one(&mut *tx)
             .await?;
 
         Ok(results)
@@ -136,6 +292,14 @@ impl RealmService {
     }
 }
 
+fn validate_max_role_depth(max_role_depth: i32) -> Result<()> {
+    if max_role_depth < 1 {
+        return Err(Error::msg(format!("max_role_depth must be at least 1, got {}", max_role_depth)));
+    }
+
+    Ok(())
+}
+
 #[derive(Clone, Serialize, sqlx::FromRow)]
 pub struct KeyPair {
     pub id: Uuid,
@@ -182,6 +346,7 @@ impl From<KeyPair> for PublicKey {
 }
 
 impl KeyPair {
+    #[allow(clippy::new_ret_no_self)]
     pub fn new(realm_id: Uuid) -> Result<KeyPairCreate> {
         let rsa = Rsa::generate(4096)?;
 
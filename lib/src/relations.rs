@@ -0,0 +1,892 @@
+use chrono::NaiveDateTime;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use uuid::Uuid;
+
+use crate::audit::Actor;
+use crate::db::page::{ListQuery, Page, PageParams, SortKey};
+use crate::db::pg::Pool;
+use crate::grants::tree::USER_ROLE_SEED;
+use crate::result::{Error, Result};
+
+// Relationship tuples for access to single objects, which don't fit in a
+// realm:resource:action string or a token.
+//
+//     doc:42#owner@user:<user id>
+//     doc:42#viewer@role:<role id>
+//     doc:42#viewer@folder:7#viewer
+//
+// A role subject stands for every user holding the role, directly or through
+// inheritance, and `folder:7#viewer` for everyone who is a viewer of folder 7.
+// Rewrites make one relation imply another on the same object type, so with
+// `owner` implying `editor` and `editor` implying `viewer` an owner can view.
+pub const USER: &str = "user";
+pub const ROLE: &str = "role";
+
+const MAX_DEPTH: usize = 16;
+
+const TUPLE_SORTS: &[SortKey] = &[
+    SortKey::new("created_at", "timestamp"),
+    SortKey::new("object_id", "varchar"),
+];
+
+const REWRITE_SORTS: &[SortKey] = &[
+    SortKey::new("object_type", "varchar"),
+    SortKey::new("created_at", "timestamp"),
+];
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ObjectRef {
+    pub object_type: String,
+    pub object_id: String,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Subject {
+    pub subject_type: String,
+    pub subject_id: String,
+    pub relation: Option<String>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RefError {
+    MissingId(String),
+    InvalidName(String),
+    InvalidId(String),
+    InvalidSubject(String),
+}
+
+impl fmt::Display for RefError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use RefError::*;
+
+        match &self {
+            MissingId(input) => write!(f, "expected type:id, got '{}'", input),
+            InvalidName(name) => write!(f, "'{}' may only contain letters, digits, '_' and '-'", name),
+            InvalidId(id) => write!(f, "'{}' may not be empty or contain '#' or whitespace", id),
+            InvalidSubject(input) => write!(f, "'{}' must be a user or role id, or an object with a relation", input),
+        }
+    }
+}
+
+impl std::error::Error for RefError {}
+
+impl ObjectRef {
+    pub fn new(object_type: &str, object_id: &str) -> Result<Self, RefError> {
+        validate_name(object_type)?;
+
+        if object_id.is_empty() || object_id.contains(|c: char| c == '#' || c.is_whitespace()) {
+            return Err(RefError::InvalidId(object_id.to_string()));
+        }
+
+        Ok(Self {
+            object_type: object_type.to_string(),
+            object_id: object_id.to_string(),
+        })
+    }
+}
+
+impl FromStr for ObjectRef {
+    type Err = RefError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut parts = input.splitn(2, ':');
+
+        match (parts.next(), parts.next()) {
+            (Some(object_type), Some(object_id)) => ObjectRef::new(object_type, object_id),
+            _ => Err(RefError::MissingId(input.to_string())),
+        }
+    }
+}
+
+impl TryFrom<String> for ObjectRef {
+    type Error = RefError;
+
+    fn try_from(input: String) -> Result<Self, Self::Error> {
+        input.parse()
+    }
+}
+
+impl From<ObjectRef> for String {
+    fn from(object: ObjectRef) -> String {
+        object.to_string()
+    }
+}
+
+impl fmt::Display for ObjectRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.object_type, self.object_id)
+    }
+}
+
+impl Subject {
+    pub fn user(user_id: Uuid) -> Self {
+        Self {
+            subject_type: USER.to_string(),
+            subject_id: user_id.to_string(),
+            relation: None,
+        }
+    }
+
+    pub fn role(role_id: Uuid) -> Self {
+        Self {
+            subject_type: ROLE.to_string(),
+            subject_id: role_id.to_string(),
+            relation: None,
+        }
+    }
+
+    pub fn set(object: ObjectRef, relation: &str) -> Self {
+        Self {
+            subject_type: object.object_type,
+            subject_id: object.object_id,
+            relation: Some(relation.to_string()),
+        }
+    }
+
+    pub fn object(&self) -> ObjectRef {
+        ObjectRef {
+            object_type: self.subject_type.clone(),
+            object_id: self.subject_id.clone(),
+        }
+    }
+
+    // the role's id, when the subject is everyone holding a role
+    pub fn role_id(&self) -> Option<Uuid> {
+        match (self.subject_type.as_str(), &self.relation) {
+            (ROLE, None) => self.subject_id.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn user_id(&self) -> Option<Uuid> {
+        match (self.subject_type.as_str(), &self.relation) {
+            (USER, None) => self.subject_id.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Subject {
+    type Err = RefError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let mut parts = input.splitn(2, '#');
+        let object: ObjectRef = parts.next().unwrap_or_default().parse()?;
+        let relation = parts.next();
+
+        if let Some(relation) = relation {
+            validate_name(relation)?;
+        }
+
+        let direct = object.object_type == USER || object.object_type == ROLE;
+
+        let invalid = || RefError::InvalidSubject(input.to_string());
+
+        match relation {
+            // ids are kept in one form so they compare as strings
+            None if direct => Ok(Self {
+                subject_id: Uuid::parse_str(&object.object_id).map_err(|_| invalid())?.to_string(),
+                subject_type: object.object_type,
+                relation: None,
+            }),
+            Some(relation) if !direct => Ok(Self::set(object, relation)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl TryFrom<String> for Subject {
+    type Error = RefError;
+
+    fn try_from(input: String) -> Result<Self, Self::Error> {
+        input.parse()
+    }
+}
+
+impl From<Subject> for String {
+    fn from(subject: Subject) -> String {
+        subject.to_string()
+    }
+}
+
+impl fmt::Display for Subject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.relation {
+            Some(relation) => write!(f, "{}:{}#{}", self.subject_type, self.subject_id, relation),
+            None => write!(f, "{}:{}", self.subject_type, self.subject_id),
+        }
+    }
+}
+
+pub fn validate_name(name: &str) -> Result<(), RefError> {
+    let valid = !name.is_empty() && name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+
+    if !valid {
+        return Err(RefError::InvalidName(name.to_string()));
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct RelationTuple {
+    pub id: Uuid,
+    pub realm_id: Uuid,
+    pub object_type: String,
+    pub object_id: String,
+    pub relation: String,
+    pub subject_type: String,
+    pub subject_id: String,
+    pub subject_relation: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl RelationTuple {
+    pub fn object(&self) -> ObjectRef {
+        ObjectRef {
+            object_type: self.object_type.clone(),
+            object_id: self.object_id.clone(),
+        }
+    }
+
+    pub fn subject(&self) -> Subject {
+        Subject {
+            subject_type: self.subject_type.clone(),
+            subject_id: self.subject_id.clone(),
+            relation: self.subject_relation.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RelationTupleCreate {
+    pub realm_id: Uuid,
+    pub object: ObjectRef,
+    pub relation: String,
+    pub subject: Subject,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RelationTupleFilter {
+    pub realm_id: Uuid,
+    pub object: Option<ObjectRef>,
+    pub relation: Option<String>,
+    pub subject: Option<Subject>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct RelationRewrite {
+    pub id: Uuid,
+    pub realm_id: Uuid,
+    pub object_type: String,
+    pub relation: String,
+    pub implied_by: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RelationRewriteCreate {
+    pub realm_id: Uuid,
+    pub object_type: String,
+    pub relation: String,
+    pub implied_by: String,
+}
+
+// a realm's rewrites, by object type and the relation they imply
+#[derive(Debug, Default)]
+pub struct Rewrites {
+    implied_by: HashMap<(String, String), Vec<String>>,
+    implies: HashMap<(String, String), Vec<String>>,
+}
+
+impl Rewrites {
+    pub fn new(rewrites: &[RelationRewrite]) -> Self {
+        let mut result = Self::default();
+
+        for rewrite in rewrites.iter() {
+            result.add(&rewrite.object_type, &rewrite.relation, &rewrite.implied_by);
+        }
+
+        result
+    }
+
+    pub fn add(&mut self, object_type: &str, relation: &str, implied_by: &str) {
+        self.implied_by
+            .entry((object_type.to_string(), relation.to_string()))
+            .or_default()
+            .push(implied_by.to_string());
+
+        self.implies
+            .entry((object_type.to_string(), implied_by.to_string()))
+            .or_default()
+            .push(relation.to_string());
+    }
+
+    // the relations that directly imply this one
+    pub fn direct(&self, object_type: &str, relation: &str) -> &[String] {
+        self.implied_by
+            .get(&(object_type.to_string(), relation.to_string()))
+            .map(|relations| relations.as_slice())
+            .unwrap_or(&[])
+    }
+
+    // the relation and every relation that implies it, however indirectly
+    pub fn implying(&self, object_type: &str, relation: &str) -> Vec<String> {
+        Self::closure(&self.implied_by, object_type, relation)
+    }
+
+    // the relation and every relation it implies, however indirectly
+    pub fn implied(&self, object_type: &str, relation: &str) -> Vec<String> {
+        Self::closure(&self.implies, object_type, relation)
+    }
+
+    fn closure(edges: &HashMap<(String, String), Vec<String>>, object_type: &str, relation: &str) -> Vec<String> {
+        let mut results = vec![relation.to_string()];
+        let mut index = 0;
+
+        while index < results.len() {
+            let key = (object_type.to_string(), results[index].clone());
+
+            for next in edges.get(&key).into_iter().flatten() {
+                if !results.contains(next) {
+                    results.push(next.clone());
+                }
+            }
+
+            index += 1;
+        }
+
+        results
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Expansion {
+    pub object: ObjectRef,
+    pub relation: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub subjects: Vec<Subject>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub usersets: Vec<Expansion>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub implied_by: Vec<Expansion>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub repeated: bool,
+}
+
+type Userset = (ObjectRef, String);
+
+#[derive(Clone)]
+pub struct RelationService {
+    pool: Pool,
+}
+
+impl RelationService {
+    pub fn new(pool: &Pool) -> Result<Self> {
+        let service = Self {
+            pool: pool.clone(),
+        };
+
+        Ok(service)
+    }
+
+    pub async fn tuples(&self, filter: &RelationTupleFilter, page: &PageParams) -> Result<Page<RelationTuple>> {
+        let object = filter.object.as_ref();
+        let subject = filter.subject.as_ref();
+
+        ListQuery::new("relation_tuples", TUPLE_SORTS)
+            .filter("realm_id = ?", Some(filter.realm_id))
+            .filter("object_type = ?", object.map(|object| &object.object_type))
+            .filter("object_id = ?", object.map(|object| &object.object_id))
+            .filter("relation = ?", filter.relation.as_ref())
+            .filter("subject_type = ?", subject.map(|subject| &subject.subject_type))
+            .filter("subject_id = ?", subject.map(|subject| &subject.subject_id))
+            .filter(
+                "COALESCE(subject_relation, '') = ?",
+                subject.map(|subject| subject.relation.clone().unwrap_or_default()),
+            )
+            .fetch(&self.pool, page)
+            .await
+    }
+
+    pub async fn create_tuple(&self, actor: &Actor, tuple: RelationTupleCreate) -> Result<RelationTuple> {
+        validate_name(&tuple.relation)?;
+        self.validate_subject(tuple.realm_id, &tuple.subject).await?;
+
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query_as::<_, RelationTuple>(r#"
+            INSERT INTO relation_tuples (
+                realm_id, object_type, object_id, relation,
+                subject_type, subject_id, subject_relation
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7)
+            RETURNING *;
+        "#)
+            .bind(tuple.realm_id)
+            .bind(tuple.object.object_type)
+            .bind(tuple.object.object_id)
+            .bind(tuple.relation)
+            .bind(tuple.subject.subject_type)
+            .bind(tuple.subject.subject_id)
+            .bind(tuple.subject.relation)
+            .fetch_one(&mut tx)
+            .await?;
+
+        actor.event("relation_tuple.created", "relation_tuples", Some(result.id))
+            .realm(result.realm_id)
+            .after(&result)
+            .record(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    pub async fn delete_tuple(&self, actor: &Actor, id: Uuid) -> Result<RelationTuple> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query_as::<_, RelationTuple>(r#"
+            DELETE FROM relation_tuples
+            WHERE id = $1
+            RETURNING *;
+        "#)
+            .bind(id)
+            .fetch_one(&mut tx)
+            .await?;
+
+        actor.event("relation_tuple.deleted", "relation_tuples", Some(result.id))
+            .realm(result.realm_id)
+            .before(&result)
+            .record(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    pub async fn rewrites(&self, realm_id: Uuid, page: &PageParams) -> Result<Page<RelationRewrite>> {
+        ListQuery::new("relation_rewrites", REWRITE_SORTS)
+            .filter("realm_id = ?", Some(realm_id))
+            .fetch(&self.pool, page)
+            .await
+    }
+
+    // every rewrite in the realm, which check, expand and list_objects need
+    // all of at once
+    async fn realm_rewrites(&self, realm_id: Uuid) -> Result<Vec<RelationRewrite>> {
+        let results = sqlx::query_as::<_, RelationRewrite>(r#"
+            SELECT * FROM relation_rewrites
+            WHERE realm_id = $1
+            ORDER BY object_type, relation, implied_by
+        "#)
+            .bind(realm_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(results)
+    }
+
+    pub async fn create_rewrite(&self, actor: &Actor, rewrite: RelationRewriteCreate) -> Result<RelationRewrite> {
+        validate_name(&rewrite.object_type)?;
+        validate_name(&rewrite.relation)?;
+        validate_name(&rewrite.implied_by)?;
+
+        if rewrite.relation == rewrite.implied_by {
+            return Err(Error::msg("a relation can't imply itself"));
+        }
+
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query_as::<_, RelationRewrite>(r#"
+            INSERT INTO relation_rewrites (realm_id, object_type, relation, implied_by)
+            VALUES ($1, $2, $3, $4)
+            RETURNING *;
+        "#)
+            .bind(rewrite.realm_id)
+            .bind(rewrite.object_type)
+            .bind(rewrite.relation)
+            .bind(rewrite.implied_by)
+            .fetch_one(&mut tx)
+            .await?;
+
+        actor.event("relation_rewrite.created", "relation_rewrites", Some(result.id))
+            .realm(result.realm_id)
+            .after(&result)
+            .record(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    pub async fn delete_rewrite(&self, actor: &Actor, id: Uuid) -> Result<RelationRewrite> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query_as::<_, RelationRewrite>(r#"
+            DELETE FROM relation_rewrites
+            WHERE id = $1
+            RETURNING *;
+        "#)
+            .bind(id)
+            .fetch_one(&mut tx)
+            .await?;
+
+        actor.event("relation_rewrite.deleted", "relation_rewrites", Some(result.id))
+            .realm(result.realm_id)
+            .before(&result)
+            .record(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    // whether the user has the relation to the object, one level of
+    // usersets at a time
+    pub async fn check(&self, realm_id: Uuid, object: &ObjectRef, relation: &str, user_id: Uuid) -> Result<bool> {
+        let rewrites = Rewrites::new(&self.realm_rewrites(realm_id).await?);
+        let subjects = self.user_subjects(realm_id, user_id).await?;
+
+        let mut visited: HashSet<Userset> = HashSet::new();
+        let mut frontier: Vec<Userset> = vec![(object.clone(), relation.to_string())];
+
+        for _ in 0..MAX_DEPTH {
+            let mut lookups = vec![];
+
+            for (object, relation) in frontier.drain(..) {
+                for relation in rewrites.implying(&object.object_type, &relation) {
+                    let userset = (object.clone(), relation);
+
+                    if visited.insert(userset.clone()) {
+                        lookups.push(userset);
+                    }
+                }
+            }
+
+            if lookups.is_empty() {
+                return Ok(false);
+            }
+
+            for tuple in self.tuples_on(realm_id, &lookups).await? {
+                let subject = tuple.subject();
+
+                match &subject.relation {
+                    Some(relation) => frontier.push((subject.object(), relation.clone())),
+                    None if subjects.contains(&subject) => return Ok(true),
+                    None => {},
+                }
+            }
+        }
+
+        Err(depth_exceeded(object, relation))
+    }
+
+    // every object of the type the user has the relation to, found by walking
+    // from the user's own subjects up through the usersets they belong to
+    pub async fn list_objects(&self, realm_id: Uuid, object_type: &str, relation: &str, user_id: Uuid) -> Result<Vec<String>> {
+        let rewrites = Rewrites::new(&self.realm_rewrites(realm_id).await?);
+
+        let mut subjects: Vec<Subject> = self.user_subjects(realm_id, user_id).await?
+            .into_iter()
+            .collect();
+        let mut visited: HashSet<Userset> = HashSet::new();
+        let mut results = BTreeSet::new();
+
+        for _ in 0..MAX_DEPTH {
+            if subjects.is_empty() {
+                return Ok(results.into_iter().collect());
+            }
+
+            let tuples = self.tuples_with(realm_id, &subjects).await?;
+
+            subjects.clear();
+
+            for tuple in tuples.iter() {
+                let object = tuple.object();
+
+                for implied in rewrites.implied(&object.object_type, &tuple.relation) {
+                    if !visited.insert((object.clone(), implied.clone())) {
+                        continue;
+                    }
+
+                    if object.object_type == object_type && implied == relation {
+                        results.insert(object.object_id.clone());
+                    }
+
+                    subjects.push(Subject::set(object.clone(), &implied));
+                }
+            }
+        }
+
+        Err(Error::msg(format!(
+            "usersets nest deeper than {} levels listing {} {}",
+            MAX_DEPTH, object_type, relation,
+        )))
+    }
+
+    // who has the relation to the object, as a tree of direct subjects,
+    // nested usersets and the relations implying it
+    pub async fn expand(&self, realm_id: Uuid, object: &ObjectRef, relation: &str) -> Result<Expansion> {
+        let rewrites = Rewrites::new(&self.realm_rewrites(realm_id).await?);
+
+        let mut tuples: HashMap<Userset, Vec<RelationTuple>> = HashMap::new();
+        let mut frontier: Vec<Userset> = vec![(object.clone(), relation.to_string())];
+
+        for _ in 0..MAX_DEPTH {
+            let mut lookups = vec![];
+            let mut next = vec![];
+
+            for userset in frontier.drain(..) {
+                if tuples.contains_key(&userset) {
+                    continue;
+                }
+
+                for implied_by in rewrites.direct(&userset.0.object_type, &userset.1) {
+                    next.push((userset.0.clone(), implied_by.clone()));
+                }
+
+                tuples.insert(userset.clone(), vec![]);
+                lookups.push(userset);
+            }
+
+            if lookups.is_empty() {
+                let root = (object.clone(), relation.to_string());
+
+                return Ok(build_expansion(&root, &tuples, &rewrites, &mut HashSet::new()));
+            }
+
+            for tuple in self.tuples_on(realm_id, &lookups).await? {
+                let subject = tuple.subject();
+
+                if let Some(relation) = &subject.relation {
+                    next.push((subject.object(), relation.clone()));
+                }
+
+                tuples
+                    .entry((tuple.object(), tuple.relation.clone()))
+                    .or_default()
+                    .push(tuple);
+            }
+
+            frontier = next;
+        }
+
+        Err(depth_exceeded(object, relation))
+    }
+
+    // the user and every role they hold, directly or through inheritance
+    async fn user_subjects(&self, realm_id: Uuid, user_id: Uuid) -> Result<HashSet<Subject>> {
+        let query = format!(r#"
+            WITH RECURSIVE closure(role_id) AS (
+                {}
+                UNION
+                SELECT role_role_grants.child_id FROM role_role_grants
+                JOIN closure ON role_role_grants.parent_id = closure.role_id
+                WHERE role_role_grants.realm_id = $1
+            )
+            SELECT role_id FROM closure
+        "#, USER_ROLE_SEED);
+
+        let roles = sqlx::query_as::<_, (Uuid,)>(&query)
+            .bind(realm_id)
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let mut subjects: HashSet<Subject> = roles
+            .into_iter()
+            .map(|(role_id,)| Subject::role(role_id))
+            .collect();
+
+        subjects.insert(Subject::user(user_id));
+
+        Ok(subjects)
+    }
+
+    async fn tuples_on(&self, realm_id: Uuid, usersets: &[Userset]) -> Result<Vec<RelationTuple>> {
+        let object_types: Vec<String> = usersets.iter().map(|(object, _)| object.object_type.clone()).collect();
+        let object_ids: Vec<String> = usersets.iter().map(|(object, _)| object.object_id.clone()).collect();
+        let relations: Vec<String> = usersets.iter().map(|(_, relation)| relation.clone()).collect();
+
+        let results = sqlx::query_as::<_, RelationTuple>(r#"
+            SELECT * FROM relation_tuples
+            WHERE realm_id = $1
+            AND (object_type, object_id, relation) IN (
+                SELECT * FROM UNNEST($2::varchar[], $3::varchar[], $4::varchar[])
+            )
+        "#)
+            .bind(realm_id)
+            .bind(object_types)
+            .bind(object_ids)
+            .bind(relations)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(results)
+    }
+
+    async fn tuples_with(&self, realm_id: Uuid, subjects: &[Subject]) -> Result<Vec<RelationTuple>> {
+        let subject_types: Vec<String> = subjects.iter().map(|subject| subject.subject_type.clone()).collect();
+        let subject_ids: Vec<String> = subjects.iter().map(|subject| subject.subject_id.clone()).collect();
+        let relations: Vec<String> = subjects.iter().map(|subject| subject.relation.clone().unwrap_or_default()).collect();
+
+        let results = sqlx::query_as::<_, RelationTuple>(r#"
+            SELECT * FROM relation_tuples
+            WHERE realm_id = $1
+            AND (subject_type, subject_id, COALESCE(subject_relation, '')) IN (
+                SELECT * FROM UNNEST($2::varchar[], $3::varchar[], $4::varchar[])
+            )
+        "#)
+            .bind(realm_id)
+            .bind(subject_types)
+            .bind(subject_ids)
+            .bind(relations)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(results)
+    }
+
+    async fn validate_subject(&self, realm_id: Uuid, subject: &Subject) -> Result<()> {
+        if let Some(user_id) = subject.user_id() {
+            sqlx::query_as::<_, (Uuid,)>(r#"
+                SELECT id FROM users
+                WHERE id = $1
+                AND deleted_at IS NULL
+            "#)
+                .bind(user_id)
+                .fetch_optional(&self.pool)
+                .await?
+                .ok_or_else(|| Error::msg(format!("user {} does not exist", user_id)))?;
+        }
+
+        if let Some(role_id) = subject.role_id() {
+            sqlx::query_as::<_, (Uuid,)>(r#"
+                SELECT id FROM roles
+                WHERE id = $1
+                AND realm_id = $2
+            "#)
+                .bind(role_id)
+                .bind(realm_id)
+                .fetch_optional(&self.pool)
+                .await?
+                .ok_or_else(|| Error::msg(format!("role {} is not in realm {}", role_id, realm_id)))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn build_expansion(
+    userset: &Userset,
+    tuples: &HashMap<Userset, Vec<RelationTuple>>,
+    rewrites: &Rewrites,
+    expanded: &mut HashSet<Userset>,
+) -> Expansion {
+    let (object, relation) = userset;
+
+    let mut expansion = Expansion {
+        object: object.clone(),
+        relation: relation.clone(),
+        subjects: vec![],
+        usersets: vec![],
+        implied_by: vec![],
+        repeated: false,
+    };
+
+    if !expanded.insert(userset.clone()) {
+        expansion.repeated = true;
+
+        return expansion;
+    }
+
+    for tuple in tuples.get(userset).into_iter().flatten() {
+        let subject = tuple.subject();
+
+        match &subject.relation {
+            Some(relation) => {
+                let nested = (subject.object(), relation.clone());
+
+                expansion.usersets.push(build_expansion(&nested, tuples, rewrites, expanded));
+            },
+            None => expansion.subjects.push(subject),
+        }
+    }
+
+    for implied_by in rewrites.direct(&object.object_type, relation) {
+        let implying = (object.clone(), implied_by.clone());
+
+        expansion.implied_by.push(build_expansion(&implying, tuples, rewrites, expanded));
+    }
+
+    expansion
+}
+
+fn depth_exceeded(object: &ObjectRef, relation: &str) -> Error {
+    Error::msg(format!("usersets nest deeper than {} levels under {}#{}", MAX_DEPTH, object, relation))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refs() {
+        let object: ObjectRef = "doc:42".parse().unwrap();
+        assert_eq!(object.object_type, "doc");
+        assert_eq!(object.object_id, "42");
+        assert_eq!(object.to_string(), "doc:42");
+
+        let object: ObjectRef = "repo:acme/widgets:main".parse().unwrap();
+        assert_eq!(object.object_id, "acme/widgets:main");
+
+        assert_eq!("doc".parse::<ObjectRef>(), Err(RefError::MissingId("doc".to_string())));
+        assert_eq!("doc:".parse::<ObjectRef>(), Err(RefError::InvalidId("".to_string())));
+        assert_eq!("d.c:1".parse::<ObjectRef>(), Err(RefError::InvalidName("d.c".to_string())));
+
+        let user_id = Uuid::new_v4();
+        let subject: Subject = format!("user:{}", user_id).parse().unwrap();
+        assert_eq!(subject, Subject::user(user_id));
+        assert_eq!(subject.user_id(), Some(user_id));
+
+        let subject: Subject = "folder:7#viewer".parse().unwrap();
+        assert_eq!(subject.object(), "folder:7".parse().unwrap());
+        assert_eq!(subject.relation.as_deref(), Some("viewer"));
+        assert_eq!(subject.to_string(), "folder:7#viewer");
+
+        assert!("user:bob".parse::<Subject>().is_err());
+        assert!(format!("role:{}#member", user_id).parse::<Subject>().is_err());
+        assert!("folder:7".parse::<Subject>().is_err());
+    }
+
+    #[test]
+    fn test_rewrites() {
+        let mut rewrites = Rewrites::default();
+        rewrites.add("doc", "editor", "owner");
+        rewrites.add("doc", "viewer", "editor");
+        rewrites.add("doc", "viewer", "commenter");
+        rewrites.add("folder", "viewer", "owner");
+
+        assert_eq!(rewrites.implying("doc", "viewer"), vec!["viewer", "editor", "commenter", "owner"]);
+        assert_eq!(rewrites.implied("doc", "owner"), vec!["owner", "editor", "viewer"]);
+        assert_eq!(rewrites.implied("folder", "editor"), vec!["editor"]);
+        assert_eq!(rewrites.direct("doc", "viewer"), &["editor".to_string(), "commenter".to_string()]);
+
+        // a cycle between relations still terminates
+        rewrites.add("doc", "owner", "viewer");
+        assert_eq!(rewrites.implying("doc", "owner").len(), 4);
+    }
+}
@@ -4,10 +4,28 @@
 // }
 
 use chrono::NaiveDateTime;
+use serde_json::json;
+use sqlx::{Postgres, Transaction};
 use uuid::Uuid;
 
+use crate::audit::Actor;
+use crate::db::page::{ListQuery, Page, PageParams, SortKey};
+use crate::db::patch::{Patch, Precondition, Versioned};
 use crate::db::pg::Pool;
 use crate::result::Result;
+use crate::webhooks;
+
+const ROLE_SORTS: &[SortKey] = &[
+    SortKey::new("created_at", "timestamp"),
+    SortKey::new("name", "varchar"),
+];
+
+// `name` matches on a prefix
+#[derive(Debug, Default, Deserialize)]
+pub struct RoleFilter {
+    pub realm_id: Option<Uuid>,
+    pub name: Option<String>,
+}
 
 #[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Role {
@@ -18,15 +36,22 @@ pub struct Role {
     pub updated_at: Option<NaiveDateTime>,
 }
 
+impl Versioned for Role {
+    fn version(&self) -> Option<NaiveDateTime> {
+        self.updated_at
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct RoleCreate {
     pub realm_id: Uuid,
     pub name: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct RoleUpdate {
-    pub name: String,
+    pub name: Patch<String>,
 }
 
 #[derive(Clone)]
@@ -43,14 +68,12 @@ impl RoleService {
         Ok(service)
     }
 
-    pub async fn all(&self) -> Result<Vec<Role>> {
-        let roles = sqlx::query_as::<_, Role>(r#"
-            SELECT * FROM roles
-        "#)
-            .fetch_all(&self.pool)
-            .await?;
-
-        Ok(roles)
+    pub async fn list(&self, filter: &RoleFilter, page: &PageParams) -> Result<Page<Role>> {
+        ListQuery::new("roles", ROLE_SORTS)
+            .filter("realm_id = ?", filter.realm_id)
+            .prefix("name", filter.name.as_ref())
+            .fetch(&self.pool, page)
+            .await
     }
 
     pub async fn by_id(&self, id: Uuid) -> Result<Role> {
@@ -65,35 +88,146 @@ impl RoleService {
         Ok(result)
     }
 
-    pub async fn create(&self, role: RoleCreate) -> Result<Role> {
+    pub async fn create(&self, actor: &Actor, role: RoleCreate) -> Result<Role> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = Self::create_in(&mut tx, actor, role).await?;
+
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    pub async fn create_in(tx: &mut Transaction<'_, Postgres>, actor: &Actor, role: RoleCreate) -> Result<Role> {
         let result = sqlx::query_as::<_, Role>(r#"
             INSERT INTO roles (name, realm_id) VALUES ($1, $2)
             RETURNING *;
         "#)
             .bind(role.name)
             .bind(role.realm_id)
-            .fetch_one(&self.pool)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        actor.event("role.created", "roles", Some(result.id))
+            .realm(result.realm_id)
+            .after(&result)
+            .record(&mut *tx)
             .await?;
 
         Ok(result)
     }
 
-    pub async fn update(&self, id: Uuid, role: RoleUpdate) -> Result<Role> {
+    pub async fn update(&self, actor: &Actor, id: Uuid, role: RoleUpdate, precondition: &Precondition) -> Result<Role> {
+        let name = role.name.required("name")?;
+
+        let mut tx = self.pool.begin().await?;
+
+        let before = sqlx::query_as::<_, Role>(r#"
+            SELECT * FROM roles
+            WHERE id = $1
+            FOR UPDATE
+        "#)
+            .bind(id)
+            .fetch_one(&mut tx)
+            .await?;
+
+        precondition.check(&before)?;
+
         let result = sqlx::query_as::<_, Role>(r#"
-            UPDATE roles 
-            SET name = $2
+            UPDATE roles
+            SET name = COALESCE($2, name)
             WHERE id = $1
             RETURNING *;
         "#)
             .bind(id)
-            .bind(role.name)
-            .fetch_one(&self.pool)
+            .bind(name)
+            .fetch_one(&mut tx)
             .await?;
 
+        actor.event("role.updated", "roles", Some(id))
+            .realm(result.realm_id)
+            .before(&before)
+            .after(&result)
+            .record(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
         Ok(result)
     }
 
-    pub async fn delete(&self, id: Uuid) -> Result<()> {
-        todo!()
+    pub async fn delete(&self, actor: &Actor, id: Uuid) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        Self::delete_in(&mut tx, actor, id).await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_in(tx: &mut Transaction<'_, Postgres>, actor: &Actor, id: Uuid) -> Result<()> {
+        let revoked = sqlx::query_as::<_, (Uuid, Uuid, Uuid)>(r#"
+            DELETE FROM user_role_grants
+            WHERE role_id = $1
+            RETURNING id, realm_id, user_id;
+        "#)
+            .bind(id)
+            .fetch_all(&mut *tx)
+            .await?;
+
+        for (grant_id, realm_id, user_id) in revoked.iter() {
+            let data = json!({
+                "user_id": user_id,
+                "role_id": id,
+                "grant_id": grant_id,
+                "reason": "role_deleted",
+            });
+
+            webhooks::enqueue(&mut *tx, *realm_id, webhooks::USER_ROLE_REVOKED, data).await?;
+        }
+
+        sqlx::query(r#"
+            DELETE FROM role_role_grants
+            WHERE parent_id = $1
+            OR child_id = $1
+        "#)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query(r#"
+            DELETE FROM role_permission_grants
+            WHERE role_id = $1
+        "#)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query(r#"
+            DELETE FROM relation_tuples
+            WHERE subject_type = 'role'
+            AND subject_id = $1
+        "#)
+            .bind(id.to_string())
+            .execute(&mut *tx)
+            .await?;
+
+        let deleted = sqlx::query_as::<_, Role>(r#"
+            DELETE FROM roles
+            WHERE id = $1
+            RETURNING *;
+        "#)
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        actor.event("role.deleted", "roles", Some(id))
+            .realm(deleted.realm_id)
+            .before(&deleted)
+            .record(&mut *tx)
+            .await?;
+
+        Ok(())
     }
 }
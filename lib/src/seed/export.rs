@@ -0,0 +1,509 @@
+use serde_json::value::Value as JsonValue;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use super::json::{Authority, Realm, Role, User};
+use crate::authorities::strategies::StrategyType;
+use crate::db::pg::Pool;
+use crate::permissions::permission::DENY_PREFIX;
+use crate::realms::RealmService;
+use crate::result::Result;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ExportOptions {
+    // authority salts and the users' password digests; without them the
+    // export seeds a realm nobody can sign in to until passwords are set
+    #[serde(default)]
+    pub secrets: bool,
+}
+
+#[derive(Clone)]
+pub struct ExportService {
+    pool: Pool,
+}
+
+impl ExportService {
+    pub fn new(pool: &Pool) -> Result<Self> {
+        Ok(Self { pool: pool.to_owned() })
+    }
+
+    // a realm as a seed file; `seed::seed` turns it back into the same realm
+    pub async fn export(&self, realm_id: Uuid, options: &ExportOptions) -> Result<Realm> {
+        let snapshot = Snapshot::load(&self.pool, realm_id).await?;
+
+        Ok(snapshot.into_realm(options))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Snapshot {
+    name: String,
+    max_role_depth: i32,
+    authorities: Vec<AuthorityRow>,
+    permissions: Vec<String>,
+    roles: Vec<(Uuid, String)>,
+    role_roles: Vec<(Uuid, Uuid)>,
+    role_permissions: Vec<GrantRow>,
+    users: Vec<UserRow>,
+    credentials: Vec<(Uuid, JsonValue)>,
+    user_roles: Vec<GrantRow>,
+    user_permissions: Vec<GrantRow>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct AuthorityRow {
+    name: String,
+    client_key: Uuid,
+    status: String,
+    strategy: StrategyType,
+    params: JsonValue,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct UserRow {
+    id: Uuid,
+    username: String,
+    email: Option<String>,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    profile: JsonValue,
+    status: String,
+    kind: String,
+}
+
+// a grant by name; `portable` is false for grants a seed file can't
+// describe, i.e. ones with a condition or a window
+#[derive(Debug, sqlx::FromRow)]
+struct GrantRow {
+    holder_id: Uuid,
+    name: String,
+    deny: bool,
+    portable: bool,
+}
+
+impl Snapshot {
+    async fn load(pool: &Pool, realm_id: Uuid) -> Result<Self> {
+        let realm = RealmService::new(pool)?.by_id(realm_id).await?;
+
+        // ordered by age so the first authority, the one users' credentials
+        // are exported for, stays first
+        let authorities = sqlx::query_as::<_, AuthorityRow>(r#"
+            SELECT name, client_key, status, strategy, params FROM authorities
+            WHERE realm_id = $1
+            ORDER BY created_at, name
+        "#)
+            .bind(realm_id)
+            .fetch_all(pool)
+            .await?;
+
+        let permissions: Vec<(String,)> = sqlx::query_as(r#"
+            SELECT realm || ':' || resource || ':' || action FROM permissions
+            WHERE realm_id = $1
+        "#)
+            .bind(realm_id)
+            .fetch_all(pool)
+            .await?;
+
+        let roles: Vec<(Uuid, String)> = sqlx::query_as(r#"
+            SELECT id, name::text FROM roles
+            WHERE realm_id = $1
+        "#)
+            .bind(realm_id)
+            .fetch_all(pool)
+            .await?;
+
+        let role_roles: Vec<(Uuid, Uuid)> = sqlx::query_as(r#"
+            SELECT parent_id, child_id FROM role_role_grants
+            WHERE realm_id = $1
+        "#)
+            .bind(realm_id)
+            .fetch_all(pool)
+            .await?;
+
+        let role_permissions = sqlx::query_as::<_, GrantRow>(r#"
+            SELECT
+                role_permission_grants.role_id AS holder_id,
+                permissions.realm || ':' || permissions.resource || ':' || permissions.action AS name,
+                role_permission_grants.effect = 'deny' AS deny,
+                role_permission_grants.condition IS NULL AS portable
+            FROM role_permission_grants
+            JOIN permissions ON permissions.id = role_permission_grants.permission_id
+            WHERE role_permission_grants.realm_id = $1
+        "#)
+            .bind(realm_id)
+            .fetch_all(pool)
+            .await?;
+
+        let users = sqlx::query_as::<_, UserRow>(r#"
+            SELECT id, username, email, first_name, last_name, profile, status, kind
+            FROM users
+            WHERE deleted_at IS NULL
+            AND (
+                EXISTS(SELECT 1 FROM user_authorities WHERE user_id = users.id AND realm_id = $1)
+                OR EXISTS(SELECT 1 FROM user_role_grants WHERE user_id = users.id AND realm_id = $1)
+                OR EXISTS(SELECT 1 FROM user_permission_grants WHERE user_id = users.id AND realm_id = $1)
+            )
+        "#)
+            .bind(realm_id)
+            .fetch_all(pool)
+            .await?;
+
+        let credentials: Vec<(Uuid, JsonValue)> = sqlx::query_as(r#"
+            SELECT user_authorities.user_id, user_authorities.params
+            FROM user_authorities
+            JOIN authorities ON authorities.id = user_authorities.authority_id
+            WHERE user_authorities.realm_id = $1
+            AND authorities.id = (
+                SELECT id FROM authorities
+                WHERE realm_id = $1
+                ORDER BY created_at, name
+                LIMIT 1
+            )
+        "#)
+            .bind(realm_id)
+            .fetch_all(pool)
+            .await?;
+
+        let user_roles = sqlx::query_as::<_, GrantRow>(r#"
+            SELECT
+                user_role_grants.user_id AS holder_id,
+                roles.name::text AS name,
+                false AS deny,
+                user_role_grants.starts_at IS NULL AND user_role_grants.expires_at IS NULL AS portable
+            FROM user_role_grants
+            JOIN roles ON roles.id = user_role_grants.role_id
+            WHERE user_role_grants.realm_id = $1
+            AND user_role_grants.swept_at IS NULL
+        "#)
+            .bind(realm_id)
+            .fetch_all(pool)
+            .await?;
+
+        let user_permissions = sqlx::query_as::<_, GrantRow>(r#"
+            SELECT
+                user_permission_grants.user_id AS holder_id,
+                permissions.realm || ':' || permissions.resource || ':' || permissions.action AS name,
+                user_permission_grants.effect = 'deny' AS deny,
+                (
+                    user_permission_grants.condition IS NULL
+                    AND user_permission_grants.starts_at IS NULL
+                    AND user_permission_grants.expires_at IS NULL
+                ) AS portable
+            FROM user_permission_grants
+            JOIN permissions ON permissions.id = user_permission_grants.permission_id
+            WHERE user_permission_grants.realm_id = $1
+            AND user_permission_grants.swept_at IS NULL
+        "#)
+            .bind(realm_id)
+            .fetch_all(pool)
+            .await?;
+
+        Ok(Self {
+            name: realm.name,
+            max_role_depth: realm.max_role_depth,
+            authorities,
+            permissions: permissions.into_iter().map(|(name,)| name).collect(),
+            roles,
+            role_roles,
+            role_permissions,
+            users,
+            credentials,
+            user_roles,
+            user_permissions,
+        })
+    }
+
+    fn into_realm(self, options: &ExportOptions) -> Realm {
+        let role_names: HashMap<Uuid, String> = self.roles.iter().cloned().collect();
+        let user_names: HashMap<Uuid, &str> = self.users.iter().map(|user| (user.id, user.username.as_str())).collect();
+
+        let mut role_grants = HashMap::<Uuid, Vec<String>>::new();
+        let mut role_includes = HashMap::<Uuid, Vec<String>>::new();
+        let mut user_grants = HashMap::<Uuid, Vec<String>>::new();
+        let mut user_roles = HashMap::<Uuid, Vec<String>>::new();
+
+        for grant in self.role_permissions.iter() {
+            let role = role_names.get(&grant.holder_id).map(String::as_str).unwrap_or_default();
+
+            if let Some(name) = portable(grant, "role", role) {
+                role_grants.entry(grant.holder_id).or_default().push(name);
+            }
+        }
+
+        for (parent_id, child_id) in self.role_roles.iter() {
+            if let Some(child) = role_names.get(child_id) {
+                role_includes.entry(*parent_id).or_default().push(child.clone());
+            }
+        }
+
+        for grant in self.user_permissions.iter() {
+            let user = user_names.get(&grant.holder_id).copied().unwrap_or_default();
+
+            if let Some(name) = portable(grant, "user", user) {
+                user_grants.entry(grant.holder_id).or_default().push(name);
+            }
+        }
+
+        for grant in self.user_roles.iter() {
+            let user = user_names.get(&grant.holder_id).copied().unwrap_or_default();
+
+            if let Some(name) = portable(grant, "user", user) {
+                user_roles.entry(grant.holder_id).or_default().push(name);
+            }
+        }
+
+        let mut credentials: HashMap<Uuid, JsonValue> = self.credentials.into_iter().collect();
+
+        let authorities = self.authorities
+            .into_iter()
+            .map(|authority| Authority {
+                realm_id: None,
+                name: authority.name,
+                client_key: authority.client_key,
+                status: Some(authority.status),
+                strategy: authority.strategy,
+                params: authority.params,
+            })
+            .collect();
+
+        let roles = self.roles
+            .into_iter()
+            .map(|(id, name)| Role {
+                name,
+                roles: role_includes.remove(&id),
+                permissions: role_grants.remove(&id),
+            })
+            .collect();
+
+        let users = self.users
+            .into_iter()
+            .map(|user| User {
+                password: None,
+                credential: credentials.remove(&user.id),
+                roles: user_roles.remove(&user.id),
+                permissions: user_grants.remove(&user.id),
+                username: user.username,
+                email: user.email,
+                first_name: user.first_name,
+                last_name: user.last_name,
+                profile: user.profile,
+                status: user.status,
+                kind: user.kind,
+            })
+            .collect();
+
+        let realm = Realm {
+            id: None,
+            name: self.name,
+            max_role_depth: Some(self.max_role_depth),
+            permissions: Some(self.permissions),
+            authorities: Some(authorities),
+            users: Some(users),
+            roles: Some(roles),
+        }
+            .normalized();
+
+        if options.secrets {
+            return realm;
+        }
+
+        realm.redacted()
+    }
+}
+
+// the grant as a seed file writes it, or none for the ones it can't
+fn portable(grant: &GrantRow, kind: &str, holder: &str) -> Option<String> {
+    if !grant.portable {
+        log::warn!("leaving {} out of the export of {} {}: it has a condition or a window", grant.name, kind, holder);
+
+        return None;
+    }
+
+    if grant.deny {
+        return Some(format!("{}{}", DENY_PREFIX, grant.name));
+    }
+
+    Some(grant.name.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seed::testing;
+    use serde_json::json;
+
+    fn fixture() -> Realm {
+        serde_json::from_value(json!({
+            "name": "acme",
+            "max_role_depth": 8,
+            "permissions": ["acme:reports:export"],
+            "authorities": [{
+                "name": "acme:username_password",
+                "client_key": "6f1d4a52-8f0e-4a3c-9d33-58e0b7f0c7a1",
+                "status": null,
+                "strategy": "username_password",
+                "params": { "password_salt": "pepper" }
+            }],
+            "users": [{
+                "username": "wile",
+                "credential": { "username": "wile", "password_digest": "$2b$04$digest" },
+                "email": "wile@acme.test",
+                "first_name": "Wile",
+                "last_name": "Coyote",
+                "profile": { "nickname": "genius" },
+                "status": "enabled",
+                "kind": "human",
+                "roles": ["acme:user"],
+                "permissions": ["!acme:rockets:fire", "acme:anvils:**"]
+            }],
+            "roles": [
+                { "name": "acme:user", "roles": ["acme:guest"], "permissions": ["acme:me.**:**"] },
+                { "name": "acme:admin", "roles": ["acme:user"], "permissions": ["acme:**:**", "!acme:me.delete:**"] },
+                { "name": "acme:guest", "roles": null, "permissions": null }
+            ]
+        }))
+            .unwrap()
+    }
+
+    // what `seed::seed` leaves in the database for a realm, as `load` reads it
+    fn seeded(realm: &Realm) -> Snapshot {
+        let realm = realm.clone().normalized();
+
+        let roles: Vec<(Uuid, String)> = realm.roles.iter().flatten().map(|role| (Uuid::new_v4(), role.name.clone())).collect();
+        let role_ids: HashMap<String, Uuid> = roles.iter().map(|(id, name)| (name.clone(), *id)).collect();
+        let users: Vec<(Uuid, &User)> = realm.users.iter().flatten().map(|user| (Uuid::new_v4(), user)).collect();
+
+        let grant = |holder_id: Uuid, name: &String| GrantRow {
+            holder_id,
+            name: name.trim_start_matches(DENY_PREFIX).to_string(),
+            deny: name.starts_with(DENY_PREFIX),
+            portable: true,
+        };
+
+        let mut snapshot = Snapshot {
+            name: realm.name.clone(),
+            max_role_depth: realm.max_role_depth.unwrap(),
+            permissions: realm.permissions.clone().unwrap(),
+            roles: roles.clone(),
+            ..Default::default()
+        };
+
+        for authority in realm.authorities.iter().flatten() {
+            snapshot.authorities.push(AuthorityRow {
+                name: authority.name.clone(),
+                client_key: authority.client_key,
+                status: authority.status.clone().unwrap(),
+                strategy: authority.strategy.clone(),
+                params: authority.params.clone(),
+            });
+        }
+
+        for role in realm.roles.iter().flatten() {
+            let id = role_ids[&role.name];
+
+            for child in role.roles.iter().flatten() {
+                snapshot.role_roles.push((id, role_ids[child]));
+            }
+
+            for name in role.permissions.iter().flatten() {
+                snapshot.role_permissions.push(grant(id, name));
+            }
+        }
+
+        for (id, user) in users.iter() {
+            snapshot.users.push(UserRow {
+                id: *id,
+                username: user.username.clone(),
+                email: user.email.clone(),
+                first_name: user.first_name.clone(),
+                last_name: user.last_name.clone(),
+                profile: user.profile.clone(),
+                status: user.status.clone(),
+                kind: user.kind.clone(),
+            });
+
+            if let Some(credential) = &user.credential {
+                snapshot.credentials.push((*id, credential.clone()));
+            }
+
+            for name in user.roles.iter().flatten() {
+                snapshot.user_roles.push(grant(*id, name));
+            }
+
+            for name in user.permissions.iter().flatten() {
+                snapshot.user_permissions.push(grant(*id, name));
+            }
+        }
+
+        snapshot
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let realm = fixture();
+
+        let exported = seeded(&realm).into_realm(&ExportOptions { secrets: true });
+        assert_eq!(exported, realm.clone().normalized());
+
+        // and once more through the file itself
+        let json = serde_json::to_string_pretty(&exported).unwrap();
+        let reimported: Realm = serde_json::from_str(&json).unwrap();
+        assert_eq!(seeded(&reimported).into_realm(&ExportOptions { secrets: true }), exported);
+    }
+
+    #[test]
+    fn test_secrets() {
+        let exported = seeded(&fixture()).into_realm(&ExportOptions::default());
+
+        let authority = &exported.authorities.as_ref().unwrap()[0];
+        assert_eq!(authority.params, json!({}));
+
+        let user = &exported.users.as_ref().unwrap()[0];
+        assert_eq!(user.credential, None);
+        assert_eq!(user.password, None);
+
+        let json = serde_json::to_string(&exported).unwrap();
+        assert!(!json.contains("pepper"));
+        assert!(!json.contains("digest"));
+    }
+
+    #[test]
+    fn test_unportable_grants() {
+        let mut snapshot = seeded(&fixture());
+
+        for grant in snapshot.user_permissions.iter_mut() {
+            grant.portable = grant.name != "acme:anvils:**";
+        }
+
+        let exported = snapshot.into_realm(&ExportOptions::default());
+        let user = &exported.users.as_ref().unwrap()[0];
+
+        assert_eq!(user.permissions, Some(vec!["!acme:rockets:fire".to_string()]));
+    }
+
+    // seeds a realm into a real database and exports it again
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_round_trip_database() {
+        let pool = testing::pool().await;
+        let (mut realm, _) = testing::unique(&fixture());
+
+        let users = realm.users.as_mut().unwrap();
+        users[0].credential = None;
+        users[0].password = Some("meep meep".to_string());
+
+        let expected = realm.clone().normalized().redacted();
+
+        super::super::seed(&pool, &mut realm).await.unwrap();
+
+        let service = ExportService::new(&pool).unwrap();
+
+        let exported = service.export(realm.id.unwrap(), &ExportOptions::default()).await.unwrap();
+        assert_eq!(exported, expected);
+
+        // the digest written at registration comes out with the secrets
+        let exported = service.export(realm.id.unwrap(), &ExportOptions { secrets: true }).await.unwrap();
+        let credential = exported.users.unwrap()[0].credential.clone().unwrap();
+        assert!(credential["password_digest"].as_str().unwrap().starts_with("$2"));
+    }
+}
@@ -1,19 +1,22 @@
 use serde_json::value::Value as JsonValue;
 use serde_json::{Map, Value};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use uuid::Uuid;
 
+use crate::audit::Actor;
 use crate::authorities::strategies::username_password::{
     AuthParams as UsernamePasswordAuthParams, AuthService as UsernamePasswordService,
     RegisterParams as UsernamePasswordRegisterParams,
 };
 use crate::authorities::strategies::Authority as AuthorityInterface;
 use crate::authorities::strategies::StrategyType;
-use crate::authorities::{Authority as AuthorityRow, AuthorityCreate, AuthorityService};
+use crate::authorities::{
+    without_secrets, Authority as AuthorityRow, AuthorityCreate, AuthorityService, UserAuthorityCreate,
+};
 use crate::db::pg::Pool;
 use crate::grants::GrantService;
-use crate::grants::PermissionType;
-use crate::permissions::permission::Permission as PermissionRaw;
+use crate::grants::{GrantOptions, GrantWindow, PermissionType};
+use crate::permissions::permission::{parse_grant, Permission as PermissionRaw, DENY_PREFIX};
 use crate::permissions::permission_service::Permission;
 use crate::permissions::permission_service::PermissionCreate;
 use crate::realms::RealmCreate;
@@ -21,15 +24,16 @@ use crate::result::{Context, Error, Result};
 use crate::roles::{Role as RoleRow, RoleCreate, RoleService};
 use crate::users::{User as UserRow, UserCreate, UserService};
 
-pub async fn oxidauth_realm<'a>(
+#[allow(clippy::too_many_arguments)]
+pub async fn oxidauth_realm(
     pool: &Pool,
-    username: &'a str,
-    password: &'a str,
-    email: &'a str,
-    first_name: &'a str,
-    last_name: &'a str,
-    password_salt: &'a str,
-    client_key: &'a str,
+    username: &str,
+    password: &str,
+    email: &str,
+    first_name: &str,
+    last_name: &str,
+    password_salt: &str,
+    client_key: &str,
 ) -> Result<()> {
     let mut authority_params = Map::new();
     authority_params.insert(
@@ -41,40 +45,43 @@ pub async fn oxidauth_realm<'a>(
 
     let mut oxidauth = Realm {
         id: None,
-        name: "oxidauth",
+        name: "oxidauth".to_string(),
+        max_role_depth: None,
+        permissions: None,
         authorities: Some(vec![Authority {
             realm_id: None,
-            name: "oxidauth:username_password",
+            name: "oxidauth:username_password".to_string(),
             client_key,
             status: None,
             strategy: StrategyType::UsernamePassword,
             params: JsonValue::Object(authority_params),
         }]),
         users: Some(vec![User {
-            username,
-            password,
-            email: Some(email),
-            first_name: Some(first_name),
-            last_name: Some(last_name),
+            username: username.to_string(),
+            password: Some(password.to_string()),
+            credential: None,
+            email: Some(email.to_string()),
+            first_name: Some(first_name.to_string()),
+            last_name: Some(last_name.to_string()),
             profile: Value::Object(Map::new()),
-            status: "enabled",
-            kind: "human",
-            roles: Some(vec!["oxidauth:admin"]),
+            status: "enabled".to_string(),
+            kind: "human".to_string(),
+            roles: Some(vec!["oxidauth:admin".to_string()]),
             permissions: None,
         }]),
         roles: Some(vec![
             Role {
-                name: "oxidauth:admin",
-                roles: Some(vec!["oxidauth:user"]),
-                permissions: Some(vec!["oxidauth:**:**"]),
+                name: "oxidauth:admin".to_string(),
+                roles: Some(vec!["oxidauth:user".to_string()]),
+                permissions: Some(vec!["oxidauth:**:**".to_string()]),
             },
             Role {
-                name: "oxidauth:user",
-                roles: Some(vec!["oxidauth:guest"]),
-                permissions: Some(vec!["oxidauth:me.**:**"]),
+                name: "oxidauth:user".to_string(),
+                roles: Some(vec!["oxidauth:guest".to_string()]),
+                permissions: Some(vec!["oxidauth:me.**:**".to_string()]),
             },
             Role {
-                name: "oxidauth:guest",
+                name: "oxidauth:guest".to_string(),
                 roles: None,
                 permissions: None,
             },
@@ -86,13 +93,13 @@ pub async fn oxidauth_realm<'a>(
     Ok(())
 }
 
-pub fn from_bytes<'a>(input: &'a str) -> Result<Box<Realm<'a>>> {
+pub fn from_bytes(input: &str) -> Result<Box<Realm>> {
     let realm: Realm = serde_json::from_str(input)?;
 
     Ok(Box::new(realm))
 }
 
-pub async fn seed(pool: &Pool, realm: &mut Realm<'_>) -> Result<()> {
+pub async fn seed(pool: &Pool, realm: &mut Realm) -> Result<()> {
     let realm_exists = realm_exists(pool, &realm.name)
         .await
         .context("unable to check realm existence")?;
@@ -109,17 +116,17 @@ pub async fn seed(pool: &Pool, realm: &mut Realm<'_>) -> Result<()> {
         .await
         .context("unable to seed authorities")?;
 
-    let permission_map = seed_permissions(pool, &realm)
+    let permission_map = seed_permissions(pool, realm)
         .await
         .context("unable to seed permissions")?;
 
-    let role_map = seed_roles(pool, &realm, &permission_map)
+    let role_map = seed_roles(pool, realm, &permission_map)
         .await
         .context("unable to seed roles")?;
 
     let users = seed_users(
         pool,
-        &realm,
+        realm,
         authorities.first(),
         &permission_map,
         &role_map,
@@ -137,7 +144,7 @@ pub async fn realm_exists(pool: &Pool, realm_name: &str) -> Result<bool> {
     let realms = RealmService::new(pool)?;
 
     match realms.by_name(realm_name.to_owned()).await {
-        Ok(_) => Ok(false),
+        Ok(_) => Ok(true),
         Err(err) => {
             if let Some(sql_err) = err.downcast_ref::<sqlx::error::Error>() {
                 match sql_err {
@@ -151,17 +158,17 @@ pub async fn realm_exists(pool: &Pool, realm_name: &str) -> Result<bool> {
     }
 }
 
-pub async fn seed_realms(pool: &Pool, realm: &mut Realm<'_>) -> Result<Uuid> {
+pub async fn seed_realms(pool: &Pool, realm: &mut Realm) -> Result<Uuid> {
     use crate::realms::RealmService;
     let realms = RealmService::new(pool)?;
-    let created = realms.create(realm.into()).await?;
+    let created = realms.create(&Actor::system(), realm.into()).await?;
 
     realm.id = Some(created.id);
 
     Ok(created.id)
 }
 
-pub async fn seed_authorities(pool: &Pool, realm: &Realm<'_>) -> Result<Vec<AuthorityRow>> {
+pub async fn seed_authorities(pool: &Pool, realm: &Realm) -> Result<Vec<AuthorityRow>> {
     if realm.authorities.is_none() {
         return Ok(vec![]);
     }
@@ -170,7 +177,7 @@ pub async fn seed_authorities(pool: &Pool, realm: &Realm<'_>) -> Result<Vec<Auth
         .authorities
         .as_ref()
         .unwrap()
-        .into_iter()
+        .iter()
         .map(|authority| {
             let mut authority = authority.clone();
             authority.realm_id = realm.id;
@@ -184,7 +191,7 @@ pub async fn seed_authorities(pool: &Pool, realm: &Realm<'_>) -> Result<Vec<Auth
 
     for authority in authorities.into_iter() {
         let created = service
-            .create(authority)
+            .create(&Actor::system(), authority)
             .await
             .context("unable to create an authority in the seed")?;
 
@@ -196,12 +203,18 @@ pub async fn seed_authorities(pool: &Pool, realm: &Realm<'_>) -> Result<Vec<Auth
 
 pub async fn seed_permissions(
     pool: &Pool,
-    realm: &Realm<'_>,
+    realm: &Realm,
 ) -> Result<HashMap<String, Permission>> {
     use crate::permissions::permission_service::PermissionService;
 
     let mut to_create: HashSet<&str> = HashSet::new();
 
+    if let Some(permissions) = &realm.permissions {
+        for permission in permissions.iter() {
+            to_create.insert(permission);
+        }
+    }
+
     if let Some(users) = &realm.users {
         for user in users.iter() {
             if let Some(permissions) = &user.permissions {
@@ -222,7 +235,11 @@ pub async fn seed_permissions(
         }
     }
 
-    let to_create: Vec<PermissionRaw<'_>> = to_create.into_iter().map(|_str| _str.into()).collect();
+    // a `!` only decides the effect of the grant, the permission is the same
+    let to_create = to_create
+        .into_iter()
+        .map(|_str| parse_grant(_str).map(|(_, permission)| permission))
+        .collect::<std::result::Result<HashSet<PermissionRaw>, _>>()?;
 
     let service = PermissionService::new(pool)?;
 
@@ -230,10 +247,10 @@ pub async fn seed_permissions(
 
     for permission in to_create.into_iter() {
         let created = service
-            .create(PermissionCreate {
-                realm: permission.realm.to_string(),
-                resource: permission.resource.to_string(),
-                action: permission.action.to_string(),
+            .create(&Actor::system(), PermissionCreate {
+                realm: permission.realm.clone(),
+                resource: permission.resource.clone(),
+                action: permission.action.clone(),
                 realm_id: realm.id.unwrap(),
             })
             .await?;
@@ -246,7 +263,7 @@ pub async fn seed_permissions(
 
 pub async fn seed_roles(
     pool: &Pool,
-    realm: &Realm<'_>,
+    realm: &Realm,
     permission_map: &HashMap<String, Permission>,
 ) -> Result<HashMap<String, RoleRow>> {
     let mut role_map = HashMap::<String, RoleRow>::new();
@@ -256,7 +273,7 @@ pub async fn seed_roles(
 
         for role in roles.iter() {
             let created = service
-                .create(RoleCreate {
+                .create(&Actor::system(), RoleCreate {
                     realm_id: realm.id.unwrap(),
                     name: role.name.to_string(),
                 })
@@ -268,25 +285,50 @@ pub async fn seed_roles(
                 let service = GrantService::new(pool)?;
 
                 for name in permissions.iter() {
+                    let (effect, name) = parse_grant(name)?;
+
                     if let Some(permission) = permission_map.get(&name.to_string()) {
                         service
                             .create(
+                                &Actor::system(),
                                 realm.id.unwrap(),
-                                PermissionType::RolePermission(created.id, permission.id),
+                                PermissionType::RolePermission(created.id, permission.id, GrantOptions::with_effect(effect)),
                             )
                             .await?;
                     }
                 }
             }
         }
+
+        // a role can include one declared after it, so these wait until every
+        // role exists
+        let service = GrantService::new(pool)?;
+
+        for role in roles.iter() {
+            let parent = &role_map[&role.name];
+
+            for name in role.roles.iter().flatten() {
+                let child = role_map
+                    .get(name)
+                    .ok_or_else(|| Error::msg(format!("role {} includes unknown role {}", role.name, name)))?;
+
+                service
+                    .create(
+                        &Actor::system(),
+                        realm.id.unwrap(),
+                        PermissionType::RoleRole(parent.id, child.id),
+                    )
+                    .await?;
+            }
+        }
     }
 
     Ok(role_map)
 }
 
-pub async fn seed_users<'a>(
+pub async fn seed_users(
     pool: &Pool,
-    realm: &Realm<'_>,
+    realm: &Realm,
     authority: Option<&AuthorityRow>,
     permission_map: &HashMap<String, Permission>,
     role_map: &HashMap<String, RoleRow>,
@@ -297,14 +339,31 @@ pub async fn seed_users<'a>(
         let service = UserService::new(pool)?;
 
         for user in users.iter() {
-            let created = if let Some(authority) = authority {
-                let service: UsernamePasswordService = AuthorityInterface::new(pool)?;
-
-                let params = (authority.client_key, user).into();
-
-                service.register(authority.client_key, params).await?
-            } else {
-                service.create(user.into()).await?
+            let created = match (authority, &user.password, &user.credential) {
+                (Some(authority), Some(password), _) => {
+                    let service: UsernamePasswordService = AuthorityInterface::new(pool)?;
+
+                    let params = (authority.client_key, user, password).into();
+
+                    service.register(&Actor::system(), authority.client_key, params).await?
+                },
+                // an exported user: their digest is kept as it was, so they
+                // sign in with the same password
+                (Some(authority), None, Some(credential)) => {
+                    let created = service.create(&Actor::system(), user.into()).await?;
+
+                    AuthorityService::create_user_authority_query(UserAuthorityCreate {
+                        user_id: created.id,
+                        authority_id: authority.id,
+                        realm_id: realm.id.unwrap(),
+                        params: credential.clone(),
+                    })
+                        .fetch_one(pool)
+                        .await?;
+
+                    created
+                },
+                _ => service.create(&Actor::system(), user.into()).await?,
             };
 
             user_list.push(created.clone());
@@ -313,11 +372,14 @@ pub async fn seed_users<'a>(
 
             if let Some(permissions) = &user.permissions {
                 for name in permissions.iter() {
+                    let (effect, name) = parse_grant(name)?;
+
                     if let Some(permission) = permission_map.get(&name.to_string()) {
                         service
                             .create(
+                                &Actor::system(),
                                 realm.id.unwrap(),
-                                PermissionType::UserPermission(created.id, permission.id),
+                                PermissionType::UserPermission(created.id, permission.id, GrantOptions::with_effect(effect)),
                             )
                             .await?;
                     }
@@ -329,8 +391,9 @@ pub async fn seed_users<'a>(
                     if let Some(role) = role_map.get(&role.to_string()) {
                         service
                             .create(
+                                &Actor::system(),
                                 realm.id.unwrap(),
-                                PermissionType::UserRole(created.id, role.id),
+                                PermissionType::UserRole(created.id, role.id, GrantWindow::default()),
                             )
                             .await?;
                     }
@@ -342,102 +405,179 @@ pub async fn seed_users<'a>(
     Ok(user_list)
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Realm<'a> {
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Realm {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub id: Option<Uuid>,
-    pub name: &'a str,
-    pub authorities: Option<Vec<Authority<'a>>>,
-    pub users: Option<Vec<User<'a>>>,
-    pub roles: Option<Vec<Role<'a>>>,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_role_depth: Option<i32>,
+    // permissions created whether or not anything here grants them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<Vec<String>>,
+    pub authorities: Option<Vec<Authority>>,
+    pub users: Option<Vec<User>>,
+    pub roles: Option<Vec<Role>>,
 }
 
-impl From<&mut Realm<'_>> for RealmCreate {
+impl Realm {
+    // the same realm written one way, so two descriptions of it compare equal:
+    // names sorted, defaults filled in and every permission listed
+    pub fn normalized(mut self) -> Self {
+        self.id = None;
+
+        let mut permissions: BTreeSet<String> = self.permissions.take().into_iter().flatten().collect();
+
+        for authority in self.authorities.iter_mut().flatten() {
+            authority.realm_id = None;
+            authority.status.get_or_insert_with(|| "enabled".to_string());
+        }
+
+        if let Some(users) = self.users.as_mut() {
+            for user in users.iter_mut() {
+                user.roles = sorted(user.roles.take());
+                user.permissions = sorted(user.permissions.take());
+                permissions.extend(user.permissions.iter().flatten().map(|grant| ungranted(grant)));
+            }
+
+            users.sort_by(|a, b| a.username.cmp(&b.username));
+        }
+
+        if let Some(roles) = self.roles.as_mut() {
+            for role in roles.iter_mut() {
+                role.roles = sorted(role.roles.take());
+                role.permissions = sorted(role.permissions.take());
+                permissions.extend(role.permissions.iter().flatten().map(|grant| ungranted(grant)));
+            }
+
+            roles.sort_by(|a, b| a.name.cmp(&b.name));
+        }
+
+        self.authorities = self.authorities.filter(|authorities| !authorities.is_empty());
+        self.users = self.users.filter(|users| !users.is_empty());
+        self.roles = self.roles.filter(|roles| !roles.is_empty());
+        self.permissions = sorted(Some(permissions.into_iter().collect()));
+
+        self
+    }
+
+    // without anything that would let someone sign in: passwords, digests
+    // and the authorities' salts
+    pub fn redacted(mut self) -> Self {
+        for authority in self.authorities.iter_mut().flatten() {
+            authority.params = without_secrets(&authority.params);
+        }
+
+        for user in self.users.iter_mut().flatten() {
+            user.password = None;
+            user.credential = None;
+        }
+
+        self
+    }
+}
+
+fn sorted(names: Option<Vec<String>>) -> Option<Vec<String>> {
+    let names: BTreeSet<String> = names.into_iter().flatten().collect();
+
+    if names.is_empty() {
+        return None;
+    }
+
+    Some(names.into_iter().collect())
+}
+
+// the permission a grant is for, whatever its effect
+pub fn ungranted(grant: &str) -> String {
+    grant.strip_prefix(DENY_PREFIX).unwrap_or(grant).to_string()
+}
+
+impl From<&mut Realm> for RealmCreate {
     fn from(from: &mut Realm) -> Self {
         Self {
             name: from.name.to_string(),
+            max_role_depth: from.max_role_depth,
         }
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
-pub struct Authority<'a> {
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Authority {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub realm_id: Option<Uuid>,
-    pub name: &'a str,
+    pub name: String,
     pub client_key: Uuid,
-    pub status: Option<&'a str>,
+    pub status: Option<String>,
     pub strategy: StrategyType,
     pub params: JsonValue,
 }
 
-impl<'a> From<Authority<'a>> for AuthorityCreate {
+impl From<Authority> for AuthorityCreate {
     fn from(from: Authority) -> AuthorityCreate {
-        let from = from.clone();
-
         AuthorityCreate {
             realm_id: from.realm_id.unwrap(),
-            name: from.name.to_string(),
+            name: from.name,
             client_key: Some(from.client_key),
-            status: from
-                .status
-                .map_or("enabled".to_string(), |status| status.to_string()),
+            status: from.status.unwrap_or_else(|| "enabled".to_string()),
             strategy: from.strategy,
             params: from.params,
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct User<'a> {
-    username: &'a str,
-    password: &'a str,
-    email: Option<&'a str>,
-    first_name: Option<&'a str>,
-    last_name: Option<&'a str>,
-    profile: JsonValue,
-    status: &'a str,
-    kind: &'a str,
-    roles: Option<Vec<&'a str>>,
-    permissions: Option<Vec<&'a str>>,
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct User {
+    pub username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    // what the realm's first authority keeps for the user, e.g. their
+    // password digest; used when there's no password
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<JsonValue>,
+    pub email: Option<String>,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub profile: JsonValue,
+    pub status: String,
+    pub kind: String,
+    pub roles: Option<Vec<String>>,
+    pub permissions: Option<Vec<String>>,
 }
 
-impl From<&User<'_>> for UserCreate {
+impl From<&User> for UserCreate {
     fn from(from: &User) -> Self {
         Self {
-            username: from.username.to_string(),
-            email: from.email.map_or(None, |email| Some(email.to_string())),
-            first_name: from
-                .first_name
-                .map_or(None, |first_name| Some(first_name.to_string())),
-            last_name: from
-                .last_name
-                .map_or(None, |last_name| Some(last_name.to_string())),
+            username: from.username.clone(),
+            email: from.email.clone(),
+            first_name: from.first_name.clone(),
+            last_name: from.last_name.clone(),
             profile: from.profile.clone(),
-            status: from.status.to_string(),
-            kind: from.kind.to_string(),
+            status: from.status.clone(),
+            kind: from.kind.clone(),
         }
     }
 }
 
-impl From<(Uuid, &User<'_>)> for UsernamePasswordRegisterParams {
-    fn from(from: (Uuid, &User)) -> Self {
-        let (client_key, user) = from;
+impl From<(Uuid, &User, &String)> for UsernamePasswordRegisterParams {
+    fn from(from: (Uuid, &User, &String)) -> Self {
+        let (client_key, user, password) = from;
 
         Self {
             client_key,
-            username: user.username.to_string(),
-            password: user.password.to_string(),
-            email: user.email.map_or(None, |s| Some(s.to_string())),
-            first_name: user.first_name.map_or(None, |s| Some(s.to_string())),
-            last_name: user.last_name.map_or(None, |s| Some(s.to_string())),
-            status: Some(user.status.to_string()),
+            username: user.username.clone(),
+            password: password.clone(),
+            email: user.email.clone(),
+            first_name: user.first_name.clone(),
+            last_name: user.last_name.clone(),
+            status: Some(user.status.clone()),
             profile: user.profile.clone(),
         }
     }
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct Role<'a> {
-    name: &'a str,
-    roles: Option<Vec<&'a str>>,
-    permissions: Option<Vec<&'a str>>,
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Role {
+    pub name: String,
+    pub roles: Option<Vec<String>>,
+    pub permissions: Option<Vec<String>>,
 }
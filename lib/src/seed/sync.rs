@@ -0,0 +1,955 @@
+use sqlx::{Postgres, Transaction};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use uuid::Uuid;
+
+use super::export::{ExportOptions, ExportService};
+use super::json::{ungranted, Authority, Realm, Role, User};
+use crate::audit::Actor;
+use crate::authorities::strategies::username_password::{AuthService as UsernamePasswordService, RegisterParams};
+use crate::authorities::strategies::{Authority as AuthorityInterface, StrategyType};
+use crate::authorities::{
+    Authority as AuthorityRow, AuthorityCreate, AuthorityService, AuthorityUpdate, UserAuthorityCreate, SECRET_PARAMS,
+};
+use crate::db::patch::{Patch, Precondition};
+use crate::db::pg::Pool;
+use crate::grants::{GrantOptions, GrantService, GrantWindow, PermissionType};
+use crate::permissions::permission::{parse_grant, Permission as PermissionRaw};
+use crate::permissions::permission_service::{PermissionCreate, PermissionService};
+use crate::realms::{RealmCreate, RealmService, RealmUpdate};
+use crate::result::{Context, Error, Result};
+use crate::roles::{RoleCreate, RoleService};
+use crate::users::{UserService, UserUpdate};
+
+#[derive(Clone, Debug, Default)]
+pub struct SyncOptions {
+    // remove the roles, permissions, authorities and grants the file leaves out
+    pub prune: bool,
+    // manage the users in the file too. users the file leaves out are never
+    // removed, pruning or not: they aren't the realm's alone
+    pub users: bool,
+}
+
+// one step of bringing a realm in line with its file, in the order they're
+// applied. passwords and credentials are only used for new users, and
+// grants with a condition or a window are left as they are
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    CreateRealm { max_role_depth: Option<i32> },
+    UpdateRealm { max_role_depth: i32 },
+    CreateAuthority(Authority),
+    UpdateAuthority { authority: Authority, fields: Vec<&'static str> },
+    CreatePermission(String),
+    CreateRole(String),
+    RevokeRolePermission { role: String, grant: String },
+    ExcludeRole { role: String, child: String },
+    RevokeUserRole { username: String, role: String },
+    RevokeUserPermission { username: String, grant: String },
+    GrantRolePermission { role: String, grant: String },
+    IncludeRole { role: String, child: String },
+    // `authority` is the one a password or credential is kept by
+    CreateUser { user: User, authority: Option<String> },
+    UpdateUser { user: User, fields: Vec<&'static str> },
+    GrantUserRole { username: String, role: String },
+    GrantUserPermission { username: String, grant: String },
+    DeleteRole(String),
+    DeletePermission(String),
+    DeleteAuthority(String),
+}
+
+impl Change {
+    fn symbol(&self) -> char {
+        use Change::*;
+
+        match self {
+            UpdateRealm { .. } | UpdateAuthority { .. } | UpdateUser { .. } => '~',
+            RevokeRolePermission { .. } | ExcludeRole { .. } | RevokeUserRole { .. } | RevokeUserPermission { .. } => '-',
+            DeleteRole(_) | DeletePermission(_) | DeleteAuthority(_) => '-',
+            _ => '+',
+        }
+    }
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Change::*;
+
+        match self {
+            CreateRealm { .. } => write!(f, "realm"),
+            UpdateRealm { max_role_depth } => write!(f, "realm (max_role_depth = {})", max_role_depth),
+            CreateAuthority(authority) => write!(f, "authority {}", authority.name),
+            UpdateAuthority { authority, fields } => write!(f, "authority {} ({})", authority.name, fields.join(", ")),
+            CreatePermission(name) | DeletePermission(name) => write!(f, "permission {}", name),
+            CreateRole(name) | DeleteRole(name) => write!(f, "role {}", name),
+            GrantRolePermission { role, grant } | RevokeRolePermission { role, grant } => write!(f, "role {} grants {}", role, grant),
+            IncludeRole { role, child } | ExcludeRole { role, child } => write!(f, "role {} includes {}", role, child),
+            CreateUser { user, .. } => write!(f, "user {}", user.username),
+            UpdateUser { user, fields } => write!(f, "user {} ({})", user.username, fields.join(", ")),
+            GrantUserRole { username, role } | RevokeUserRole { username, role } => write!(f, "user {} has role {}", username, role),
+            GrantUserPermission { username, grant } | RevokeUserPermission { username, grant } => write!(f, "user {} grants {}", username, grant),
+            DeleteAuthority(name) => write!(f, "authority {}", name),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Plan {
+    pub realm: String,
+    pub changes: Vec<Change>,
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "realm {} is up to date", self.realm);
+        }
+
+        writeln!(f, "realm {}", self.realm)?;
+
+        let mut counts = BTreeMap::<char, usize>::new();
+
+        for change in self.changes.iter() {
+            *counts.entry(change.symbol()).or_default() += 1;
+
+            writeln!(f, "  {} {}", change.symbol(), change)?;
+        }
+
+        writeln!(
+            f,
+            "{} to add, {} to change, {} to remove",
+            counts.get(&'+').unwrap_or(&0),
+            counts.get(&'~').unwrap_or(&0),
+            counts.get(&'-').unwrap_or(&0),
+        )
+    }
+}
+
+// what it takes to turn `current`, as exported, into `desired`; none for a
+// realm that doesn't exist yet
+pub fn plan(current: Option<&Realm>, desired: &Realm, options: &SyncOptions) -> Result<Plan> {
+    let desired = desired.clone().normalized();
+    let exists = current.is_some();
+    let current = current.cloned().unwrap_or_default().normalized();
+
+    let mut changes = Changes::default();
+
+    if !exists {
+        changes.realm.push(Change::CreateRealm { max_role_depth: desired.max_role_depth });
+    } else if let Some(max_role_depth) = desired.max_role_depth {
+        if current.max_role_depth != Some(max_role_depth) {
+            changes.realm.push(Change::UpdateRealm { max_role_depth });
+        }
+    }
+
+    plan_authorities(&mut changes, &current, &desired, options)?;
+    plan_permissions(&mut changes, &current, &desired, options);
+    plan_roles(&mut changes, &current, &desired, options);
+
+    if options.users {
+        plan_users(&mut changes, &current, &desired, options);
+    }
+
+    Ok(Plan {
+        realm: desired.name,
+        changes: changes.into_vec(),
+    })
+}
+
+// changes by the step they're applied in: revokes go before grants so an
+// effect can flip and a hierarchy can be rearranged without a cycle, and
+// deletes go last, after every grant that moved off them
+#[derive(Default)]
+struct Changes {
+    realm: Vec<Change>,
+    authorities: Vec<Change>,
+    permissions: Vec<Change>,
+    roles: Vec<Change>,
+    revokes: Vec<Change>,
+    role_grants: Vec<Change>,
+    users: Vec<Change>,
+    user_grants: Vec<Change>,
+    deletes: Vec<Change>,
+}
+
+impl Changes {
+    fn into_vec(self) -> Vec<Change> {
+        vec![
+            self.realm,
+            self.authorities,
+            self.permissions,
+            self.roles,
+            self.revokes,
+            self.role_grants,
+            self.users,
+            self.user_grants,
+            self.deletes,
+        ]
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+}
+
+fn plan_authorities(changes: &mut Changes, current: &Realm, desired: &Realm, options: &SyncOptions) -> Result<()> {
+    let existing: HashMap<&str, &Authority> = current.authorities.iter().flatten().map(|authority| (authority.name.as_str(), authority)).collect();
+    let declared: BTreeSet<&str> = desired.authorities.iter().flatten().map(|authority| authority.name.as_str()).collect();
+
+    for authority in desired.authorities.iter().flatten() {
+        let before = match existing.get(authority.name.as_str()) {
+            Some(before) => before,
+            None => {
+                changes.authorities.push(Change::CreateAuthority(authority.clone()));
+                continue;
+            },
+        };
+
+        if before.strategy != authority.strategy {
+            return Err(Error::msg(format!("authority {} can't change its strategy", authority.name)));
+        }
+
+        // secrets stay out of files kept in git, so one that isn't given
+        // keeps the value it has
+        let mut authority = authority.clone();
+
+        if let (Some(params), Some(existing)) = (authority.params.as_object_mut(), before.params.as_object()) {
+            for key in SECRET_PARAMS.iter() {
+                if let (false, Some(value)) = (params.contains_key(*key), existing.get(*key)) {
+                    params.insert(key.to_string(), value.clone());
+                }
+            }
+        }
+
+        let mut fields = vec![];
+
+        if before.client_key != authority.client_key {
+            fields.push("client_key");
+        }
+
+        if before.status != authority.status {
+            fields.push("status");
+        }
+
+        if before.params != authority.params {
+            fields.push("params");
+        }
+
+        if !fields.is_empty() {
+            changes.authorities.push(Change::UpdateAuthority { authority, fields });
+        }
+    }
+
+    if options.prune {
+        let mut undeclared: Vec<&&str> = existing.keys().filter(|name| !declared.contains(*name)).collect();
+        undeclared.sort();
+
+        for name in undeclared {
+            changes.deletes.push(Change::DeleteAuthority(name.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+fn plan_permissions(changes: &mut Changes, current: &Realm, desired: &Realm, options: &SyncOptions) {
+    let existing: BTreeSet<&String> = current.permissions.iter().flatten().collect();
+    let declared: BTreeSet<&String> = desired.permissions.iter().flatten().collect();
+
+    for name in declared.difference(&existing) {
+        changes.permissions.push(Change::CreatePermission(name.to_string()));
+    }
+
+    if options.prune {
+        for name in existing.difference(&declared) {
+            changes.deletes.push(Change::DeletePermission(name.to_string()));
+        }
+    }
+}
+
+fn plan_roles(changes: &mut Changes, current: &Realm, desired: &Realm, options: &SyncOptions) {
+    let existing: HashMap<&str, &Role> = current.roles.iter().flatten().map(|role| (role.name.as_str(), role)).collect();
+    let declared: BTreeSet<&str> = desired.roles.iter().flatten().map(|role| role.name.as_str()).collect();
+    let empty = Role { name: String::new(), roles: None, permissions: None };
+
+    for role in desired.roles.iter().flatten() {
+        let before = existing.get(role.name.as_str()).copied().unwrap_or_else(|| {
+            changes.roles.push(Change::CreateRole(role.name.clone()));
+            &empty
+        });
+
+        let (revoked, granted) = diff_grants(&before.permissions, &role.permissions, options.prune);
+
+        for grant in revoked {
+            changes.revokes.push(Change::RevokeRolePermission { role: role.name.clone(), grant });
+        }
+
+        for grant in granted {
+            changes.role_grants.push(Change::GrantRolePermission { role: role.name.clone(), grant });
+        }
+
+        let (excluded, included) = diff_names(&before.roles, &role.roles, options.prune);
+
+        for child in excluded {
+            changes.revokes.push(Change::ExcludeRole { role: role.name.clone(), child });
+        }
+
+        for child in included {
+            changes.role_grants.push(Change::IncludeRole { role: role.name.clone(), child });
+        }
+    }
+
+    if options.prune {
+        let mut undeclared: Vec<&&str> = existing.keys().filter(|name| !declared.contains(*name)).collect();
+        undeclared.sort();
+
+        for name in undeclared {
+            changes.deletes.push(Change::DeleteRole(name.to_string()));
+        }
+    }
+}
+
+fn plan_users(changes: &mut Changes, current: &Realm, desired: &Realm, options: &SyncOptions) {
+    let existing: HashMap<&str, &User> = current.users.iter().flatten().map(|user| (user.username.as_str(), user)).collect();
+    let authority = desired.authorities.iter().flatten().next().map(|authority| authority.name.clone());
+
+    for user in desired.users.iter().flatten() {
+        let (before_roles, before_permissions) = match existing.get(user.username.as_str()) {
+            Some(before) => {
+                let mut fields = vec![];
+
+                if before.email != user.email {
+                    fields.push("email");
+                }
+
+                if before.first_name != user.first_name {
+                    fields.push("first_name");
+                }
+
+                if before.last_name != user.last_name {
+                    fields.push("last_name");
+                }
+
+                if before.profile != user.profile {
+                    fields.push("profile");
+                }
+
+                if before.status != user.status {
+                    fields.push("status");
+                }
+
+                if !fields.is_empty() {
+                    changes.users.push(Change::UpdateUser { user: user.clone(), fields });
+                }
+
+                (before.roles.clone(), before.permissions.clone())
+            },
+            None => {
+                changes.users.push(Change::CreateUser { user: user.clone(), authority: authority.clone() });
+
+                (None, None)
+            },
+        };
+
+        let (removed, added) = diff_names(&before_roles, &user.roles, options.prune);
+
+        for role in removed {
+            changes.revokes.push(Change::RevokeUserRole { username: user.username.clone(), role });
+        }
+
+        for role in added {
+            changes.user_grants.push(Change::GrantUserRole { username: user.username.clone(), role });
+        }
+
+        let (revoked, granted) = diff_grants(&before_permissions, &user.permissions, options.prune);
+
+        for grant in revoked {
+            changes.revokes.push(Change::RevokeUserPermission { username: user.username.clone(), grant });
+        }
+
+        for grant in granted {
+            changes.user_grants.push(Change::GrantUserPermission { username: user.username.clone(), grant });
+        }
+    }
+}
+
+// (taken away, added) to get from one list of names to the other
+fn diff_names(current: &Option<Vec<String>>, desired: &Option<Vec<String>>, prune: bool) -> (Vec<String>, Vec<String>) {
+    let current: BTreeSet<&String> = current.iter().flatten().collect();
+    let desired: BTreeSet<&String> = desired.iter().flatten().collect();
+
+    let added = desired.difference(&current).map(|name| name.to_string()).collect();
+
+    if !prune {
+        return (vec![], added);
+    }
+
+    (current.difference(&desired).map(|name| name.to_string()).collect(), added)
+}
+
+// like `diff_names`, but a grant whose effect flips is taken away and added
+// again whether or not anything is pruned
+fn diff_grants(current: &Option<Vec<String>>, desired: &Option<Vec<String>>, prune: bool) -> (Vec<String>, Vec<String>) {
+    let current: BTreeMap<String, &String> = current.iter().flatten().map(|grant| (ungranted(grant), grant)).collect();
+    let desired: BTreeMap<String, &String> = desired.iter().flatten().map(|grant| (ungranted(grant), grant)).collect();
+
+    let mut revoked = vec![];
+    let mut granted = vec![];
+
+    for (permission, grant) in desired.iter() {
+        match current.get(permission) {
+            Some(before) if before == grant => {},
+            Some(before) => {
+                revoked.push(before.to_string());
+                granted.push(grant.to_string());
+            },
+            None => granted.push(grant.to_string()),
+        }
+    }
+
+    if prune {
+        for (permission, grant) in current.iter() {
+            if !desired.contains_key(permission) {
+                revoked.push(grant.to_string());
+            }
+        }
+    }
+
+    (revoked, granted)
+}
+
+#[derive(Clone)]
+pub struct SyncService {
+    pool: Pool,
+}
+
+impl SyncService {
+    pub fn new(pool: &Pool) -> Result<Self> {
+        Ok(Self { pool: pool.to_owned() })
+    }
+
+    pub async fn plan(&self, desired: &Realm, options: &SyncOptions) -> Result<Plan> {
+        let current = match RealmService::new(&self.pool)?.by_name(desired.name.clone()).await {
+            Ok(realm) => {
+                let exported = ExportService::new(&self.pool)?
+                    .export(realm.id, &ExportOptions { secrets: true })
+                    .await?;
+
+                Some(exported)
+            },
+            Err(err) if matches!(err.downcast_ref::<sqlx::Error>(), Some(sqlx::Error::RowNotFound)) => None,
+            Err(err) => return Err(err),
+        };
+
+        plan(current.as_ref(), desired, options)
+    }
+
+    // all or nothing. a plan made against a realm that's since changed fails
+    // rather than half applies, and can be made again
+    pub async fn apply(&self, actor: &Actor, plan: &Plan) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        let mut ids = Ids::load(&mut tx, &plan.realm).await?;
+
+        for change in plan.changes.iter() {
+            self.apply_change(&mut tx, actor, &mut ids, change)
+                .await
+                .with_context(|| format!("unable to apply {} {}", change.symbol(), change))?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn apply_change(&self, tx: &mut Transaction<'_, Postgres>, actor: &Actor, ids: &mut Ids, change: &Change) -> Result<()> {
+        use Change::*;
+
+        match change {
+            CreateRealm { max_role_depth } => {
+                let realm = RealmService::create_in(&mut *tx, actor, RealmCreate {
+                    name: ids.realm.clone(),
+                    max_role_depth: *max_role_depth,
+                })
+                    .await?;
+
+                RealmService::create_key_pair_in(&mut *tx, realm.id).await?;
+
+                ids.realm_id = Some(realm.id);
+            },
+            UpdateRealm { max_role_depth } => {
+                let update = RealmUpdate {
+                    max_role_depth: Patch::Value(*max_role_depth),
+                    ..RealmUpdate::default()
+                };
+
+                RealmService::update_in(&mut *tx, actor, ids.realm_id()?, update, &Precondition::any()).await?;
+            },
+            CreateAuthority(authority) => {
+                let mut authority = authority.clone();
+                authority.realm_id = Some(ids.realm_id()?);
+
+                let created = AuthorityService::create_in(&mut *tx, actor, AuthorityCreate::from(authority)).await?;
+
+                ids.authorities.insert(created.name.clone(), created);
+            },
+            UpdateAuthority { authority, .. } => {
+                let update = AuthorityUpdate {
+                    client_key: Patch::Value(authority.client_key),
+                    status: authority.status.clone().into(),
+                    params: Patch::Value(authority.params.clone()),
+                    ..AuthorityUpdate::default()
+                };
+
+                let updated = AuthorityService::update_in(&mut *tx, actor, ids.authority(&authority.name)?.id, update, &Precondition::any()).await?;
+
+                ids.authorities.insert(updated.name.clone(), updated);
+            },
+            CreatePermission(name) => {
+                let permission: PermissionRaw = name.parse()?;
+
+                let created = PermissionService::create_in(&mut *tx, actor, PermissionCreate {
+                    realm: permission.realm,
+                    resource: permission.resource,
+                    action: permission.action,
+                    realm_id: ids.realm_id()?,
+                })
+                    .await?;
+
+                ids.permissions.insert(name.clone(), created.id);
+            },
+            CreateRole(name) => {
+                let created = RoleService::create_in(&mut *tx, actor, RoleCreate {
+                    realm_id: ids.realm_id()?,
+                    name: name.clone(),
+                })
+                    .await?;
+
+                ids.roles.insert(name.clone(), created.id);
+            },
+            RevokeRolePermission { role, grant } => {
+                let (_, permission) = parse_grant(grant)?;
+                let grant = PermissionType::RolePermission(ids.role(role)?, ids.permission(&permission)?, GrantOptions::allow());
+
+                GrantService::revoke_in(&mut *tx, actor, ids.realm_id()?, grant).await?;
+            },
+            ExcludeRole { role, child } => {
+                let grant = PermissionType::RoleRole(ids.role(role)?, ids.role(child)?);
+
+                GrantService::revoke_in(&mut *tx, actor, ids.realm_id()?, grant).await?;
+            },
+            RevokeUserRole { username, role } => {
+                let user_id = ids.user(&mut *tx, username).await?;
+                let grant = PermissionType::UserRole(user_id, ids.role(role)?, GrantWindow::default());
+
+                GrantService::revoke_in(&mut *tx, actor, ids.realm_id()?, grant).await?;
+            },
+            RevokeUserPermission { username, grant } => {
+                let (_, permission) = parse_grant(grant)?;
+                let user_id = ids.user(&mut *tx, username).await?;
+                let grant = PermissionType::UserPermission(user_id, ids.permission(&permission)?, GrantOptions::allow());
+
+                GrantService::revoke_in(&mut *tx, actor, ids.realm_id()?, grant).await?;
+            },
+            GrantRolePermission { role, grant } => {
+                let (effect, permission) = parse_grant(grant)?;
+                let grant = PermissionType::RolePermission(ids.role(role)?, ids.permission(&permission)?, GrantOptions::with_effect(effect));
+
+                GrantService::create_in(&mut *tx, actor, ids.realm_id()?, grant).await?;
+            },
+            IncludeRole { role, child } => {
+                let grant = PermissionType::RoleRole(ids.role(role)?, ids.role(child)?);
+
+                GrantService::create_in(&mut *tx, actor, ids.realm_id()?, grant).await?;
+            },
+            CreateUser { user, authority } => {
+                self.create_user(&mut *tx, actor, ids, user, authority.as_deref()).await?;
+            },
+            UpdateUser { user, .. } => {
+                let update = UserUpdate {
+                    email: user.email.clone().into(),
+                    first_name: user.first_name.clone().into(),
+                    last_name: user.last_name.clone().into(),
+                    profile: Patch::Value(user.profile.clone()),
+                    status: Patch::Value(user.status.clone()),
+                    ..UserUpdate::default()
+                };
+
+                let user_id = ids.user(&mut *tx, &user.username).await?;
+
+                UserService::update_in(&mut *tx, actor, user_id, update, &Precondition::any()).await?;
+            },
+            GrantUserRole { username, role } => {
+                let user_id = ids.user(&mut *tx, username).await?;
+                let grant = PermissionType::UserRole(user_id, ids.role(role)?, GrantWindow::default());
+
+                GrantService::create_in(&mut *tx, actor, ids.realm_id()?, grant).await?;
+            },
+            GrantUserPermission { username, grant } => {
+                let (effect, permission) = parse_grant(grant)?;
+                let user_id = ids.user(&mut *tx, username).await?;
+                let grant = PermissionType::UserPermission(user_id, ids.permission(&permission)?, GrantOptions::with_effect(effect));
+
+                GrantService::create_in(&mut *tx, actor, ids.realm_id()?, grant).await?;
+            },
+            DeleteRole(name) => {
+                RoleService::delete_in(&mut *tx, actor, ids.role(name)?).await?;
+            },
+            DeletePermission(name) => {
+                let permission: PermissionRaw = name.parse()?;
+
+                PermissionService::delete_in(&mut *tx, actor, ids.permission(&permission)?).await?;
+            },
+            DeleteAuthority(name) => {
+                AuthorityService::delete_in(&mut *tx, actor, ids.authority(name)?.id).await?;
+            },
+        }
+
+        Ok(())
+    }
+
+    // as `seed` does it: a password is hashed the way the authority would on
+    // registration, an exported credential is kept as it is
+    async fn create_user(
+        &self,
+        tx: &mut Transaction<'_, Postgres>,
+        actor: &Actor,
+        ids: &mut Ids,
+        user: &User,
+        authority: Option<&str>,
+    ) -> Result<()> {
+        if ids.find_user(&mut *tx, &user.username).await?.is_some() {
+            return Err(Error::msg(format!("a user named {} already exists outside the realm", user.username)));
+        }
+
+        let authority = match authority {
+            Some(name) => Some(ids.authority(name)?),
+            None => None,
+        };
+
+        let params = match (authority, &user.password, &user.credential) {
+            (Some(authority), Some(password), _) => {
+                if authority.strategy != StrategyType::UsernamePassword {
+                    return Err(Error::msg(format!("authority {} doesn't take passwords", authority.name)));
+                }
+
+                let service: UsernamePasswordService = AuthorityInterface::new(&self.pool)?;
+
+                let (_, params) = service.user_values(authority, RegisterParams {
+                    client_key: authority.client_key,
+                    username: user.username.clone(),
+                    password: password.clone(),
+                    email: user.email.clone(),
+                    first_name: user.first_name.clone(),
+                    last_name: user.last_name.clone(),
+                    profile: user.profile.clone(),
+                    status: Some(user.status.clone()),
+                })?;
+
+                Some(params)
+            },
+            (Some(_), None, Some(credential)) => Some(credential.clone()),
+            _ => None,
+        };
+
+        let created = UserService::create_in(&mut *tx, actor, user.into()).await?;
+
+        if let (Some(authority), Some(params)) = (authority, params) {
+            AuthorityService::create_user_authority_query(UserAuthorityCreate {
+                user_id: created.id,
+                authority_id: authority.id,
+                realm_id: authority.realm_id,
+                params,
+            })
+                .fetch_one(&mut *tx)
+                .await?;
+        }
+
+        ids.users.insert(created.username.clone(), created.id);
+
+        Ok(())
+    }
+}
+
+// what the changes in a plan refer to by name, as of the transaction
+#[derive(Default)]
+struct Ids {
+    realm: String,
+    realm_id: Option<Uuid>,
+    authorities: HashMap<String, AuthorityRow>,
+    permissions: HashMap<String, Uuid>,
+    roles: HashMap<String, Uuid>,
+    users: HashMap<String, Uuid>,
+}
+
+impl Ids {
+    async fn load(tx: &mut Transaction<'_, Postgres>, realm: &str) -> Result<Self> {
+        // locked so two syncs of one realm can't interleave
+        let realm_id: Option<(Uuid,)> = sqlx::query_as(r#"
+            SELECT id FROM realms
+            WHERE name = $1
+            FOR UPDATE
+        "#)
+            .bind(realm)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+        let mut ids = Self {
+            realm: realm.to_string(),
+            realm_id: realm_id.map(|(id,)| id),
+            ..Self::default()
+        };
+
+        let realm_id = match ids.realm_id {
+            Some(realm_id) => realm_id,
+            None => return Ok(ids),
+        };
+
+        let authorities = sqlx::query_as::<_, AuthorityRow>(r#"
+            SELECT * FROM authorities
+            WHERE realm_id = $1
+        "#)
+            .bind(realm_id)
+            .fetch_all(&mut *tx)
+            .await?;
+
+        let permissions: Vec<(String, Uuid)> = sqlx::query_as(r#"
+            SELECT realm || ':' || resource || ':' || action, id FROM permissions
+            WHERE realm_id = $1
+        "#)
+            .bind(realm_id)
+            .fetch_all(&mut *tx)
+            .await?;
+
+        let roles: Vec<(String, Uuid)> = sqlx::query_as(r#"
+            SELECT name, id FROM roles
+            WHERE realm_id = $1
+        "#)
+            .bind(realm_id)
+            .fetch_all(&mut *tx)
+            .await?;
+
+        ids.authorities = authorities.into_iter().map(|authority| (authority.name.clone(), authority)).collect();
+        ids.permissions = permissions.into_iter().collect();
+        ids.roles = roles.into_iter().collect();
+
+        Ok(ids)
+    }
+
+    fn realm_id(&self) -> Result<Uuid> {
+        self.realm_id.ok_or_else(|| Error::msg(format!("realm {} doesn't exist", self.realm)))
+    }
+
+    fn authority(&self, name: &str) -> Result<&AuthorityRow> {
+        self.authorities.get(name).ok_or_else(|| Error::msg(format!("unknown authority {}", name)))
+    }
+
+    fn permission(&self, permission: &PermissionRaw) -> Result<Uuid> {
+        let name = permission.to_string();
+
+        self.permissions.get(&name).copied().ok_or_else(|| Error::msg(format!("unknown permission {}", name)))
+    }
+
+    fn role(&self, name: &str) -> Result<Uuid> {
+        self.roles.get(name).copied().ok_or_else(|| Error::msg(format!("unknown role {}", name)))
+    }
+
+    async fn user(&mut self, tx: &mut Transaction<'_, Postgres>, username: &str) -> Result<Uuid> {
+        self.find_user(tx, username)
+            .await?
+            .ok_or_else(|| Error::msg(format!("unknown user {}", username)))
+    }
+
+    async fn find_user(&mut self, tx: &mut Transaction<'_, Postgres>, username: &str) -> Result<Option<Uuid>> {
+        if let Some(id) = self.users.get(username) {
+            return Ok(Some(*id));
+        }
+
+        let user: Option<(Uuid,)> = sqlx::query_as(r#"
+            SELECT id FROM users
+            WHERE username = $1
+            AND deleted_at IS NULL
+        "#)
+            .bind(username)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+        if let Some((id,)) = user {
+            self.users.insert(username.to_string(), id);
+        }
+
+        Ok(user.map(|(id,)| id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seed::testing;
+    use serde_json::json;
+
+    fn realm() -> Realm {
+        serde_json::from_value(json!({
+            "name": "acme",
+            "max_role_depth": 8,
+            "authorities": [{
+                "name": "acme:username_password",
+                "client_key": "6f1d4a52-8f0e-4a3c-9d33-58e0b7f0c7a1",
+                "status": "enabled",
+                "strategy": "username_password",
+                "params": { "password_salt": "pepper", "lockout": 5 }
+            }],
+            "users": [{
+                "username": "wile",
+                "email": "wile@acme.test",
+                "first_name": "Wile",
+                "last_name": "Coyote",
+                "profile": {},
+                "status": "enabled",
+                "kind": "human",
+                "roles": ["acme:user"],
+                "permissions": ["acme:anvils:**"]
+            }],
+            "roles": [
+                { "name": "acme:admin", "roles": ["acme:user"], "permissions": ["acme:**:**", "!acme:me.delete:**"] },
+                { "name": "acme:user", "roles": null, "permissions": ["acme:me.**:**"] }
+            ]
+        }))
+            .unwrap()
+    }
+
+    fn lines(plan: &Plan) -> Vec<String> {
+        plan.changes.iter().map(|change| format!("{} {}", change.symbol(), change)).collect()
+    }
+
+    #[test]
+    fn test_up_to_date() {
+        let current = realm().normalized();
+        let options = SyncOptions { prune: true, users: true };
+
+        let plan = plan(Some(&current), &realm(), &options).unwrap();
+
+        assert!(plan.is_empty());
+        assert_eq!(plan.to_string(), "realm acme is up to date\n");
+    }
+
+    #[test]
+    fn test_new_realm() {
+        let plan = plan(None, &realm(), &SyncOptions::default()).unwrap();
+
+        assert_eq!(lines(&plan), vec![
+            "+ realm",
+            "+ authority acme:username_password",
+            "+ permission acme:**:**",
+            "+ permission acme:anvils:**",
+            "+ permission acme:me.**:**",
+            "+ permission acme:me.delete:**",
+            "+ role acme:admin",
+            "+ role acme:user",
+            "+ role acme:admin grants acme:**:**",
+            "+ role acme:admin grants !acme:me.delete:**",
+            "+ role acme:admin includes acme:user",
+            "+ role acme:user grants acme:me.**:**",
+        ]);
+    }
+
+    #[test]
+    fn test_drift() {
+        let mut current = realm();
+        current.max_role_depth = Some(4);
+
+        let roles = current.roles.as_mut().unwrap();
+        roles[0].permissions = Some(vec!["acme:**:**".to_string(), "acme:me.delete:**".to_string()]);
+        roles[0].roles = None;
+        roles.push(Role { name: "acme:legacy".to_string(), roles: None, permissions: Some(vec!["acme:old:**".to_string()]) });
+
+        let authority = &mut current.authorities.as_mut().unwrap()[0];
+        authority.params = json!({ "password_salt": "pepper", "lockout": 3 });
+
+        let users = current.users.as_mut().unwrap();
+        users[0].email = None;
+        users[0].roles = Some(vec!["acme:legacy".to_string()]);
+
+        // the file has no salt to give, so the one in the database stays
+        let mut desired = realm();
+        desired.authorities.as_mut().unwrap()[0].params = json!({ "lockout": 5 });
+
+        let current = current.normalized();
+
+        let plan = plan(Some(&current), &desired, &SyncOptions { prune: false, users: true }).unwrap();
+
+        assert_eq!(lines(&plan), vec![
+            "~ realm (max_role_depth = 8)",
+            "~ authority acme:username_password (params)",
+            "- role acme:admin grants acme:me.delete:**",
+            "+ role acme:admin grants !acme:me.delete:**",
+            "+ role acme:admin includes acme:user",
+            "~ user wile (email)",
+            "+ user wile has role acme:user",
+        ]);
+
+        match &plan.changes[1] {
+            Change::UpdateAuthority { authority, .. } => {
+                assert_eq!(authority.params, json!({ "password_salt": "pepper", "lockout": 5 }));
+            },
+            change => panic!("unexpected {:?}", change),
+        }
+
+        let pruned = super::plan(Some(&current), &desired, &SyncOptions { prune: true, users: true }).unwrap();
+
+        assert_eq!(lines(&pruned)[pruned.changes.len() - 3..].to_vec(), vec![
+            "+ user wile has role acme:user",
+            "- permission acme:old:**",
+            "- role acme:legacy",
+        ]);
+        assert!(lines(&pruned).contains(&"- user wile has role acme:legacy".to_string()));
+        assert!(pruned.to_string().ends_with("3 to add, 3 to change, 4 to remove\n"));
+    }
+
+    #[test]
+    fn test_users_left_alone() {
+        let mut current = realm().normalized();
+        current.users = None;
+
+        let plan = plan(Some(&current), &realm(), &SyncOptions { prune: true, users: false }).unwrap();
+
+        assert!(plan.is_empty());
+    }
+
+    // syncs a realm into a real database, then checks a second sync has
+    // nothing left to do
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_sync_database() {
+        let pool = testing::pool().await;
+        let (mut desired, suffix) = testing::unique(&realm());
+
+        desired.users.as_mut().unwrap()[0].password = Some("meep meep".to_string());
+
+        let service = SyncService::new(&pool).unwrap();
+        let options = SyncOptions { prune: true, users: true };
+
+        let plan = service.plan(&desired, &options).await.unwrap();
+        assert!(!plan.is_empty());
+
+        service.apply(&Actor::system(), &plan).await.unwrap();
+        assert!(service.plan(&desired, &options).await.unwrap().is_empty());
+
+        // drop a role and flip a grant
+        let roles = desired.roles.as_mut().unwrap();
+        roles[0].permissions = Some(vec![format!("acme{}:me.delete:**", suffix)]);
+        roles[0].roles = None;
+        roles.pop();
+        desired.users.as_mut().unwrap()[0].roles = None;
+
+        let plan = service.plan(&desired, &options).await.unwrap();
+        assert!(!plan.is_empty());
+
+        service.apply(&Actor::system(), &plan).await.unwrap();
+        assert!(service.plan(&desired, &options).await.unwrap().is_empty());
+    }
+}
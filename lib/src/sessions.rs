@@ -0,0 +1,269 @@
+use chrono::{Duration, NaiveDateTime, Utc};
+use serde_json::json;
+use sqlx::{Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::audit::Actor;
+use crate::db::page::{ListQuery, Page, PageParams, SortKey};
+use crate::db::pg::Pool;
+use crate::result::{Error, Result};
+
+// how long a session lasts without being refreshed
+pub const SESSION_DAYS: i64 = 30;
+
+// last_seen_at is only written this often so every request isn't a write
+const TOUCH_SECONDS: f64 = 60.0;
+
+const SESSION_SORTS: &[SortKey] = &[
+    SortKey::desc("last_seen_at", "timestamp"),
+    SortKey::new("created_at", "timestamp"),
+];
+
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Session {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub realm_id: Uuid,
+    pub device: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub last_seen_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub revoked_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Clone)]
+pub struct SessionService {
+    pool: Pool,
+}
+
+impl SessionService {
+    pub fn new(pool: &Pool) -> Result<Self> {
+        let service = Self {
+            pool: pool.clone(),
+        };
+
+        Ok(service)
+    }
+
+    pub async fn by_id(&self, id: Uuid) -> Result<Session> {
+        let result = sqlx::query_as::<_, Session>(r#"
+            SELECT * FROM sessions
+            WHERE id = $1
+        "#)
+            .bind(id)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(result)
+    }
+
+    // the sessions a user is still signed in with, most recently used first
+    pub async fn by_user_id(&self, user_id: Uuid, page: &PageParams) -> Result<Page<Session>> {
+        ListQuery::new("sessions", SESSION_SORTS)
+            .filter("user_id = ?", Some(user_id))
+            .and("revoked_at IS NULL")
+            .and("expires_at > CURRENT_TIMESTAMP")
+            .fetch(&self.pool, page)
+            .await
+    }
+
+    // opens a session along with the refresh token that keeps it going
+    pub async fn start(&self, actor: &Actor, realm_id: Uuid, user_id: Uuid, device: Option<String>) -> Result<(Session, Uuid)> {
+        let mut tx = self.pool.begin().await?;
+
+        let session = sqlx::query_as::<_, Session>(r#"
+            INSERT INTO sessions (user_id, realm_id, device, ip, user_agent, expires_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING *;
+        "#)
+            .bind(user_id)
+            .bind(realm_id)
+            .bind(device)
+            .bind(&actor.ip)
+            .bind(&actor.user_agent)
+            .bind(expires_at())
+            .fetch_one(&mut tx)
+            .await?;
+
+        let refresh_token = Self::issue_refresh_token(&mut tx, &session).await?;
+
+        actor.event("session.started", "sessions", Some(session.id))
+            .realm(realm_id)
+            .details(json!({ "user_id": user_id, "device": session.device }))
+            .record(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok((session, refresh_token))
+    }
+
+    // trades a refresh token for a new one, pushing the session's expiry out;
+    // each refresh token only works once, and only in the realm it came from
+    pub async fn refresh(&self, actor: &Actor, realm_id: Uuid, refresh_token: Uuid) -> Result<(Session, Uuid)> {
+        let mut tx = self.pool.begin().await?;
+
+        let (session_id,): (Option<Uuid>,) = sqlx::query_as(r#"
+            DELETE FROM refresh_tokens
+            WHERE id = $1
+            AND realm_id = $2
+            AND expires_at > CURRENT_TIMESTAMP
+            RETURNING session_id;
+        "#)
+            .bind(refresh_token)
+            .bind(realm_id)
+            .fetch_optional(&mut tx)
+            .await?
+            .ok_or_else(|| Error::msg("refresh token is invalid or expired"))?;
+
+        let session_id = session_id.ok_or_else(|| Error::msg("refresh token has no session"))?;
+
+        let session = sqlx::query_as::<_, Session>(r#"
+            UPDATE sessions
+            SET
+                ip = COALESCE($2, ip),
+                user_agent = COALESCE($3, user_agent),
+                last_seen_at = CURRENT_TIMESTAMP,
+                expires_at = $4,
+                updated_at = CURRENT_TIMESTAMP
+            WHERE id = $1
+            AND revoked_at IS NULL
+            AND expires_at > CURRENT_TIMESTAMP
+            RETURNING *;
+        "#)
+            .bind(session_id)
+            .bind(&actor.ip)
+            .bind(&actor.user_agent)
+            .bind(expires_at())
+            .fetch_optional(&mut tx)
+            .await?
+            .ok_or_else(|| Error::msg("session has been revoked or has expired"))?;
+
+        let refresh_token = Self::issue_refresh_token(&mut tx, &session).await?;
+
+        tx.commit().await?;
+
+        Ok((session, refresh_token))
+    }
+
+    pub async fn revoke(&self, actor: &Actor, id: Uuid) -> Result<Session> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query_as::<_, Session>(r#"
+            UPDATE sessions
+            SET
+                revoked_at = CURRENT_TIMESTAMP,
+                updated_at = CURRENT_TIMESTAMP
+            WHERE id = $1
+            AND revoked_at IS NULL
+            RETURNING *;
+        "#)
+            .bind(id)
+            .fetch_one(&mut tx)
+            .await?;
+
+        Self::revoked(&mut tx, actor, std::slice::from_ref(&result)).await?;
+
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    // signs a user out everywhere, optionally keeping the session asking
+    pub async fn revoke_all(&self, actor: &Actor, user_id: Uuid, except: Option<Uuid>) -> Result<Vec<Session>> {
+        let mut tx = self.pool.begin().await?;
+
+        let results = sqlx::query_as::<_, Session>(r#"
+            UPDATE sessions
+            SET
+                revoked_at = CURRENT_TIMESTAMP,
+                updated_at = CURRENT_TIMESTAMP
+            WHERE user_id = $1
+            AND revoked_at IS NULL
+            AND ($2::uuid IS NULL OR id <> $2)
+            RETURNING *;
+        "#)
+            .bind(user_id)
+            .bind(except)
+            .fetch_all(&mut tx)
+            .await?;
+
+        Self::revoked(&mut tx, actor, &results).await?;
+
+        tx.commit().await?;
+
+        Ok(results)
+    }
+
+    // whether a token's session can still be used; the Jwt middleware asks
+    // on every request, so this is also where last_seen_at gets bumped
+    pub async fn touch(&self, id: Uuid) -> Result<bool> {
+        let (active,): (bool,) = sqlx::query_as(r#"
+            WITH session AS (
+                SELECT id, last_seen_at FROM sessions
+                WHERE id = $1
+                AND revoked_at IS NULL
+                AND expires_at > CURRENT_TIMESTAMP
+            ), touched AS (
+                UPDATE sessions
+                SET last_seen_at = CURRENT_TIMESTAMP
+                WHERE id IN (
+                    SELECT id FROM session
+                    WHERE last_seen_at < CURRENT_TIMESTAMP - make_interval(secs => $2)
+                )
+            )
+            SELECT EXISTS(SELECT 1 FROM session);
+        "#)
+            .bind(id)
+            .bind(TOUCH_SECONDS)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(active)
+    }
+
+    async fn issue_refresh_token(tx: &mut Transaction<'_, Postgres>, session: &Session) -> Result<Uuid> {
+        let (id,): (Uuid,) = sqlx::query_as(r#"
+            INSERT INTO refresh_tokens (user_id, realm_id, session_id, expires_at)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id;
+        "#)
+            .bind(session.user_id)
+            .bind(session.realm_id)
+            .bind(session.id)
+            .bind(session.expires_at)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        Ok(id)
+    }
+
+    async fn revoked(tx: &mut Transaction<'_, Postgres>, actor: &Actor, sessions: &[Session]) -> Result<()> {
+        let ids: Vec<Uuid> = sessions.iter().map(|session| session.id).collect();
+
+        sqlx::query(r#"
+            DELETE FROM refresh_tokens
+            WHERE session_id = ANY($1)
+        "#)
+            .bind(ids)
+            .execute(&mut *tx)
+            .await?;
+
+        for session in sessions.iter() {
+            actor.event("session.revoked", "sessions", Some(session.id))
+                .realm(session.realm_id)
+                .details(json!({ "user_id": session.user_id }))
+                .record(&mut *tx)
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+fn expires_at() -> NaiveDateTime {
+    (Utc::now() + Duration::days(SESSION_DAYS)).naive_utc()
+}
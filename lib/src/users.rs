@@ -8,12 +8,20 @@
 //     roles: Vec<Role>,
 // }
 
+use std::collections::BTreeMap;
+
 use chrono::NaiveDateTime;
+use sqlx::{Postgres, Transaction};
 use uuid::Uuid;
 use serde_json::value::Value as JsonValue;
 
+use crate::audit::Actor;
+use crate::db::page::{escape_like, ListQuery, Page, PageParams, SortKey};
+use crate::db::patch::{Patch, Precondition, Versioned};
 use crate::db::pg::{Pool, QueryResult};
-use crate::result::Result;
+use crate::grants::GrantService;
+use crate::result::{Error, Result};
+use crate::webhooks;
 
 #[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
 pub struct User {
@@ -27,6 +35,103 @@ pub struct User {
     pub kind: String,
     pub created_at: Option<NaiveDateTime>,
     pub updated_at: Option<NaiveDateTime>,
+    pub deleted_at: Option<NaiveDateTime>,
+}
+
+const USER_SORTS: &[SortKey] = &[
+    SortKey::new("created_at", "timestamp"),
+    SortKey::new("updated_at", "timestamp"),
+    SortKey::new("username", "varchar"),
+];
+
+impl Versioned for User {
+    fn version(&self) -> Option<NaiveDateTime> {
+        self.updated_at
+    }
+}
+
+const SEARCH_SORTS: &[SortKey] = &[
+    SortKey::desc("score", "float8"),
+    SortKey::new("username", "varchar"),
+    SortKey::new("created_at", "timestamp"),
+];
+
+// the profile keys user_search_document takes in; keep the two in step
+pub const SEARCH_PROFILE_KEYS: &[&str] = &["display_name", "nickname", "company", "phone"];
+
+// has to match the indexed expression exactly for the indexes to be used
+const SEARCH_DOCUMENT: &str = "user_search_document(username, email, first_name, last_name, profile)";
+
+const MARK_START: &str = "<mark>";
+const MARK_END: &str = "</mark>";
+
+// `username` and `email` match on a prefix
+#[derive(Debug, Default, Deserialize)]
+pub struct UserFilter {
+    pub realm_id: Option<Uuid>,
+    pub status: Option<String>,
+    pub kind: Option<String>,
+    pub username: Option<String>,
+    pub email: Option<String>,
+}
+
+// `q` is matched as words, as a substring and loosely, so typos still find
+// someone
+#[derive(Debug, Deserialize)]
+pub struct UserSearch {
+    pub q: String,
+    pub status: Option<String>,
+    pub kind: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserMatch {
+    #[serde(flatten)]
+    pub user: User,
+    pub score: f64,
+    // each field the terms were found in, html escaped with the terms wrapped
+    // in <mark>; a match on a typo has nothing to mark
+    pub highlights: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct SearchRow {
+    id: Uuid,
+    username: String,
+    email: Option<String>,
+    first_name: Option<String>,
+    last_name: Option<String>,
+    profile: JsonValue,
+    status: String,
+    kind: String,
+    created_at: Option<NaiveDateTime>,
+    updated_at: Option<NaiveDateTime>,
+    deleted_at: Option<NaiveDateTime>,
+    score: f64,
+}
+
+impl SearchRow {
+    fn into_match(self, terms: &[String]) -> UserMatch {
+        let user = User {
+            id: self.id,
+            username: self.username,
+            email: self.email,
+            first_name: self.first_name,
+            last_name: self.last_name,
+            profile: self.profile,
+            status: self.status,
+            kind: self.kind,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+            deleted_at: self.deleted_at,
+        };
+
+        UserMatch {
+            highlights: highlights(&user, terms),
+            user,
+            score: self.score,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
@@ -40,14 +145,33 @@ pub struct UserCreate {
     pub kind: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, sqlx::FromRow)]
+// fields left out are left alone; null clears the ones that can be empty
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct UserUpdate {
-    pub username: String,
-    pub email: Option<String>,
-    pub first_name: Option<String>,
-    pub last_name: Option<String>,
-    pub profile: JsonValue,
-    pub status: String,
+    pub username: Patch<String>,
+    pub email: Patch<String>,
+    pub first_name: Patch<String>,
+    pub last_name: Patch<String>,
+    pub profile: Patch<JsonValue>,
+    pub status: Patch<String>,
+}
+
+// the fields a user may change about themselves; anything else in the body
+// is rejected rather than quietly ignored
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileUpdate {
+    pub email: Patch<String>,
+    pub first_name: Patch<String>,
+    pub last_name: Patch<String>,
+    pub profile: Patch<JsonValue>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct UserDelete {
+    #[serde(default)]
+    pub anonymize: bool,
 }
 
 #[derive(Clone)]
@@ -64,20 +188,94 @@ impl UserService {
         Ok(service)
     }
 
-    pub async fn all(&self) -> Result<Vec<User>> {
-        let users = sqlx::query_as::<_, User>(r#"
+    pub async fn list(&self, filter: &UserFilter, page: &PageParams) -> Result<Page<User>> {
+        ListQuery::new("users", USER_SORTS)
+            .and("deleted_at IS NULL")
+            .filter("status = ?", filter.status.as_ref())
+            .filter("kind = ?", filter.kind.as_ref())
+            .prefix("username", filter.username.as_ref())
+            .prefix("email", filter.email.as_ref())
+            .filter(r#"
+                EXISTS(SELECT 1 FROM user_authorities WHERE user_id = users.id AND realm_id = ?)
+                OR EXISTS(SELECT 1 FROM user_role_grants WHERE user_id = users.id AND realm_id = ?)
+                OR EXISTS(SELECT 1 FROM user_permission_grants WHERE user_id = users.id AND realm_id = ?)
+            "#, filter.realm_id)
+            .fetch(&self.pool, page)
+            .await
+    }
+
+    // best matches first unless another sort is asked for
+    pub async fn search(&self, realm_id: Uuid, search: &UserSearch, page: &PageParams) -> Result<Page<UserMatch>> {
+        let q = search.q.trim().to_lowercase();
+
+        if q.is_empty() {
+            return Err(Error::msg("a search needs a query"));
+        }
+
+        let source = format!(r#"
+            SELECT users.*, ROUND(GREATEST(
+                ts_rank(to_tsvector('simple', {document}), websearch_to_tsquery('simple', $1)),
+                word_similarity($1, {document})
+            )::numeric, 6)::float8 AS score
+            FROM users
+            WHERE deleted_at IS NULL
+            AND (
+                EXISTS(SELECT 1 FROM user_authorities WHERE user_id = users.id AND realm_id = $3)
+                OR EXISTS(SELECT 1 FROM user_role_grants WHERE user_id = users.id AND realm_id = $3)
+                OR EXISTS(SELECT 1 FROM user_permission_grants WHERE user_id = users.id AND realm_id = $3)
+            )
+            AND (
+                to_tsvector('simple', {document}) @@ websearch_to_tsquery('simple', $1)
+                OR {document} LIKE $2
+                OR $1 <% {document}
+            )
+        "#, document = SEARCH_DOCUMENT);
+
+        let binds = vec![
+            q.as_str().into(),
+            format!("%{}%", escape_like(&q)).into(),
+            realm_id.into(),
+        ];
+
+        let rows = ListQuery::over("matches", source, binds, SEARCH_SORTS)
+            .filter("status = ?", search.status.as_ref())
+            .filter("kind = ?", search.kind.as_ref())
+            .fetch::<SearchRow>(&self.pool, page)
+            .await?;
+
+        let terms = search_terms(&q);
+
+        Ok(Page {
+            items: rows.items.into_iter().map(|row| row.into_match(&terms)).collect(),
+            total: rows.total,
+            next: rows.next,
+        })
+    }
+
+    pub async fn in_realm(&self, realm_id: Uuid, id: Uuid) -> Result<User> {
+        let result = sqlx::query_as::<_, User>(r#"
             SELECT * FROM users
+            WHERE id = $2
+            AND deleted_at IS NULL
+            AND (
+                EXISTS(SELECT 1 FROM user_authorities WHERE user_id = users.id AND realm_id = $1)
+                OR EXISTS(SELECT 1 FROM user_role_grants WHERE user_id = users.id AND realm_id = $1)
+                OR EXISTS(SELECT 1 FROM user_permission_grants WHERE user_id = users.id AND realm_id = $1)
+            )
         "#)
-            .fetch_all(&self.pool)
+            .bind(realm_id)
+            .bind(id)
+            .fetch_one(&self.pool)
             .await?;
 
-        Ok(users)
+        Ok(result)
     }
 
     pub async fn by_id(&self, id: Uuid) -> Result<User> {
         let result = sqlx::query_as::<_, User>(r#"
             SELECT * FROM users
             WHERE id = $1
+            AND deleted_at IS NULL
         "#)
             .bind(id)
             .fetch_one(&self.pool)
@@ -90,6 +288,7 @@ impl UserService {
         let result = sqlx::query_as::<_, User>(r#"
             SELECT * FROM users
             WHERE username = $1
+            AND deleted_at IS NULL
         "#)
             .bind(username)
             .fetch_one(&self.pool)
@@ -117,41 +316,448 @@ impl UserService {
             .bind(user.status)
     }
 
-    pub async fn create(&self, user: UserCreate) -> Result<User> {
+    pub async fn create(&self, actor: &Actor, user: UserCreate) -> Result<User> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = Self::create_in(&mut tx, actor, user).await?;
+
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    pub async fn create_in(tx: &mut Transaction<'_, Postgres>, actor: &Actor, user: UserCreate) -> Result<User> {
         let result = UserService::create_query(user)
-            .fetch_one(&self.pool)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        actor.event("user.created", "users", Some(result.id))
+            .after(&result)
+            .record(&mut *tx)
             .await?;
 
         Ok(result)
     }
 
-    pub async fn update(&self, id: Uuid, user: UserUpdate) -> Result<User> {
+    pub async fn update(&self, actor: &Actor, id: Uuid, user: UserUpdate, precondition: &Precondition) -> Result<User> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = Self::update_in(&mut tx, actor, id, user, precondition).await?;
+
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    pub async fn update_in(tx: &mut Transaction<'_, Postgres>, actor: &Actor, id: Uuid, user: UserUpdate, precondition: &Precondition) -> Result<User> {
+        let username = user.username.required("username")?;
+        let profile = user.profile.required("profile")?;
+        let status = user.status.required("status")?;
+        let (email_set, email) = user.email.parts();
+        let (first_name_set, first_name) = user.first_name.parts();
+        let (last_name_set, last_name) = user.last_name.parts();
+
+        let before = sqlx::query_as::<_, User>(r#"
+            SELECT * FROM users
+            WHERE id = $1
+            AND deleted_at IS NULL
+            FOR UPDATE
+        "#)
+            .bind(id)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        precondition.check(&before)?;
+
         let result = sqlx::query_as::<_, User>(r#"
-            UPDATE users 
+            UPDATE users
             SET
-                username = $2
-                email = $3
-                first_name = $4
-                last_name = $5
-                profile = $6
-                status = $7
+                username = COALESCE($2, username),
+                email = CASE WHEN $3 THEN $4 ELSE email END,
+                first_name = CASE WHEN $5 THEN $6 ELSE first_name END,
+                last_name = CASE WHEN $7 THEN $8 ELSE last_name END,
+                profile = COALESCE($9, profile),
+                status = COALESCE($10, status)
             WHERE id = $1
             RETURNING *;
         "#)
             .bind(id)
-            .bind(user.username)
-            .bind(user.email)
-            .bind(user.first_name)
-            .bind(user.last_name)
-            .bind(user.profile)
-            .bind(user.status)
-            .fetch_one(&self.pool)
+            .bind(username)
+            .bind(email_set)
+            .bind(email)
+            .bind(first_name_set)
+            .bind(first_name)
+            .bind(last_name_set)
+            .bind(last_name)
+            .bind(profile)
+            .bind(status)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        actor.event("user.updated", "users", Some(id))
+            .before(&before)
+            .after(&result)
+            .record(&mut *tx)
+            .await?;
+
+        if before.status != result.status {
+            let data = serde_json::json!({
+                "user_id": id,
+                "from": before.status,
+                "to": result.status,
+            });
+
+            webhooks::enqueue_for_user(&mut *tx, id, webhooks::USER_STATUS_CHANGED, data).await?;
+        }
+
+        Ok(result)
+    }
+
+    pub async fn update_profile(&self, actor: &Actor, id: Uuid, update: ProfileUpdate, precondition: &Precondition) -> Result<User> {
+        let profile = update.profile.required("profile")?;
+        let (email_set, email) = update.email.parts();
+        let (first_name_set, first_name) = update.first_name.parts();
+        let (last_name_set, last_name) = update.last_name.parts();
+
+        let mut tx = self.pool.begin().await?;
+
+        let before = sqlx::query_as::<_, User>(r#"
+            SELECT * FROM users
+            WHERE id = $1
+            AND deleted_at IS NULL
+            FOR UPDATE
+        "#)
+            .bind(id)
+            .fetch_one(&mut tx)
+            .await?;
+
+        precondition.check(&before)?;
+
+        let result = sqlx::query_as::<_, User>(r#"
+            UPDATE users
+            SET
+                email = CASE WHEN $2 THEN $3 ELSE email END,
+                first_name = CASE WHEN $4 THEN $5 ELSE first_name END,
+                last_name = CASE WHEN $6 THEN $7 ELSE last_name END,
+                profile = COALESCE($8, profile)
+            WHERE id = $1
+            RETURNING *;
+        "#)
+            .bind(id)
+            .bind(email_set)
+            .bind(email)
+            .bind(first_name_set)
+            .bind(first_name)
+            .bind(last_name_set)
+            .bind(last_name)
+            .bind(profile)
+            .fetch_one(&mut tx)
             .await?;
 
+        actor.event("user.updated", "users", Some(id))
+            .before(&before)
+            .after(&result)
+            .record(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
         Ok(result)
     }
 
-    pub async fn delete(&self, id: Uuid) -> Result<()> {
-        todo!()
+    pub async fn delete(&self, actor: &Actor, id: Uuid, params: UserDelete) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        let before = sqlx::query_as::<_, User>(r#"
+            SELECT * FROM users
+            WHERE id = $1
+            AND deleted_at IS NULL
+            FOR UPDATE
+        "#)
+            .bind(id)
+            .fetch_one(&mut tx)
+            .await?;
+
+        // goes out while the user's authorities and grants still say which
+        // realms to tell
+        let data = serde_json::json!({
+            "user_id": id,
+            "from": before.status,
+            "to": "deleted",
+        });
+
+        webhooks::enqueue_for_user(&mut tx, id, webhooks::USER_STATUS_CHANGED, data).await?;
+
+        sqlx::query(r#"
+            DELETE FROM user_permission_grants
+            WHERE user_id = $1
+        "#)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+
+        let revoked = sqlx::query_as::<_, (Uuid, JsonValue)>(r#"
+            DELETE FROM user_role_grants
+            WHERE user_id = $1
+            RETURNING realm_id, to_jsonb(user_role_grants.*)
+        "#)
+            .bind(id)
+            .fetch_all(&mut tx)
+            .await?;
+
+        for (realm_id, grant) in revoked.iter() {
+            GrantService::role_revoked(&mut tx, *realm_id, grant, "user_deleted").await?;
+        }
+
+        sqlx::query(r#"
+            DELETE FROM relation_tuples
+            WHERE subject_type = 'user'
+            AND subject_id = $1
+        "#)
+            .bind(id.to_string())
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query(r#"
+            DELETE FROM user_authorities
+            WHERE user_id = $1
+        "#)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query(r#"
+            DELETE FROM refresh_tokens
+            WHERE user_id = $1
+        "#)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+
+        sqlx::query(r#"
+            DELETE FROM sessions
+            WHERE user_id = $1
+        "#)
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+
+        let query = if params.anonymize {
+            r#"
+                UPDATE users
+                SET
+                    username = REPLACE(id::text, '-', ''),
+                    email = NULL,
+                    first_name = NULL,
+                    last_name = NULL,
+                    profile = '{}'::jsonb,
+                    status = 'deleted',
+                    deleted_at = CURRENT_TIMESTAMP
+                WHERE id = $1
+                AND deleted_at IS NULL
+                RETURNING *;
+            "#
+        } else {
+            r#"
+                UPDATE users
+                SET
+                    status = 'deleted',
+                    deleted_at = CURRENT_TIMESTAMP
+                WHERE id = $1
+                AND deleted_at IS NULL
+                RETURNING *;
+            "#
+        };
+
+        let deleted = sqlx::query_as::<_, User>(query)
+            .bind(id)
+            .fetch_one(&mut tx)
+            .await?;
+
+        let event = actor.event("user.deleted", "users", Some(id))
+            .details(serde_json::json!({ "anonymize": params.anonymize }));
+
+        // an anonymized user's details shouldn't live on in the audit log
+        let event = match params.anonymize {
+            true => event,
+            false => event.before(&deleted),
+        };
+
+        event.record(&mut tx).await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+// the words of a query worth marking; websearch syntax like quotes, `or` and
+// `-excluded` is dropped
+fn search_terms(q: &str) -> Vec<String> {
+    q.split_whitespace()
+        .filter(|term| !term.starts_with('-') && *term != "or")
+        .map(|term| term.trim_matches('"').to_lowercase())
+        .filter(|term| !term.is_empty())
+        .collect()
+}
+
+fn highlights(user: &User, terms: &[String]) -> BTreeMap<String, String> {
+    let mut fields = vec![("username".to_string(), Some(user.username.as_str()))];
+
+    fields.push(("email".to_string(), user.email.as_deref()));
+    fields.push(("first_name".to_string(), user.first_name.as_deref()));
+    fields.push(("last_name".to_string(), user.last_name.as_deref()));
+
+    for key in SEARCH_PROFILE_KEYS.iter() {
+        fields.push((format!("profile.{}", key), user.profile[*key].as_str()));
+    }
+
+    fields
+        .into_iter()
+        .filter_map(|(field, text)| {
+            let marked = highlight(text?, terms)?;
+
+            Some((field, marked))
+        })
+        .collect()
+}
+
+// wraps every case-insensitive occurrence of a term in <mark>, preferring the
+// longest term where several start at the same place
+fn highlight(text: &str, terms: &[String]) -> Option<String> {
+    let mut marked = String::with_capacity(text.len());
+    let mut found = false;
+    let mut rest = text;
+
+    while let Some(c) = rest.chars().next() {
+        match terms.iter().filter_map(|term| matched_len(rest, term)).max() {
+            Some(len) => {
+                marked.push_str(MARK_START);
+                marked.push_str(&escape_html(&rest[..len]));
+                marked.push_str(MARK_END);
+
+                rest = &rest[len..];
+                found = true;
+            },
+            None => {
+                marked.push_str(&escape_html(&rest[..c.len_utf8()]));
+
+                rest = &rest[c.len_utf8()..];
+            },
+        }
+    }
+
+    match found {
+        true => Some(marked),
+        false => None,
+    }
+}
+
+// how many bytes at the start of `text` spell out `term`, ignoring case
+fn matched_len(text: &str, term: &str) -> Option<usize> {
+    let mut lowered = String::new();
+
+    for (i, c) in text.char_indices() {
+        lowered.extend(c.to_lowercase());
+
+        if lowered == term {
+            return Some(i + c.len_utf8());
+        }
+
+        if !term.starts_with(&lowered) {
+            return None;
+        }
+    }
+
+    None
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seed::testing;
+    use crate::webhooks::{WebhookDeliveryFilter, WebhookService, WebhookSubscriptionCreate};
+
+    #[test]
+    fn test_highlight() {
+        let terms = search_terms(r#"ann "smith" -bob or"#);
+
+        assert_eq!(terms, vec!["ann", "smith"]);
+        assert_eq!(highlight("Anne Smith", &terms).unwrap(), "<mark>Ann</mark>e <mark>Smith</mark>");
+        assert_eq!(highlight("<ann>", &terms).unwrap(), "&lt;<mark>ann</mark>&gt;");
+        assert_eq!(highlight("Zoë", &["zoë".to_string()]).unwrap(), "<mark>Zoë</mark>");
+        assert_eq!(highlight("anastasia", &terms), None);
+    }
+
+    #[test]
+    fn test_highlights() {
+        let user = User {
+            id: Uuid::new_v4(),
+            username: "jsmith".to_string(),
+            email: Some("jane@smithco.com".to_string()),
+            first_name: Some("Jane".to_string()),
+            last_name: Some("Smith".to_string()),
+            profile: serde_json::json!({ "company": "Smith & Co", "secret": "smith" }),
+            status: "enabled".to_string(),
+            kind: "human".to_string(),
+            created_at: None,
+            updated_at: None,
+            deleted_at: None,
+        };
+
+        let highlights = highlights(&user, &search_terms("smith"));
+
+        assert_eq!(highlights.keys().collect::<Vec<_>>(), vec!["email", "last_name", "profile.company", "username"]);
+        assert_eq!(highlights["profile.company"], "<mark>Smith</mark> &amp; Co");
+    }
+
+    // subscribers hear about the status change and every role the user lost
+    #[actix_rt::test]
+    #[ignore]
+    async fn test_delete_webhooks() {
+        let pool = testing::pool().await;
+        let (mut realm, suffix) = testing::unique(&testing::realm());
+
+        crate::seed::seed(&pool, &mut realm).await.unwrap();
+
+        let realm_id = realm.id.unwrap();
+        let webhooks = WebhookService::new(&pool).unwrap();
+        let subscription = webhooks
+            .create(&Actor::system(), WebhookSubscriptionCreate {
+                realm_id,
+                url: "https://hooks.example.com/oxidauth".to_string(),
+                events: vec![webhooks::USER_STATUS_CHANGED.to_string(), webhooks::USER_ROLE_REVOKED.to_string()],
+                secret: None,
+            })
+            .await
+            .unwrap();
+
+        let users = UserService::new(&pool).unwrap();
+        let wile = users.by_username(format!("wile{}", suffix)).await.unwrap();
+
+        users.delete(&Actor::system(), wile.id, UserDelete::default()).await.unwrap();
+
+        let filter = WebhookDeliveryFilter {
+            realm_id,
+            subscription_id: Some(subscription.subscription.id),
+            status: None,
+        };
+
+        let mut deliveries = webhooks.deliveries(&filter, &PageParams::default()).await.unwrap().items;
+        deliveries.sort_by(|left, right| left.event.cmp(&right.event));
+
+        let events: Vec<&str> = deliveries.iter().map(|delivery| delivery.event.as_str()).collect();
+        assert_eq!(events, vec![webhooks::USER_ROLE_REVOKED, webhooks::USER_STATUS_CHANGED]);
+
+        assert_eq!(deliveries[0].payload["data"]["reason"], "user_deleted");
+        assert_eq!(deliveries[1].payload["data"]["from"], "enabled");
+        assert_eq!(deliveries[1].payload["data"]["to"], "deleted");
     }
 }
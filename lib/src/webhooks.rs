@@ -0,0 +1,837 @@
+use actix_web::client::Client;
+use chrono::{NaiveDateTime, Utc};
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use serde_json::json;
+use serde_json::Value as JsonValue;
+use sqlx::{Executor, Postgres};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::audit::Actor;
+use crate::db::page::{ListQuery, Page, PageParams, SortKey};
+use crate::db::patch::{Patch, Precondition, Versioned};
+use crate::db::pg::Pool;
+use crate::result::{Error, Result};
+
+pub const USER_REGISTERED: &str = "user.registered";
+pub const USER_STATUS_CHANGED: &str = "user.status_changed";
+pub const USER_ROLE_GRANTED: &str = "user.role_granted";
+pub const USER_ROLE_REVOKED: &str = "user.role_revoked";
+
+pub const EVENTS: [&str; 4] = [
+    USER_REGISTERED,
+    USER_STATUS_CHANGED,
+    USER_ROLE_GRANTED,
+    USER_ROLE_REVOKED,
+];
+
+const SUBSCRIPTION_SORTS: &[SortKey] = &[
+    SortKey::new("created_at", "timestamp"),
+];
+
+// newest first, since the recent failures are what someone is looking for
+const DELIVERY_SORTS: &[SortKey] = &[
+    SortKey::desc("created_at", "timestamp"),
+    SortKey::new("next_attempt_at", "timestamp"),
+];
+
+pub const SIGNATURE_HEADER: &str = "X-Oxidauth-Signature";
+pub const EVENT_HEADER: &str = "X-Oxidauth-Event";
+pub const DELIVERY_HEADER: &str = "X-Oxidauth-Delivery";
+
+const MAX_ATTEMPTS: i32 = 10;
+const BATCH_SIZE: i64 = 50;
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// how long a claimed delivery is hidden from other workers; if the worker
+// dies mid-batch the delivery comes back around after this. A batch posts
+// one delivery at a time, so the lease has to outlast every one of them
+// timing out, with a margin for the bookkeeping in between
+const LEASE_SECONDS: i64 = BATCH_SIZE * TIMEOUT.as_secs() as i64 + 60;
+
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct WebhookSubscription {
+    pub id: Uuid,
+    pub realm_id: Uuid,
+    pub url: String,
+    #[serde(skip_serializing)]
+    pub secret: String,
+    pub events: Vec<String>,
+    pub status: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Versioned for WebhookSubscription {
+    fn version(&self) -> Option<NaiveDateTime> {
+        Some(self.updated_at)
+    }
+}
+
+// the secret is only ever handed back when the subscription is created
+#[derive(Debug, Serialize)]
+pub struct CreatedWebhookSubscription {
+    #[serde(flatten)]
+    pub subscription: WebhookSubscription,
+    pub secret: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookSubscriptionCreate {
+    pub realm_id: Uuid,
+    pub url: String,
+    pub events: Vec<String>,
+    pub secret: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebhookSubscriptionUpdate {
+    pub url: Patch<String>,
+    pub events: Patch<Vec<String>>,
+    pub status: Patch<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, sqlx::FromRow)]
+pub struct WebhookDelivery {
+    pub id: Uuid,
+    pub subscription_id: Uuid,
+    pub realm_id: Uuid,
+    pub event: String,
+    pub payload: JsonValue,
+    pub status: String,
+    pub attempts: i32,
+    pub next_attempt_at: NaiveDateTime,
+    pub last_status: Option<i32>,
+    pub last_error: Option<String>,
+    pub delivered_at: Option<NaiveDateTime>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WebhookDeliveryFilter {
+    pub realm_id: Uuid,
+    pub subscription_id: Option<Uuid>,
+    // `dead` for the dead letters
+    pub status: Option<String>,
+}
+
+#[derive(Debug, sqlx::FromRow)]
+struct DueDelivery {
+    id: Uuid,
+    event: String,
+    payload: JsonValue,
+    attempts: i32,
+    url: String,
+    secret: String,
+}
+
+// the body every subscriber receives; `id` is stable across retries so
+// receivers can drop duplicates
+fn payload(event: &str, data: JsonValue) -> JsonValue {
+    json!({
+        "id": Uuid::new_v4(),
+        "event": event,
+        "occurred_at": Utc::now().naive_utc(),
+        "data": data,
+    })
+}
+
+// queue `event` for every enabled subscription in the realm that wants it;
+// pass the transaction making the change so nothing goes out for a change
+// that was rolled back
+pub async fn enqueue<'c, E>(executor: E, realm_id: Uuid, event: &str, data: JsonValue) -> Result<usize>
+where
+    E: Executor<'c, Database = Postgres>,
+{
+    let queued = sqlx::query_as::<_, (Uuid,)>(r#"
+        INSERT INTO webhook_deliveries (subscription_id, realm_id, event, payload)
+        SELECT id, realm_id, $2, jsonb_set($3, '{realm_id}', to_jsonb(realm_id))
+        FROM webhook_subscriptions
+        WHERE realm_id = $1
+        AND status = 'enabled'
+        AND $2 = ANY(events)
+        RETURNING id;
+    "#)
+        .bind(realm_id)
+        .bind(event)
+        .bind(payload(event, data))
+        .fetch_all(executor)
+        .await?;
+
+    Ok(queued.len())
+}
+
+// users aren't scoped to a realm, so a change to one goes out to every realm
+// they have an authority or a grant in
+pub async fn enqueue_for_user<'c, E>(executor: E, user_id: Uuid, event: &str, data: JsonValue) -> Result<usize>
+where
+    E: Executor<'c, Database = Postgres>,
+{
+    let queued = sqlx::query_as::<_, (Uuid,)>(r#"
+        INSERT INTO webhook_deliveries (subscription_id, realm_id, event, payload)
+        SELECT id, realm_id, $2, jsonb_set($3, '{realm_id}', to_jsonb(realm_id))
+        FROM webhook_subscriptions
+        WHERE realm_id IN (
+            SELECT realm_id FROM user_authorities WHERE user_id = $1
+            UNION SELECT realm_id FROM user_role_grants WHERE user_id = $1
+            UNION SELECT realm_id FROM user_permission_grants WHERE user_id = $1
+        )
+        AND status = 'enabled'
+        AND $2 = ANY(events)
+        RETURNING id;
+    "#)
+        .bind(user_id)
+        .bind(event)
+        .bind(payload(event, data))
+        .fetch_all(executor)
+        .await?;
+
+    Ok(queued.len())
+}
+
+// `t=<unix seconds>,v1=<hex hmac-sha256 of "<t>.<body>">`; the timestamp is
+// part of what's signed so a captured request can't be replayed later
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> Result<String> {
+    let key = PKey::hmac(secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+
+    signer.update(timestamp.to_string().as_bytes())?;
+    signer.update(b".")?;
+    signer.update(body)?;
+
+    Ok(format!("t={},v1={}", timestamp, hex(&signer.sign_to_vec()?)))
+}
+
+// 30s, 1m, 2m, ... capped at six hours
+pub fn backoff(attempts: i32) -> Duration {
+    let exponent = attempts.clamp(1, 16) as u32 - 1;
+
+    Duration::from_secs((30 * 2u64.pow(exponent)).min(6 * 60 * 60))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn generate_secret() -> Result<String> {
+    let mut bytes = [0u8; 32];
+
+    openssl::rand::rand_bytes(&mut bytes)?;
+
+    Ok(hex(&bytes))
+}
+
+// the host and port a webhook url delivers to, with the brackets taken off
+// an IPv6 literal; anything but https is refused
+fn destination(url: &str) -> Option<(String, u16)> {
+    let rest = url.strip_prefix("https://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+
+    // credentials in the url only serve to disguise where it really goes
+    if authority.contains('@') {
+        return None;
+    }
+
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(literal) => {
+            let end = literal.find(']')?;
+            let after = &literal[end + 1..];
+
+            if !after.is_empty() && !after.starts_with(':') {
+                return None;
+            }
+
+            (&literal[..end], after.strip_prefix(':'))
+        },
+        None => match authority.rfind(':') {
+            Some(i) => (&authority[..i], Some(&authority[i + 1..])),
+            None => (authority, None),
+        },
+    };
+
+    if host.is_empty() {
+        return None;
+    }
+
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => 443,
+    };
+
+    Some((host.trim_end_matches('.').to_lowercase(), port))
+}
+
+// loopback, private, link-local and the other ranges that only make sense
+// from inside the network the server runs in
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, _, _] = ip.octets();
+
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || a == 0
+                // shared address space, 100.64.0.0/10
+                || (a == 100 && (64..128).contains(&b))
+        },
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_internal(IpAddr::V4(ip));
+            }
+
+            let first = ip.segments()[0];
+
+            ip.is_loopback()
+                || ip.is_unspecified()
+                // unique local, fc00::/7
+                || (first & 0xfe00) == 0xfc00
+                // link-local, fe80::/10
+                || (first & 0xffc0) == 0xfe80
+        },
+    }
+}
+
+fn is_internal_host(host: &str) -> bool {
+    match host.parse::<IpAddr>() {
+        Ok(ip) => is_internal(ip),
+        Err(_) => {
+            host == "localhost"
+                || [".localhost", ".local", ".internal"]
+                    .iter()
+                    .any(|suffix| host.ends_with(suffix))
+        },
+    }
+}
+
+fn validate_subscription(url: &str, events: &[String]) -> Result<()> {
+    // a realm admin shouldn't be able to aim deliveries at the network the
+    // server sits in, and the signed payloads shouldn't travel in the clear.
+    // this only catches literal addresses and well known internal names;
+    // where a name resolves to is checked on every delivery
+    let (host, _) = destination(url)
+        .ok_or_else(|| Error::msg(format!("webhook url must be https: {}", url)))?;
+
+    if is_internal_host(&host) {
+        return Err(Error::msg(format!("webhook url points at an internal host: {}", url)));
+    }
+
+    if events.is_empty() {
+        return Err(Error::msg("a webhook needs at least one event"));
+    }
+
+    for event in events.iter() {
+        if !EVENTS.contains(&event.as_str()) {
+            return Err(Error::msg(format!("unknown webhook event: {}", event)));
+        }
+    }
+
+    Ok(())
+}
+
+// a name can resolve somewhere different from when it was subscribed, so
+// each delivery looks it up again and refuses internal addresses
+async fn ensure_external(url: &str) -> Result<()> {
+    let (host, port) = destination(url)
+        .ok_or_else(|| Error::msg(format!("webhook url must be https: {}", url)))?;
+
+    if is_internal_host(&host) {
+        return Err(Error::msg(format!("webhook url points at an internal host: {}", url)));
+    }
+
+    let addrs = actix_web::web::block(move || {
+        (host.as_str(), port)
+            .to_socket_addrs()
+            .map(|addrs| addrs.collect::<Vec<SocketAddr>>())
+    })
+        .await
+        .map_err(|err| Error::msg(format!("unable to resolve {}: {}", url, err)))?;
+
+    if addrs.iter().any(|addr| is_internal(addr.ip())) {
+        return Err(Error::msg(format!("webhook url resolves to an internal address: {}", url)));
+    }
+
+    Ok(())
+}
+
+// a 2xx is the only thing that counts as delivered
+async fn post(client: &Client, url: &str, secret: &str, id: Uuid, event: &str, payload: &JsonValue) -> Result<u16> {
+    let body = serde_json::to_vec(payload)?;
+    let signature = sign(secret, Utc::now().timestamp(), &body)?;
+
+    let response = client
+        .post(url)
+        .content_type("application/json")
+        .header(SIGNATURE_HEADER, signature)
+        .header(EVENT_HEADER, event)
+        .header(DELIVERY_HEADER, id.to_string())
+        .send_body(body)
+        .await
+        .map_err(|err| Error::msg(format!("unable to reach {}: {}", url, err)))?;
+
+    let status = response.status();
+
+    if !status.is_success() {
+        return Err(Error::msg(format!("{} responded with {}", url, status.as_u16())));
+    }
+
+    Ok(status.as_u16())
+}
+
+#[derive(Clone)]
+pub struct WebhookService {
+    pool: Pool,
+}
+
+impl WebhookService {
+    pub fn new(pool: &Pool) -> Result<Self> {
+        let service = Self {
+            pool: pool.clone(),
+        };
+
+        Ok(service)
+    }
+
+    pub async fn subscriptions(&self, realm_id: Uuid, page: &PageParams) -> Result<Page<WebhookSubscription>> {
+        ListQuery::new("webhook_subscriptions", SUBSCRIPTION_SORTS)
+            .filter("realm_id = ?", Some(realm_id))
+            .fetch(&self.pool, page)
+            .await
+    }
+
+    pub async fn subscription(&self, id: Uuid) -> Result<WebhookSubscription> {
+        let result = sqlx::query_as::<_, WebhookSubscription>(r#"
+            SELECT * FROM webhook_subscriptions
+            WHERE id = $1
+        "#)
+            .bind(id)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(result)
+    }
+
+    pub async fn create(&self, actor: &Actor, subscription: WebhookSubscriptionCreate) -> Result<CreatedWebhookSubscription> {
+        validate_subscription(&subscription.url, &subscription.events)?;
+
+        let secret = match subscription.secret {
+            Some(secret) => secret,
+            None => generate_secret()?,
+        };
+
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query_as::<_, WebhookSubscription>(r#"
+            INSERT INTO webhook_subscriptions (realm_id, url, secret, events)
+            VALUES ($1, $2, $3, $4)
+            RETURNING *;
+        "#)
+            .bind(subscription.realm_id)
+            .bind(subscription.url)
+            .bind(&secret)
+            .bind(subscription.events)
+            .fetch_one(&mut tx)
+            .await?;
+
+        actor.event("webhook.created", "webhook_subscriptions", Some(result.id))
+            .realm(result.realm_id)
+            .after(&result)
+            .record(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(CreatedWebhookSubscription { subscription: result, secret })
+    }
+
+    pub async fn update(
+        &self,
+        actor: &Actor,
+        id: Uuid,
+        subscription: WebhookSubscriptionUpdate,
+        precondition: &Precondition,
+    ) -> Result<WebhookSubscription> {
+        let url = subscription.url.required("url")?;
+        let events = subscription.events.required("events")?;
+        let status = subscription.status.required("status")?;
+
+        let mut tx = self.pool.begin().await?;
+
+        let before = sqlx::query_as::<_, WebhookSubscription>(r#"
+            SELECT * FROM webhook_subscriptions
+            WHERE id = $1
+            FOR UPDATE
+        "#)
+            .bind(id)
+            .fetch_one(&mut tx)
+            .await?;
+
+        precondition.check(&before)?;
+
+        validate_subscription(
+            url.as_ref().unwrap_or(&before.url),
+            events.as_ref().unwrap_or(&before.events),
+        )?;
+
+        let result = sqlx::query_as::<_, WebhookSubscription>(r#"
+            UPDATE webhook_subscriptions
+            SET
+                url = COALESCE($2, url),
+                events = COALESCE($3, events),
+                status = COALESCE($4, status)
+            WHERE id = $1
+            RETURNING *;
+        "#)
+            .bind(id)
+            .bind(url)
+            .bind(events)
+            .bind(status)
+            .fetch_one(&mut tx)
+            .await?;
+
+        actor.event("webhook.updated", "webhook_subscriptions", Some(id))
+            .realm(result.realm_id)
+            .before(&before)
+            .after(&result)
+            .record(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    pub async fn delete(&self, actor: &Actor, id: Uuid) -> Result<WebhookSubscription> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query_as::<_, WebhookSubscription>(r#"
+            DELETE FROM webhook_subscriptions
+            WHERE id = $1
+            RETURNING *;
+        "#)
+            .bind(id)
+            .fetch_one(&mut tx)
+            .await?;
+
+        actor.event("webhook.deleted", "webhook_subscriptions", Some(id))
+            .realm(result.realm_id)
+            .before(&result)
+            .record(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    pub async fn delivery(&self, id: Uuid) -> Result<WebhookDelivery> {
+        let result = sqlx::query_as::<_, WebhookDelivery>(r#"
+            SELECT * FROM webhook_deliveries
+            WHERE id = $1
+        "#)
+            .bind(id)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(result)
+    }
+
+    pub async fn deliveries(&self, filter: &WebhookDeliveryFilter, page: &PageParams) -> Result<Page<WebhookDelivery>> {
+        ListQuery::new("webhook_deliveries", DELIVERY_SORTS)
+            .filter("realm_id = ?", Some(filter.realm_id))
+            .filter("subscription_id = ?", filter.subscription_id)
+            .filter("status = ?", filter.status.as_ref())
+            .fetch(&self.pool, page)
+            .await
+    }
+
+    // puts a dead (or already delivered) delivery back in the queue with a
+    // fresh set of attempts
+    pub async fn replay(&self, actor: &Actor, id: Uuid) -> Result<WebhookDelivery> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query_as::<_, WebhookDelivery>(r#"
+            UPDATE webhook_deliveries
+            SET
+                status = 'pending',
+                attempts = 0,
+                next_attempt_at = CURRENT_TIMESTAMP,
+                last_error = NULL,
+                updated_at = CURRENT_TIMESTAMP
+            WHERE id = $1
+            AND status IN ('dead', 'delivered')
+            RETURNING *;
+        "#)
+            .bind(id)
+            .fetch_optional(&mut tx)
+            .await?
+            .ok_or_else(|| Error::msg(format!("no dead or delivered webhook delivery {}", id)))?;
+
+        actor.event("webhook.replayed", "webhook_deliveries", Some(id))
+            .realm(result.realm_id)
+            .details(json!({ "subscription_id": result.subscription_id, "event": result.event }))
+            .record(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(result)
+    }
+
+    // works through one batch of due deliveries, returning how many went out
+    pub async fn deliver_due(&self) -> Result<usize> {
+        let due = self.claim_due().await?;
+
+        if due.is_empty() {
+            return Ok(0);
+        }
+
+        let client = Client::builder().timeout(TIMEOUT).finish();
+        let mut delivered = 0;
+
+        for delivery in due.iter() {
+            let result = match ensure_external(&delivery.url).await {
+                Ok(()) => post(&client, &delivery.url, &delivery.secret, delivery.id, &delivery.event, &delivery.payload).await,
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(status) => {
+                    self.mark_delivered(delivery.id, status).await?;
+                    delivered += 1;
+                },
+                Err(err) => {
+                    log::warn!("webhook delivery {} failed: {}", delivery.id, err);
+                    self.mark_failed(delivery, &err.to_string()).await?;
+                },
+            }
+        }
+
+        Ok(delivered)
+    }
+
+    // pushing next_attempt_at out doubles as the lock, so several workers
+    // can share the table without sending anything twice
+    async fn claim_due(&self) -> Result<Vec<DueDelivery>> {
+        let results = sqlx::query_as::<_, DueDelivery>(r#"
+            UPDATE webhook_deliveries d
+            SET next_attempt_at = CURRENT_TIMESTAMP + make_interval(secs => $2)
+            FROM webhook_subscriptions s
+            WHERE s.id = d.subscription_id
+            AND d.id IN (
+                SELECT id FROM webhook_deliveries
+                WHERE status = 'pending'
+                AND next_attempt_at <= CURRENT_TIMESTAMP
+                ORDER BY next_attempt_at
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING d.id, d.event, d.payload, d.attempts, s.url, s.secret;
+        "#)
+            .bind(BATCH_SIZE)
+            .bind(LEASE_SECONDS as f64)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(results)
+    }
+
+    async fn mark_delivered(&self, id: Uuid, status: u16) -> Result<()> {
+        sqlx::query(r#"
+            UPDATE webhook_deliveries
+            SET
+                status = 'delivered',
+                attempts = attempts + 1,
+                last_status = $2,
+                last_error = NULL,
+                delivered_at = CURRENT_TIMESTAMP,
+                updated_at = CURRENT_TIMESTAMP
+            WHERE id = $1
+        "#)
+            .bind(id)
+            .bind(status as i32)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    // backs off until MAX_ATTEMPTS, then the delivery is dead until replayed
+    async fn mark_failed(&self, delivery: &DueDelivery, error: &str) -> Result<()> {
+        let attempts = delivery.attempts + 1;
+        let status = if attempts >= MAX_ATTEMPTS { "dead" } else { "pending" };
+
+        sqlx::query(r#"
+            UPDATE webhook_deliveries
+            SET
+                status = $2,
+                attempts = $3,
+                next_attempt_at = CURRENT_TIMESTAMP + make_interval(secs => $4),
+                last_error = $5,
+                updated_at = CURRENT_TIMESTAMP
+            WHERE id = $1
+        "#)
+            .bind(delivery.id)
+            .bind(status)
+            .bind(attempts)
+            .bind(backoff(attempts).as_secs() as f64)
+            .bind(error)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+
+    // answers a single request with `status` and hands back what it received
+    fn receiver(status: &'static str) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks", listener.local_addr().unwrap());
+        let (sender, received) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 4096];
+
+            loop {
+                let read = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..read]);
+
+                let text = String::from_utf8_lossy(&request).to_string();
+
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length = text[..end]
+                        .lines()
+                        .find_map(|line| {
+                            let (name, value) = line.split_at(line.find(':')?);
+                            match name.eq_ignore_ascii_case("content-length") {
+                                true => value[1..].trim().parse::<usize>().ok(),
+                                false => None,
+                            }
+                        })
+                        .unwrap_or(0);
+
+                    if request.len() >= end + 4 + length || read == 0 {
+                        break;
+                    }
+                }
+            }
+
+            let response = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+            stream.write_all(response.as_bytes()).unwrap();
+
+            sender.send(String::from_utf8_lossy(&request).to_string()).unwrap();
+        });
+
+        (url, received)
+    }
+
+    #[test]
+    fn test_sign() {
+        let signature = sign("secret", 1646136000, b"{}").unwrap();
+
+        assert!(signature.starts_with("t=1646136000,v1="));
+        assert_eq!(signature.len(), "t=1646136000,v1=".len() + 64);
+        assert_eq!(signature, sign("secret", 1646136000, b"{}").unwrap());
+        assert_ne!(signature, sign("other", 1646136000, b"{}").unwrap());
+        assert_ne!(signature, sign("secret", 1646136001, b"{}").unwrap());
+    }
+
+    #[test]
+    fn test_validate_subscription() {
+        let events = vec![USER_REGISTERED.to_string()];
+
+        assert!(validate_subscription("https://hooks.example.com/oxidauth", &events).is_ok());
+        assert!(validate_subscription("http://hooks.example.com/oxidauth", &events).is_err());
+        assert!(validate_subscription("http://169.254.169.254/latest", &events).is_err());
+        assert!(validate_subscription("https:///oxidauth", &events).is_err());
+        assert!(validate_subscription("ftp://hooks.example.com", &events).is_err());
+        assert!(validate_subscription("https://10.0.0.1/", &events).is_err());
+        assert!(validate_subscription("https://localhost/", &events).is_err());
+        assert!(validate_subscription("https://metadata.internal/", &events).is_err());
+        assert!(validate_subscription("https://169.254.169.254/latest", &events).is_err());
+        assert!(validate_subscription("https://[::1]/", &events).is_err());
+        assert!(validate_subscription("https://[::ffff:127.0.0.1]:8443/", &events).is_err());
+        assert!(validate_subscription("https://[fd00::1]/", &events).is_err());
+        assert!(validate_subscription("https://100.64.0.1/", &events).is_err());
+        assert!(validate_subscription("https://LOCALHOST./", &events).is_err());
+        assert!(validate_subscription("https://hooks.example.com@127.0.0.1/", &events).is_err());
+        assert!(validate_subscription("https://hooks.example.com:8443/oxidauth", &events).is_ok());
+        assert!(validate_subscription("https://93.184.216.34/oxidauth", &events).is_ok());
+        assert!(validate_subscription("https://hooks.example.com", &[]).is_err());
+        assert!(validate_subscription("https://hooks.example.com", &["user.unknown".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(30));
+        assert_eq!(backoff(2), Duration::from_secs(60));
+        assert_eq!(backoff(5), Duration::from_secs(480));
+        assert_eq!(backoff(MAX_ATTEMPTS * 10), Duration::from_secs(6 * 60 * 60));
+    }
+
+    #[actix_rt::test]
+    async fn test_ensure_external() {
+        // 2130706433 is 127.0.0.1 written as a single number, which the
+        // resolver accepts but the subscription check can't see through
+        let events = vec![USER_REGISTERED.to_string()];
+
+        assert!(validate_subscription("https://2130706433/", &events).is_ok());
+        assert!(ensure_external("https://2130706433/").await.is_err());
+        assert!(ensure_external("https://127.0.0.1/").await.is_err());
+        assert!(ensure_external("https://93.184.216.34/").await.is_ok());
+    }
+
+    #[actix_rt::test]
+    async fn test_post_signs_the_body() {
+        let (url, received) = receiver("204 No Content");
+        let id = Uuid::new_v4();
+        let body = payload(USER_REGISTERED, json!({ "user_id": id }));
+
+        let status = post(&Client::default(), &url, "secret", id, USER_REGISTERED, &body).await.unwrap();
+        assert_eq!(status, 204);
+
+        let request = received.recv().unwrap();
+        let (head, sent) = request.split_at(request.find("\r\n\r\n").unwrap() + 4);
+        let head = head.to_lowercase();
+
+        assert!(head.starts_with("post /hooks "));
+        assert!(head.contains(&format!("{}: {}", EVENT_HEADER.to_lowercase(), USER_REGISTERED)));
+        assert!(head.contains(&format!("{}: {}", DELIVERY_HEADER.to_lowercase(), id)));
+        assert_eq!(serde_json::from_str::<JsonValue>(sent).unwrap(), body);
+
+        let signature = head
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!("{}: ", SIGNATURE_HEADER.to_lowercase())))
+            .unwrap();
+        let timestamp = signature[2..signature.find(',').unwrap()].parse::<i64>().unwrap();
+
+        assert_eq!(signature, sign("secret", timestamp, sent.as_bytes()).unwrap());
+    }
+
+    #[actix_rt::test]
+    async fn test_post_fails_on_error_status() {
+        let (url, _received) = receiver("500 Internal Server Error");
+        let body = payload(USER_REGISTERED, json!({}));
+
+        let result = post(&Client::default(), &url, "secret", Uuid::new_v4(), USER_REGISTERED, &body).await;
+
+        assert!(result.is_err());
+    }
+}